@@ -0,0 +1,267 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Deserialize;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::{config::CategoriesConfig, services::parse_rule};
+
+/// Buckets flows into categories like streaming or gaming by the rules in
+/// a TOML file, which is checked every now and then and read again once it
+/// changes. Rules are tried in order and the first one matching wins:
+///
+/// ```toml
+/// [[category]]
+/// name = "streaming"
+/// asns = [2906]
+/// as_names = ["*NETFLIX*"]
+/// ports = ["1935/tcp"]
+/// hosts = ["*.nflxvideo.net"]
+/// ```
+///
+/// A rule matches flows with any of the ASNs, AS names, server ports or
+/// hostnames listed. Names and hostnames are matched case-insensitively,
+/// with `*` for any number of characters.
+pub struct Categories {
+    enabled: bool,
+    path: PathBuf,
+    reload: Duration,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    category: Vec<RawRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    name: String,
+    #[serde(default)]
+    asns: Vec<u32>,
+    #[serde(default)]
+    as_names: Vec<String>,
+    #[serde(default)]
+    ports: Vec<String>,
+    #[serde(default)]
+    hosts: Vec<String>,
+}
+
+struct Rule {
+    name: String,
+    asns: Vec<u32>,
+    as_names: Vec<String>,
+    ports: Vec<(u16, Option<u8>)>,
+    hosts: Vec<String>,
+}
+
+/// What flows are matched on.
+pub struct Flow<'a> {
+    pub asn: Option<u32>,
+    pub as_name: &'a str,
+    pub protocol: u8,
+    pub port: u16,
+    /// Hostnames of the server, from enterprise fields.
+    pub hosts: Vec<&'a str>,
+}
+
+impl Categories {
+    pub fn new(config: &CategoriesConfig) -> Self {
+        let mut categories = Self {
+            enabled: config.enabled,
+            path: config.path.clone(),
+            reload: config.reload,
+            modified: None,
+            last_check: Instant::now(),
+            rules: Vec::new(),
+        };
+
+        categories.load();
+
+        categories
+    }
+
+    pub fn reconfigure(&mut self, config: &CategoriesConfig) {
+        let changed = config.enabled != self.enabled || config.path != self.path;
+
+        self.enabled = config.enabled;
+        self.path.clone_from(&config.path);
+        self.reload = config.reload;
+
+        if changed {
+            self.modified = None;
+            self.load();
+        }
+    }
+
+    /// Reads the rules again if the file changed since the last check.
+    pub fn refresh(&mut self) {
+        if !self.enabled || self.last_check.elapsed() < self.reload {
+            return;
+        }
+
+        self.load();
+    }
+
+    /// Category of the flow, empty if no rule matches.
+    pub fn category(&self, flow: &Flow) -> &str {
+        if self.rules.is_empty() {
+            return "";
+        }
+
+        // Patterns are lowercase already.
+        let as_name = flow.as_name.to_lowercase();
+        let hosts = flow
+            .hosts
+            .iter()
+            .map(|host| host.to_lowercase())
+            .collect::<Vec<_>>();
+
+        let flow = Flow {
+            as_name: &as_name,
+            hosts: hosts.iter().map(String::as_str).collect(),
+            ..*flow
+        };
+
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&flow))
+            .map(|rule| rule.name.as_str())
+            .unwrap_or_default()
+    }
+
+    fn load(&mut self) {
+        self.last_check = Instant::now();
+
+        if !self.enabled {
+            self.rules.clear();
+            return;
+        }
+
+        let path = &self.path;
+
+        let modified = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                warn!(target: "categories", "Cannot read {}: {e}", path.display());
+                return;
+            }
+        };
+
+        if self.modified == Some(modified) {
+            return;
+        }
+
+        self.modified = Some(modified);
+
+        // Rules of the last good file are kept until the mistake is fixed.
+        let rules = block_in_place(|| fs::read_to_string(path))
+            .map_err(|e| e.to_string())
+            .and_then(|contents| parse(&contents));
+
+        match rules {
+            Ok(rules) => {
+                info!(
+                    target: "categories",
+                    "Loaded {} category rules from {}",
+                    rules.len(),
+                    path.display()
+                );
+
+                self.rules = rules;
+            }
+            Err(e) => warn!(target: "categories", "Cannot load {}: {e}", path.display()),
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, flow: &Flow) -> bool {
+        flow.asn.is_some_and(|asn| self.asns.contains(&asn))
+            || self
+                .as_names
+                .iter()
+                .any(|pattern| matches_glob(pattern, flow.as_name))
+            || self.ports.iter().any(|(port, protocol)| {
+                *port == flow.port && protocol.is_none_or(|protocol| protocol == flow.protocol)
+            })
+            || self
+                .hosts
+                .iter()
+                .any(|pattern| flow.hosts.iter().any(|host| matches_glob(pattern, host)))
+    }
+}
+
+fn parse(contents: &str) -> Result<Vec<Rule>, String> {
+    let file = toml::from_str::<RulesFile>(contents).map_err(|e| e.to_string())?;
+
+    file.category
+        .into_iter()
+        .map(|rule| {
+            let ports = rule
+                .ports
+                .iter()
+                .map(|port| parse_rule(port).ok_or_else(|| format!("invalid port: {port}")))
+                .collect::<Result<_, _>>()?;
+
+            let lowercase = |patterns: Vec<String>| {
+                patterns
+                    .into_iter()
+                    .map(|pattern| pattern.to_lowercase())
+                    .collect()
+            };
+
+            Ok(Rule {
+                name: rule.name,
+                asns: rule.asns,
+                as_names: lowercase(rule.as_names),
+                ports,
+                hosts: lowercase(rule.hosts),
+            })
+        })
+        .collect()
+}
+
+/// Matches patterns with `*` for any number of characters.
+fn matches_glob(pattern: &str, text: &str) -> bool {
+    if text.is_empty() {
+        return false;
+    }
+
+    let mut parts = pattern.split('*');
+
+    // Without a star, the only part has to match the whole text.
+    let first = parts.next().unwrap_or_default();
+
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.peekable();
+
+    if parts.peek().is_none() {
+        return rest.is_empty();
+    }
+
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    true
+}
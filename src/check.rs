@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use tokio::net::lookup_host;
+
+use crate::{config::Config, row::SCHEMA, tls};
+
+/// Validates the configuration against the environment, printing a report.
+/// Returns the process exit code: zero if everything checks out.
+pub async fn run(config: &Config) -> i32 {
+    let mut failures = 0;
+
+    let mut report = |what: String, result: Result<String, String>| match result {
+        Ok(details) => println!("ok     {what}: {details}"),
+        Err(details) => {
+            failures += 1;
+            println!("FAILED {what}: {details}");
+        }
+    };
+
+    for listener in &config.ipfix.bind {
+        report(
+            format!("ipfix listener {}", listener.name),
+            resolve(&listener.addr).await,
+        );
+    }
+
+    for listener in &config.ipfix.tcp_bind {
+        report(
+            format!("ipfix tcp listener {}", listener.name),
+            resolve(&listener.addr).await,
+        );
+    }
+
+    for listener in &config.ipfix.tls_bind {
+        report(
+            format!("ipfix tls listener {}", listener.name),
+            resolve(&listener.addr).await,
+        );
+    }
+
+    for listener in &config.ipfix.dtls_bind {
+        report(
+            format!("ipfix dtls listener {}", listener.name),
+            resolve(&listener.addr).await,
+        );
+    }
+
+    for listener in &config.sflow.bind {
+        report(
+            format!("sflow listener {}", listener.name),
+            resolve(&listener.addr).await,
+        );
+    }
+
+    if !config.ipfix.tls_bind.is_empty() || !config.ipfix.dtls_bind.is_empty() {
+        report(
+            "tls certificates".to_owned(),
+            tls::acceptor(&config.tls)
+                .map(|_| "loaded".to_owned())
+                .map_err(|e| e.to_string()),
+        );
+    }
+
+    report(
+        "metrics listener".to_owned(),
+        resolve(&config.metrics.bind).await,
+    );
+
+    if config.clickhouse.enabled {
+        report(
+            format!(
+                "clickhouse table {}.{}",
+                config.clickhouse.database, config.clickhouse.table
+            ),
+            check_schema(config).await,
+        );
+    }
+
+    if failures > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+async fn resolve(addr: &str) -> Result<String, String> {
+    let addrs = lookup_host(addr)
+        .await
+        .map_err(|e| format!("cannot resolve {addr}: {e}"))?
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>();
+
+    if addrs.is_empty() {
+        return Err(format!("{addr} resolves to nothing"));
+    }
+
+    Ok(addrs.join(", "))
+}
+
+async fn check_schema(config: &Config) -> Result<String, String> {
+    let columns = config
+        .clickhouse
+        .client()
+        .map_err(|e| format!("cannot set up the client: {e}"))?
+        .query("SELECT name, type FROM system.columns WHERE database = ? AND table = ?")
+        .bind(&config.clickhouse.database)
+        .bind(&config.clickhouse.table)
+        .fetch_all::<(String, String)>()
+        .await
+        .map_err(|e| format!("cannot query columns: {e}"))?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    if columns.is_empty() {
+        return Err("table does not exist".to_owned());
+    }
+
+    let problems = SCHEMA
+        .iter()
+        .map(|(name, expected)| (config.clickhouse.column(name), expected))
+        .filter_map(|(name, expected)| match columns.get(name) {
+            None => Some(format!("missing column {name} {expected}")),
+            Some(actual) if base_type(actual) != base_type(expected) => {
+                Some(format!("column {name} is {actual}, expected {expected}"))
+            }
+            Some(_) => None,
+        })
+        .collect::<Vec<_>>();
+
+    if problems.is_empty() {
+        Ok(format!("all {} columns match", SCHEMA.len()))
+    } else {
+        Err(problems.join("; "))
+    }
+}
+
+/// Strips wrappers that don't change how values are inserted.
+fn base_type(column_type: &str) -> &str {
+    column_type
+        .strip_prefix("LowCardinality(")
+        .and_then(|inner| inner.strip_suffix(')'))
+        .unwrap_or(column_type)
+}
@@ -0,0 +1,252 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
+
+use ipnet::IpNet;
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::{
+    select,
+    sync::watch,
+    time::{sleep, timeout},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    config::{CloudsConfig, Config},
+    prefixes::Prefixes,
+    sink::{http, Error},
+};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Failed fetches are retried sooner than `refresh`, which is a day usually.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Ranges of cloud providers, matched by the most specific one.
+#[derive(Default)]
+pub struct Clouds {
+    ranges: Prefixes<Cloud>,
+}
+
+pub struct Cloud {
+    /// One of `aws`, `gcp`, `azure` or `cloudflare`.
+    pub provider: &'static str,
+    /// Service as the provider names it, like `S3` or `AzureStorage`.
+    pub service: String,
+}
+
+impl Clouds {
+    fn new(fetched: &HashMap<&'static str, Vec<(IpNet, String)>>) -> Self {
+        let mut ranges = Prefixes::default();
+
+        for (provider, entries) in fetched {
+            for (range, service) in entries {
+                let cloud = Cloud {
+                    provider,
+                    service: service.clone(),
+                };
+
+                // Ranges of specific services are listed under the whole
+                // cloud as well, the specific one is more useful.
+                match generic(service) {
+                    true => ranges.add(*range, cloud),
+                    false => ranges.replace(*range, cloud),
+                }
+            }
+        }
+
+        Self { ranges }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Cloud with the most specific range containing the address.
+    pub fn get(&self, addr: IpAddr) -> Option<&Cloud> {
+        self.ranges.get(addr)
+    }
+}
+
+/// Services that stand for the whole cloud rather than a part of it.
+fn generic(service: &str) -> bool {
+    matches!(service, "AMAZON" | "AzureCloud")
+}
+
+/// Fetches the published ranges of cloud providers every `refresh`, so
+/// that flows can be tagged with the cloud and service of their server.
+/// Ranges of providers that cannot be fetched are kept from the last time.
+pub async fn poll(mut config: watch::Receiver<Arc<Config>>, clouds: watch::Sender<Arc<Clouds>>) {
+    let mut fetched = HashMap::new();
+
+    loop {
+        let current = config.borrow().clouds.clone();
+
+        let mut interval = current.refresh;
+
+        if current.enabled {
+            for (provider, url) in feeds(&current) {
+                if url.is_empty() {
+                    fetched.remove(provider);
+                    continue;
+                }
+
+                match timeout(FETCH_TIMEOUT, fetch(provider, url)).await {
+                    Ok(Ok(ranges)) => {
+                        debug!(target: "clouds", "Fetched {} ranges of {provider}", ranges.len());
+
+                        fetched.insert(provider, ranges);
+                    }
+                    Ok(Err(e)) => {
+                        warn!(target: "clouds", "Cannot fetch ranges of {provider} from {url}: {e}");
+                        interval = interval.min(RETRY_INTERVAL);
+                    }
+                    Err(_) => {
+                        warn!(target: "clouds", "Timed out fetching ranges of {provider} from {url}");
+                        interval = interval.min(RETRY_INTERVAL);
+                    }
+                }
+            }
+        } else {
+            fetched.clear();
+        }
+
+        if !fetched.is_empty() || clouds.borrow().len() > 0 {
+            clouds.send_replace(Arc::new(Clouds::new(&fetched)));
+        }
+
+        // Changes to the section are picked up right away, not a day later.
+        select! {
+            _ = sleep(interval) => {}
+            Ok(_) = config.wait_for(|config| config.clouds != current) => {}
+        }
+    }
+}
+
+fn feeds(config: &CloudsConfig) -> [(&'static str, &str); 4] {
+    [
+        ("aws", &config.aws),
+        ("gcp", &config.gcp),
+        ("azure", &config.azure),
+        ("cloudflare", &config.cloudflare),
+    ]
+}
+
+async fn fetch(provider: &str, url: &str) -> Result<Vec<(IpNet, String)>, Error> {
+    let ranges = match provider {
+        "aws" => {
+            let feed = get::<AwsFeed>(url).await?;
+
+            let v4 = feed.prefixes.into_iter().map(|p| (p.ip_prefix, p.service));
+            let v6 = feed
+                .ipv6_prefixes
+                .into_iter()
+                .map(|p| (p.ipv6_prefix, p.service));
+
+            v4.chain(v6).collect()
+        }
+        "gcp" => get::<GcpFeed>(url)
+            .await?
+            .prefixes
+            .into_iter()
+            .filter_map(|p| Some((p.ipv4_prefix.or(p.ipv6_prefix)?, p.service)))
+            .collect(),
+        "azure" => get::<AzureFeed>(url)
+            .await?
+            .values
+            .into_iter()
+            .flat_map(|tag| {
+                // Tags of whole regions, like `AzureCloud.westeurope`, have no service.
+                let service = match tag.properties.system_service.is_empty() {
+                    true => tag.name.split('.').next().unwrap_or_default().to_owned(),
+                    false => tag.properties.system_service,
+                };
+
+                tag.properties
+                    .address_prefixes
+                    .into_iter()
+                    .map(move |p| (p, service.clone()))
+            })
+            .collect(),
+        _ => {
+            let feed = get::<CloudflareFeed>(url).await?.result;
+
+            feed.ipv4_cidrs
+                .into_iter()
+                .chain(feed.ipv6_cidrs)
+                .map(|range| (range, String::new()))
+                .collect()
+        }
+    };
+
+    Ok(ranges)
+}
+
+async fn get<T: DeserializeOwned>(url: &str) -> Result<T, Error> {
+    let body = http::get(url, &[], None).await?;
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// https://docs.aws.amazon.com/vpc/latest/userguide/aws-ip-ranges.html
+#[derive(Deserialize)]
+struct AwsFeed {
+    prefixes: Vec<AwsPrefix>,
+    ipv6_prefixes: Vec<AwsIpv6Prefix>,
+}
+
+#[derive(Deserialize)]
+struct AwsPrefix {
+    ip_prefix: IpNet,
+    service: String,
+}
+
+#[derive(Deserialize)]
+struct AwsIpv6Prefix {
+    ipv6_prefix: IpNet,
+    service: String,
+}
+
+/// https://cloud.google.com/compute/docs/faq#find_ip_range
+#[derive(Deserialize)]
+struct GcpFeed {
+    prefixes: Vec<GcpPrefix>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GcpPrefix {
+    ipv4_prefix: Option<IpNet>,
+    ipv6_prefix: Option<IpNet>,
+    service: String,
+}
+
+/// https://learn.microsoft.com/en-us/azure/virtual-network/service-tags-overview
+#[derive(Deserialize)]
+struct AzureFeed {
+    values: Vec<AzureTag>,
+}
+
+#[derive(Deserialize)]
+struct AzureTag {
+    name: String,
+    properties: AzureProperties,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureProperties {
+    #[serde(default)]
+    system_service: String,
+    address_prefixes: Vec<IpNet>,
+}
+
+/// https://developers.cloudflare.com/api/resources/ips/
+#[derive(Deserialize)]
+struct CloudflareFeed {
+    result: CloudflareRanges,
+}
+
+#[derive(Deserialize)]
+struct CloudflareRanges {
+    ipv4_cidrs: Vec<IpNet>,
+    ipv6_cidrs: Vec<IpNet>,
+}
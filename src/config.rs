@@ -0,0 +1,2688 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    env, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use clickhouse::Client;
+use hyper::header::{HeaderName, HeaderValue};
+use ipnet::IpNet;
+use prometheus_client::metrics::counter::Counter;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use toml::{Table, Value};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    connector,
+    filter::{Filter, ParseError},
+    row::SCHEMA,
+    services::{format_rule, parse_rule},
+    vpn::TOR,
+};
+
+/// Prefix for environment variables that override config file values,
+/// e.g. `HOGS_CLICKHOUSE_URL` sets `url` in the `[clickhouse]` section.
+const ENV_PREFIX: &str = "HOGS_";
+
+/// Netflow (IPFIX) collector that tracks who's talking to whom over the Internet connection.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to the TOML configuration file.
+    #[arg(long, value_name = "PATH", env = "HOGS_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to receive IPFIX datagrams on, can be repeated. An optional
+    /// name to tell the listeners apart defaults to the address itself.
+    #[arg(long, value_name = "[NAME=]ADDR")]
+    pub ipfix_bind: Vec<Listener>,
+
+    /// Address to accept IPFIX connections over TCP on, can be repeated.
+    #[arg(long, value_name = "[NAME=]ADDR")]
+    pub ipfix_tcp_bind: Vec<Listener>,
+
+    /// Address to accept IPFIX connections over TLS on, can be repeated.
+    #[arg(long, value_name = "[NAME=]ADDR")]
+    pub ipfix_tls_bind: Vec<Listener>,
+
+    /// Address to receive IPFIX over DTLS on, can be repeated.
+    #[arg(long, value_name = "[NAME=]ADDR")]
+    pub ipfix_dtls_bind: Vec<Listener>,
+
+    /// PEM file with the certificate chain for TLS and DTLS listeners.
+    #[arg(long, value_name = "PATH")]
+    pub tls_certificate: Option<PathBuf>,
+
+    /// PEM file with the private key for TLS and DTLS listeners.
+    #[arg(long, value_name = "PATH")]
+    pub tls_private_key: Option<PathBuf>,
+
+    /// PEM file with the CA certificates to verify exporters with.
+    #[arg(long, value_name = "PATH")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Address to receive sFlow v5 datagrams on, can be repeated.
+    #[arg(long, value_name = "[NAME=]ADDR")]
+    pub sflow_bind: Vec<Listener>,
+
+    /// Address to serve Prometheus metrics on.
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_bind: Option<String>,
+
+    /// Do not insert flows into ClickHouse, only export metrics.
+    #[arg(long)]
+    pub no_clickhouse: bool,
+
+    /// ClickHouse HTTP interface to insert flows into.
+    #[arg(long, value_name = "URL")]
+    pub clickhouse_url: Option<String>,
+
+    /// ClickHouse database with the flows table.
+    #[arg(long, value_name = "NAME")]
+    pub clickhouse_database: Option<String>,
+
+    /// ClickHouse table to insert flows into.
+    #[arg(long, value_name = "NAME")]
+    pub clickhouse_table: Option<String>,
+
+    /// ClickHouse user. The password is only accepted from the config file,
+    /// `HOGS_CLICKHOUSE_PASSWORD` or a file to keep it out of process listings.
+    #[arg(long, value_name = "NAME")]
+    pub clickhouse_user: Option<String>,
+
+    /// File with the ClickHouse password.
+    #[arg(long, value_name = "PATH")]
+    pub clickhouse_password_file: Option<PathBuf>,
+
+    /// Size of the buffer for a single IPFIX datagram.
+    #[arg(long, value_name = "BYTES")]
+    pub buffer_size: Option<usize>,
+
+    /// Kernel receive buffer size (SO_RCVBUF) of the IPFIX socket.
+    #[arg(long, value_name = "BYTES")]
+    pub receive_buffer: Option<usize>,
+
+    /// Detach from the terminal and run in the background.
+    #[arg(long)]
+    pub daemonize: bool,
+
+    /// File to write the process id into.
+    #[arg(long, value_name = "PATH")]
+    pub pidfile: Option<PathBuf>,
+
+    /// User to switch to after binding the listening sockets.
+    #[arg(long, value_name = "NAME")]
+    pub user: Option<String>,
+
+    /// Group to switch to after binding the listening sockets,
+    /// the primary group of the user by default.
+    #[arg(long, value_name = "NAME")]
+    pub group: Option<String>,
+
+    /// Log filter, a level like `debug` or per target directives like
+    /// `info,parser=debug`. Received flows are logged at the debug level.
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+
+    /// Format of the log lines written to stderr.
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Do not log every received flow, regardless of the log level.
+    #[arg(long, conflicts_with = "dry_run")]
+    pub quiet: bool,
+
+    /// Only parse and log flows, without inserting them into ClickHouse
+    /// or counting them in metrics. Useful to check exporter configuration.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Load the configuration, resolve bind addresses and verify that the
+    /// ClickHouse table matches the expected schema, then exit.
+    CheckConfig,
+    /// Create the ClickHouse table, or add the columns it's missing, then exit.
+    InitSchema,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("cannot read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("cannot parse {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("{0}")]
+    Invalid(toml::de::Error),
+    #[error("invalid MAC address in [devices]: {0}")]
+    InvalidMac(String),
+    #[error("MAC address in more than one group in [groups]: {0}")]
+    GroupedTwice(String),
+    #[error("unknown group in [threats] alert_groups: {0}")]
+    UnknownGroup(String),
+    #[error("invalid interface index in [interfaces]: {0}")]
+    InvalidInterface(String),
+    #[error("invalid port in [services], expected PORT or PORT/PROTOCOL: {0}")]
+    InvalidService(String),
+    #[error("invalid field in [ipfix.enterprise_fields], expected PEN:ID: {0}")]
+    InvalidEnterpriseField(String),
+    #[error("unknown column in [clickhouse.columns]: {0}")]
+    UnknownColumn(String),
+    #[error("invalid log level {0:?}: {1}")]
+    InvalidLogLevel(String, tracing_subscriber::filter::ParseError),
+    #[error("invalid filter in [{section}]: {source}")]
+    InvalidFilter {
+        section: &'static str,
+        source: ParseError,
+    },
+    #[error("invalid [{section}] settings: {message}")]
+    Inconsistent {
+        section: &'static str,
+        message: &'static str,
+    },
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub ipfix: IpfixConfig,
+    pub sflow: SflowConfig,
+    pub tls: TlsConfig,
+    pub metrics: MetricsConfig,
+    pub clickhouse: ClickhouseConfig,
+    pub kafka: KafkaConfig,
+    pub sqlite: SqliteConfig,
+    pub parquet: ParquetConfig,
+    pub file: FileConfig,
+    pub influxdb: InfluxdbConfig,
+    pub otlp: OtlpConfig,
+    pub syslog: SyslogConfig,
+    pub redis: RedisConfig,
+    pub network: NetworkConfig,
+    pub dead_letter: DeadLetterConfig,
+    pub wal: WalConfig,
+    pub geoip: GeoIpConfig,
+    pub asn: AsnConfig,
+    pub leases: LeasesConfig,
+    pub router: RouterConfig,
+    pub neighbors: NeighborsConfig,
+    pub mac_cache: MacCacheConfig,
+    pub categories: CategoriesConfig,
+    pub dnstap: DnstapConfig,
+    pub clouds: CloudsConfig,
+    pub threats: ThreatsConfig,
+    pub vpn: VpnConfig,
+    pub rules: RulesConfig,
+    pub script: ScriptConfig,
+    pub dedup: DedupConfig,
+    pub top: TopConfig,
+    /// Friendly device names keyed by MAC address.
+    pub devices: BTreeMap<String, String>,
+    /// MAC addresses of devices keyed by the name of their group.
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// Interface names keyed by the ifIndex reported by exporters.
+    pub interfaces: BTreeMap<String, String>,
+    /// Service names keyed by server port, optionally with the protocol
+    /// like `3074/udp`, counted in their own metric.
+    pub services: BTreeMap<String, String>,
+    pub daemon: DaemonConfig,
+    pub log: LogConfig,
+    /// `HOGS_*` environment variables that match no setting and are ignored.
+    #[serde(skip)]
+    pub unknown_env: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter directives in the `RUST_LOG` syntax, e.g. `info,parser=debug`.
+    pub level: String,
+    pub format: LogFormat,
+    /// Log only one in this many flows.
+    pub flow_sample: u64,
+    /// Maximum number of flows logged per second, unlimited if zero.
+    pub flow_rate_limit: u64,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_owned(),
+            format: LogFormat::Text,
+            flow_sample: 1,
+            flow_rate_limit: 0,
+        }
+    }
+}
+
+impl LogConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Err(e) = EnvFilter::try_new(&self.level) {
+            return Err(ConfigError::InvalidLogLevel(self.level.clone(), e));
+        }
+
+        if self.flow_sample == 0 {
+            return Err(ConfigError::Inconsistent {
+                section: "log",
+                message: "flow_sample must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Single line per event.
+    #[default]
+    Text,
+    /// Multiple lines per event, easier on the eyes.
+    Pretty,
+    /// JSON object per line for log shippers.
+    Json,
+}
+
+#[derive(Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    pub daemonize: bool,
+    pub pidfile: Option<PathBuf>,
+    /// User to switch to after binding the listening sockets.
+    pub user: Option<String>,
+    /// Group to switch to, the primary group of the user by default.
+    pub group: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpfixConfig {
+    pub bind: Vec<Listener>,
+    /// Listeners for exporters that send IPFIX over TCP.
+    pub tcp_bind: Vec<Listener>,
+    /// Listeners for IPFIX over TLS, see the `[tls]` section.
+    pub tls_bind: Vec<Listener>,
+    /// Listeners for IPFIX over DTLS, see the `[tls]` section.
+    pub dtls_bind: Vec<Listener>,
+    /// How long exporters get to finish the TLS handshake.
+    #[serde(with = "humantime_serde")]
+    pub handshake_timeout: Duration,
+    /// How long a TCP or TLS connection can go without a whole message.
+    #[serde(with = "humantime_serde")]
+    pub read_timeout: Duration,
+    pub buffer_size: usize,
+    /// Kernel receive buffer size, system default if unset.
+    pub receive_buffer: Option<usize>,
+    /// Exporters allowed to send datagrams, everyone if empty.
+    pub allowed_exporters: Vec<IpNet>,
+    /// Names of the fields to keep in the `enterpriseFields` column, keyed
+    /// by the enterprise number and field id as `PEN:ID`, PEN 0 is IANA.
+    pub enterprise_fields: BTreeMap<String, String>,
+}
+
+impl IpfixConfig {
+    pub fn is_allowed(&self, exporter: &IpAddr) -> bool {
+        // Exporters on IPv4 show up as mapped addresses on dual stack sockets.
+        let exporter = exporter.to_canonical();
+
+        self.allowed_exporters.is_empty()
+            || self
+                .allowed_exporters
+                .iter()
+                .any(|net| net.contains(&exporter))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.handshake_timeout.is_zero() || self.read_timeout.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "ipfix",
+                message: "handshake_timeout and read_timeout must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Certificates for the TLS and DTLS listeners.
+#[derive(Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub certificate: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+    /// CA to verify exporter certificates with, any exporter if unset.
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Listeners for sFlow, which share the buffer sizes and allowed
+/// exporters with the `[ipfix]` section.
+#[derive(Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SflowConfig {
+    pub bind: Vec<Listener>,
+}
+
+/// A listener in the `[NAME=]ADDR` form.
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Listener {
+    pub name: String,
+    pub addr: String,
+}
+
+impl FromStr for Listener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, addr) = s.split_once('=').unwrap_or((s, s));
+
+        if name.is_empty() || addr.is_empty() {
+            return Err(format!("invalid listener {s:?}, expected [NAME=]ADDR"));
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            addr: addr.to_owned(),
+        })
+    }
+}
+
+impl TryFrom<String> for Listener {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Listener> for String {
+    fn from(listener: Listener) -> Self {
+        if listener.name == listener.addr {
+            listener.addr
+        } else {
+            format!("{}={}", listener.name, listener.addr)
+        }
+    }
+}
+
+impl Default for IpfixConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec![Listener {
+                name: "0.0.0.0:2055".to_owned(),
+                addr: "0.0.0.0:2055".to_owned(),
+            }],
+            tcp_bind: vec![],
+            tls_bind: vec![],
+            dtls_bind: vec![],
+            handshake_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(300),
+            buffer_size: 65535,
+            receive_buffer: None,
+            allowed_exporters: vec![],
+            enterprise_fields: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address to serve metrics on for scrapes, none if empty.
+    pub bind: String,
+    /// Replaces `ipfix_` in front of the names of flow metrics.
+    pub prefix: String,
+    /// Labels added to every series, to tell collectors apart.
+    pub labels: BTreeMap<String, String>,
+    /// Adds the VLAN of flows as a label to tell apart segmented networks.
+    pub vlan_label: bool,
+    /// Adds the observation domain to tell apart line cards of an exporter.
+    pub observation_domain_label: bool,
+    /// Adds the interface facing the client, named by `interfaces` if listed.
+    pub interface_label: bool,
+    /// Adds the country of the server, with `geoip` enabled.
+    pub country_label: bool,
+    /// Adds the category of flows, with `categories` enabled.
+    pub category_label: bool,
+    /// Adds the group of devices from `groups`.
+    pub group_label: bool,
+    /// Exports bytes per IP protocol and direction of devices.
+    pub protocols: bool,
+    /// Series of devices without traffic for this long are removed, never if zero.
+    #[serde(with = "humantime_serde")]
+    pub expiry: Duration,
+    /// Devices with series of their own, the rest are counted together, unlimited if zero.
+    pub max_devices: usize,
+    /// Remote ASNs with the most bytes exported on their own, none if zero.
+    pub top_asns: usize,
+    /// Attaches flows to byte counters as OpenMetrics exemplars.
+    pub exemplars: bool,
+    /// Flows with fewer bytes are not worth an exemplar.
+    pub exemplar_min_bytes: u64,
+    /// Window over which current throughput of devices is exported, none if zero.
+    #[serde(with = "humantime_serde")]
+    pub throughput_window: Duration,
+    /// Prometheus remote_write endpoint to push metrics to, for collectors
+    /// that cannot be scraped. Nothing is pushed if unset.
+    pub remote_write_url: Option<String>,
+    /// How often metrics are pushed.
+    #[serde(with = "humantime_serde")]
+    pub remote_write_interval: Duration,
+    /// Labels added to every pushed series, `job` and `instance` included.
+    pub remote_write_labels: BTreeMap<String, String>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            bind: "[::]:3434".to_owned(),
+            prefix: "ipfix_".to_owned(),
+            labels: BTreeMap::new(),
+            vlan_label: false,
+            observation_domain_label: false,
+            interface_label: false,
+            country_label: false,
+            category_label: false,
+            group_label: false,
+            protocols: false,
+            expiry: Duration::ZERO,
+            max_devices: 0,
+            top_asns: 0,
+            exemplars: false,
+            exemplar_min_bytes: 10 * 1024 * 1024,
+            throughput_window: Duration::ZERO,
+            remote_write_url: None,
+            remote_write_interval: Duration::from_secs(15),
+            remote_write_labels: BTreeMap::new(),
+        }
+    }
+}
+
+impl MetricsConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let name = |name: &str, colons: bool| {
+            name.chars().enumerate().all(|(i, c)| {
+                c.is_ascii_alphabetic()
+                    || c == '_'
+                    || (colons && c == ':')
+                    || (i > 0 && c.is_ascii_digit())
+            })
+        };
+
+        if !name(&self.prefix, true) {
+            return Err(ConfigError::Inconsistent {
+                section: "metrics",
+                message: "prefix must only have letters, digits, underscores and colons",
+            });
+        }
+
+        if !self
+            .labels
+            .keys()
+            .all(|label| !label.is_empty() && !label.starts_with("__") && name(label, false))
+        {
+            return Err(ConfigError::Inconsistent {
+                section: "metrics",
+                message:
+                    "names of labels must be letters, digits and underscores, not starting with __",
+            });
+        }
+
+        if self.remote_write_interval.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "metrics",
+                message: "remote_write_interval must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Prefixes of the local network. Flows from these to elsewhere are uploads
+    /// and the other way around are downloads. The `FlowDirection` reported by
+    /// the exporter is only used if the addresses do not tell the direction.
+    pub local_subnets: Vec<IpNet>,
+}
+
+impl NetworkConfig {
+    pub fn is_local(&self, addr: &IpAddr) -> bool {
+        self.local_subnets.iter().any(|net| net.contains(addr))
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClickhouseConfig {
+    pub enabled: bool,
+    /// Only flows matching this expression are written, see `Filter`.
+    pub filter: String,
+    pub url: String,
+    pub database: String,
+    pub table: String,
+    pub user: String,
+    pub password: String,
+    pub password_file: Option<PathBuf>,
+    /// Column names in the table that differ from the default ones.
+    pub columns: BTreeMap<String, String>,
+    pub max_bytes: u64,
+    pub max_rows: u64,
+    #[serde(with = "humantime_serde")]
+    pub period: Duration,
+    #[serde(with = "humantime_serde")]
+    pub send_timeout: Duration,
+    #[serde(with = "humantime_serde")]
+    pub end_timeout: Duration,
+    /// Attempts to insert a failed batch again before dropping it.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubling with every attempt.
+    #[serde(with = "humantime_serde")]
+    pub retry_backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_retry_backoff: Duration,
+    /// Rows kept in memory while retrying before they are spooled or dropped.
+    pub max_pending: u64,
+    /// Directory to keep rows in once out of retries, until they can be inserted.
+    pub spool_directory: Option<PathBuf>,
+    /// Size of the spool above which the oldest rows are dropped.
+    pub spool_max_bytes: u64,
+    /// How long rows are kept, both as the TTL of tables created by
+    /// `init-schema` and by dropping partitions past it.
+    #[serde(with = "humantime_serde")]
+    pub retention: Option<Duration>,
+    /// Whether `init-schema` creates hourly and daily rollups of the table.
+    pub rollups: bool,
+    /// CA to verify HTTPS servers with instead of the system roots.
+    pub ca: Option<PathBuf>,
+    /// Client certificate and key for servers that require them.
+    pub certificate: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+    /// Name to expect in the server certificate instead of the host of `url`.
+    pub server_name: Option<String>,
+    pub compression: ClickhouseCompression,
+}
+
+impl Default for ClickhouseConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            filter: String::new(),
+            url: "http://ip6-localhost:8123".to_owned(),
+            database: "default".to_owned(),
+            table: "ipfix".to_owned(),
+            user: "default".to_owned(),
+            password: String::new(),
+            password_file: None,
+            columns: BTreeMap::new(),
+            max_bytes: 1024 * 1024,
+            max_rows: 1000,
+            period: Duration::from_secs(5),
+            send_timeout: Duration::from_secs(5),
+            end_timeout: Duration::from_secs(20),
+            max_retries: 5,
+            retry_backoff: Duration::from_secs(1),
+            max_retry_backoff: Duration::from_secs(60),
+            max_pending: 100_000,
+            spool_directory: None,
+            spool_max_bytes: 1024 * 1024 * 1024,
+            retention: None,
+            rollups: false,
+            ca: None,
+            certificate: None,
+            private_key: None,
+            server_name: None,
+            compression: ClickhouseCompression::Lz4,
+        }
+    }
+}
+
+impl ClickhouseConfig {
+    /// Client for the server, reading the certificates for HTTPS.
+    pub fn client(&self) -> io::Result<Client> {
+        self.counting_client(Counter::default())
+    }
+
+    /// Client counting the bytes sent to the server in `sent`.
+    pub fn counting_client(&self, sent: Counter) -> io::Result<Client> {
+        Ok(connector::clickhouse_client(self, sent)?
+            .with_url(&self.url)
+            .with_database(&self.database)
+            .with_user(&self.user)
+            .with_password(&self.password))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let inconsistent = |message| {
+            Err(ConfigError::Inconsistent {
+                section: "clickhouse",
+                message,
+            })
+        };
+
+        if self.max_rows == 0 || self.max_bytes == 0 {
+            return inconsistent("max_rows and max_bytes must be positive");
+        }
+
+        if self.period.is_zero() {
+            return inconsistent("period must be positive");
+        }
+
+        if self.send_timeout.is_zero() || self.end_timeout.is_zero() {
+            return inconsistent("send_timeout and end_timeout must be positive");
+        }
+
+        if self.end_timeout < self.send_timeout {
+            return inconsistent("end_timeout must not be shorter than send_timeout");
+        }
+
+        if self.retry_backoff.is_zero() || self.max_retry_backoff < self.retry_backoff {
+            return inconsistent("retry_backoff must be positive and not above max_retry_backoff");
+        }
+
+        if self.max_pending < self.max_rows {
+            return inconsistent("max_pending must be at least as large as max_rows");
+        }
+
+        if self.certificate.is_some() != self.private_key.is_some() {
+            return inconsistent("certificate and private_key go together");
+        }
+
+        if self.spool_directory.is_some() && self.spool_max_bytes == 0 {
+            return inconsistent("spool_max_bytes must be positive");
+        }
+
+        if self
+            .retention
+            .is_some_and(|retention| retention.as_secs() == 0)
+        {
+            return inconsistent("retention must be at least a second");
+        }
+
+        if let Some(name) = self
+            .columns
+            .keys()
+            .find(|name| !SCHEMA.iter().any(|(column, _)| column == name))
+        {
+            return Err(ConfigError::UnknownColumn(name.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Logs the effective batching settings of the inserter.
+    pub fn log_batching(&self) {
+        if !self.enabled {
+            return;
+        }
+
+        info!(
+            target: "clickhouse",
+            "Batches of up to {} rows or {} bytes, flushed every {}, timeouts: send {}, end {}",
+            self.max_rows,
+            self.max_bytes,
+            humantime::format_duration(self.period),
+            humantime::format_duration(self.send_timeout),
+            humantime::format_duration(self.end_timeout),
+        );
+    }
+
+    /// Name of the column in the table, the default one unless remapped.
+    pub fn column<'a>(&'a self, name: &'a str) -> &'a str {
+        self.columns.get(name).map(String::as_str).unwrap_or(name)
+    }
+
+    /// Target for the inserter. The client always lists the default column
+    /// names after the table, so with renamed columns the rows are selected
+    /// from the `input()` table function instead, and the default column list
+    /// ends up in an `ORDER BY` clause that doesn't change the outcome.
+    pub fn insert_target(&self) -> String {
+        if self.columns.is_empty() {
+            return self.table.clone();
+        }
+
+        let columns = SCHEMA
+            .iter()
+            .map(|(name, _)| quote_identifier(self.column(name)))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let structure = SCHEMA
+            .iter()
+            .map(|(name, column_type)| format!("{name} {column_type}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        format!(
+            "{}({columns}) SELECT * FROM input('{structure}') ORDER BY ",
+            self.table
+        )
+    }
+
+    fn same_destination(&self, other: &Self) -> bool {
+        self.enabled == other.enabled
+            && self.url == other.url
+            && self.database == other.database
+            && self.table == other.table
+            && self.user == other.user
+            && self.password == other.password
+            && self.columns == other.columns
+            && self.spool_directory == other.spool_directory
+            && self.ca == other.ca
+            && self.certificate == other.certificate
+            && self.private_key == other.private_key
+            && self.server_name == other.server_name
+    }
+}
+
+/// Compression of inserts, mapped to `clickhouse::Compression`. There is
+/// no ZSTD, as version 0.13 of the clickhouse crate only compresses with
+/// LZ4, so `zstd` fails to parse rather than being silently ignored.
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClickhouseCompression {
+    None,
+    /// The only codec the ClickHouse client can compress inserts with.
+    #[default]
+    Lz4,
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaConfig {
+    pub enabled: bool,
+    /// Only flows matching this expression are written, see `Filter`.
+    pub filter: String,
+    /// Bootstrap brokers as `HOST:PORT`.
+    pub brokers: Vec<String>,
+    pub topic: String,
+    pub format: KafkaFormat,
+    /// URL of the schema registry the Avro schema is registered with.
+    pub schema_registry: Option<String>,
+    pub max_rows: u64,
+    #[serde(with = "humantime_serde")]
+    pub period: Duration,
+    /// Records kept while Kafka is unavailable, the oldest are dropped beyond that.
+    pub max_pending: u64,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            filter: String::new(),
+            brokers: vec!["localhost:9092".to_owned()],
+            topic: "flows".to_owned(),
+            format: KafkaFormat::Json,
+            schema_registry: None,
+            max_rows: 1000,
+            period: Duration::from_secs(1),
+            max_pending: 100_000,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl KafkaConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let inconsistent = |message| {
+            Err(ConfigError::Inconsistent {
+                section: "kafka",
+                message,
+            })
+        };
+
+        if self.brokers.is_empty() || self.topic.is_empty() {
+            return inconsistent("brokers and topic must not be empty");
+        }
+
+        if self.format == KafkaFormat::Avro && self.schema_registry.is_none() {
+            return inconsistent("schema_registry is required for the avro format");
+        }
+
+        if self.max_rows == 0 || self.max_pending < self.max_rows {
+            return inconsistent("max_rows must be positive and max_pending at least as large");
+        }
+
+        if self.period.is_zero() || self.timeout.is_zero() {
+            return inconsistent("period and timeout must be positive");
+        }
+
+        Ok(())
+    }
+
+    fn same_destination(&self, other: &Self) -> bool {
+        self.enabled == other.enabled
+            && self.brokers == other.brokers
+            && self.topic == other.topic
+            && self.format == other.format
+            && self.schema_registry == other.schema_registry
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaFormat {
+    /// JSON object per message, with the column names of the table.
+    #[default]
+    Json,
+    /// Avro in the wire format of the Confluent schema registry.
+    Avro,
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqliteConfig {
+    pub enabled: bool,
+    /// Only flows matching this expression are written, see `Filter`.
+    pub filter: String,
+    pub path: PathBuf,
+    /// Rows older than this are deleted, kept forever if zero.
+    #[serde(with = "humantime_serde")]
+    pub retention: Duration,
+    pub max_rows: u64,
+    #[serde(with = "humantime_serde")]
+    pub period: Duration,
+    /// Rows kept while inserts fail, the oldest are dropped beyond that.
+    pub max_pending: u64,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            filter: String::new(),
+            path: PathBuf::from("/var/lib/internet-hogs/flows.sqlite"),
+            retention: Duration::from_secs(30 * 24 * 60 * 60),
+            max_rows: 1000,
+            period: Duration::from_secs(5),
+            max_pending: 100_000,
+        }
+    }
+}
+
+impl SqliteConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let inconsistent = |message| {
+            Err(ConfigError::Inconsistent {
+                section: "sqlite",
+                message,
+            })
+        };
+
+        if self.max_rows == 0 || self.max_pending < self.max_rows {
+            return inconsistent("max_rows must be positive and max_pending at least as large");
+        }
+
+        if self.period.is_zero() {
+            return inconsistent("period must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParquetConfig {
+    pub enabled: bool,
+    /// Only flows matching this expression are written, see `Filter`.
+    pub filter: String,
+    /// Directory for the files, created if missing.
+    pub directory: PathBuf,
+    /// How long each file covers, aligned to the Unix epoch.
+    #[serde(with = "humantime_serde")]
+    pub rotation: Duration,
+    /// Rows buffered before they are encoded into the open file.
+    pub max_rows: u64,
+}
+
+impl Default for ParquetConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            filter: String::new(),
+            directory: PathBuf::from("/var/lib/internet-hogs/parquet"),
+            rotation: Duration::from_secs(60 * 60),
+            max_rows: 10000,
+        }
+    }
+}
+
+impl ParquetConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let inconsistent = |message| {
+            Err(ConfigError::Inconsistent {
+                section: "parquet",
+                message,
+            })
+        };
+
+        if self.max_rows == 0 {
+            return inconsistent("max_rows must be positive");
+        }
+
+        if self.rotation.as_secs() == 0 {
+            return inconsistent("rotation must be at least a second");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub enabled: bool,
+    /// Only flows matching this expression are written, see `Filter`.
+    pub filter: String,
+    /// Directory for the files, created if missing.
+    pub directory: PathBuf,
+    pub format: FileFormat,
+    /// How long each file covers, aligned to the Unix epoch, forever if zero.
+    #[serde(with = "humantime_serde")]
+    pub rotation: Duration,
+    /// Uncompressed bytes after which a new file is started, unlimited if zero.
+    pub max_size: u64,
+    pub gzip: bool,
+    /// How often buffered lines are written out.
+    #[serde(with = "humantime_serde")]
+    pub period: Duration,
+}
+
+impl Default for FileConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            filter: String::new(),
+            directory: PathBuf::from("/var/log/internet-hogs"),
+            format: FileFormat::Json,
+            rotation: Duration::from_secs(24 * 60 * 60),
+            max_size: 0,
+            gzip: false,
+            period: Duration::from_secs(1),
+        }
+    }
+}
+
+impl FileConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.period.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "file",
+                message: "period must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Capture of datagrams that cannot be parsed, see `DeadLetters`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeadLetterConfig {
+    pub enabled: bool,
+    /// Directory for the pcap files, created if missing.
+    pub directory: PathBuf,
+    /// Bytes after which a new file is started.
+    pub max_size: u64,
+    /// Number of files to keep, the oldest ones are removed.
+    pub max_files: usize,
+}
+
+impl Default for DeadLetterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: PathBuf::from("/var/lib/internet-hogs/dead-letter"),
+            max_size: 16 * 1024 * 1024,
+            max_files: 10,
+        }
+    }
+}
+
+impl DeadLetterConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_size == 0 || self.max_files == 0 {
+            return Err(ConfigError::Inconsistent {
+                section: "dead_letter",
+                message: "max_size and max_files must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Write-ahead log of received datagrams, see `Wal`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalConfig {
+    pub enabled: bool,
+    /// Directory for the log, created if missing.
+    pub directory: PathBuf,
+    /// How often the sinks are asked to store what they have, after
+    /// which the datagrams received so far are removed from the log.
+    #[serde(with = "humantime_serde")]
+    pub checkpoint: Duration,
+    /// Bytes kept at most, the oldest datagrams are dropped beyond that.
+    pub max_bytes: u64,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: PathBuf::from("/var/lib/internet-hogs/wal"),
+            checkpoint: Duration::from_secs(10),
+            max_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl WalConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.checkpoint.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "wal",
+                message: "checkpoint must be positive",
+            });
+        }
+
+        if self.max_bytes == 0 {
+            return Err(ConfigError::Inconsistent {
+                section: "wal",
+                message: "max_bytes must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Location of servers from a MaxMind DB file, see `GeoIp`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoIpConfig {
+    pub enabled: bool,
+    /// Country or city database, like GeoLite2-City.mmdb.
+    pub database: PathBuf,
+    /// How often the file is checked for a new database.
+    #[serde(with = "humantime_serde")]
+    pub reload: Duration,
+    /// Where to download the database from, none if empty, see `geoip::update`.
+    pub url: String,
+    /// Basic auth for the URL, the account ID and license key for MaxMind.
+    pub username: String,
+    pub password: String,
+    /// How old the file gets before it's downloaded again.
+    #[serde(with = "humantime_serde")]
+    pub update: Duration,
+}
+
+impl Default for GeoIpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            database: PathBuf::from("/var/lib/GeoIP/GeoLite2-City.mmdb"),
+            reload: Duration::from_secs(60 * 60),
+            url: String::new(),
+            username: String::new(),
+            password: String::new(),
+            update: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl GeoIpConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.reload.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "geoip",
+                message: "reload must be positive",
+            });
+        }
+
+        if self.update.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "geoip",
+                message: "update must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Autonomous systems of servers from a MaxMind DB file, see `GeoIp`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AsnConfig {
+    pub enabled: bool,
+    /// ASN database, like GeoLite2-ASN.mmdb.
+    pub database: PathBuf,
+    /// How often the file is checked for a new database.
+    #[serde(with = "humantime_serde")]
+    pub reload: Duration,
+    /// Where to download the database from, none if empty, see `geoip::update`.
+    pub url: String,
+    /// Basic auth for the URL, the account ID and license key for MaxMind.
+    pub username: String,
+    pub password: String,
+    /// How old the file gets before it's downloaded again.
+    #[serde(with = "humantime_serde")]
+    pub update: Duration,
+}
+
+impl Default for AsnConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            database: PathBuf::from("/var/lib/GeoIP/GeoLite2-ASN.mmdb"),
+            reload: Duration::from_secs(60 * 60),
+            url: String::new(),
+            username: String::new(),
+            password: String::new(),
+            update: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl AsnConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.reload.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "asn",
+                message: "reload must be positive",
+            });
+        }
+
+        if self.update.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "asn",
+                message: "update must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Device names from the lease file of a DHCP server, see `Leases`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeasesConfig {
+    pub enabled: bool,
+    pub path: PathBuf,
+    pub format: LeaseFormat,
+    /// How often the file is checked for changes.
+    #[serde(with = "humantime_serde")]
+    pub reload: Duration,
+}
+
+impl Default for LeasesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("/var/lib/misc/dnsmasq.leases"),
+            format: LeaseFormat::Dnsmasq,
+            reload: Duration::from_secs(10),
+        }
+    }
+}
+
+impl LeasesConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.reload.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "leases",
+                message: "reload must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaseFormat {
+    /// A lease per line: expiry, MAC, address, hostname and client id.
+    #[default]
+    Dnsmasq,
+    /// CSV of the memfile backend of Kea.
+    Kea,
+    /// `lease` blocks of ISC dhcpd.
+    Isc,
+}
+
+/// Clients known to the router, polled from its API, see `router::poll`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouterConfig {
+    pub enabled: bool,
+    pub api: RouterApi,
+    /// Base URL, like `https://192.168.88.1` or `https://unifi.local`.
+    pub url: String,
+    /// User for the RouterOS REST API.
+    pub username: String,
+    pub password: String,
+    /// API key of the UniFi Network application.
+    pub api_key: String,
+    /// UniFi site to list the clients of.
+    pub site: String,
+    /// CA to verify the router against instead of the system roots,
+    /// like its self-signed certificate.
+    pub ca: Option<PathBuf>,
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            api: RouterApi::Mikrotik,
+            url: String::new(),
+            username: String::new(),
+            password: String::new(),
+            api_key: String::new(),
+            site: "default".to_owned(),
+            ca: None,
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RouterConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let inconsistent = |message| {
+            Err(ConfigError::Inconsistent {
+                section: "router",
+                message,
+            })
+        };
+
+        if !self.enabled {
+            return Ok(());
+        }
+
+        if self.url.is_empty() {
+            return inconsistent("url is required");
+        }
+
+        if self.interval.is_zero() {
+            return inconsistent("interval must be positive");
+        }
+
+        if self.api == RouterApi::Unifi && self.api_key.is_empty() {
+            return inconsistent("api_key is required for UniFi");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RouterApi {
+    /// REST API of RouterOS 7, with DHCP leases, ARP and IPv6 neighbors.
+    #[default]
+    Mikrotik,
+    /// Clients of a site of the UniFi Network application.
+    Unifi,
+}
+
+/// MACs of local addresses from the neighbor table of the kernel, for
+/// collectors running on the router, see `neighbors::scrape`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NeighborsConfig {
+    pub enabled: bool,
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl Default for NeighborsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl NeighborsConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.interval.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "neighbors",
+                message: "interval must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// MACs of local addresses learned from uploads, see `MacCache`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MacCacheConfig {
+    /// Keeps the learned MACs in `path` across restarts.
+    pub enabled: bool,
+    pub path: PathBuf,
+    /// MACs not seen for this long are forgotten.
+    #[serde(with = "humantime_serde")]
+    pub max_age: Duration,
+}
+
+impl Default for MacCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("/var/lib/internet-hogs/macs.json"),
+            max_age: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl MacCacheConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_age.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "mac_cache",
+                message: "max_age must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Categories of flows from a file of rules, see `Categories`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CategoriesConfig {
+    pub enabled: bool,
+    /// TOML file with the rules.
+    pub path: PathBuf,
+    /// How often the file is checked for changes.
+    #[serde(with = "humantime_serde")]
+    pub reload: Duration,
+    /// Names from `[ipfix.enterprise_fields]` with hostnames of servers,
+    /// like the TLS SNI or HTTP host, for rules with `hosts`.
+    pub host_fields: Vec<String>,
+}
+
+impl Default for CategoriesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("/etc/internet-hogs/categories.toml"),
+            reload: Duration::from_secs(60),
+            host_fields: Vec::new(),
+        }
+    }
+}
+
+impl CategoriesConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.reload.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "categories",
+                message: "reload must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Site specific rules that tag, rename or drop records, see `Rules`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesConfig {
+    pub enabled: bool,
+    /// TOML file with the rules.
+    pub path: PathBuf,
+    /// How often the file is checked for changes.
+    #[serde(with = "humantime_serde")]
+    pub reload: Duration,
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("/etc/internet-hogs/rules.toml"),
+            reload: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RulesConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.reload.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "rules",
+                message: "reload must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Rhai script that tags, renames or drops records, see `Script`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptConfig {
+    pub enabled: bool,
+    pub path: PathBuf,
+    /// How often the file is checked for changes.
+    #[serde(with = "humantime_serde")]
+    pub reload: Duration,
+    /// Operations the script can run per record before it's stopped.
+    pub max_operations: u64,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("/etc/internet-hogs/script.rhai"),
+            reload: Duration::from_secs(60),
+            max_operations: 100_000,
+        }
+    }
+}
+
+impl ScriptConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let inconsistent = |message| {
+            Err(ConfigError::Inconsistent {
+                section: "script",
+                message,
+            })
+        };
+
+        if self.reload.is_zero() {
+            return inconsistent("reload must be positive");
+        }
+
+        if self.max_operations == 0 {
+            return inconsistent("max_operations must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+/// Domains of servers from DNS answers of the local resolver, see `dnstap`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnstapConfig {
+    pub enabled: bool,
+    /// Unix socket the resolver sends dnstap to, replaced if it exists.
+    pub socket: PathBuf,
+    /// Addresses not seen in answers for this long lose their domain.
+    #[serde(with = "humantime_serde")]
+    pub max_age: Duration,
+}
+
+impl Default for DnstapConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            socket: PathBuf::from("/run/internet-hogs/dnstap.sock"),
+            max_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl DnstapConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_age.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "dnstap",
+                message: "max_age must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Published IP ranges of cloud providers, see `clouds::poll`. Providers
+/// with an empty URL are skipped.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CloudsConfig {
+    pub enabled: bool,
+    /// How often the ranges are fetched again.
+    #[serde(with = "humantime_serde")]
+    pub refresh: Duration,
+    pub aws: String,
+    pub gcp: String,
+    /// Service tags of Azure, their URL changes every week.
+    pub azure: String,
+    pub cloudflare: String,
+}
+
+impl Default for CloudsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            refresh: Duration::from_secs(24 * 60 * 60),
+            aws: "https://ip-ranges.amazonaws.com/ip-ranges.json".to_owned(),
+            gcp: "https://www.gstatic.com/ipranges/cloud.json".to_owned(),
+            azure: String::new(),
+            cloudflare: "https://api.cloudflare.com/client/v4/ips".to_owned(),
+        }
+    }
+}
+
+impl CloudsConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.refresh.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "clouds",
+                message: "refresh must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Blocklists that flows of listed addresses are tagged with and alerted
+/// on, see `threats::poll`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThreatsConfig {
+    pub enabled: bool,
+    /// Files or http(s) URLs with an address or network per line, keyed
+    /// by the name flows are tagged with.
+    pub lists: BTreeMap<String, String>,
+    /// How often the lists are read again.
+    #[serde(with = "humantime_serde")]
+    pub refresh: Duration,
+    /// URL that flows of listed addresses are posted to, none if empty.
+    pub webhook: String,
+    /// Alerts for the same local and remote address are sent at most
+    /// this often.
+    #[serde(with = "humantime_serde")]
+    pub alert_interval: Duration,
+    /// Groups of devices that alerts are sent for, all devices if empty.
+    pub alert_groups: Vec<String>,
+}
+
+impl Default for ThreatsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lists: BTreeMap::new(),
+            refresh: Duration::from_secs(60 * 60),
+            webhook: String::new(),
+            alert_interval: Duration::from_secs(60 * 60),
+            alert_groups: Vec::new(),
+        }
+    }
+}
+
+impl ThreatsConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let inconsistent = |message| {
+            Err(ConfigError::Inconsistent {
+                section: "threats",
+                message,
+            })
+        };
+
+        if !self.enabled {
+            return Ok(());
+        }
+
+        if self.lists.is_empty() {
+            return inconsistent("lists are required");
+        }
+
+        if self.refresh.is_zero() {
+            return inconsistent("refresh must be positive");
+        }
+
+        if self.alert_interval.is_zero() {
+            return inconsistent("alert_interval must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+/// Endpoints of Tor and VPNs that devices tunnel their traffic through,
+/// see `vpn::poll`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct VpnConfig {
+    pub enabled: bool,
+    /// Onionoo summary of Tor relays, skipped if empty.
+    pub tor: String,
+    /// Files or http(s) URLs with an address or network per line, keyed
+    /// by the name of the VPN.
+    pub lists: BTreeMap<String, String>,
+    /// How often the relays and lists are read again.
+    #[serde(with = "humantime_serde")]
+    pub refresh: Duration,
+}
+
+impl Default for VpnConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tor: "https://onionoo.torproject.org/summary?running=true".to_owned(),
+            lists: BTreeMap::new(),
+            refresh: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl VpnConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.refresh.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "vpn",
+                message: "refresh must be positive",
+            });
+        }
+
+        // The name of Tor relays in flows, which a list would shadow.
+        if self.lists.contains_key(TOR) {
+            return Err(ConfigError::Inconsistent {
+                section: "vpn",
+                message: "lists cannot be named tor",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies of flows from several exporters on the path, see `Dedup`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    pub enabled: bool,
+    /// Records of the same flow with the same bytes arriving this close
+    /// to each other are copies.
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    /// Exporters whose copies are kept, most preferred first, with the
+    /// ones missing from the list after them.
+    pub prefer: Vec<IpAddr>,
+    /// How long a flow stays with the exporter it was given to since its
+    /// last record.
+    #[serde(with = "humantime_serde")]
+    pub max_age: Duration,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window: Duration::from_secs(10),
+            prefer: Vec::new(),
+            max_age: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl DedupConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.window.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "dedup",
+                message: "window must be positive",
+            });
+        }
+
+        if self.max_age < self.window {
+            return Err(ConfigError::Inconsistent {
+                section: "dedup",
+                message: "max_age cannot be shorter than window",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Recent bytes of devices and servers served on `/api/top`, see `Top`.
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TopConfig {
+    pub enabled: bool,
+    /// Longest window that can be asked for, kept in memory.
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    /// Devices or servers returned unless asked for another number.
+    pub limit: usize,
+}
+
+impl Default for TopConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window: Duration::from_secs(60 * 60),
+            limit: 10,
+        }
+    }
+}
+
+impl TopConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.window.is_zero() {
+            return Err(ConfigError::Inconsistent {
+                section: "top",
+                message: "window must be positive",
+            });
+        }
+
+        if self.limit == 0 {
+            return Err(ConfigError::Inconsistent {
+                section: "top",
+                message: "limit must be positive",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    /// JSON object per line, with the column names of the table.
+    #[default]
+    Json,
+    /// Comma separated values with a header of column names.
+    Csv,
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxdbConfig {
+    pub enabled: bool,
+    /// Only flows matching this expression are written, see `Filter`.
+    pub filter: String,
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    pub token_file: Option<PathBuf>,
+    pub mode: InfluxdbMode,
+    pub measurement: String,
+    /// Lines sent in one request, flows that is, unless aggregated.
+    pub max_rows: u64,
+    /// How often lines are sent, which is the interval of aggregation.
+    #[serde(with = "humantime_serde")]
+    pub period: Duration,
+    /// Lines kept while InfluxDB is unavailable, the oldest are dropped beyond that.
+    pub max_pending: u64,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl Default for InfluxdbConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            filter: String::new(),
+            url: "http://localhost:8086".to_owned(),
+            org: String::new(),
+            bucket: "flows".to_owned(),
+            token: String::new(),
+            token_file: None,
+            mode: InfluxdbMode::Flows,
+            measurement: "flows".to_owned(),
+            max_rows: 5000,
+            period: Duration::from_secs(10),
+            max_pending: 100_000,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl InfluxdbConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let inconsistent = |message| {
+            Err(ConfigError::Inconsistent {
+                section: "influxdb",
+                message,
+            })
+        };
+
+        if self.bucket.is_empty() || self.measurement.is_empty() {
+            return inconsistent("bucket and measurement must not be empty");
+        }
+
+        if self.max_rows == 0 || self.max_pending < self.max_rows {
+            return inconsistent("max_rows must be positive and max_pending at least as large");
+        }
+
+        if self.period.is_zero() || self.timeout.is_zero() {
+            return inconsistent("period and timeout must be positive");
+        }
+
+        Ok(())
+    }
+
+    fn same_destination(&self, other: &Self) -> bool {
+        self.enabled == other.enabled
+            && self.url == other.url
+            && self.org == other.org
+            && self.bucket == other.bucket
+            && self.token == other.token
+            && self.mode == other.mode
+            && self.measurement == other.measurement
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InfluxdbMode {
+    /// A point per flow.
+    #[default]
+    Flows,
+    /// A point per device and direction with the totals of every period.
+    Aggregated,
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpConfig {
+    pub enabled: bool,
+    /// Only flows matching this expression are written, see `Filter`.
+    pub filter: String,
+    /// Base URL of the OTLP/HTTP receiver, signal paths are appended to it.
+    pub endpoint: String,
+    /// Extra request headers, usually for authentication.
+    pub headers: BTreeMap<String, String>,
+    pub service_name: String,
+    /// How often the counters are exported.
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// Export every flow as a log record too.
+    pub logs: bool,
+    /// Log records sent in one request.
+    pub max_rows: u64,
+    /// How often log records are sent.
+    #[serde(with = "humantime_serde")]
+    pub period: Duration,
+    /// Log records kept while the collector is unavailable, the oldest are
+    /// dropped beyond that.
+    pub max_pending: u64,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            filter: String::new(),
+            endpoint: "http://localhost:4318".to_owned(),
+            headers: BTreeMap::new(),
+            service_name: "internet-hogs".to_owned(),
+            interval: Duration::from_secs(60),
+            logs: false,
+            max_rows: 1000,
+            period: Duration::from_secs(5),
+            max_pending: 100_000,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl OtlpConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let inconsistent = |message| {
+            Err(ConfigError::Inconsistent {
+                section: "otlp",
+                message,
+            })
+        };
+
+        let valid = |(name, value): (&String, &String)| {
+            HeaderName::from_bytes(name.as_bytes()).is_ok() && HeaderValue::from_str(value).is_ok()
+        };
+
+        if !self.headers.iter().all(valid) {
+            return inconsistent("headers must be valid HTTP header names and values");
+        }
+
+        if self.max_rows == 0 || self.max_pending < self.max_rows {
+            return inconsistent("max_rows must be positive and max_pending at least as large");
+        }
+
+        if self.interval.is_zero() || self.period.is_zero() || self.timeout.is_zero() {
+            return inconsistent("interval, period and timeout must be positive");
+        }
+
+        Ok(())
+    }
+
+    fn same_destination(&self, other: &Self) -> bool {
+        self.enabled == other.enabled
+            && self.endpoint == other.endpoint
+            && self.headers == other.headers
+            && self.service_name == other.service_name
+            && self.logs == other.logs
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogConfig {
+    pub enabled: bool,
+    /// Only flows matching this expression are written, see `Filter`.
+    pub filter: String,
+    /// Host and port of the syslog server.
+    pub address: String,
+    pub transport: SyslogTransport,
+    pub format: SyslogFormat,
+    /// Facility code, 16 to 23 are local0 to local7.
+    pub facility: u8,
+    /// Reported hostname, the one of the machine if empty.
+    pub hostname: String,
+    /// CA to verify the server with over TLS instead of the system roots.
+    pub ca: Option<PathBuf>,
+    /// Messages per second, flows over the limit are dropped. Zero for no limit.
+    pub rate_limit: u64,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            filter: String::new(),
+            address: "localhost:514".to_owned(),
+            transport: SyslogTransport::Udp,
+            format: SyslogFormat::Rfc5424,
+            facility: 16,
+            hostname: String::new(),
+            ca: None,
+            rate_limit: 1000,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl SyslogConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let inconsistent = |message| {
+            Err(ConfigError::Inconsistent {
+                section: "syslog",
+                message,
+            })
+        };
+
+        let port = self
+            .address
+            .rsplit_once(':')
+            .map(|(_, port)| port.parse::<u16>());
+
+        if !matches!(port, Some(Ok(_))) {
+            return inconsistent("address must be a host and a port");
+        }
+
+        if self.facility > 23 {
+            return inconsistent("facility must be between 0 and 23");
+        }
+
+        if self.timeout.is_zero() {
+            return inconsistent("timeout must be positive");
+        }
+
+        Ok(())
+    }
+
+    fn same_destination(&self, other: &Self) -> bool {
+        self.enabled == other.enabled
+            && self.address == other.address
+            && self.transport == other.transport
+            && self.format == other.format
+            && self.facility == other.facility
+            && self.hostname == other.hostname
+            && self.ca == other.ca
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport {
+    /// A datagram per message.
+    #[default]
+    Udp,
+    /// Messages prefixed with their length (RFC 6587).
+    Tcp,
+    /// Same as TCP, but encrypted (RFC 5425).
+    Tls,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFormat {
+    /// The JSON object of the flow as the message.
+    #[default]
+    Rfc5424,
+    /// ArcSight Common Event Format as the message.
+    Cef,
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    pub enabled: bool,
+    /// Only flows matching this expression are written, see `Filter`.
+    pub filter: String,
+    pub url: String,
+    pub stream: String,
+    /// Approximate length the stream is trimmed to, zero to keep everything.
+    pub max_len: u64,
+    /// Entries added in one pipeline.
+    pub max_rows: u64,
+    #[serde(with = "humantime_serde")]
+    pub period: Duration,
+    /// Entries kept while Redis is unavailable, the oldest are dropped beyond that.
+    pub max_pending: u64,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            filter: String::new(),
+            url: "redis://localhost:6379".to_owned(),
+            stream: "flows".to_owned(),
+            max_len: 1_000_000,
+            max_rows: 1000,
+            period: Duration::from_secs(1),
+            max_pending: 100_000,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RedisConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let inconsistent = |message| {
+            Err(ConfigError::Inconsistent {
+                section: "redis",
+                message,
+            })
+        };
+
+        if self.stream.is_empty() {
+            return inconsistent("stream must not be empty");
+        }
+
+        if self.max_rows == 0 || self.max_pending < self.max_rows {
+            return inconsistent("max_rows must be positive and max_pending at least as large");
+        }
+
+        if self.period.is_zero() || self.timeout.is_zero() {
+            return inconsistent("period and timeout must be positive");
+        }
+
+        Ok(())
+    }
+
+    fn same_destination(&self, other: &Self) -> bool {
+        self.enabled == other.enabled && self.url == other.url && self.stream == other.stream
+    }
+}
+
+impl Config {
+    /// Reads the config file (if any), then applies `HOGS_*` environment
+    /// variables and command line overrides on top, in that order.
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        let mut table = match &args.config {
+            Some(path) => Self::read_file(path)?,
+            None => Table::new(),
+        };
+
+        let defaults = Table::try_from(Self::default()).expect("default config is serializable");
+
+        let mut unknown_env = vec![];
+
+        for (name, value) in env::vars() {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+
+            if key == "CONFIG" {
+                continue;
+            }
+
+            if !apply_env(&mut table, &defaults, &key.to_lowercase(), &value) {
+                unknown_env.push(name);
+            }
+        }
+
+        let mut config: Self = Value::Table(table)
+            .try_into()
+            .map_err(ConfigError::Invalid)?;
+
+        config.unknown_env = unknown_env;
+
+        if !args.ipfix_bind.is_empty() {
+            config.ipfix.bind = args.ipfix_bind.clone();
+        }
+
+        if !args.ipfix_tcp_bind.is_empty() {
+            config.ipfix.tcp_bind = args.ipfix_tcp_bind.clone();
+        }
+
+        if !args.ipfix_tls_bind.is_empty() {
+            config.ipfix.tls_bind = args.ipfix_tls_bind.clone();
+        }
+
+        if !args.ipfix_dtls_bind.is_empty() {
+            config.ipfix.dtls_bind = args.ipfix_dtls_bind.clone();
+        }
+
+        if let Some(path) = &args.tls_certificate {
+            config.tls.certificate = Some(path.clone());
+        }
+
+        if let Some(path) = &args.tls_private_key {
+            config.tls.private_key = Some(path.clone());
+        }
+
+        if let Some(path) = &args.tls_client_ca {
+            config.tls.client_ca = Some(path.clone());
+        }
+
+        if !args.sflow_bind.is_empty() {
+            config.sflow.bind = args.sflow_bind.clone();
+        }
+
+        if let Some(buffer_size) = args.buffer_size {
+            config.ipfix.buffer_size = buffer_size;
+        }
+
+        if let Some(receive_buffer) = args.receive_buffer {
+            config.ipfix.receive_buffer = Some(receive_buffer);
+        }
+
+        if let Some(bind) = &args.metrics_bind {
+            config.metrics.bind = bind.clone();
+        }
+
+        if args.daemonize {
+            config.daemon.daemonize = true;
+        }
+
+        if let Some(pidfile) = &args.pidfile {
+            config.daemon.pidfile = Some(pidfile.clone());
+        }
+
+        if let Some(user) = &args.user {
+            config.daemon.user = Some(user.clone());
+        }
+
+        if let Some(group) = &args.group {
+            config.daemon.group = Some(group.clone());
+        }
+
+        if let Some(level) = &args.log_level {
+            config.log.level = level.clone();
+        }
+
+        if let Some(format) = args.log_format {
+            config.log.format = format;
+        }
+
+        // Directives for a more specific target take precedence.
+        if args.quiet {
+            config.log.level.push_str(",parser=info");
+        }
+
+        if args.dry_run {
+            config.log.level.push_str(",parser=debug");
+        }
+
+        if args.no_clickhouse || args.dry_run {
+            config.clickhouse.enabled = false;
+        }
+
+        if args.dry_run {
+            config.kafka.enabled = false;
+            config.sqlite.enabled = false;
+            config.parquet.enabled = false;
+            config.file.enabled = false;
+            config.influxdb.enabled = false;
+            config.otlp.enabled = false;
+            config.syslog.enabled = false;
+            config.redis.enabled = false;
+            config.wal.enabled = false;
+        }
+
+        if let Some(url) = &args.clickhouse_url {
+            config.clickhouse.url = url.clone();
+        }
+
+        if let Some(database) = &args.clickhouse_database {
+            config.clickhouse.database = database.clone();
+        }
+
+        if let Some(table) = &args.clickhouse_table {
+            config.clickhouse.table = table.clone();
+        }
+
+        if let Some(user) = &args.clickhouse_user {
+            config.clickhouse.user = user.clone();
+        }
+
+        if let Some(path) = &args.clickhouse_password_file {
+            config.clickhouse.password_file = Some(path.clone());
+        }
+
+        if let Some(path) = &config.clickhouse.password_file {
+            let password = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+                path: path.clone(),
+                source,
+            })?;
+
+            config.clickhouse.password = password.trim_end_matches(['\r', '\n']).to_owned();
+        }
+
+        if let Some(path) = &config.influxdb.token_file {
+            let token = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+                path: path.clone(),
+                source,
+            })?;
+
+            config.influxdb.token = token.trim_end_matches(['\r', '\n']).to_owned();
+        }
+
+        config.ipfix.validate()?;
+
+        config.metrics.validate()?;
+
+        config.clickhouse.validate()?;
+
+        config.kafka.validate()?;
+
+        config.sqlite.validate()?;
+
+        config.parquet.validate()?;
+
+        config.file.validate()?;
+
+        config.influxdb.validate()?;
+
+        config.otlp.validate()?;
+
+        config.syslog.validate()?;
+
+        config.redis.validate()?;
+
+        config.dead_letter.validate()?;
+
+        config.wal.validate()?;
+
+        config.geoip.validate()?;
+
+        config.asn.validate()?;
+
+        config.leases.validate()?;
+
+        config.router.validate()?;
+
+        config.neighbors.validate()?;
+
+        config.mac_cache.validate()?;
+
+        config.categories.validate()?;
+
+        config.dnstap.validate()?;
+
+        config.clouds.validate()?;
+
+        config.threats.validate()?;
+
+        config.vpn.validate()?;
+
+        config.rules.validate()?;
+
+        config.script.validate()?;
+
+        config.dedup.validate()?;
+
+        config.top.validate()?;
+
+        // Parquet files are only readable once closed, which is too late.
+        if config.wal.enabled && config.parquet.enabled {
+            return Err(ConfigError::Inconsistent {
+                section: "wal",
+                message: "cannot be used with the parquet sink",
+            });
+        }
+
+        config.log.validate()?;
+
+        for (section, filter) in config.filters() {
+            Filter::parse(filter)
+                .map_err(|source| ConfigError::InvalidFilter { section, source })?;
+        }
+
+        if (!config.ipfix.tls_bind.is_empty() || !config.ipfix.dtls_bind.is_empty())
+            && (config.tls.certificate.is_none() || config.tls.private_key.is_none())
+        {
+            return Err(ConfigError::Inconsistent {
+                section: "tls",
+                message: "certificate and private_key are required for TLS and DTLS listeners",
+            });
+        }
+
+        config.devices = config
+            .devices
+            .into_iter()
+            .map(|(mac, name)| normalize_mac(&mac).map(|mac| (mac, name)))
+            .collect::<Result<_, _>>()?;
+
+        let mut grouped = HashSet::new();
+
+        for macs in config.groups.values_mut() {
+            for mac in macs {
+                *mac = normalize_mac(mac)?;
+
+                if !grouped.insert(mac.clone()) {
+                    return Err(ConfigError::GroupedTwice(mac.clone()));
+                }
+            }
+        }
+
+        if let Some(group) = config
+            .threats
+            .alert_groups
+            .iter()
+            .find(|group| !config.groups.contains_key(*group))
+        {
+            return Err(ConfigError::UnknownGroup(group.clone()));
+        }
+
+        config.interfaces = config
+            .interfaces
+            .into_iter()
+            .map(|(index, name)| match index.parse::<u32>() {
+                Ok(parsed) => Ok((parsed.to_string(), name)),
+                Err(_) => Err(ConfigError::InvalidInterface(index)),
+            })
+            .collect::<Result<_, _>>()?;
+
+        config.services = config
+            .services
+            .into_iter()
+            .map(|(rule, name)| match parse_rule(&rule) {
+                Some(parsed) => Ok((format_rule(parsed), name)),
+                None => Err(ConfigError::InvalidService(rule)),
+            })
+            .collect::<Result<_, _>>()?;
+
+        config.ipfix.enterprise_fields = config
+            .ipfix
+            .enterprise_fields
+            .into_iter()
+            .map(|(key, name)| normalize_field(&key).map(|key| (key, name)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(config)
+    }
+
+    /// Group of the device with the MAC address, if any.
+    pub fn group(&self, mac: &str) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|(_, macs)| macs.iter().any(|grouped| grouped == mac))
+            .map(|(group, _)| group.as_str())
+    }
+
+    /// Filter expressions of the sinks keyed by their section.
+    pub fn filters(&self) -> [(&'static str, &str); 9] {
+        [
+            ("clickhouse", &self.clickhouse.filter),
+            ("kafka", &self.kafka.filter),
+            ("sqlite", &self.sqlite.filter),
+            ("parquet", &self.parquet.filter),
+            ("file", &self.file.filter),
+            ("influxdb", &self.influxdb.filter),
+            ("otlp", &self.otlp.filter),
+            ("syslog", &self.syslog.filter),
+            ("redis", &self.redis.filter),
+        ]
+    }
+
+    fn read_file(path: &Path) -> Result<Table, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
+
+/// Reloads the configuration on every SIGHUP and publishes it to the watchers.
+/// Invalid configuration is reported and ignored, keeping the previous one.
+pub async fn reload_on_sighup(args: Args, sender: watch::Sender<Arc<Config>>) {
+    let mut hangups = signal(SignalKind::hangup()).unwrap();
+
+    while hangups.recv().await.is_some() {
+        let config = match Config::load(&args) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!(target: "config", "Ignoring invalid configuration on reload: {e}");
+                continue;
+            }
+        };
+
+        let current = sender.borrow().clone();
+
+        if config.ipfix.bind != current.ipfix.bind
+            || config.ipfix.tcp_bind != current.ipfix.tcp_bind
+            || config.ipfix.tls_bind != current.ipfix.tls_bind
+            || config.ipfix.dtls_bind != current.ipfix.dtls_bind
+            || config.tls != current.tls
+            || config.sflow != current.sflow
+            || config.ipfix.receive_buffer != current.ipfix.receive_buffer
+            || config.metrics.bind != current.metrics.bind
+            || config.dnstap.enabled != current.dnstap.enabled
+            || config.dnstap.socket != current.dnstap.socket
+            || config.metrics.prefix != current.metrics.prefix
+            || config.metrics.labels != current.metrics.labels
+            || config.daemon != current.daemon
+            || config.log.format != current.log.format
+            || !config.clickhouse.same_destination(&current.clickhouse)
+            || !config.kafka.same_destination(&current.kafka)
+            || config.sqlite.enabled != current.sqlite.enabled
+            || config.sqlite.path != current.sqlite.path
+            || config.parquet.enabled != current.parquet.enabled
+            || config.parquet.directory != current.parquet.directory
+            || config.file.enabled != current.file.enabled
+            || config.file.directory != current.file.directory
+            || config.file.format != current.file.format
+            || config.file.gzip != current.file.gzip
+            || !config.influxdb.same_destination(&current.influxdb)
+            || !config.otlp.same_destination(&current.otlp)
+            || !config.syslog.same_destination(&current.syslog)
+            || !config.redis.same_destination(&current.redis)
+            || config.wal.enabled != current.wal.enabled
+            || config.wal.directory != current.wal.directory
+        {
+            warn!(
+                target: "config",
+                "Changes to listeners, metric names and labels, daemon, log format, sink destinations and the write-ahead log require a restart"
+            );
+        }
+
+        info!(target: "config", "Configuration reloaded");
+
+        config.clickhouse.log_batching();
+
+        sender.send_replace(Arc::new(config));
+    }
+}
+
+pub fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+/// Converts a MAC address into the upper case colon separated form that
+/// the IPFIX parser produces, so that it can be looked up directly.
+pub fn normalize_mac(mac: &str) -> Result<String, ConfigError> {
+    let octets = mac.split([':', '-']).collect::<Vec<_>>();
+
+    if octets.len() != 6
+        || octets
+            .iter()
+            .any(|octet| octet.len() != 2 || u8::from_str_radix(octet, 16).is_err())
+    {
+        return Err(ConfigError::InvalidMac(mac.to_owned()));
+    }
+
+    Ok(octets.join(":").to_uppercase())
+}
+
+/// Parses a MAC address into the number of the `clientMac` column.
+pub fn parse_mac(mac: &str) -> Option<u64> {
+    let mac = normalize_mac(mac).ok()?;
+
+    u64::from_str_radix(&mac.replace(':', ""), 16).ok()
+}
+
+/// Parses a `PEN:ID` key into the form used to look fields up, without
+/// leading zeros and the like.
+fn normalize_field(key: &str) -> Result<String, ConfigError> {
+    key.split_once(':')
+        .and_then(|(pen, id)| Some((pen.parse::<u32>().ok()?, id.parse::<u16>().ok()?)))
+        .filter(|(_, id)| *id < 0x8000)
+        .map(|(pen, id)| format!("{pen}:{id}"))
+        .ok_or_else(|| ConfigError::InvalidEnterpriseField(key.to_owned()))
+}
+
+/// Sets the value of an environment variable in the config table, returning
+/// whether the config has such a key. The key is matched against the known
+/// config keys, so that underscores in the variable name can be either
+/// section separators or part of the key name.
+fn apply_env(table: &mut Table, known: &Table, key: &str, raw: &str) -> bool {
+    let (path, default) = env_key(known, key);
+
+    let value = match default {
+        Some(Value::String(_)) => Value::String(raw.to_owned()),
+        Some(Value::Array(_)) if !raw.starts_with('[') => Value::Array(
+            raw.split(',')
+                .map(|item| parse_value(item.trim()))
+                .collect(),
+        ),
+        _ => parse_value(raw),
+    };
+
+    // Keys without a default are optional ones or not in the config at
+    // all, which would fail it as a whole.
+    if default.is_none() {
+        let mut probe = Table::new();
+        set_env(&mut probe, &path, value.clone());
+
+        if let Err(e) = Value::Table(probe).try_into::<Config>() {
+            if e.to_string().contains("unknown field") {
+                return false;
+            }
+        }
+    }
+
+    set_env(table, &path, value);
+
+    true
+}
+
+/// Path of a key in the config table along with its default, if it has one.
+fn env_key<'a>(known: &'a Table, key: &str) -> (Vec<String>, Option<&'a Value>) {
+    for (name, default) in known {
+        if let Value::Table(known) = default {
+            if let Some(rest) = key
+                .strip_prefix(name.as_str())
+                .and_then(|k| k.strip_prefix('_'))
+            {
+                let (mut path, default) = env_key(known, rest);
+                path.insert(0, name.clone());
+
+                return (path, default);
+            }
+        } else if name == key {
+            return (vec![name.clone()], Some(default));
+        }
+    }
+
+    (vec![key.to_owned()], None)
+}
+
+fn set_env(table: &mut Table, path: &[String], value: Value) {
+    let (key, sections) = path.split_last().expect("paths have a key");
+
+    let mut table = table;
+
+    for section in sections {
+        match table
+            .entry(section.clone())
+            .or_insert_with(|| Value::Table(Table::new()))
+        {
+            Value::Table(section) => table = section,
+            _ => return,
+        }
+    }
+
+    table.insert(key.clone(), value);
+}
+
+/// Parses a raw environment variable value as a TOML value, so that numbers,
+/// booleans and arrays work, falling back to a plain string.
+fn parse_value(raw: &str) -> Value {
+    format!("value = {raw}")
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_owned()))
+}
@@ -0,0 +1,184 @@
+use std::{
+    future::Future,
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use clickhouse::{Client, Compression};
+use hyper::{
+    body::Body,
+    rt::{Read, ReadBufCursor, Write},
+    Uri,
+};
+use hyper_rustls::{FixedServerNameResolver, HttpsConnectorBuilder};
+use hyper_util::{
+    client::legacy::{
+        connect::{Connect, Connected, Connection, HttpConnector},
+        Client as HttpClient,
+    },
+    rt::TokioExecutor,
+};
+use prometheus_client::metrics::counter::Counter;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tower_service::Service;
+
+use crate::{
+    config::{ClickhouseCompression, ClickhouseConfig},
+    tls,
+};
+
+/// Builds a ClickHouse client over HTTP or HTTPS that counts the bytes
+/// it sends, after compression and encryption.
+pub fn clickhouse_client(config: &ClickhouseConfig, sent: Counter) -> io::Result<Client> {
+    let mut http = HttpConnector::new();
+
+    // Same as the default client of the ClickHouse crate.
+    http.set_keepalive(Some(Duration::from_secs(60)));
+    http.enforce_http(false);
+
+    let connector = Counting { inner: http, sent };
+
+    let client = match config.url.starts_with("https://") {
+        true => {
+            let identity = config
+                .certificate
+                .as_deref()
+                .zip(config.private_key.as_deref());
+
+            let builder = HttpsConnectorBuilder::new()
+                .with_tls_config(tls::client_config(config.ca.as_deref(), identity)?)
+                .https_or_http();
+
+            let builder = match &config.server_name {
+                Some(server_name) => {
+                    let server_name =
+                        ServerName::try_from(server_name.clone()).map_err(io::Error::other)?;
+                    builder.with_server_name_resolver(FixedServerNameResolver::new(server_name))
+                }
+                None => builder,
+            };
+
+            Client::with_http_client(http_client(
+                builder.enable_http1().wrap_connector(connector),
+            ))
+        }
+        false => Client::with_http_client(http_client(connector)),
+    };
+
+    let compression = match config.compression {
+        ClickhouseCompression::None => Compression::None,
+        ClickhouseCompression::Lz4 => Compression::Lz4,
+    };
+
+    Ok(client.with_compression(compression))
+}
+
+fn http_client<C, B>(connector: C) -> HttpClient<C, B>
+where
+    C: Connect + Clone,
+    B: Body + Send,
+    B::Data: Send,
+{
+    // ClickHouse closes idle connections after 3s.
+    HttpClient::builder(TokioExecutor::new())
+        .pool_idle_timeout(Duration::from_secs(2))
+        .build(connector)
+}
+
+/// Connector wrapping connections to count the bytes written to them.
+#[derive(Clone)]
+struct Counting<C> {
+    inner: C,
+    sent: Counter,
+}
+
+impl<C> Service<Uri> for Counting<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+{
+    type Response = CountingStream<C::Response>;
+    type Error = C::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.inner.call(uri);
+        let sent = self.sent.clone();
+
+        Box::pin(async move {
+            Ok(CountingStream {
+                inner: connecting.await?,
+                sent,
+            })
+        })
+    }
+}
+
+struct CountingStream<T> {
+    inner: T,
+    sent: Counter,
+}
+
+impl<T: Connection> Connection for CountingStream<T> {
+    fn connected(&self) -> Connected {
+        self.inner.connected()
+    }
+}
+
+impl<T: Read + Unpin> Read for CountingStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: Write + Unpin> Write for CountingStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(written)) = result {
+            self.sent.inc_by(written as u64);
+        }
+
+        result
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+
+        if let Poll::Ready(Ok(written)) = result {
+            self.sent.inc_by(written as u64);
+        }
+
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
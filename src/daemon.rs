@@ -0,0 +1,75 @@
+use std::{fs, os::fd::AsRawFd, path::Path, process::exit};
+
+use nix::{
+    sys::stat::{umask, Mode},
+    unistd::{chdir, dup2, fork, setgid, setgroups, setsid, setuid, ForkResult, Group, User},
+};
+
+/// Detaches from the terminal with the usual double fork. Must be called
+/// before any threads are started, which includes the Tokio runtime.
+/// Standard input and output are pointed to `/dev/null`, while standard
+/// error is kept for logging and can be redirected by the caller.
+pub fn daemonize() -> nix::Result<()> {
+    // SAFETY: the process is still single threaded at this point.
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        exit(0);
+    }
+
+    setsid()?;
+
+    // SAFETY: the process is still single threaded at this point.
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        exit(0);
+    }
+
+    chdir("/")?;
+
+    umask(Mode::from_bits_truncate(0o022));
+
+    let null = fs::File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|e| nix::Error::from_raw(e.raw_os_error().unwrap_or_default()))?;
+
+    dup2(null.as_raw_fd(), 0)?;
+    dup2(null.as_raw_fd(), 1)?;
+
+    Ok(())
+}
+
+pub fn write_pidfile(path: &Path) -> std::io::Result<()> {
+    fs::write(path, format!("{}\n", std::process::id()))
+}
+
+/// Switches to the given user and group once privileged ports are bound.
+/// The group defaults to the primary group of the user.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), String> {
+    let user = user
+        .map(|name| match User::from_name(name) {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) => Err(format!("unknown user {name}")),
+            Err(e) => Err(format!("cannot look up user {name}: {e}")),
+        })
+        .transpose()?;
+
+    let gid = match group {
+        Some(name) => match Group::from_name(name) {
+            Ok(Some(group)) => Some(group.gid),
+            Ok(None) => return Err(format!("unknown group {name}")),
+            Err(e) => return Err(format!("cannot look up group {name}: {e}")),
+        },
+        None => user.as_ref().map(|user| user.gid),
+    };
+
+    if let Some(gid) = gid {
+        setgroups(&[gid]).map_err(|e| format!("cannot drop supplementary groups: {e}"))?;
+        setgid(gid).map_err(|e| format!("cannot switch to group {gid}: {e}"))?;
+    }
+
+    if let Some(user) = user {
+        setuid(user.uid).map_err(|e| format!("cannot switch to user {}: {e}", user.name))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,9 @@
+use axum::response::Html;
+
+/// Page with the top devices, servers and protocols from `/api/top`,
+/// for those without Grafana. It's built into the binary as is.
+const PAGE: &str = include_str!("dashboard.html");
+
+pub async fn handler() -> Html<&'static str> {
+    Html(PAGE)
+}
@@ -0,0 +1,205 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::{config::DeadLetterConfig, listener::Format};
+
+/// Raw IPv4 or IPv6 packets, told apart by the version in the header.
+const LINKTYPE_RAW: u32 = 101;
+
+/// Largest payload that fits into a UDP datagram.
+const MAX_PAYLOAD: usize = 65_507;
+
+/// Keeps datagrams that cannot be parsed in pcap files, wrapped into UDP
+/// packets from the exporter, so that Wireshark can dissect them and they
+/// can be attached to bug reports. Files are rotated once they get large,
+/// with only the most recent ones kept.
+pub struct DeadLetters {
+    config: DeadLetterConfig,
+    file: Option<(BufWriter<File>, u64)>,
+}
+
+impl DeadLetters {
+    pub fn new(config: &DeadLetterConfig) -> Self {
+        Self {
+            config: config.clone(),
+            file: None,
+        }
+    }
+
+    pub fn reconfigure(&mut self, config: &DeadLetterConfig) {
+        // A new file is started in the new directory, if it's changed.
+        if config.directory != self.config.directory || !config.enabled {
+            self.file = None;
+        }
+
+        self.config = config.clone();
+    }
+
+    pub fn capture(&mut self, exporter: SocketAddr, format: Format, data: &[u8]) {
+        if !self.config.enabled {
+            return;
+        }
+
+        if let Err(e) = block_in_place(|| self.write(exporter, format, data)) {
+            warn!(target: "parser", "Cannot capture an unparsable datagram: {e}");
+            self.file = None;
+        }
+    }
+
+    fn write(&mut self, exporter: SocketAddr, format: Format, data: &[u8]) -> io::Result<()> {
+        let packet = packet(exporter, format, &data[..data.len().min(MAX_PAYLOAD)]);
+
+        let full = self
+            .file
+            .as_ref()
+            .is_some_and(|(_, size)| *size >= self.config.max_size);
+
+        if full {
+            self.file = None;
+        }
+
+        let (writer, size) = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(self.open()?),
+        };
+
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend((since_epoch.as_secs() as u32).to_le_bytes());
+        record.extend(since_epoch.subsec_micros().to_le_bytes());
+        record.extend((packet.len() as u32).to_le_bytes());
+        record.extend((packet.len() as u32).to_le_bytes());
+        record.extend(packet);
+
+        writer.write_all(&record)?;
+        writer.flush()?;
+
+        *size += record.len() as u64;
+
+        Ok(())
+    }
+
+    /// Starts a new file, removing the oldest ones over the limit.
+    fn open(&self) -> io::Result<(BufWriter<File>, u64)> {
+        fs::create_dir_all(&self.config.directory)?;
+
+        let name = humantime::format_rfc3339_micros(SystemTime::now())
+            .to_string()
+            .replace(':', "");
+
+        let path = self
+            .config
+            .directory
+            .join(format!("dead-letter-{name}.pcap"));
+
+        let mut writer = BufWriter::new(
+            OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .open(&path)?,
+        );
+
+        let mut header = vec![];
+        header.extend(0xa1b2c3d4u32.to_le_bytes());
+        header.extend(2u16.to_le_bytes());
+        header.extend(4u16.to_le_bytes());
+        header.extend(0i32.to_le_bytes());
+        header.extend(0u32.to_le_bytes());
+        header.extend(65_535u32.to_le_bytes());
+        header.extend(LINKTYPE_RAW.to_le_bytes());
+
+        writer.write_all(&header)?;
+
+        info!(target: "parser", "Capturing unparsable datagrams to {}", path.display());
+
+        let mut files = fs::read_dir(&self.config.directory)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| is_dead_letter(path))
+            .collect::<Vec<_>>();
+
+        // Names sort in the order the files were started.
+        files.sort_unstable();
+
+        let excess = files.len().saturating_sub(self.config.max_files);
+
+        for old in &files[..excess] {
+            fs::remove_file(old)?;
+        }
+
+        Ok((writer, header.len() as u64))
+    }
+}
+
+fn is_dead_letter(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with("dead-letter-") && name.ends_with(".pcap"))
+}
+
+/// Wraps the data into a UDP packet from the exporter to the port usually
+/// used for the format. The destination address is left unspecified.
+fn packet(exporter: SocketAddr, format: Format, data: &[u8]) -> Vec<u8> {
+    let port = match format {
+        Format::Netflow => 2055u16,
+        Format::Sflow => 6343,
+    };
+
+    let udp_length = (8 + data.len()) as u16;
+
+    let mut udp = Vec::with_capacity(udp_length as usize);
+    udp.extend(exporter.port().to_be_bytes());
+    udp.extend(port.to_be_bytes());
+    udp.extend(udp_length.to_be_bytes());
+    // Zero means no checksum, which is fine for captures.
+    udp.extend(0u16.to_be_bytes());
+    udp.extend(data);
+
+    let mut packet = vec![];
+
+    match exporter.ip().to_canonical() {
+        IpAddr::V4(source) => {
+            let mut header = [0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&(20 + udp_length).to_be_bytes());
+            header[6] = 0x40;
+            header[8] = 64;
+            header[9] = 17;
+            header[12..16].copy_from_slice(&source.octets());
+
+            let mut sum = header
+                .chunks(2)
+                .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+                .sum::<u32>();
+
+            while sum > 0xffff {
+                sum = (sum & 0xffff) + (sum >> 16);
+            }
+
+            header[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+
+            packet.extend(header);
+        }
+        IpAddr::V6(source) => {
+            packet.extend(0x6000_0000u32.to_be_bytes());
+            packet.extend(udp_length.to_be_bytes());
+            packet.push(17);
+            packet.push(64);
+            packet.extend(source.octets());
+            packet.extend([0u8; 16]);
+        }
+    }
+
+    packet.extend(udp);
+
+    packet
+}
@@ -0,0 +1,107 @@
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+use crate::config::DedupConfig;
+
+/// Drops copies of flows that several exporters on the path report, like a
+/// core switch and an edge router, which would count the bytes twice.
+/// Records are copies when they have the same addresses, ports, protocol
+/// and bytes and come from different exporters within `window`.
+///
+/// The first copy is kept, being counted already when the next one comes,
+/// but the flow then goes to the preferred exporter of the two. Its later
+/// records from the other exporter are dropped even with different bytes,
+/// as exporters with different active timeouts split flows differently,
+/// until the flow has no records for `max_age`.
+#[derive(Default)]
+pub struct Dedup {
+    seen: HashMap<(Connection, u64), Seen>,
+    owners: HashMap<Connection, Seen>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Connection {
+    pub protocol: u8,
+    pub src_addr: IpAddr,
+    pub src_port: u16,
+    pub dst_addr: IpAddr,
+    pub dst_port: u16,
+}
+
+/// Exporter of a record and when it came.
+struct Seen {
+    exporter: IpAddr,
+    time: Instant,
+}
+
+impl Dedup {
+    /// Tells whether the record is the one to keep rather than a copy.
+    pub fn keep(
+        &mut self,
+        config: &DedupConfig,
+        exporter: IpAddr,
+        connection: Connection,
+        bytes: u64,
+    ) -> bool {
+        let now = Instant::now();
+
+        if let Some(owner) = self.owners.get_mut(&connection) {
+            if owner.time.elapsed() < config.max_age {
+                owner.time = now;
+                return owner.exporter == exporter;
+            }
+        }
+
+        match self.seen.get(&(connection, bytes)) {
+            Some(seen) if seen.exporter != exporter && seen.time.elapsed() < config.window => {
+                let owner = match rank(config, exporter) < rank(config, seen.exporter) {
+                    true => exporter,
+                    false => seen.exporter,
+                };
+
+                self.owners.insert(
+                    connection,
+                    Seen {
+                        exporter: owner,
+                        time: now,
+                    },
+                );
+
+                false
+            }
+            _ => {
+                self.seen.insert(
+                    (connection, bytes),
+                    Seen {
+                        exporter,
+                        time: now,
+                    },
+                );
+
+                true
+            }
+        }
+    }
+
+    /// Forgets records older than `window` and flows without records for
+    /// `max_age`, returning the number of flows forgotten.
+    pub fn expire(&mut self, config: &DedupConfig) -> usize {
+        self.seen
+            .retain(|_, seen| seen.time.elapsed() < config.window);
+
+        let before = self.owners.len();
+
+        self.owners
+            .retain(|_, owner| owner.time.elapsed() < config.max_age);
+
+        before - self.owners.len()
+    }
+}
+
+/// Position of the exporter in `prefer`, with the missing ones last.
+fn rank(config: &DedupConfig, exporter: IpAddr) -> usize {
+    config
+        .prefer
+        .iter()
+        .position(|preferred| *preferred == exporter)
+        .unwrap_or(config.prefer.len())
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UnixListener,
+    select, spawn,
+    sync::watch,
+};
+use tracing::{debug, warn};
+
+/// Content type of dnstap in Frame Streams.
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+
+/// Frames larger than this are not dnstap messages.
+const MAX_FRAME: usize = 1024 * 1024;
+
+/// Addresses remembered at most, all are forgotten once there are more.
+const MAX_DOMAINS: usize = 1 << 20;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// Domains that addresses were recently given out for by the resolver.
+#[derive(Clone, Default)]
+pub struct Domains {
+    entries: Arc<Mutex<HashMap<IpAddr, Entry>>>,
+}
+
+struct Entry {
+    domain: Arc<str>,
+    /// When the address was last in an answer.
+    seen: Instant,
+}
+
+impl Domains {
+    /// Domain last answered with the address, unless that was longer
+    /// than `max_age` ago.
+    pub fn get(&self, addr: &IpAddr, max_age: Duration) -> Option<Arc<str>> {
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .get(addr)
+            .filter(|entry| entry.seen.elapsed() < max_age)
+            .map(|entry| entry.domain.clone())
+    }
+
+    /// Forgets addresses not answered with for `max_age`, returning how many.
+    pub fn expire(&self, max_age: Duration) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+
+        entries.retain(|_, entry| entry.seen.elapsed() < max_age);
+
+        before - entries.len()
+    }
+
+    fn insert(&self, domain: &str, addrs: Vec<IpAddr>) {
+        let domain = Arc::<str>::from(domain);
+        let seen = Instant::now();
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= MAX_DOMAINS {
+            entries.clear();
+        }
+
+        for addr in addrs {
+            let domain = domain.clone();
+            entries.insert(addr, Entry { domain, seen });
+        }
+    }
+}
+
+/// Binds the socket that resolvers like Unbound, Knot Resolver or BIND
+/// send dnstap to. Anyone who can reach the socket can send answers, so
+/// access is left to the permissions of its directory.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let socket = UnixListener::bind(path)?;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+
+    Ok(socket)
+}
+
+/// Accepts connections of resolvers, remembering the domains of the
+/// addresses in the answers they send.
+pub async fn accept(socket: UnixListener, domains: Domains, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        let accepted = select! {
+            accepted = socket.accept() => accepted,
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        };
+
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!(target: "dnstap", "Cannot accept connection: {e}");
+                continue;
+            }
+        };
+
+        let domains = domains.clone();
+
+        spawn(async move {
+            match read_frames(stream, domains).await {
+                Ok(()) => debug!(target: "dnstap", "Resolver disconnected"),
+                Err(e) => warn!(target: "dnstap", "Cannot read from the resolver: {e}"),
+            }
+        });
+    }
+}
+
+/// Reads Frame Streams, answering the handshake of bidirectional writers,
+/// see https://farsightsec.github.io/fstrm/ for the protocol.
+async fn read_frames(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    domains: Domains,
+) -> io::Result<()> {
+    loop {
+        let length = match stream.read_u32().await {
+            Ok(length) => length as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        // Control frames are escaped with a zero length.
+        let control = length == 0;
+
+        let length = match control {
+            true => stream.read_u32().await? as usize,
+            false => length,
+        };
+
+        if length > MAX_FRAME {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame is too large",
+            ));
+        }
+
+        let mut frame = vec![0; length];
+        stream.read_exact(&mut frame).await?;
+
+        if !control {
+            if let Some((domain, addrs)) = dnstap_response(&frame).and_then(answers) {
+                domains.insert(&domain, addrs);
+            }
+
+            continue;
+        }
+
+        let kind = frame
+            .get(..4)
+            .map(|kind| u32::from_be_bytes(kind.try_into().unwrap()));
+
+        match kind {
+            Some(CONTROL_READY) => {
+                let mut accept = Vec::new();
+                accept.extend_from_slice(&0u32.to_be_bytes());
+                accept.extend_from_slice(&(12 + CONTENT_TYPE.len() as u32).to_be_bytes());
+                accept.extend_from_slice(&CONTROL_ACCEPT.to_be_bytes());
+                accept.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+                accept.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+                accept.extend_from_slice(CONTENT_TYPE);
+
+                stream.write_all(&accept).await?;
+            }
+            Some(CONTROL_STOP) => {
+                let mut finish = Vec::new();
+                finish.extend_from_slice(&0u32.to_be_bytes());
+                finish.extend_from_slice(&4u32.to_be_bytes());
+                finish.extend_from_slice(&CONTROL_FINISH.to_be_bytes());
+
+                // Unidirectional writers hang up without waiting for it.
+                let _ = stream.write_all(&finish).await;
+
+                return Ok(());
+            }
+            Some(CONTROL_START) => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected control frame",
+                ))
+            }
+        }
+    }
+}
+
+/// DNS response in a dnstap message, if it has one.
+fn dnstap_response(frame: &[u8]) -> Option<&[u8]> {
+    // Dnstap.message = 14, Message.response_message = 14.
+    let message = protobuf_field(frame, 14)?;
+
+    protobuf_field(message, 14)
+}
+
+/// Value of a length-delimited protobuf field, skipping the other ones.
+fn protobuf_field(mut buffer: &[u8], number: u64) -> Option<&[u8]> {
+    while !buffer.is_empty() {
+        let key = varint(&mut buffer)?;
+
+        match key & 0x7 {
+            0 => {
+                varint(&mut buffer)?;
+            }
+            1 => buffer = buffer.get(8..)?,
+            2 => {
+                let length = varint(&mut buffer)? as usize;
+                let value = buffer.get(..length)?;
+
+                if key >> 3 == number {
+                    return Some(value);
+                }
+
+                buffer = &buffer[length..];
+            }
+            5 => buffer = buffer.get(4..)?,
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+fn varint(buffer: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = buffer.split_first()?;
+        *buffer = rest;
+
+        value |= u64::from(byte & 0x7f) << shift;
+
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Name asked about in the DNS response with the addresses of the answer,
+/// which are for the name at the end of the CNAME chain.
+fn answers(message: &[u8]) -> Option<(String, Vec<IpAddr>)> {
+    let u16_at = |offset: usize| {
+        message
+            .get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes(bytes.try_into().unwrap()))
+    };
+
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    if questions == 0 || answers == 0 {
+        return None;
+    }
+
+    let (domain, mut offset) = name(message, 12)?;
+
+    // Type and class of the question, resolvers only send one.
+    offset += 4;
+
+    for _ in 1..questions {
+        offset = name(message, offset)?.1 + 4;
+    }
+
+    let mut addrs = Vec::new();
+
+    for _ in 0..answers {
+        offset = name(message, offset)?.1;
+
+        let kind = u16_at(offset)?;
+        let length = u16_at(offset + 8)? as usize;
+        let data = message.get(offset + 10..offset + 10 + length)?;
+
+        match (kind, length) {
+            (TYPE_A, 4) => addrs.push(IpAddr::V4(Ipv4Addr::from(
+                <[u8; 4]>::try_from(data).unwrap(),
+            ))),
+            (TYPE_AAAA, 16) => addrs.push(IpAddr::V6(Ipv6Addr::from(
+                <[u8; 16]>::try_from(data).unwrap(),
+            ))),
+            _ => {}
+        }
+
+        offset += 10 + length;
+    }
+
+    (!addrs.is_empty()).then_some((domain, addrs))
+}
+
+/// Reads a possibly compressed name, returning it in lowercase without
+/// the trailing dot, with the offset right after it.
+fn name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+
+    // Pointers can only go back, but loops are cut short anyway.
+    for _ in 0..128 {
+        let length = *message.get(offset)? as usize;
+
+        match length {
+            0 => {
+                let name = labels.join(".").to_lowercase();
+                return Some((name, end.unwrap_or(offset + 1)));
+            }
+            length if length & 0xc0 == 0xc0 => {
+                let pointer = (length & 0x3f) << 8 | *message.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            length => {
+                let label = message.get(offset + 1..offset + 1 + length)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + length;
+            }
+        }
+    }
+
+    None
+}
@@ -0,0 +1,409 @@
+use std::{cmp::Ordering, net::IpAddr};
+
+use ipnet::IpNet;
+use serde_json::{Map, Value};
+
+use crate::row::{FlowRecord, SCHEMA};
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct ParseError(String);
+
+/// Expression deciding which flows a sink gets, such as
+/// `protocol == 6 and not serverIP in [10.0.0.0/8, 192.168.0.0/16]`.
+/// Empty expressions match every flow.
+pub struct Filter(Option<Expr>);
+
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Column, Op, Literal),
+    In(Column, Vec<IpNet>),
+}
+
+struct Column {
+    name: &'static str,
+    kind: Kind,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Number,
+    Bool,
+    String,
+    Address,
+}
+
+enum Literal {
+    Number(u64),
+    Bool(bool),
+    String(String),
+    Address(IpAddr),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(PartialEq)]
+enum Token {
+    /// Keywords, column names, numbers and addresses.
+    Word(String),
+    /// Quoted string.
+    String(String),
+    Symbol(&'static str),
+}
+
+/// Columns that hold whichever of the IPv4 and IPv6 addresses is set.
+const VIRTUAL_COLUMNS: &[&str] = &["clientIP", "serverIP"];
+
+const SYMBOLS: &[&str] = &["==", "!=", "<=", ">=", "<", ">", "(", ")", "[", "]", ","];
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            position: 0,
+        };
+
+        if parser.tokens.is_empty() {
+            return Ok(Self(None));
+        }
+
+        let expr = parser.or()?;
+
+        match parser.next() {
+            None => Ok(Self(Some(expr))),
+            Some(token) => Err(unexpected(token, "the end")),
+        }
+    }
+
+    pub fn matches(&self, record: &FlowRecord) -> bool {
+        let Some(expr) = &self.0 else {
+            return true;
+        };
+
+        match serde_json::to_value(record) {
+            Ok(Value::Object(columns)) => expr.eval(&columns),
+            _ => false,
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, columns: &Map<String, Value>) -> bool {
+        match self {
+            Self::Or(left, right) => left.eval(columns) || right.eval(columns),
+            Self::And(left, right) => left.eval(columns) && right.eval(columns),
+            Self::Not(expr) => !expr.eval(columns),
+            Self::In(column, networks) => column
+                .address(columns)
+                .is_some_and(|address| networks.iter().any(|net| net.contains(&address))),
+            Self::Compare(column, op, literal) => {
+                let value = &columns[column.name];
+
+                // Missing values, like nulls, don't compare to anything.
+                let ordering = match literal {
+                    Literal::Number(number) => value.as_u64().map(|value| value.cmp(number)),
+                    Literal::Bool(bool) => value.as_bool().map(|value| value.cmp(bool)),
+                    Literal::String(string) => value.as_str().map(|value| value.cmp(string)),
+                    Literal::Address(address) => {
+                        column.address(columns).map(|value| value.cmp(address))
+                    }
+                };
+
+                ordering.is_some_and(|ordering| op.holds(ordering))
+            }
+        }
+    }
+}
+
+impl Column {
+    fn find(name: &str) -> Result<Self, ParseError> {
+        if let Some(name) = VIRTUAL_COLUMNS.iter().find(|column| **column == name) {
+            return Ok(Self {
+                name,
+                kind: Kind::Address,
+            });
+        }
+
+        let Some((name, column_type)) = SCHEMA.iter().find(|(column, _)| *column == name) else {
+            return Err(ParseError(format!("unknown column {name}")));
+        };
+
+        let kind = match *column_type {
+            "LowCardinality(String)" => Kind::String,
+            "IPv4" | "IPv6" => Kind::Address,
+            "Bool" => Kind::Bool,
+            "Array(UInt32)" | "Map(String, String)" => {
+                return Err(ParseError(format!("column {name} cannot be filtered on")));
+            }
+            _ => Kind::Number,
+        };
+
+        Ok(Self { name, kind })
+    }
+
+    fn address(&self, columns: &Map<String, Value>) -> Option<IpAddr> {
+        let value = match VIRTUAL_COLUMNS.contains(&self.name) {
+            true => match &columns[&format!("{}v6", self.name)] {
+                Value::String(v6) if v6 == "::" => &columns[&format!("{}v4", self.name)],
+                v6 => v6,
+            },
+            false => &columns[self.name],
+        };
+
+        value.as_str()?.parse().ok()
+    }
+}
+
+impl Op {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Self::Eq => ordering.is_eq(),
+            Self::Ne => ordering.is_ne(),
+            Self::Lt => ordering.is_lt(),
+            Self::Le => ordering.is_le(),
+            Self::Gt => ordering.is_gt(),
+            Self::Ge => ordering.is_ge(),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<&Token> {
+        self.position += 1;
+        self.tokens.get(self.position - 1)
+    }
+
+    /// Skips the next token if it's the expected one.
+    fn eat(&mut self, expected: &Token) -> bool {
+        let found = self.tokens.get(self.position) == Some(expected);
+
+        if found {
+            self.position += 1;
+        }
+
+        found
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        self.eat(&Token::Word(word.to_owned()))
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), ParseError> {
+        match self.eat(&Token::Symbol(symbol)) {
+            true => Ok(()),
+            false => Err(self.unexpected(symbol)),
+        }
+    }
+
+    fn unexpected(&mut self, expected: &str) -> ParseError {
+        match self.next() {
+            Some(token) => unexpected(token, expected),
+            None => ParseError(format!("expected {expected}, found the end")),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.and()?;
+
+        while self.eat_word("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.not()?;
+
+        while self.eat_word("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, ParseError> {
+        if self.eat_word("not") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+
+        if self.eat(&Token::Symbol("(")) {
+            let expr = self.or()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
+        let column = match self.next() {
+            Some(Token::Word(name)) => Column::find(name)?,
+            _ => {
+                self.position -= 1;
+                return Err(self.unexpected("a column"));
+            }
+        };
+
+        if self.eat_word("in") {
+            if column.kind != Kind::Address {
+                return Err(ParseError(format!("{} is not an address", column.name)));
+            }
+
+            return Ok(Expr::In(column, self.networks()?));
+        }
+
+        let op = match self.next() {
+            Some(Token::Symbol("==")) => Op::Eq,
+            Some(Token::Symbol("!=")) => Op::Ne,
+            Some(Token::Symbol("<")) => Op::Lt,
+            Some(Token::Symbol("<=")) => Op::Le,
+            Some(Token::Symbol(">")) => Op::Gt,
+            Some(Token::Symbol(">=")) => Op::Ge,
+            _ => {
+                self.position -= 1;
+                return Err(self.unexpected("a comparison"));
+            }
+        };
+
+        if column.kind != Kind::Number && !matches!(op, Op::Eq | Op::Ne) {
+            return Err(ParseError(format!(
+                "{} can only be compared with == and !=",
+                column.name
+            )));
+        }
+
+        let literal = match (column.kind, self.next()) {
+            (Kind::Number, Some(Token::Word(word))) if word.parse::<u64>().is_ok() => {
+                Literal::Number(word.parse().unwrap())
+            }
+            (Kind::Bool, Some(Token::Word(word))) if word == "true" || word == "false" => {
+                Literal::Bool(word == "true")
+            }
+            (Kind::String, Some(Token::String(string))) => Literal::String(string.clone()),
+            (Kind::Address, Some(Token::Word(word))) if word.parse::<IpAddr>().is_ok() => {
+                Literal::Address(word.parse().unwrap())
+            }
+            _ => {
+                self.position -= 1;
+
+                let expected = match column.kind {
+                    Kind::Number => "a number",
+                    Kind::Bool => "true or false",
+                    Kind::String => "a quoted string",
+                    Kind::Address => "an address",
+                };
+
+                return Err(self.unexpected(expected));
+            }
+        };
+
+        Ok(Expr::Compare(column, op, literal))
+    }
+
+    /// A network or a bracketed list of them, addresses are networks too.
+    fn networks(&mut self) -> Result<Vec<IpNet>, ParseError> {
+        let bracketed = self.eat(&Token::Symbol("["));
+        let mut networks = vec![];
+
+        loop {
+            let network = match self.next() {
+                Some(Token::Word(word)) => word
+                    .parse::<IpNet>()
+                    .ok()
+                    .or_else(|| word.parse::<IpAddr>().ok().map(IpNet::from)),
+                _ => None,
+            };
+
+            match network {
+                Some(network) => networks.push(network),
+                None => {
+                    self.position -= 1;
+                    return Err(self.unexpected("a network"));
+                }
+            }
+
+            if !bracketed || !self.eat(&Token::Symbol(",")) {
+                break;
+            }
+        }
+
+        if bracketed {
+            self.expect("]")?;
+        }
+
+        Ok(networks)
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    let mut rest = input.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else if c == '"' {
+            let mut string = String::new();
+            let mut chars = rest[1..].char_indices();
+
+            loop {
+                match chars.next() {
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => string.push(escaped),
+                        None => return Err(ParseError("unterminated string".to_owned())),
+                    },
+                    Some((end, '"')) => {
+                        rest = &rest[end + 2..];
+                        break;
+                    }
+                    Some((_, c)) => string.push(c),
+                    None => return Err(ParseError("unterminated string".to_owned())),
+                }
+            }
+
+            tokens.push(Token::String(string));
+        } else if is_word(c) {
+            let end = rest.find(|c| !is_word(c)).unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..end].to_owned()));
+            rest = &rest[end..];
+        } else {
+            return Err(ParseError(format!("unexpected character {c:?}")));
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// Characters of keywords, column names, numbers, addresses and networks.
+fn is_word(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | ':' | '/')
+}
+
+fn unexpected(token: &Token, expected: &str) -> ParseError {
+    let found = match token {
+        Token::Word(word) => word.clone(),
+        Token::String(string) => format!("{string:?}"),
+        Token::Symbol(symbol) => symbol.to_string(),
+    };
+
+    ParseError(format!("expected {expected}, found {found}"))
+}
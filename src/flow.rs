@@ -0,0 +1,796 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    iter,
+    net::IpAddr,
+};
+
+use netflow_parser::{
+    static_versions::v5,
+    variable_versions::{
+        data_number::{DataNumber, FieldValue},
+        ipfix::{FlowSetBody as IpfixBody, IPFixParser, Template},
+        ipfix_lookup::{IANAIPFixField as Field, IPFixField, ReverseInformationElement as Reverse},
+        v9::FlowSetBody as V9Body,
+        v9_lookup::V9Field,
+    },
+    NetflowPacket,
+};
+
+use crate::{records, structured};
+
+/// Looks up a field and converts it, `None` if it's missing or has
+/// an unexpected type. The fallback field is tried if the first one fails.
+macro_rules! extract_field {
+    ($map:ident, $key:expr, $output:ty) => {
+        $map.get(&$key)
+            .and_then(|value| <$output>::try_from(value).ok())
+    };
+
+    ($map:ident, $key:expr, $fallback:expr, $output:ty) => {
+        extract_field!($map, $key, $output).or_else(|| extract_field!($map, $fallback, $output))
+    };
+}
+
+/// Sampling rates announced by an exporter in options data records,
+/// kept per observation domain until the exporter goes away.
+#[derive(Default)]
+pub struct SamplingRates(HashMap<u32, u32>);
+
+/// Records a datagram can be behind the expected sequence number and still
+/// be taken as late, rather than a sign of an exporter restart.
+const REORDERING: u32 = 1 << 16;
+
+/// Sequence numbers expected next from an exporter, per observation domain.
+#[derive(Default)]
+pub struct Sequences(HashMap<u32, u32>);
+
+impl Sequences {
+    /// Checks the sequence number of an IPFIX packet, which counts the data
+    /// records sent before it, and returns the observation domain with
+    /// the number of records that never arrived. Packets arriving late,
+    /// after the ones following them, are ignored, while bigger jumps
+    /// backwards are taken as exporter restarts. Data without a template
+    /// has an unknown number of records, so the packet after it is not
+    /// checked.
+    pub fn missed(&mut self, packet: &NetflowPacket) -> Option<(u32, u32)> {
+        let NetflowPacket::IPFix(ipfix) = packet else {
+            return None;
+        };
+
+        let domain = ipfix.header.observation_domain_id;
+        let sequence = ipfix.header.sequence_number;
+
+        let mut records = Some(0usize);
+
+        for flowset in &ipfix.flowsets {
+            match &flowset.body {
+                IpfixBody::Data(data) => records = records.map(|n| n + data.fields.len()),
+                IpfixBody::OptionsData(data) => records = records.map(|n| n + data.fields.len()),
+                IpfixBody::NoTemplate(_) => records = None,
+                _ => {}
+            }
+        }
+
+        let Some(records) = records else {
+            self.0.remove(&domain);
+            return None;
+        };
+
+        // Records of a late packet were counted as missed already, and
+        // the packets after it are still expected where they were.
+        if let Some(expected) = self.0.get(&domain) {
+            let behind = expected.wrapping_sub(sequence);
+
+            if behind != 0 && behind <= REORDERING {
+                return None;
+            }
+        }
+
+        let expected = self
+            .0
+            .insert(domain, sequence.wrapping_add(records as u32))?;
+
+        let missed = sequence.wrapping_sub(expected);
+
+        (missed != 0 && missed < 1 << 31).then_some((domain, missed))
+    }
+}
+
+/// A flow record in the form shared by all the supported export protocols.
+pub struct Flow {
+    /// IPFIX observation domain or NetFlow v9 source id of the exporter.
+    pub observation_domain: Option<u32>,
+    /// MAC address of the sender, if the exporter reports it.
+    pub src_mac: Option<String>,
+    pub src_addr: IpAddr,
+    pub src_port: u16,
+    pub dst_addr: IpAddr,
+    pub dst_port: u16,
+    pub protocol: u8,
+    /// TCP flags seen over the lifetime of the flow, ORed together.
+    pub tcp_flags: u16,
+    /// ICMP type and code in the upper and lower byte, only for ICMP flows.
+    pub icmp_type_code: Option<u16>,
+    /// Type of service byte, with the DSCP in the upper six bits.
+    pub tos: u8,
+    /// Why the exporter expired the flow, e.g. 1 for idle timeout.
+    pub end_reason: Option<u8>,
+    /// 802.1Q VLAN the flow was seen on, if the exporter reports it.
+    pub vlan: Option<u16>,
+    /// SNMP ifIndex of the interfaces the flow entered and left through.
+    pub in_interface: Option<u32>,
+    pub out_interface: Option<u32>,
+    /// Source address and port after NAT, if the exporter translates them.
+    pub nat_src_addr: Option<IpAddr>,
+    pub nat_src_port: Option<u16>,
+    pub packets: u64,
+    pub bytes: u64,
+    /// Direction reported by the exporter: 0 for ingress, 1 for egress.
+    pub direction: Option<u8>,
+    /// MPLS labels from the top of the stack down, without EXP and S bits.
+    pub mpls_labels: Vec<u32>,
+    /// Overlay network segment, e.g. the VXLAN VNI.
+    pub tunnel_id: Option<u64>,
+    /// BGP origin AS of the addresses, if the exporter knows the routes.
+    pub src_as: Option<u32>,
+    pub dst_as: Option<u32>,
+    /// Selected enterprise-specific fields of IPFIX records as name and value.
+    pub enterprise_fields: Vec<(String, String)>,
+}
+
+impl Flow {
+    /// Extracts the flows from a packet of any NetFlow version or IPFIX,
+    /// giving back packets of unsupported versions and parsing errors.
+    /// Records without addresses or counters are returned as `None`.
+    /// Counters of sampled IPFIX flows are scaled up by the sampling rate.
+    /// Fields named in `enterprise_fields` by `PEN:ID` are kept as strings,
+    /// the IPFIX parser that decoded the packet has the templates for that.
+    /// The raw message is needed for fields the parser can't decode as is.
+    pub fn from_packet(
+        packet: NetflowPacket,
+        message: &[u8],
+        parser: &IPFixParser,
+        sampling_rates: &mut SamplingRates,
+        enterprise_fields: &BTreeMap<String, String>,
+    ) -> Result<Vec<Option<Self>>, NetflowPacket> {
+        let flows = match packet {
+            NetflowPacket::IPFix(ipfix) => {
+                let domain = ipfix.header.observation_domain_id;
+
+                let options = ipfix
+                    .flowsets
+                    .iter()
+                    .filter_map(|flowset| match &flowset.body {
+                        IpfixBody::OptionsData(data) => Some(&data.fields),
+                        _ => None,
+                    })
+                    .flatten();
+
+                for fields in options {
+                    if let Some(rate) = sampling_rate(&split_reverse(fields).0) {
+                        sampling_rates.0.insert(domain, rate);
+                    }
+                }
+
+                let domain_rate = sampling_rates.0.get(&domain).copied().unwrap_or(1);
+
+                let mut raw_sets = records::data_sets(message, parser).into_iter();
+
+                let records = ipfix
+                    .flowsets
+                    .iter()
+                    .filter_map(|flowset| match &flowset.body {
+                        IpfixBody::Data(data) => {
+                            let id = flowset.header.header_id;
+                            let template = parser
+                                .templates
+                                .peek(&id)
+                                .map(|template| &template.template);
+
+                            // Sets are only used if they are split the same way.
+                            let raw = raw_sets
+                                .find(|(raw_id, _)| *raw_id == id)
+                                .map(|(_, records)| records)
+                                .filter(|records| records.len() == data.fields.len());
+
+                            Some(data.fields.iter().enumerate().map(move |(i, fields)| {
+                                let labels = match (template, &raw) {
+                                    (Some(template), Some(raw)) => mpls_labels(template, &raw[i]),
+                                    _ => vec![],
+                                };
+
+                                (template, fields, labels)
+                            }))
+                        }
+                        _ => None,
+                    })
+                    .flatten()
+                    .map(|(template, fields, mpls_labels)| {
+                        let (forward, reverse) = split_reverse(fields);
+
+                        Record {
+                            forward,
+                            reverse,
+                            mpls_labels,
+                            enterprise_fields: select_fields(
+                                fields,
+                                template,
+                                parser,
+                                enterprise_fields,
+                            ),
+                        }
+                    });
+
+                merge_records(records)
+                    .into_iter()
+                    .flat_map(|record| {
+                        // Some exporters put the rate into every record instead.
+                        let rate = sampling_rate(&record.forward).unwrap_or(domain_rate);
+
+                        let flow = Self::from_ipfix(&record.forward).map(|flow| Self {
+                            observation_domain: Some(domain),
+                            mpls_labels: record.mpls_labels,
+                            enterprise_fields: record.enterprise_fields,
+                            ..flow
+                        });
+                        let reversed = flow.as_ref().and_then(|flow| flow.reverse(&record.reverse));
+
+                        iter::once(flow)
+                            .chain(reversed.map(Some))
+                            .map(move |flow| flow.map(|flow| flow.scale(rate)))
+                    })
+                    .collect()
+            }
+            NetflowPacket::V9(v9) => v9
+                .flowsets
+                .iter()
+                .filter_map(|flowset| match &flowset.body {
+                    V9Body::Data(data) => Some(&data.fields),
+                    _ => None,
+                })
+                .flatten()
+                .map(|fields| {
+                    Self::from_v9(&fields.iter().cloned().collect()).map(|flow| Self {
+                        observation_domain: Some(v9.header.source_id),
+                        ..flow
+                    })
+                })
+                .collect(),
+            NetflowPacket::V5(v5) => v5.flowsets.iter().map(Self::from_v5).map(Some).collect(),
+            packet => return Err(packet),
+        };
+
+        Ok(flows)
+    }
+
+    /// Ports, protocol, ICMP type, TCP flags, ToS, end reason, VLAN, interfaces, NAT,
+    /// MAC, direction and ASNs are optional, the rest of the fields must be present. Counters can be
+    /// either reduced-size 32-bit or full 64-bit encoded.
+    pub fn from_ipfix(map: &BTreeMap<Field, FieldValue>) -> Option<Self> {
+        let protocol = protocol(map.get(&Field::ProtocolIdentifier));
+        let dst_port =
+            extract_field!(map, Field::DestinationTransportPort, u16).unwrap_or_default();
+
+        let icmp = extract_field!(map, Field::IcmpTypeCodeIpv4, Field::IcmpTypeCodeIpv6, u16)
+            .or_else(|| {
+                let icmp_type = extract_field!(map, Field::IcmpTypeIpv4, Field::IcmpTypeIpv6, u8)?;
+                let icmp_code = extract_field!(map, Field::IcmpCodeIpv4, Field::IcmpCodeIpv6, u8);
+
+                Some(u16::from(icmp_type) << 8 | u16::from(icmp_code.unwrap_or_default()))
+            });
+
+        Some(Self {
+            observation_domain: None,
+            src_mac: extract_field!(
+                map,
+                Field::SourceMacaddress,
+                Field::PostSourceMacaddress,
+                String
+            ),
+            src_addr: extract_field!(
+                map,
+                Field::SourceIpv4address,
+                Field::SourceIpv6address,
+                IpAddr
+            )?,
+            src_port: extract_field!(map, Field::SourceTransportPort, u16).unwrap_or_default(),
+            dst_addr: extract_field!(
+                map,
+                Field::DestinationIpv4address,
+                Field::DestinationIpv6address,
+                IpAddr
+            )?,
+            dst_port,
+            protocol,
+            icmp_type_code: icmp_type_code(protocol, icmp, dst_port),
+            tcp_flags: tcp_flags(map.get(&Field::TcpControlBits)),
+            tos: extract_field!(map, Field::IpClassOfService, u8)
+                .or_else(|| {
+                    extract_field!(map, Field::IpDiffServCodePoint, u8).map(|dscp| dscp << 2)
+                })
+                .unwrap_or_default(),
+            end_reason: extract_field!(map, Field::FlowEndReason, u8),
+            vlan: extract_field!(map, Field::VlanId, Field::PostVlanId, u16),
+            in_interface: interface(map.get(&Field::IngressInterface)),
+            out_interface: interface(map.get(&Field::EgressInterface)),
+            nat_src_addr: extract_field!(
+                map,
+                Field::PostNatsourceIpv4address,
+                Field::PostNatsourceIpv6address,
+                IpAddr
+            ),
+            nat_src_port: extract_field!(map, Field::PostNaptsourceTransportPort, u16),
+            packets: map.get(&Field::PacketDeltaCount).and_then(widen)?,
+            bytes: map.get(&Field::OctetDeltaCount).and_then(widen)?,
+            direction: extract_field!(map, Field::FlowDirection, u8),
+            mpls_labels: vec![],
+            tunnel_id: map
+                .get(&Field::Layer2segmentId)
+                .and_then(widen)
+                .map(|id| id & 0x00ff_ffff_ffff_ffff),
+            src_as: asn(map.get(&Field::BgpSourceAsNumber)),
+            dst_as: asn(map.get(&Field::BgpDestinationAsNumber)),
+            enterprise_fields: vec![],
+        })
+    }
+
+    /// NetFlow v9 exporters pick the width of counters and some send
+    /// ingress and egress counters separately, so both are handled.
+    pub fn from_v9(map: &BTreeMap<V9Field, FieldValue>) -> Option<Self> {
+        let counter = |ingress, egress| {
+            map.get(&ingress)
+                .or_else(|| map.get(&egress))
+                .and_then(widen)
+        };
+
+        let protocol = protocol(map.get(&V9Field::Protocol));
+        let dst_port = extract_field!(map, V9Field::L4DstPort, u16).unwrap_or_default();
+
+        Some(Self {
+            observation_domain: None,
+            src_mac: extract_field!(map, V9Field::InSrcMac, V9Field::OutSrcMac, String),
+            src_addr: extract_field!(map, V9Field::Ipv4SrcAddr, V9Field::Ipv6SrcAddr, IpAddr)?,
+            src_port: extract_field!(map, V9Field::L4SrcPort, u16).unwrap_or_default(),
+            dst_addr: extract_field!(map, V9Field::Ipv4DstAddr, V9Field::Ipv6DstAddr, IpAddr)?,
+            dst_port,
+            protocol,
+            icmp_type_code: icmp_type_code(
+                protocol,
+                extract_field!(map, V9Field::IcmpType, u16),
+                dst_port,
+            ),
+            tcp_flags: tcp_flags(map.get(&V9Field::TcpFlags)),
+            tos: extract_field!(map, V9Field::SrcTos, V9Field::DstTos, u8).unwrap_or_default(),
+            end_reason: None,
+            vlan: extract_field!(map, V9Field::SrcVlan, V9Field::DstVlan, u16),
+            in_interface: interface(map.get(&V9Field::InputSnmp)),
+            out_interface: interface(map.get(&V9Field::OutputSnmp)),
+            nat_src_addr: extract_field!(
+                map,
+                V9Field::PostNATSourceIPv4Address,
+                V9Field::PostNATSourceIpv6Address,
+                IpAddr
+            ),
+            nat_src_port: extract_field!(map, V9Field::PostNATTSourceTransportPort, u16),
+            packets: counter(V9Field::InPkts, V9Field::OutPkts)?,
+            bytes: counter(V9Field::InBytes, V9Field::OutBytes)?,
+            direction: extract_field!(map, V9Field::Direction, u8),
+            mpls_labels: mpls_stack(
+                [
+                    V9Field::MplsLabel1,
+                    V9Field::MplsLabel2,
+                    V9Field::MplsLabel3,
+                    V9Field::MplsLabel4,
+                    V9Field::MplsLabel5,
+                    V9Field::MplsLabel6,
+                    V9Field::MplsLabel7,
+                    V9Field::MplsLabel8,
+                    V9Field::MplsLabel9,
+                    V9Field::MplsLabel10,
+                ]
+                .iter()
+                .filter_map(|field| map.get(field).and_then(widen))
+                .map(|entry| entry as u32),
+            ),
+            tunnel_id: None,
+            src_as: asn(map.get(&V9Field::SrcAs)),
+            dst_as: asn(map.get(&V9Field::DstAs)),
+            enterprise_fields: vec![],
+        })
+    }
+
+    /// NetFlow v5 records have a fixed layout without MAC addresses,
+    /// VLANs, NAT, end reason or direction, which is then only known from the local subnets.
+    pub fn from_v5(record: &v5::FlowSet) -> Self {
+        Self {
+            observation_domain: None,
+            src_mac: None,
+            src_addr: record.src_addr.into(),
+            src_port: record.src_port,
+            dst_addr: record.dst_addr.into(),
+            dst_port: record.dst_port,
+            protocol: record.protocol_number,
+            icmp_type_code: icmp_type_code(record.protocol_number, None, record.dst_port),
+            tcp_flags: record.tcp_flags.into(),
+            tos: record.tos,
+            end_reason: None,
+            vlan: None,
+            in_interface: Some(record.input.into()),
+            out_interface: Some(record.output.into()),
+            nat_src_addr: None,
+            nat_src_port: None,
+            packets: record.d_pkts.into(),
+            bytes: record.d_octets.into(),
+            direction: None,
+            mpls_labels: vec![],
+            tunnel_id: None,
+            src_as: (record.src_as > 0).then_some(record.src_as.into()),
+            dst_as: (record.dst_as > 0).then_some(record.dst_as.into()),
+            enterprise_fields: vec![],
+        }
+    }
+
+    /// The opposite direction of a biflow (RFC 5103), which shares the
+    /// addresses and ports with the forward one. `None` if the record
+    /// is not a biflow or nothing came back.
+    fn reverse(&self, map: &BTreeMap<Reverse, FieldValue>) -> Option<Self> {
+        let packets = map.get(&Reverse::ReversePacketDeltaCount).and_then(widen)?;
+        let bytes = map.get(&Reverse::ReverseOctetDeltaCount).and_then(widen)?;
+
+        if packets == 0 && bytes == 0 {
+            return None;
+        }
+
+        Some(Self {
+            observation_domain: self.observation_domain,
+            src_mac: extract_field!(
+                map,
+                Reverse::ReverseSourceMacAddress,
+                Reverse::ReversePostSourceMacAddress,
+                String
+            ),
+            src_addr: self.dst_addr,
+            src_port: self.dst_port,
+            dst_addr: self.src_addr,
+            dst_port: self.src_port,
+            protocol: self.protocol,
+            icmp_type_code: self.icmp_type_code.map(|forward| {
+                extract_field!(
+                    map,
+                    Reverse::ReverseIcmpTypeCodeIPv4,
+                    Reverse::ReverseIcmpTypeCodeIPv6,
+                    u16
+                )
+                .unwrap_or(forward)
+            }),
+            tcp_flags: tcp_flags(map.get(&Reverse::ReverseTcpControlBits)),
+            tos: extract_field!(map, Reverse::ReverseIpClassOfService, u8).unwrap_or(self.tos),
+            end_reason: self.end_reason,
+            vlan: extract_field!(map, Reverse::ReverseVlanId, u16).or(self.vlan),
+            in_interface: interface(map.get(&Reverse::ReverseIngressInterface))
+                .or(self.out_interface),
+            out_interface: interface(map.get(&Reverse::ReverseEgressInterface))
+                .or(self.in_interface),
+            nat_src_addr: None,
+            nat_src_port: None,
+            packets,
+            bytes,
+            direction: self.direction.map(|direction| direction ^ 1),
+            mpls_labels: vec![],
+            tunnel_id: self.tunnel_id,
+            src_as: asn(map.get(&Reverse::ReverseBgpSourceAsNumber)).or(self.dst_as),
+            dst_as: asn(map.get(&Reverse::ReverseBgpDestinationAsNumber)).or(self.src_as),
+            enterprise_fields: self.enterprise_fields.clone(),
+        })
+    }
+
+    /// Turns the counters of a flow sampled at 1 out of `rate` packets
+    /// into an estimate of the actual traffic.
+    fn scale(self, rate: u32) -> Self {
+        Self {
+            packets: self.packets.saturating_mul(rate.into()),
+            bytes: self.bytes.saturating_mul(rate.into()),
+            ..self
+        }
+    }
+}
+
+/// Fields of an IPFIX data record, or of several ones describing the same flow.
+struct Record {
+    forward: BTreeMap<Field, FieldValue>,
+    reverse: BTreeMap<Reverse, FieldValue>,
+    mpls_labels: Vec<u32>,
+    enterprise_fields: Vec<(String, String)>,
+}
+
+impl Record {
+    /// Addresses, ports and protocol, `None` without addresses.
+    fn key(&self) -> Option<(IpAddr, IpAddr, u16, u16, u8)> {
+        let map = &self.forward;
+
+        Some((
+            extract_field!(
+                map,
+                Field::SourceIpv4address,
+                Field::SourceIpv6address,
+                IpAddr
+            )?,
+            extract_field!(
+                map,
+                Field::DestinationIpv4address,
+                Field::DestinationIpv6address,
+                IpAddr
+            )?,
+            extract_field!(map, Field::SourceTransportPort, u16).unwrap_or_default(),
+            extract_field!(map, Field::DestinationTransportPort, u16).unwrap_or_default(),
+            protocol(map.get(&Field::ProtocolIdentifier)),
+        ))
+    }
+
+    fn has_counters(&self) -> bool {
+        self.forward.contains_key(&Field::OctetDeltaCount)
+    }
+
+    /// Fills in the fields missing from this record, the ones present win.
+    fn merge(&mut self, other: Self) {
+        for (field, value) in other.forward {
+            self.forward.entry(field).or_insert(value);
+        }
+
+        for (field, value) in other.reverse {
+            self.reverse.entry(field).or_insert(value);
+        }
+
+        if self.mpls_labels.is_empty() {
+            self.mpls_labels = other.mpls_labels;
+        }
+
+        for (name, value) in other.enterprise_fields {
+            if !self
+                .enterprise_fields
+                .iter()
+                .any(|(existing, _)| *existing == name)
+            {
+                self.enterprise_fields.push((name, value));
+            }
+        }
+    }
+}
+
+/// Some exporters split a flow across records of the same message, e.g. one
+/// with just the MAC address and another one with the counters. Records with
+/// the same addresses, ports and protocol are merged if either of them lacks
+/// counters, complete ones are kept apart to have all their traffic counted.
+fn merge_records(records: impl Iterator<Item = Record>) -> Vec<Record> {
+    let mut merged = Vec::<Record>::new();
+    let mut latest = HashMap::<_, usize>::new();
+
+    for record in records {
+        let Some(key) = record.key() else {
+            merged.push(record);
+            continue;
+        };
+
+        if let Some(&index) = latest.get(&key) {
+            let pending = &mut merged[index];
+
+            if !pending.has_counters() || !record.has_counters() {
+                pending.merge(record);
+                continue;
+            }
+        }
+
+        latest.insert(key, merged.len());
+        merged.push(record);
+    }
+
+    merged
+}
+
+/// Separates the IANA fields of an IPFIX record from the reverse ones
+/// of biflows. Fields of other enterprises are dropped.
+fn split_reverse(
+    fields: &[(IPFixField, FieldValue)],
+) -> (BTreeMap<Field, FieldValue>, BTreeMap<Reverse, FieldValue>) {
+    let mut forward = BTreeMap::new();
+    let mut reverse = BTreeMap::new();
+
+    for (field, value) in fields {
+        match field {
+            IPFixField::IANA(field) => {
+                forward.insert(*field, value.clone());
+            }
+            IPFixField::ReverseInformationElement(field) => {
+                reverse.insert(*field, value.clone());
+            }
+            _ => {}
+        }
+    }
+
+    (forward, reverse)
+}
+
+/// Picks the selected fields out of an IPFIX record. The parser forgets
+/// the enterprise of fields it doesn't know, so it comes from the template,
+/// which has a field for every value of the record in the same order.
+/// Fields nested in lists are picked too, multiple values of a field
+/// end up separated with commas.
+fn select_fields(
+    fields: &[(IPFixField, FieldValue)],
+    template: Option<&Template>,
+    parser: &IPFixParser,
+    selected: &BTreeMap<String, String>,
+) -> Vec<(String, String)> {
+    let Some(template) = template.filter(|_| !selected.is_empty()) else {
+        return vec![];
+    };
+
+    let mut picked = Vec::<(String, String)>::new();
+
+    let mut pick = |enterprise: u32, id: u16, value: &FieldValue| {
+        let Some(name) = selected.get(&format!("{enterprise}:{id}")) else {
+            return;
+        };
+
+        let value = format_value(value);
+
+        match picked.iter_mut().find(|(existing, _)| existing == name) {
+            Some((_, values)) => {
+                values.push(',');
+                values.push_str(&value);
+            }
+            None => picked.push((name.clone(), value)),
+        }
+    };
+
+    for (field, (_, value)) in template.fields.iter().zip(fields) {
+        let enterprise = field.enterprise_number;
+        let id = field.field_type_number;
+
+        pick(enterprise.unwrap_or_default(), id, value);
+
+        for (enterprise, id, value) in structured::flatten(enterprise, id, value, parser) {
+            pick(enterprise, id, &value);
+        }
+    }
+
+    picked
+}
+
+/// Numbers are decimal, timestamps are in milliseconds and
+/// fields of unknown types are hex encoded.
+fn format_value(value: &FieldValue) -> String {
+    match value {
+        FieldValue::String(s) | FieldValue::MacAddr(s) => s.clone(),
+        FieldValue::DataNumber(number) => format_number(number),
+        FieldValue::Float64(n) => n.to_string(),
+        FieldValue::Duration(duration) => duration.as_millis().to_string(),
+        FieldValue::Ip4Addr(addr) => addr.to_string(),
+        FieldValue::Ip6Addr(addr) => addr.to_string(),
+        FieldValue::ProtocolType(protocol) => (*protocol as u8).to_string(),
+        FieldValue::ApplicationId(id) => format!(
+            "{}:{}",
+            id.classification_engine_id,
+            format_number(&id.selector_id)
+        ),
+        FieldValue::Vec(bytes) | FieldValue::Unknown(bytes) => {
+            bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+        }
+    }
+}
+
+fn format_number(number: &DataNumber) -> String {
+    match *number {
+        DataNumber::U8(n) => n.to_string(),
+        DataNumber::I8(n) => n.to_string(),
+        DataNumber::U16(n) => n.to_string(),
+        DataNumber::I16(n) => n.to_string(),
+        DataNumber::U24(n) | DataNumber::U32(n) => n.to_string(),
+        DataNumber::I24(n) | DataNumber::I32(n) => n.to_string(),
+        DataNumber::U64(n) => n.to_string(),
+        DataNumber::I64(n) => n.to_string(),
+        DataNumber::U128(n) => n.to_string(),
+        DataNumber::I128(n) => n.to_string(),
+    }
+}
+
+/// The parser takes MPLS label stack sections for strings, so the labels
+/// come from the raw fields, which are in the order of the template.
+fn mpls_labels(template: &Template, raw: &[&[u8]]) -> Vec<u32> {
+    let mut sections = template
+        .fields
+        .iter()
+        .zip(raw)
+        .filter(|(field, value)| {
+            field.enterprise_number.is_none()
+                && (70..=79).contains(&field.field_type_number)
+                && value.len() == 3
+        })
+        .map(|(field, value)| {
+            let entry = u32::from_be_bytes([0, value[0], value[1], value[2]]);
+            (field.field_type_number, entry)
+        })
+        .collect::<Vec<_>>();
+
+    // The top of the stack comes first.
+    sections.sort_unstable();
+
+    mpls_stack(sections.into_iter().map(|(_, entry)| entry))
+}
+
+/// Takes labels out of 24-bit label stack entries until the bottom of
+/// the stack. Exporters fill unused entries with zeroes, which are skipped.
+fn mpls_stack(entries: impl Iterator<Item = u32>) -> Vec<u32> {
+    let mut labels = vec![];
+
+    for entry in entries.filter(|entry| *entry != 0) {
+        labels.push(entry >> 4);
+
+        if entry & 1 == 1 {
+            break;
+        }
+    }
+
+    labels
+}
+
+/// Looks up the sampling rate in either of the fields exporters use for it.
+/// Zero means that the exporter doesn't sample.
+fn sampling_rate(map: &BTreeMap<Field, FieldValue>) -> Option<u32> {
+    map.get(&Field::SamplingInterval)
+        .or_else(|| map.get(&Field::SamplerRandomInterval))
+        .and_then(widen)
+        .map(|n| n.clamp(1, u32::MAX.into()) as u32)
+}
+
+/// The protocol is decoded into a known one unless the exporter
+/// uses an unexpected width for it.
+fn protocol(value: Option<&FieldValue>) -> u8 {
+    match value {
+        Some(FieldValue::ProtocolType(protocol)) => *protocol as u8,
+        value => value
+            .and_then(widen)
+            .and_then(|n| n.try_into().ok())
+            .unwrap_or_default(),
+    }
+}
+
+/// Exporters without dedicated ICMP fields put the type and code into
+/// the destination port, which is what NetFlow v5 does too.
+fn icmp_type_code(protocol: u8, value: Option<u16>, dst_port: u16) -> Option<u16> {
+    matches!(protocol, 1 | 58).then(|| value.unwrap_or(dst_port))
+}
+
+/// Exporters send either just the classic 8 flags or all 16 bits.
+fn tcp_flags(value: Option<&FieldValue>) -> u16 {
+    value
+        .and_then(widen)
+        .and_then(|n| n.try_into().ok())
+        .unwrap_or_default()
+}
+
+/// Interface indexes are either 16 or 32 bits wide.
+fn interface(value: Option<&FieldValue>) -> Option<u32> {
+    value.and_then(widen).and_then(|n| n.try_into().ok())
+}
+
+/// AS number, with zero meaning the exporter doesn't know it.
+fn asn(value: Option<&FieldValue>) -> Option<u32> {
+    interface(value).filter(|asn| *asn > 0)
+}
+
+/// Converts a number of any width into `u64`.
+fn widen(value: &FieldValue) -> Option<u64> {
+    let FieldValue::DataNumber(number) = value else {
+        return None;
+    };
+
+    match *number {
+        DataNumber::U8(n) => Some(n.into()),
+        DataNumber::U16(n) => Some(n.into()),
+        DataNumber::U24(n) | DataNumber::U32(n) => Some(n.into()),
+        DataNumber::U64(n) => Some(n),
+        DataNumber::U128(n) => n.try_into().ok(),
+        _ => None,
+    }
+}
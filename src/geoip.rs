@@ -0,0 +1,420 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::read::MultiGzDecoder;
+use hyper::header::AUTHORIZATION;
+use tokio::{
+    select,
+    sync::watch,
+    task::block_in_place,
+    time::{sleep, timeout},
+};
+use tracing::{info, warn};
+
+use crate::{
+    config::{AsnConfig, Config, GeoIpConfig},
+    mmdb::{Mmdb, Value},
+    sink::{http, Error},
+};
+
+/// Lookups kept at most per database, cleared once there are more.
+const MAX_CACHED: usize = 65_536;
+
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Failed downloads are tried again after this long, not right away,
+/// since MaxMind limits downloads per day.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Longest sleep between checks, when there's nothing to download.
+const IDLE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Default)]
+pub struct Location {
+    /// ISO 3166-1 code of the country, like `US`.
+    pub country: Arc<str>,
+    /// Name of the city in English.
+    pub city: Arc<str>,
+}
+
+#[derive(Clone, Default)]
+pub struct AutonomousSystem {
+    pub number: Option<u32>,
+    /// Name of the organization, like `CLOUDFLARENET`.
+    pub name: Arc<str>,
+}
+
+/// Looks up where servers are and which AS they belong to in MaxMind DB
+/// files, like GeoLite2 City and ASN or the free ones of DB-IP. The files
+/// are checked every now and then and loaded again once they change,
+/// after `geoipupdate` runs.
+pub struct GeoIp {
+    locations: Database<Location>,
+    systems: Database<AutonomousSystem>,
+}
+
+impl GeoIp {
+    pub fn new(geoip: &GeoIpConfig, asn: &AsnConfig) -> Self {
+        Self {
+            locations: Database::new(geoip.enabled, &geoip.database, geoip.reload),
+            systems: Database::new(asn.enabled, &asn.database, asn.reload),
+        }
+    }
+
+    pub fn reconfigure(&mut self, geoip: &GeoIpConfig, asn: &AsnConfig) {
+        self.locations
+            .reconfigure(geoip.enabled, &geoip.database, geoip.reload);
+        self.systems
+            .reconfigure(asn.enabled, &asn.database, asn.reload);
+    }
+
+    /// Loads the databases again if their files changed since the last check.
+    pub fn refresh(&mut self) {
+        self.locations.refresh();
+        self.systems.refresh();
+    }
+
+    /// Location of the address, empty if it's unknown.
+    pub fn location(&mut self, ip: IpAddr) -> Location {
+        self.locations.lookup(ip, |data| Location {
+            country: text(data, &["country", "iso_code"]),
+            city: text(data, &["city", "names", "en"]),
+        })
+    }
+
+    /// AS the address is announced by, empty if it's unknown.
+    pub fn autonomous_system(&mut self, ip: IpAddr) -> AutonomousSystem {
+        self.systems.lookup(ip, |data| AutonomousSystem {
+            number: data
+                .get(&["autonomous_system_number"])
+                .and_then(Value::as_u64)
+                .and_then(|number| number.try_into().ok()),
+            name: text(data, &["autonomous_system_organization"]),
+        })
+    }
+}
+
+/// A database file with what was looked up in it so far.
+struct Database<T> {
+    enabled: bool,
+    path: PathBuf,
+    reload: Duration,
+    mmdb: Option<(Mmdb, SystemTime)>,
+    last_check: Instant,
+    /// Results by the offset of their data, which is the same for all
+    /// the addresses of a network.
+    cache: HashMap<usize, T>,
+}
+
+impl<T: Clone + Default> Database<T> {
+    fn new(enabled: bool, path: &Path, reload: Duration) -> Self {
+        let mut database = Self {
+            enabled,
+            path: path.to_owned(),
+            reload,
+            mmdb: None,
+            last_check: Instant::now(),
+            cache: HashMap::new(),
+        };
+
+        database.load();
+
+        database
+    }
+
+    fn reconfigure(&mut self, enabled: bool, path: &Path, reload: Duration) {
+        let changed = enabled != self.enabled || self.path != path;
+
+        self.enabled = enabled;
+        self.path = path.to_owned();
+        self.reload = reload;
+
+        if changed {
+            self.mmdb = None;
+            self.load();
+        }
+    }
+
+    fn refresh(&mut self) {
+        if !self.enabled || self.last_check.elapsed() < self.reload {
+            return;
+        }
+
+        self.load();
+    }
+
+    fn load(&mut self) {
+        self.last_check = Instant::now();
+
+        if !self.enabled {
+            self.cache.clear();
+            return;
+        }
+
+        let path = &self.path;
+
+        let modified = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                warn!(target: "geoip", "Cannot read {}: {e}", path.display());
+                return;
+            }
+        };
+
+        if self
+            .mmdb
+            .as_ref()
+            .is_some_and(|(_, loaded)| *loaded == modified)
+        {
+            return;
+        }
+
+        match block_in_place(|| Mmdb::open(path)) {
+            Ok(mmdb) => {
+                info!(
+                    target: "geoip",
+                    "Loaded {} database from {}",
+                    mmdb.database_type,
+                    path.display()
+                );
+
+                self.mmdb = Some((mmdb, modified));
+                self.cache.clear();
+            }
+            // The previous database, if any, is better than nothing.
+            Err(e) => warn!(target: "geoip", "Cannot load {}: {e}", path.display()),
+        }
+    }
+
+    fn lookup(&mut self, ip: IpAddr, extract: impl FnOnce(&Value) -> T) -> T {
+        let Some((mmdb, _)) = &self.mmdb else {
+            return T::default();
+        };
+
+        let offset = match mmdb.find(ip) {
+            Ok(Some(offset)) => offset,
+            Ok(None) => return T::default(),
+            Err(e) => {
+                warn!(target: "geoip", "Cannot look up {ip}: {e}");
+                return T::default();
+            }
+        };
+
+        if let Some(result) = self.cache.get(&offset) {
+            return result.clone();
+        }
+
+        let result = match mmdb.decode(offset) {
+            Ok(data) => extract(&data),
+            Err(e) => {
+                warn!(target: "geoip", "Cannot decode the data of {ip}: {e}");
+                T::default()
+            }
+        };
+
+        if self.cache.len() >= MAX_CACHED {
+            self.cache.clear();
+        }
+
+        self.cache.insert(offset, result.clone());
+
+        result
+    }
+}
+
+fn text(data: &Value, path: &[&str]) -> Arc<str> {
+    Arc::from(data.get(path).and_then(Value::as_str).unwrap_or_default())
+}
+
+/// Where and how to download a database from.
+struct Download<'a> {
+    section: &'static str,
+    path: &'a Path,
+    url: &'a str,
+    username: &'a str,
+    password: &'a str,
+    update: Duration,
+}
+
+/// Downloads the databases with a `url` once their files are older than
+/// `update`. The new file replaces the old one in one go, once it's known
+/// to be a database, and `GeoIp::refresh` picks it up like it would after
+/// `geoipupdate`. The age of the file decides, so restarts don't download
+/// the databases again.
+pub async fn update(mut config: watch::Receiver<Arc<Config>>) {
+    loop {
+        let current = config.borrow().clone();
+
+        let downloads = [
+            (
+                current.geoip.enabled,
+                Download {
+                    section: "geoip",
+                    path: &current.geoip.database,
+                    url: &current.geoip.url,
+                    username: &current.geoip.username,
+                    password: &current.geoip.password,
+                    update: current.geoip.update,
+                },
+            ),
+            (
+                current.asn.enabled,
+                Download {
+                    section: "asn",
+                    path: &current.asn.database,
+                    url: &current.asn.url,
+                    username: &current.asn.username,
+                    password: &current.asn.password,
+                    update: current.asn.update,
+                },
+            ),
+        ];
+
+        let mut interval = IDLE_INTERVAL;
+
+        for (enabled, download) in downloads {
+            if !enabled || download.url.is_empty() {
+                continue;
+            }
+
+            let (section, path) = (download.section, download.path);
+
+            let age = fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok());
+
+            if let Some(age) = age.filter(|age| *age < download.update) {
+                interval = interval.min(download.update - age);
+                continue;
+            }
+
+            match timeout(DOWNLOAD_TIMEOUT, fetch(&download)).await {
+                Ok(Ok(database_type)) => {
+                    info!(
+                        target: "geoip",
+                        "Downloaded {database_type} database to {}",
+                        path.display()
+                    );
+
+                    interval = interval.min(download.update);
+                }
+                Ok(Err(e)) => {
+                    warn!(target: "geoip", "Cannot download [{section}] database: {e}");
+                    interval = interval.min(RETRY_INTERVAL);
+                }
+                Err(_) => {
+                    warn!(target: "geoip", "Timed out downloading [{section}] database");
+                    interval = interval.min(RETRY_INTERVAL);
+                }
+            }
+        }
+
+        select! {
+            _ = sleep(interval) => {}
+            Ok(_) = config.wait_for(|config| {
+                config.geoip != current.geoip || config.asn != current.asn
+            }) => {}
+        }
+    }
+}
+
+/// Downloads the database next to its file and moves it over the file,
+/// returning its type.
+async fn fetch(download: &Download<'_>) -> Result<String, Error> {
+    let authorization = format!(
+        "Basic {}",
+        STANDARD.encode(format!("{}:{}", download.username, download.password))
+    );
+
+    let headers = match download.username.is_empty() {
+        true => vec![],
+        false => vec![(AUTHORIZATION, authorization.as_str())],
+    };
+
+    let body = http::get(download.url, &headers, None).await?;
+
+    let path = download.path;
+    let temporary = path.with_extension("tmp");
+
+    block_in_place(|| {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(&temporary, unpack(body.to_vec())?)?;
+
+        // Whatever the URL gave is no good if it's not a database.
+        let database_type = match Mmdb::open(&temporary) {
+            Ok(mmdb) => mmdb.database_type,
+            Err(e) => {
+                let _ = fs::remove_file(&temporary);
+                return Err(e.into());
+            }
+        };
+
+        fs::rename(&temporary, path)?;
+
+        Ok(database_type)
+    })
+}
+
+/// Unpacks gzip, like DB-IP ships its databases in, and takes the first
+/// `.mmdb` file out of tar archives, like MaxMind ships them in.
+fn unpack(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let data = match data.starts_with(&[0x1f, 0x8b]) {
+        true => {
+            let mut unpacked = Vec::new();
+            MultiGzDecoder::new(&data[..]).read_to_end(&mut unpacked)?;
+            unpacked
+        }
+        false => data,
+    };
+
+    if data.get(257..262) != Some(b"ustar") {
+        return Ok(data);
+    }
+
+    let mut offset = 0;
+
+    while let Some(header) = data.get(offset..offset + 512) {
+        let name = &header[..100];
+        let name = &name[..name.iter().position(|byte| *byte == 0).unwrap_or(100)];
+
+        // The archive ends with empty blocks.
+        if name.is_empty() {
+            break;
+        }
+
+        let size = std::str::from_utf8(&header[124..136])
+            .ok()
+            .and_then(|size| usize::from_str_radix(size.trim_matches(['\0', ' ']), 8).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid tar header"))?;
+
+        let start = offset + 512;
+
+        if name.ends_with(b".mmdb") {
+            return data
+                .get(start..start + size)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "truncated tar archive")
+                });
+        }
+
+        offset = start + size.div_ceil(512) * 512;
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "no .mmdb file in the tar archive",
+    ))
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::config::{normalize_mac, LeaseFormat, LeasesConfig};
+
+/// Names devices by the hostnames they send to the DHCP server, as found
+/// in its lease file. The file is checked every now and then and read
+/// again once it changes, so new devices get names as they join.
+pub struct Leases {
+    enabled: bool,
+    path: PathBuf,
+    format: LeaseFormat,
+    reload: Duration,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+    /// Hostnames keyed by normalized MAC address.
+    names: HashMap<String, String>,
+}
+
+impl Leases {
+    pub fn new(config: &LeasesConfig) -> Self {
+        let mut leases = Self {
+            enabled: config.enabled,
+            path: config.path.clone(),
+            format: config.format,
+            reload: config.reload,
+            modified: None,
+            last_check: Instant::now(),
+            names: HashMap::new(),
+        };
+
+        leases.load();
+
+        leases
+    }
+
+    pub fn reconfigure(&mut self, config: &LeasesConfig) {
+        let changed = config.enabled != self.enabled
+            || config.path != self.path
+            || config.format != self.format;
+
+        self.enabled = config.enabled;
+        self.path.clone_from(&config.path);
+        self.format = config.format;
+        self.reload = config.reload;
+
+        if changed {
+            self.modified = None;
+            self.load();
+        }
+    }
+
+    /// Reads the file again if it changed since the last check.
+    pub fn refresh(&mut self) {
+        if !self.enabled || self.last_check.elapsed() < self.reload {
+            return;
+        }
+
+        self.load();
+    }
+
+    /// Hostname of the device with the MAC address, as normalized in the config.
+    pub fn name(&self, mac: &str) -> Option<&str> {
+        self.names.get(mac).map(String::as_str)
+    }
+
+    fn load(&mut self) {
+        self.last_check = Instant::now();
+
+        if !self.enabled {
+            self.names.clear();
+            return;
+        }
+
+        let path = &self.path;
+
+        let modified = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                warn!(target: "leases", "Cannot read {}: {e}", path.display());
+                return;
+            }
+        };
+
+        if self.modified == Some(modified) {
+            return;
+        }
+
+        // Names from the last read are kept if the file is gone for a moment,
+        // which happens while DHCP servers replace it.
+        let contents = match block_in_place(|| fs::read_to_string(path)) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!(target: "leases", "Cannot read {}: {e}", path.display());
+                return;
+            }
+        };
+
+        let names = match self.format {
+            LeaseFormat::Dnsmasq => parse_dnsmasq(&contents),
+            LeaseFormat::Kea => parse_kea(&contents),
+            LeaseFormat::Isc => parse_isc(&contents),
+        };
+
+        if self.modified.is_none() || names.len() != self.names.len() {
+            info!(
+                target: "leases",
+                "Loaded {} device names from {}",
+                names.len(),
+                path.display()
+            );
+        }
+
+        self.modified = Some(modified);
+        self.names = names;
+    }
+}
+
+/// Adds a lease, skipping the ones without a hostname or with addresses
+/// other than MACs, like the DUIDs of DHCPv6 clients.
+fn insert(names: &mut HashMap<String, String>, mac: &str, hostname: &str) {
+    let hostname = hostname.trim_end_matches('.');
+
+    if hostname.is_empty() || hostname == "*" {
+        return;
+    }
+
+    if let Ok(mac) = normalize_mac(mac) {
+        names.insert(mac, hostname.to_owned());
+    }
+}
+
+/// Lines like `1700000000 aa:bb:cc:dd:ee:ff 192.168.1.50 laptop 01:aa:bb:cc:dd:ee:ff`,
+/// with `*` for unknown hostnames.
+fn parse_dnsmasq(contents: &str) -> HashMap<String, String> {
+    let mut names = HashMap::new();
+
+    for line in contents.lines() {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+
+        if let [_, mac, _, hostname, ..] = fields[..] {
+            insert(&mut names, mac, hostname);
+        }
+    }
+
+    names
+}
+
+/// CSV with a header naming the columns, where renewals are appended, so
+/// the last lease of a device wins.
+fn parse_kea(contents: &str) -> HashMap<String, String> {
+    let mut names = HashMap::new();
+
+    let mut lines = contents.lines();
+
+    let Some(header) = lines.next() else {
+        return names;
+    };
+
+    let column = |name| header.split(',').position(|column| column == name);
+
+    let (Some(hwaddr), Some(hostname)) = (column("hwaddr"), column("hostname")) else {
+        warn!(target: "leases", "Kea lease file has no hwaddr or hostname column");
+        return names;
+    };
+
+    for line in lines {
+        let fields = line.split(',').collect::<Vec<_>>();
+
+        if let (Some(mac), Some(name)) = (fields.get(hwaddr), fields.get(hostname)) {
+            insert(&mut names, mac, name);
+        }
+    }
+
+    names
+}
+
+/// Blocks like `lease 192.168.1.50 { hardware ethernet aa:bb:cc:dd:ee:ff;
+/// client-hostname "laptop"; }`, appended as leases change.
+fn parse_isc(contents: &str) -> HashMap<String, String> {
+    let mut names = HashMap::new();
+
+    let mut mac = None;
+    let mut hostname = None;
+
+    for line in contents.lines() {
+        let line = line.trim().trim_end_matches(';');
+
+        if line.starts_with("lease ") {
+            mac = None;
+            hostname = None;
+        } else if let Some(value) = line.strip_prefix("hardware ethernet ") {
+            mac = Some(value);
+        } else if let Some(value) = line.strip_prefix("client-hostname ") {
+            hostname = Some(value.trim_matches('"'));
+        } else if line == "}" {
+            if let (Some(mac), Some(hostname)) = (mac.take(), hostname.take()) {
+                insert(&mut names, mac, hostname);
+            }
+        }
+    }
+
+    names
+}
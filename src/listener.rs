@@ -0,0 +1,326 @@
+use std::{
+    fs, io,
+    net::SocketAddr,
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    sync::Arc,
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::{lookup_host, TcpListener, UdpSocket},
+    select, spawn,
+    sync::{mpsc, watch},
+    time::{interval, timeout},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+
+use crate::{config::Config, metrics::Metrics};
+
+/// Flow export protocol family spoken on a listener.
+#[derive(Clone, Copy)]
+pub enum Format {
+    /// IPFIX and NetFlow v5 and v9, told apart by the version in the header.
+    Netflow,
+    Sflow,
+}
+
+/// A datagram received by one of the listeners. Messages received over
+/// TCP are passed on the same way, one message per datagram.
+pub struct Datagram {
+    /// Name of the listener that received the datagram.
+    pub listener: Arc<str>,
+    /// Address the datagram came from, which has its own templates.
+    /// For TCP it's the peer of the connection the message came over.
+    pub exporter: SocketAddr,
+    pub format: Format,
+    /// Empty when a TCP connection is closed and its templates can go.
+    pub data: Vec<u8>,
+}
+
+/// Binds a UDP socket, optionally with a larger kernel receive buffer
+/// to avoid drops when exporters send bursts of datagrams.
+pub async fn bind_udp(addr: &str, receive_buffer: Option<usize>) -> io::Result<UdpSocket> {
+    let addr = lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot resolve {addr}"),
+        )
+    })?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if let Some(size) = receive_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+/// How often the kernel is asked how many datagrams it dropped.
+const DROPS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Receives datagrams from the socket and passes them on for processing,
+/// dropping the ones from exporters that are not allowed. Datagrams the
+/// kernel dropped because nobody was reading fast enough are counted too.
+pub async fn receive(
+    socket: UdpSocket,
+    listener: Arc<str>,
+    format: Format,
+    mut config: watch::Receiver<Arc<Config>>,
+    sender: mpsc::Sender<Datagram>,
+    mut shutdown: watch::Receiver<bool>,
+    metrics: Metrics,
+) {
+    let mut buf = vec![];
+
+    let mut current = config.borrow().clone();
+
+    config.mark_changed();
+
+    let dropped = metrics
+        .datagrams_dropped
+        .get_or_create(&vec![("listener".to_owned(), listener.to_string())])
+        .clone();
+
+    let receive_drops = metrics
+        .receive_drops
+        .get_or_create(&vec![("listener".to_owned(), listener.to_string())])
+        .clone();
+
+    let inode = fs::metadata(format!("/proc/self/fd/{}", socket.as_raw_fd()))
+        .map(|metadata| metadata.ino())
+        .ok();
+
+    let mut drops_check = interval(DROPS_INTERVAL);
+
+    let mut last_drops = 0;
+
+    loop {
+        if config.has_changed().unwrap_or(false) {
+            current = config.borrow_and_update().clone();
+
+            buf.resize(current.ipfix.buffer_size, 0);
+        }
+
+        let received = select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = drops_check.tick(), if inode.is_some() => {
+                match kernel_drops(inode.unwrap()) {
+                    Ok(Some(drops)) => {
+                        receive_drops.inc_by(drops.saturating_sub(last_drops));
+                        last_drops = drops;
+                    }
+                    Ok(None) => {}
+                    Err(e) => debug!(target: "listener", "Cannot read drops of {listener}: {e}"),
+                }
+
+                continue;
+            }
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        };
+
+        let Ok((size, exporter)) = received else {
+            break;
+        };
+
+        if !current.ipfix.is_allowed(&exporter.ip()) {
+            dropped.inc();
+            continue;
+        }
+
+        if size == buf.len() {
+            warn!(
+                target: "listener",
+                "Datagram of {size} bytes on {listener} might have been truncated, consider a larger buffer size"
+            );
+        }
+
+        let datagram = Datagram {
+            listener: listener.clone(),
+            exporter,
+            format,
+            data: buf[..size].to_vec(),
+        };
+
+        if sender.send(datagram).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Reads the number of datagrams dropped by the kernel for the socket with
+/// the inode from the last column of `/proc/net/udp` or `/proc/net/udp6`.
+fn kernel_drops(inode: u64) -> io::Result<Option<u64>> {
+    let inode = inode.to_string();
+
+    for table in ["/proc/net/udp", "/proc/net/udp6"] {
+        for line in fs::read_to_string(table)?.lines().skip(1) {
+            let columns = line.split_whitespace().collect::<Vec<_>>();
+
+            if columns.get(9) == Some(&inode.as_str()) {
+                return Ok(columns.last().and_then(|drops| drops.parse().ok()));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Accepts IPFIX connections over TCP (RFC 7011, section 10.4), optionally
+/// wrapped in TLS, and reads messages until the exporter disconnects.
+pub async fn accept(
+    socket: TcpListener,
+    listener: Arc<str>,
+    tls: Option<TlsAcceptor>,
+    config: watch::Receiver<Arc<Config>>,
+    sender: mpsc::Sender<Datagram>,
+    mut shutdown: watch::Receiver<bool>,
+    metrics: Metrics,
+) {
+    let dropped = metrics
+        .datagrams_dropped
+        .get_or_create(&vec![("listener".to_owned(), listener.to_string())])
+        .clone();
+
+    loop {
+        let accepted = select! {
+            accepted = socket.accept() => accepted,
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        };
+
+        let (stream, exporter) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(target: "listener", "Cannot accept connection on {listener}: {e}");
+                continue;
+            }
+        };
+
+        if !config.borrow().ipfix.is_allowed(&exporter.ip()) {
+            dropped.inc();
+            continue;
+        }
+
+        let listener = listener.clone();
+        let sender = sender.clone();
+        let shutdown = shutdown.clone();
+        let handshake_timeout = config.borrow().ipfix.handshake_timeout;
+        let read_timeout = config.borrow().ipfix.read_timeout;
+
+        let Some(tls) = tls.clone() else {
+            spawn(read_messages(
+                stream,
+                exporter,
+                listener,
+                sender,
+                shutdown,
+                read_timeout,
+            ));
+            continue;
+        };
+
+        // The handshake is done in the background to keep accepting.
+        spawn(async move {
+            let accepted = match timeout(handshake_timeout, tls.accept(stream)).await {
+                Ok(accepted) => accepted,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+            };
+
+            match accepted {
+                Ok(stream) => {
+                    read_messages(stream, exporter, listener, sender, shutdown, read_timeout).await
+                }
+                Err(e) => {
+                    warn!(target: "listener", "TLS handshake with {exporter} on {listener} failed: {e}")
+                }
+            }
+        });
+    }
+}
+
+async fn read_messages(
+    mut stream: impl AsyncRead + Unpin,
+    exporter: SocketAddr,
+    listener: Arc<str>,
+    sender: mpsc::Sender<Datagram>,
+    mut shutdown: watch::Receiver<bool>,
+    read_timeout: Duration,
+) {
+    loop {
+        // Exporters that went away without closing the connection would
+        // hold on to it and its templates forever.
+        let received = select! {
+            received = timeout(read_timeout, read_message(&mut stream)) => match received {
+                Ok(received) => received,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "no message in time")),
+            },
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        };
+
+        let data = match received {
+            Ok(Some(data)) => data,
+            Ok(None) => break,
+            Err(e) => {
+                warn!(target: "listener", "Dropping connection from {exporter} on {listener}: {e}");
+                break;
+            }
+        };
+
+        let datagram = Datagram {
+            listener: listener.clone(),
+            exporter,
+            format: Format::Netflow,
+            data,
+        };
+
+        if sender.send(datagram).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = sender
+        .send(Datagram {
+            listener,
+            exporter,
+            format: Format::Netflow,
+            data: vec![],
+        })
+        .await;
+}
+
+/// Reads a single IPFIX message using the length from its header,
+/// returns `None` if the exporter closed the connection in between.
+async fn read_message(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+
+    match stream.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let version = u16::from_be_bytes([header[0], header[1]]);
+    let length = usize::from(u16::from_be_bytes([header[2], header[3]]));
+
+    // Without a valid header there is no way to find the next message.
+    if version != 10 || length < 16 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid message header with version {version} and length {length}"),
+        ));
+    }
+
+    let mut message = vec![0; length];
+
+    message[..4].copy_from_slice(&header);
+
+    stream.read_exact(&mut message[4..]).await?;
+
+    Ok(Some(message))
+}
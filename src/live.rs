@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use futures_util::stream;
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::{
+    select,
+    sync::{broadcast, watch},
+};
+
+use crate::{config::parse_mac, filter::Filter, metrics::AppState, row::FlowRecord};
+
+/// Records a watcher can fall behind by before missing some.
+const CAPACITY: usize = 1024;
+
+/// Records of flows for watchers of `/api/flows/stream`, only copied while
+/// there are any.
+#[derive(Clone)]
+pub struct LiveFlows {
+    sender: broadcast::Sender<Arc<FlowRecord>>,
+    /// Ends the streams, which would hold up the shutdown of the server.
+    shutdown: watch::Receiver<bool>,
+}
+
+impl LiveFlows {
+    pub fn new(shutdown: watch::Receiver<bool>) -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            shutdown,
+        }
+    }
+
+    pub fn is_watched(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn send(&self, record: &FlowRecord) {
+        if self.is_watched() {
+            let _ = self.sender.send(Arc::new(record.clone()));
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Params {
+    /// MAC of the client.
+    mac: Option<String>,
+    /// Network with the client or the server.
+    subnet: Option<IpNet>,
+    /// Port of the client or the server.
+    port: Option<u16>,
+}
+
+impl Params {
+    /// Filter expression of the parameters, matching every flow without any.
+    fn filter(&self) -> Result<Filter, String> {
+        let mut conditions = vec![];
+
+        if let Some(mac) = &self.mac {
+            let mac = parse_mac(mac).ok_or_else(|| format!("invalid MAC address: {mac}"))?;
+            conditions.push(format!("clientMac == {mac}"));
+        }
+
+        if let Some(subnet) = self.subnet {
+            conditions.push(format!(
+                "(clientIP in [{subnet}] or serverIP in [{subnet}])"
+            ));
+        }
+
+        if let Some(port) = self.port {
+            conditions.push(format!("(clientPort == {port} or serverPort == {port})"));
+        }
+
+        Filter::parse(&conditions.join(" and ")).map_err(|e| e.to_string())
+    }
+}
+
+/// Streams records of flows as Server-Sent Events with the JSON of the
+/// file sink, like `/api/flows/stream?mac=aa:bb:cc:dd:ee:01&port=443`.
+pub async fn handler(State(state): State<Arc<AppState>>, Query(params): Query<Params>) -> Response {
+    let filter = match params.filter() {
+        Ok(filter) => filter,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("{e}\n")).into_response(),
+    };
+
+    let receiver = state.live.sender.subscribe();
+    let shutdown = state.live.shutdown.clone();
+
+    let events = stream::unfold(
+        (receiver, shutdown, filter),
+        |(mut receiver, mut shutdown, filter)| async move {
+            loop {
+                let event = select! {
+                    record = receiver.recv() => match record {
+                        Ok(record) if filter.matches(&record) => {
+                            Event::default().json_data(&*record)
+                        }
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            Ok(Event::default().comment(format!("missed {missed} flows")))
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    },
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => return None,
+                };
+
+                return Some((event, (receiver, shutdown, filter)));
+            }
+        },
+    );
+
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
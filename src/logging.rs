@@ -0,0 +1,113 @@
+use std::{
+    io::{self, IsTerminal},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+use tracing::{debug, warn};
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+use crate::config::{Config, LogConfig, LogFormat};
+
+pub type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Installs the global subscriber writing to stderr. The returned handle
+/// allows changing the level later, while the format is fixed at startup.
+pub fn init(config: &LogConfig) -> FilterHandle {
+    // The level is validated when the configuration is loaded.
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(&config.level));
+
+    let output = fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+
+    let output = match config.format {
+        LogFormat::Text => output.boxed(),
+        LogFormat::Pretty => output.pretty().boxed(),
+        LogFormat::Json => output.json().boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .init();
+
+    handle
+}
+
+/// Applies the log level from reloaded configuration.
+pub async fn follow_level(handle: FilterHandle, mut config: watch::Receiver<Arc<Config>>) {
+    let mut level = config.borrow_and_update().log.level.clone();
+
+    while config.changed().await.is_ok() {
+        let current = config.borrow_and_update().log.level.clone();
+
+        if current == level {
+            continue;
+        }
+
+        if let Err(e) = handle.reload(EnvFilter::new(&current)) {
+            warn!(target: "config", "Cannot change log level: {e}");
+            continue;
+        }
+
+        level = current;
+    }
+}
+
+/// Decides which flows get logged, so that a taste of live traffic
+/// doesn't drown the journal.
+#[derive(Default)]
+pub struct FlowSampler {
+    seen: u64,
+    second: Option<Instant>,
+    logged: u64,
+    suppressed: u64,
+}
+
+impl FlowSampler {
+    /// Counts a flow and tells whether it should be logged.
+    pub fn sample(&mut self, config: &LogConfig) -> bool {
+        self.seen += 1;
+
+        if !self.seen.is_multiple_of(config.flow_sample) {
+            return false;
+        }
+
+        if config.flow_rate_limit == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+
+        if self
+            .second
+            .is_none_or(|start| now.duration_since(start) >= Duration::from_secs(1))
+        {
+            if self.suppressed > 0 {
+                debug!(
+                    target: "parser",
+                    "Rate limit of {} flows per second suppressed {} flows",
+                    config.flow_rate_limit,
+                    self.suppressed
+                );
+            }
+
+            self.second = Some(now);
+            self.logged = 0;
+            self.suppressed = 0;
+        }
+
+        if self.logged >= config.flow_rate_limit {
+            self.suppressed += 1;
+            return false;
+        }
+
+        self.logged += 1;
+
+        true
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::config::{normalize_mac, MacCacheConfig};
+
+/// MACs behind local addresses, learned from uploads. Every upload renews
+/// the MAC of its address, the ones not renewed for `max_age` are evicted.
+/// With `[mac_cache]` enabled they are kept in a file, so downloads right
+/// after a restart get the MAC of their client instead of waiting for it
+/// to upload something.
+#[derive(Default)]
+pub struct MacCache {
+    entries: HashMap<IpAddr, Entry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    mac: String,
+    /// Seconds since the epoch of the last upload.
+    seen: u64,
+}
+
+impl MacCache {
+    /// Reads the MACs saved by the last run, if enabled.
+    pub fn load(config: &MacCacheConfig) -> Self {
+        let mut cache = Self::default();
+
+        if !config.enabled {
+            return cache;
+        }
+
+        let path = &config.path;
+
+        match block_in_place(|| fs::read(path)) {
+            Ok(contents) => match serde_json::from_slice(&contents) {
+                Ok(entries) => cache.entries = entries,
+                Err(e) => warn!(target: "mac_cache", "Cannot parse {}: {e}", path.display()),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!(target: "mac_cache", "Cannot read {}: {e}", path.display()),
+        }
+
+        // The file may have been edited by hand.
+        cache
+            .entries
+            .retain(|addr, entry| match normalize_mac(&entry.mac) {
+                Ok(mac) => {
+                    entry.mac = mac;
+                    true
+                }
+                Err(_) => {
+                    warn!(target: "mac_cache", "Skipping invalid MAC {:?} of {addr}", entry.mac);
+                    false
+                }
+            });
+
+        cache.expire(config.max_age);
+
+        info!(
+            target: "mac_cache",
+            "Loaded {} MACs of local addresses from {}",
+            cache.entries.len(),
+            path.display()
+        );
+
+        cache
+    }
+
+    /// MAC of the address, unless it wasn't seen for `max_age`, in which
+    /// case DHCP may have given the address to another device by now.
+    pub fn get(&self, addr: &IpAddr, max_age: Duration) -> Option<&str> {
+        let oldest = now().saturating_sub(max_age.as_secs());
+
+        self.entries
+            .get(addr)
+            .filter(|entry| entry.seen >= oldest)
+            .map(|entry| entry.mac.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Remembers the MAC an upload came from.
+    pub fn learn(&mut self, addr: IpAddr, mac: &str) {
+        let seen = now();
+
+        match self.entries.get_mut(&addr) {
+            Some(entry) if entry.mac == mac => entry.seen = seen,
+            _ => {
+                let mac = mac.to_owned();
+                self.entries.insert(addr, Entry { mac, seen });
+            }
+        }
+    }
+
+    /// Writes the MACs to the file, if enabled. The file is replaced as
+    /// a whole, so a crash midway leaves the previous one.
+    pub fn save(&self, config: &MacCacheConfig) {
+        if !config.enabled {
+            return;
+        }
+
+        let path = &config.path;
+        let temporary = path.with_extension("tmp");
+
+        let result = block_in_place(|| {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::write(&temporary, serde_json::to_vec(&self.entries)?)?;
+            fs::rename(&temporary, path)
+        });
+
+        if let Err(e) = result {
+            warn!(target: "mac_cache", "Cannot write {}: {e}", path.display());
+        }
+    }
+
+    /// Forgets MACs not seen for `max_age`, returning how many.
+    pub fn expire(&mut self, max_age: Duration) -> usize {
+        let oldest = now().saturating_sub(max_age.as_secs());
+        let before = self.entries.len();
+
+        self.entries.retain(|_, entry| entry.seen >= oldest);
+
+        before - self.entries.len()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -1,281 +1,1152 @@
 use std::{
-    collections::{BTreeMap, HashMap},
-    env,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    collections::{HashMap, VecDeque},
+    fs,
+    net::SocketAddr,
     process::exit,
     sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use axum::{extract::State, routing::get, Router};
-use clickhouse::{Client, Row};
-use netflow_parser::{
-    variable_versions::{data_number::FieldValue, ipfix_lookup::IPFixField},
-    NetflowPacket, NetflowParser,
-};
+use axum::{routing::get, Router};
+use clap::Parser;
+use netflow_parser::NetflowParser;
 use prometheus_client::{
-    encoding::text::encode,
     metrics::{counter::Counter, family::Family},
     registry::Registry,
 };
-use serde::Serialize;
 use tokio::{
-    net::{TcpListener, UdpSocket},
+    net::TcpListener,
+    runtime::Runtime,
+    select,
+    signal::unix::{signal, SignalKind},
     spawn,
+    sync::{mpsc, watch},
+    task::block_in_place,
+    time::interval,
+};
+use tracing::{debug, error, info, warn, Level};
+
+use crate::{
+    categories::Categories,
+    clouds::Clouds,
+    config::{reload_on_sighup, Args, Command, Config},
+    dead_letter::DeadLetters,
+    dedup::{Connection, Dedup},
+    dnstap::Domains,
+    flow::{Flow, SamplingRates, Sequences},
+    geoip::GeoIp,
+    leases::Leases,
+    listener::{accept, bind_udp, receive, Datagram, Format},
+    live::LiveFlows,
+    logging::FlowSampler,
+    mac_cache::MacCache,
+    metrics::{AppState, DeviceFamily, DeviceSeries, Labels, Metrics, RemoteAsns, OTHER_DEVICES},
+    neighbors::Neighbors,
+    router::Clients,
+    row::FlowRecord,
+    rules::Rules,
+    script::Script,
+    services::Services,
+    sink::FlowSink,
+    threats::{Alert, Alerts, Threats},
+    top::Top,
+    vpn::Endpoints,
+    wal::Wal,
 };
 
+mod categories;
+mod check;
+mod clouds;
+mod config;
+mod connector;
+mod daemon;
+mod dashboard;
+mod dead_letter;
+mod dedup;
+mod dnstap;
+mod filter;
+mod flow;
+mod geoip;
+mod leases;
+mod listener;
+mod live;
+mod logging;
+mod mac_cache;
+mod metrics;
+mod mmdb;
+mod neighbors;
+mod prefixes;
+mod process;
+mod records;
+mod remote_write;
+mod retention;
+mod router;
+mod row;
+mod rules;
+mod schema;
+mod script;
+mod segments;
+mod services;
+mod sflow;
+mod sink;
+mod structured;
+mod systemd;
+mod threats;
+mod tls;
+mod top;
+mod vpn;
+mod wal;
+
 const EMPTY_MAC: &str = "00:00:00:00:00:00";
 
-#[derive(Default)]
-struct AppState {
-    registry: Registry,
+/// How often series of devices are checked against the expiry
+/// and the top remote ASNs are picked.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(60);
+
+fn main() {
+    let args = Args::parse();
+
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            exit(1);
+        }
+    };
+
+    let log_level = logging::init(&config.log);
+
+    for name in &config.unknown_env {
+        warn!(target: "config", "Ignoring {name}, which matches no setting");
+    }
+
+    if args.command.is_none() && config.daemon.daemonize {
+        if let Err(e) = daemon::daemonize() {
+            eprintln!("Cannot daemonize: {e}");
+            exit(1);
+        }
+    }
+
+    if let Some(pidfile) = &config.daemon.pidfile {
+        if let Err(e) = daemon::write_pidfile(pidfile) {
+            eprintln!("Cannot write pid file {}: {e}", pidfile.display());
+            exit(1);
+        }
+    }
+
+    let runtime = Runtime::new().unwrap();
+
+    match args.command {
+        Some(Command::CheckConfig) => exit(runtime.block_on(check::run(&config))),
+        Some(Command::InitSchema) => exit(runtime.block_on(schema::init(&config))),
+        None => {}
+    }
+
+    let pidfile = config.daemon.pidfile.clone();
+
+    runtime.block_on(run(args, config, log_level));
+
+    if let Some(pidfile) = pidfile {
+        let _ = fs::remove_file(pidfile);
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    let mut args = env::args().skip(1);
+async fn run(args: Args, config: Config, log_level: logging::FilterHandle) {
+    config.clickhouse.log_batching();
 
-    let Some(ipfix_addr) = args.next() else {
-        eprintln!("Missing ipfix address. Expected arguments: <ipfix bind address> <metrics bind address>");
-        exit(1);
+    let mut sockets = vec![];
+
+    let listeners = config
+        .ipfix
+        .bind
+        .iter()
+        .map(|listener| (listener, Format::Netflow))
+        .chain(
+            config
+                .sflow
+                .bind
+                .iter()
+                .map(|listener| (listener, Format::Sflow)),
+        );
+
+    for (listener, format) in listeners {
+        let socket = bind_udp(&listener.addr, config.ipfix.receive_buffer)
+            .await
+            .unwrap();
+
+        sockets.push((Arc::<str>::from(listener.name.as_str()), format, socket));
+    }
+
+    let mut tcp_sockets = vec![];
+
+    let acceptor = match config.ipfix.tls_bind.is_empty() {
+        true => None,
+        false => match tls::acceptor(&config.tls) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                error!("Cannot set up TLS: {e}");
+                exit(1);
+            }
+        },
     };
 
-    let Some(metrics_addr) = args.next() else {
-        eprintln!("Missing metrics address. Expected arguments: <ipfix bind address> <metrics bind address>");
+    let tcp_listeners = config
+        .ipfix
+        .tcp_bind
+        .iter()
+        .map(|listener| (listener, None))
+        .chain(
+            config
+                .ipfix
+                .tls_bind
+                .iter()
+                .map(|listener| (listener, acceptor.clone())),
+        );
+
+    for (listener, tls) in tcp_listeners {
+        let socket = TcpListener::bind(&listener.addr).await.unwrap();
+
+        tcp_sockets.push((Arc::<str>::from(listener.name.as_str()), tls, socket));
+    }
+
+    let mut dtls_sockets = vec![];
+
+    for listener in &config.ipfix.dtls_bind {
+        let socket = match tls::bind_dtls(&listener.addr, &config.tls).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Cannot set up DTLS listener {}: {e}", listener.name);
+                exit(1);
+            }
+        };
+
+        dtls_sockets.push((Arc::<str>::from(listener.name.as_str()), socket));
+    }
+
+    let metrics_listener = match config.metrics.bind.as_str() {
+        "" => None,
+        bind => Some(TcpListener::bind(bind).await.unwrap()),
+    };
+
+    // The socket is bound before dropping privileges, its directory usually isn't writable.
+    let dnstap_socket = match config.dnstap.enabled {
+        true => match dnstap::bind(&config.dnstap.socket) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                error!(
+                    "Cannot bind dnstap socket {}: {e}",
+                    config.dnstap.socket.display()
+                );
+                exit(1);
+            }
+        },
+        false => None,
+    };
+
+    if let Err(e) = daemon::drop_privileges(
+        config.daemon.user.as_deref(),
+        config.daemon.group.as_deref(),
+    ) {
+        error!("Cannot drop privileges: {e}");
         exit(1);
+    }
+
+    let labels = config
+        .metrics
+        .labels
+        .iter()
+        .map(|(name, value)| (name.clone().into(), value.clone().into()));
+
+    let mut registry = Registry::with_labels(labels);
+
+    let metrics = Metrics::register(&mut registry, &config.metrics.prefix);
+
+    process::register(&mut registry, &config.metrics.prefix);
+
+    let client = match config
+        .clickhouse
+        .enabled
+        .then(|| config.clickhouse.client())
+        .transpose()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Cannot set up the ClickHouse client: {e}");
+            exit(1);
+        }
     };
 
-    let socket = UdpSocket::bind(ipfix_addr).await.unwrap();
+    let sinks = match sink::from_config(&config, &metrics) {
+        Ok(sinks) => sinks,
+        Err(e) => {
+            error!("Cannot set up sinks: {e}");
+            exit(1);
+        }
+    };
 
-    let mut registry = Registry::default();
-    let family = Family::<Vec<(String, String)>, Counter>::default();
+    // Without sinks there's nothing to store, so nothing to replay either.
+    let wal = match (config.wal.enabled && !sinks.is_empty())
+        .then(|| Wal::open(&config.wal.directory, config.wal.max_bytes))
+        .transpose()
+    {
+        Ok(wal) => wal,
+        Err(e) => {
+            error!("Cannot open the write-ahead log: {e}");
+            exit(1);
+        }
+    };
 
-    registry.register(
-        "ipfix_bytes_received_total",
-        "Total number of bytes received by a local IP.",
-        family.clone(),
-    );
+    let (config_sender, config_receiver) = watch::channel(Arc::new(config));
 
-    let client = Client::default().with_url("http://ip6-localhost:8123");
+    let (shutdown_sender, shutdown) = watch::channel(false);
 
-    spawn(measure(socket, client, family));
+    spawn(async move {
+        wait_for_termination().await;
 
-    let state = Arc::new(AppState { registry });
+        info!("Shutting down");
 
-    let app = Router::new()
-        .route("/metrics", get(metrics))
-        .with_state(state);
+        systemd::notify_stopping();
 
-    let listener = TcpListener::bind(metrics_addr).await.unwrap();
+        shutdown_sender.send_replace(true);
+    });
 
-    axum::serve(listener, app).await.unwrap();
-}
+    let (datagram_sender, datagram_receiver) = mpsc::channel(1024);
 
-#[derive(Row, Serialize)]
-struct IpFixRow {
-    #[serde(rename = "insertionTime")]
-    insertion_time: i64,
-    #[serde(rename = "clientMac")]
-    client_mac: u64,
-    #[serde(rename = "clientIPv4", with = "clickhouse::serde::ipv4")]
-    client_ipv4: Ipv4Addr,
-    #[serde(rename = "clientIPv6")]
-    client_ipv6: Ipv6Addr,
-    #[serde(rename = "clientPort")]
-    client_port: u16,
-    #[serde(rename = "serverIPv4", with = "clickhouse::serde::ipv4")]
-    server_ipv4: Ipv4Addr,
-    #[serde(rename = "serverIPv6")]
-    server_ipv6: Ipv6Addr,
-    #[serde(rename = "serverPort")]
-    server_port: u16,
-    protocol: u8,
-    packets: u32,
-    bytes: u32,
-    is_download: bool,
-}
+    for (listener, format, socket) in sockets {
+        spawn(receive(
+            socket,
+            listener,
+            format,
+            config_receiver.clone(),
+            datagram_sender.clone(),
+            shutdown.clone(),
+            metrics.clone(),
+        ));
+    }
 
-impl IpFixRow {
-    #[allow(clippy::too_many_arguments)]
-    fn new(
-        client_mac: &str,
-        client_addr: IpAddr,
-        client_port: u16,
-        server_addr: IpAddr,
-        server_port: u16,
-        protocol: u8,
-        packets: u32,
-        bytes: u32,
-        is_download: bool,
-    ) -> Self {
-        let insertion_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-
-        let (client_ipv4, client_ipv6) = match client_addr {
-            IpAddr::V4(ipv4_addr) => (ipv4_addr, Ipv6Addr::UNSPECIFIED),
-            IpAddr::V6(ipv6_addr) => (Ipv4Addr::UNSPECIFIED, ipv6_addr),
-        };
+    for (listener, tls, socket) in tcp_sockets {
+        spawn(accept(
+            socket,
+            listener,
+            tls,
+            config_receiver.clone(),
+            datagram_sender.clone(),
+            shutdown.clone(),
+            metrics.clone(),
+        ));
+    }
 
-        let (server_ipv4, server_ipv6) = match server_addr {
-            IpAddr::V4(ipv4_addr) => (ipv4_addr, Ipv6Addr::UNSPECIFIED),
-            IpAddr::V6(ipv6_addr) => (Ipv4Addr::UNSPECIFIED, ipv6_addr),
-        };
+    for (listener, socket) in dtls_sockets {
+        spawn(tls::receive_dtls(
+            socket,
+            listener,
+            config_receiver.clone(),
+            datagram_sender.clone(),
+            shutdown.clone(),
+            metrics.clone(),
+        ));
+    }
+
+    // Processing stops once all the listeners are done and drop their senders.
+    drop(datagram_sender);
+
+    spawn(systemd::notify_ready(client));
 
-        let client_mac = u64::from_str_radix(&client_mac.replace(':', ""), 16).unwrap();
-
-        Self {
-            insertion_time,
-            client_mac,
-            client_ipv4,
-            client_ipv6,
-            client_port,
-            server_ipv4,
-            server_ipv6,
-            server_port,
-            protocol,
-            is_download,
-            packets,
-            bytes,
+    spawn(logging::follow_level(log_level, config_receiver.clone()));
+
+    if config_receiver.borrow().clickhouse.enabled {
+        spawn(retention::enforce(config_receiver.clone()));
+    }
+
+    let top = Top::default();
+
+    let live = LiveFlows::new(shutdown.clone());
+
+    let state = Arc::new(AppState {
+        registry,
+        top: top.clone(),
+        live: live.clone(),
+    });
+
+    spawn(remote_write::push(state.clone(), config_receiver.clone()));
+
+    spawn(geoip::update(config_receiver.clone()));
+
+    let (clients_sender, clients_receiver) = watch::channel(Arc::default());
+
+    spawn(router::poll(config_receiver.clone(), clients_sender));
+
+    let (neighbors_sender, neighbors_receiver) = watch::channel(Arc::default());
+
+    spawn(neighbors::scrape(config_receiver.clone(), neighbors_sender));
+
+    let (clouds_sender, clouds_receiver) = watch::channel(Arc::default());
+
+    spawn(clouds::poll(config_receiver.clone(), clouds_sender));
+
+    let (threats_sender, threats_receiver) = watch::channel(Arc::default());
+
+    spawn(threats::poll(config_receiver.clone(), threats_sender));
+
+    let (endpoints_sender, endpoints_receiver) = watch::channel(Arc::default());
+
+    spawn(vpn::poll(config_receiver.clone(), endpoints_sender));
+
+    let domains = Domains::default();
+
+    if let Some(socket) = dnstap_socket {
+        spawn(dnstap::accept(socket, domains.clone(), shutdown.clone()));
+    }
+
+    let measurer = spawn(measure(
+        datagram_receiver,
+        sinks,
+        wal,
+        config_receiver,
+        clients_receiver,
+        neighbors_receiver,
+        clouds_receiver,
+        threats_receiver,
+        endpoints_receiver,
+        domains,
+        metrics,
+        top,
+        live,
+        args.dry_run,
+    ));
+
+    spawn(reload_on_sighup(args, config_sender));
+
+    let mut serve_shutdown = shutdown.clone();
+
+    let shutdown = async move {
+        let _ = serve_shutdown.wait_for(|shutdown| *shutdown).await;
+    };
+
+    match metrics_listener {
+        Some(metrics_listener) => {
+            let app = Router::new()
+                .route("/", get(dashboard::handler))
+                .route("/metrics", get(metrics::handler))
+                .route("/api/top", get(top::handler))
+                .route("/api/flows/stream", get(live::handler))
+                .with_state(state);
+
+            info!(target: "metrics", "Serving metrics on {}", metrics_listener.local_addr().unwrap());
+
+            axum::serve(metrics_listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await
+                .unwrap();
         }
+        None => shutdown.await,
     }
+
+    measurer.await.unwrap();
 }
 
-macro_rules! extract_field {
-    ($map:ident, $key:expr, $output:ty) => {
-        <$output>::try_from($map.get(&$key).unwrap()).unwrap()
-    };
+async fn wait_for_termination() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
 
-    ($map:ident, $key:expr, $fallback:expr, $output:ty) => {
-        <$output>::try_from($map.get(&$key).or_else(|| $map.get(&$fallback)).unwrap()).unwrap()
-    };
+    select! {
+        _ = terminate.recv() => {},
+        _ = interrupt.recv() => {},
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn measure(
-    socket: UdpSocket,
-    client: Client,
-    family: Family<Vec<(String, String)>, Counter>,
+    mut datagrams: mpsc::Receiver<Datagram>,
+    mut sinks: Vec<Box<dyn FlowSink>>,
+    wal: Option<(Wal, Vec<Datagram>)>,
+    mut config: watch::Receiver<Arc<Config>>,
+    mut clients: watch::Receiver<Arc<Clients>>,
+    mut neighbors: watch::Receiver<Arc<Neighbors>>,
+    mut clouds: watch::Receiver<Arc<Clouds>>,
+    mut threats: watch::Receiver<Arc<Threats>>,
+    mut endpoints: watch::Receiver<Arc<Endpoints>>,
+    domains: Domains,
+    metrics: Metrics,
+    top: Top,
+    live: LiveFlows,
+    dry_run: bool,
 ) {
-    let mut inserter = client
-        .inserter("ipfix")
-        .unwrap()
-        .with_timeouts(Some(Duration::from_secs(5)), Some(Duration::from_secs(20)))
-        .with_max_bytes(1024 * 1024)
-        .with_max_rows(1000)
-        .with_period(Some(Duration::from_secs(5)));
+    // Exporters can use the same template ids for different layouts,
+    // so every exporter gets its own parser with its own templates.
+    let mut exporters = HashMap::<SocketAddr, (NetflowParser, SamplingRates, Sequences)>::default();
 
-    let mut local_ip_to_mac = HashMap::<IpAddr, String>::default();
+    let mut flow_sampler = FlowSampler::default();
 
-    let mut parser = NetflowParser::default();
+    let mut current = config.borrow().clone();
 
-    let mut buf = vec![0u8; 4096];
+    let mut local_ip_to_mac = MacCache::load(&current.mac_cache);
 
-    while let Ok(size) = socket.recv(&mut buf).await {
-        for packet in parser.parse_bytes(&buf[..size]) {
-            let NetflowPacket::IPFix(ipfix) = packet else {
-                panic!("not ipfix packet: {packet:?}");
-            };
+    metrics.local_macs.set(local_ip_to_mac.len() as i64);
+
+    let mut dead_letters = DeadLetters::new(&current.dead_letter);
+
+    let mut geoip = GeoIp::new(&current.geoip, &current.asn);
+
+    let mut leases = Leases::new(&current.leases);
+
+    let mut services = Services::new(&current.services);
+
+    let mut categories = Categories::new(&current.categories);
+
+    let mut rules = Rules::new(&current.rules);
+    let mut script = Script::new(&current.script, metrics.script_errors.clone());
+
+    let mut router = clients.borrow().clone();
+
+    let mut neighbor_macs = neighbors.borrow().clone();
+
+    let mut cloud_ranges = clouds.borrow().clone();
+
+    let mut blocklists = threats.borrow().clone();
+
+    let mut alerts = Alerts::default();
+
+    let mut vpn_endpoints = endpoints.borrow().clone();
+
+    let mut dedup = Dedup::default();
+
+    let mut device_series = DeviceSeries::default();
+
+    let mut remote_asns = RemoteAsns::default();
+
+    let mut last_housekeeping = Instant::now();
+
+    // Datagrams left in the log by the last run go first.
+    let (mut wal, replayed) = wal.unzip();
+    let mut replayed = VecDeque::from(replayed.unwrap_or_default());
+
+    let mut checkpoints = wal.as_ref().map(|_| interval(current.wal.checkpoint));
+
+    config.mark_changed();
+
+    // The watchdog is only petted when the loop is not stuck.
+    let mut watchdog = systemd::watchdog_interval().map(interval);
+
+    loop {
+        if config.has_changed().unwrap_or(false) {
+            current = config.borrow_and_update().clone();
+
+            for sink in &mut sinks {
+                sink.reconfigure(&current);
+            }
+
+            dead_letters.reconfigure(&current.dead_letter);
+
+            geoip.reconfigure(&current.geoip, &current.asn);
+
+            leases.reconfigure(&current.leases);
+
+            services = Services::new(&current.services);
+
+            categories.reconfigure(&current.categories);
+            rules.reconfigure(&current.rules);
+            script.reconfigure(&current.script);
+
+            top.configure(&current.top);
+
+            if let Some(wal) = &mut wal {
+                wal.set_max_bytes(current.wal.max_bytes);
+
+                if checkpoints.as_ref().unwrap().period() != current.wal.checkpoint {
+                    checkpoints = Some(interval(current.wal.checkpoint));
+                }
+            }
+        }
+
+        let datagram = match replayed.pop_front() {
+            Some(datagram) => datagram,
+            None => {
+                let datagram = select! {
+                    datagram = datagrams.recv() => datagram,
+                    _ = async { watchdog.as_mut().unwrap().tick().await }, if watchdog.is_some() => {
+                        systemd::pet_watchdog();
+                        continue;
+                    }
+                    _ = async { checkpoints.as_mut().unwrap().tick().await }, if checkpoints.is_some() => {
+                        checkpoint(&mut sinks, wal.as_mut().unwrap()).await;
+                        continue;
+                    }
+                };
+
+                let Some(datagram) = datagram else {
+                    break;
+                };
+
+                if let Some(wal) = &mut wal {
+                    if let Err(e) = block_in_place(|| wal.append(&datagram)) {
+                        error!(target: "wal", "Cannot append a datagram: {e}");
+                    }
+                }
+
+                datagram
+            }
+        };
+
+        if last_housekeeping.elapsed() >= HOUSEKEEPING_INTERVAL {
+            last_housekeeping = Instant::now();
+
+            if !current.metrics.expiry.is_zero() {
+                let expired = device_series.expire(&metrics, current.metrics.expiry);
+
+                if expired > 0 {
+                    debug!(target: "metrics", "Removed {expired} series of devices without traffic");
+                }
+            }
+
+            remote_asns.refresh(&metrics, current.metrics.top_asns);
 
-            for flowset in ipfix.flowsets {
-                if let Some(data) = &flowset.body.data {
-                    for data_field in &data.data_fields {
-                        let map: BTreeMap<IPFixField, FieldValue> =
-                            data_field.values().cloned().collect();
+            geoip.refresh();
 
-                        let src_mac = extract_field!(
-                            map,
-                            IPFixField::SourceMacaddress,
-                            IPFixField::PostSourceMacaddress,
-                            String
-                        );
+            let expired = local_ip_to_mac.expire(current.mac_cache.max_age);
 
-                        let src_addr = extract_field!(
-                            map,
-                            IPFixField::SourceIpv4address,
-                            IPFixField::SourceIpv6address,
-                            IpAddr
-                        );
+            if expired > 0 {
+                debug!(target: "mac_cache", "Forgot {expired} MACs of local addresses");
+            }
 
-                        let src_port = extract_field!(map, IPFixField::SourceTransportPort, u16);
+            metrics.local_macs.set(local_ip_to_mac.len() as i64);
 
-                        let dst_addr = extract_field!(
-                            map,
-                            IPFixField::DestinationIpv4address,
-                            IPFixField::DestinationIpv6address,
-                            IpAddr
-                        );
+            local_ip_to_mac.save(&current.mac_cache);
 
-                        let dst_port =
-                            extract_field!(map, IPFixField::DestinationTransportPort, u16);
+            alerts.expire(&current.threats);
 
-                        let protocol = extract_field!(map, IPFixField::ProtocolIdentifier, u8);
+            let expired = dedup.expire(&current.dedup);
 
-                        let packets = extract_field!(map, IPFixField::PacketDeltaCount, u32);
+            if expired > 0 {
+                debug!(target: "dedup", "Forgot exporters of {expired} flows");
+            }
 
-                        let bytes = extract_field!(map, IPFixField::OctetDeltaCount, u32);
+            let expired = domains.expire(current.dnstap.max_age);
 
-                        let direction = extract_field!(map, IPFixField::FlowDirection, u8);
+            if expired > 0 {
+                debug!(target: "dnstap", "Forgot domains of {expired} addresses");
+            }
+        }
 
-                        let is_download = direction == 0;
+        // Leases, rules and the script change more often than housekeeping runs.
+        leases.refresh();
+        categories.refresh();
+        rules.refresh();
+        script.refresh();
 
-                        let (client_addr, client_port, server_addr, server_port, arrow) =
-                            if is_download {
-                                (dst_addr, dst_port, src_addr, src_port, "<-")
-                            } else {
-                                (src_addr, src_port, dst_addr, dst_port, "->")
-                            };
+        if clients.has_changed().unwrap_or(false) {
+            router = clients.borrow_and_update().clone();
+        }
 
-                        let client = format!("{client_addr}:{client_port}");
-                        let server = format!("{server_addr}:{server_port}");
+        if neighbors.has_changed().unwrap_or(false) {
+            neighbor_macs = neighbors.borrow_and_update().clone();
+        }
+
+        if clouds.has_changed().unwrap_or(false) {
+            cloud_ranges = clouds.borrow_and_update().clone();
+        }
 
-                        let client_mac = if is_download {
-                            match local_ip_to_mac.get(&client_addr) {
-                                Some(mac) => mac,
-                                None => EMPTY_MAC,
+        if threats.has_changed().unwrap_or(false) {
+            blocklists = threats.borrow_and_update().clone();
+        }
+
+        if endpoints.has_changed().unwrap_or(false) {
+            vpn_endpoints = endpoints.borrow_and_update().clone();
+        }
+
+        if datagram.data.is_empty() {
+            exporters.remove(&datagram.exporter);
+            continue;
+        }
+
+        let labels = vec![
+            ("listener".to_owned(), datagram.listener.to_string()),
+            (
+                "exporter".to_owned(),
+                datagram.exporter.ip().to_canonical().to_string(),
+            ),
+        ];
+
+        // A dry run counts nothing, not even exporters.
+        let count = |family: &Family<Labels, Counter>, labels: &Labels, value: u64| {
+            if !dry_run {
+                family.get_or_create(labels).inc_by(value);
+            }
+        };
+
+        count(&metrics.exporter_datagrams, &labels, 1);
+
+        let flows = match datagram.format {
+            Format::Netflow => {
+                let (parser, sampling_rates, sequences) =
+                    exporters.entry(datagram.exporter).or_default();
+
+                let packets = parser.parse_bytes(&datagram.data);
+
+                let mut flows = vec![];
+
+                let mut captured = false;
+
+                for packet in packets {
+                    if let Some((domain, missed)) = sequences.missed(&packet) {
+                        let mut labels = labels.clone();
+                        labels.push(("observation_domain".to_owned(), domain.to_string()));
+                        count(&metrics.records_missed, &labels, missed.into());
+                        warn!(target: "parser", "Missed {missed} records in observation domain {domain} from {} on {}", datagram.exporter, datagram.listener);
+                    }
+
+                    match Flow::from_packet(
+                        packet,
+                        &datagram.data,
+                        &parser.ipfix_parser,
+                        sampling_rates,
+                        &current.ipfix.enterprise_fields,
+                    ) {
+                        Ok(records) => {
+                            for record in records {
+                                let Some(flow) = record else {
+                                    count(&metrics.records_skipped, &labels, 1);
+                                    continue;
+                                };
+
+                                flows.push(flow);
                             }
-                        } else {
-                            if Some(&src_mac) != local_ip_to_mac.get(&client_addr) {
-                                local_ip_to_mac.insert(client_addr.clone(), src_mac.clone());
+                        }
+                        Err(packet) => {
+                            count(&metrics.packets_unsupported, &labels, 1);
+                            debug!(target: "parser", "Skipping unsupported packet from {} on {}: {packet:?}", datagram.exporter, datagram.listener);
+
+                            // The whole datagram is kept once, however many of its packets fail.
+                            if !captured {
+                                dead_letters.capture(datagram.exporter, datagram.format, &datagram.data);
+                                captured = true;
                             }
+                        }
+                    }
+                }
+
+                flows
+            }
+            Format::Sflow => sflow::parse(&datagram.data).unwrap_or_else(|| {
+                count(&metrics.packets_unsupported, &labels, 1);
+                debug!(target: "parser", "Skipping malformed sFlow datagram from {} on {}", datagram.exporter, datagram.listener);
+                dead_letters.capture(datagram.exporter, datagram.format, &datagram.data);
+                vec![]
+            }),
+        };
+
+        for flow in flows {
+            let Flow {
+                observation_domain,
+                src_addr,
+                src_port,
+                dst_addr,
+                dst_port,
+                protocol,
+                icmp_type_code,
+                tcp_flags,
+                vlan,
+                in_interface,
+                out_interface,
+                nat_src_addr,
+                nat_src_port,
+                tos,
+                end_reason,
+                mpls_labels,
+                tunnel_id,
+                src_as,
+                dst_as,
+                enterprise_fields,
+                packets,
+                bytes,
+                ..
+            } = flow;
+
+            let is_download = match (
+                current.network.is_local(&src_addr),
+                current.network.is_local(&dst_addr),
+            ) {
+                (false, true) => true,
+                (true, false) => false,
+                _ => flow.direction == Some(0),
+            };
+
+            let (client_addr, client_port, server_addr, server_port, arrow) = if is_download {
+                (dst_addr, dst_port, src_addr, src_port, "<-")
+            } else {
+                (src_addr, src_port, dst_addr, dst_port, "->")
+            };
+
+            let client = format!("{client_addr}:{client_port}");
+            let server = format!("{server_addr}:{server_port}");
+
+            // Only uploads tell which MAC is behind a local address.
+            let client_mac = match &flow.src_mac {
+                Some(src_mac) if !is_download => {
+                    local_ip_to_mac.learn(client_addr, src_mac);
+                    metrics.local_macs.set(local_ip_to_mac.len() as i64);
+
+                    src_mac
+                }
+                _ => match local_ip_to_mac.get(&client_addr, current.mac_cache.max_age) {
+                    Some(mac) => mac,
+                    None => router
+                        .mac(&client_addr)
+                        .or_else(|| neighbor_macs.get(&client_addr).map(String::as_str))
+                        .unwrap_or(EMPTY_MAC),
+                },
+            };
+
+            // Names from the config beat the ones devices give themselves.
+            let device_name = current
+                .devices
+                .get(client_mac)
+                .map(String::as_str)
+                .or_else(|| router.name(client_mac))
+                .or_else(|| leases.name(client_mac))
+                .unwrap_or_default();
+
+            let device_group = current.group(client_mac).unwrap_or_default();
+
+            let location = geoip.location(server_addr);
+
+            // What the exporter knows from BGP beats the database.
+            let system = geoip.autonomous_system(server_addr);
+            let server_as = if is_download { src_as } else { dst_as }.or(system.number);
+
+            let cloud = cloud_ranges.get(server_addr);
+
+            let threat = blocklists.list(server_addr);
 
-                            &src_mac
-                        };
+            let vpn = vpn_endpoints.vpn(server_addr);
 
-                        eprintln!("{client_mac} | {client:50} {arrow} {server:50} : [0x{protocol:02x}] {packets:10} packets, {bytes:10} bytes");
+            // Answers of the local resolver beat reverse DNS by far.
+            let domain = domains.get(&server_addr, current.dnstap.max_age);
 
-                        if is_download {
-                            family
-                                .get_or_create(&vec![("mac".to_owned(), client_mac.to_string())])
-                                .inc_by(bytes as u64);
+            // Exporters can send more than one hostname, joined by commas.
+            let category = categories.category(&categories::Flow {
+                asn: server_as,
+                as_name: &system.name,
+                protocol,
+                port: server_port,
+                hosts: enterprise_fields
+                    .iter()
+                    .filter(|(name, _)| current.categories.host_fields.contains(name))
+                    .flat_map(|(_, value)| value.split(','))
+                    .chain(domain.as_deref())
+                    .collect(),
+            });
+
+            // Sampling only makes sense when flows are logged at all.
+            if tracing::enabled!(target: "parser", Level::DEBUG)
+                && flow_sampler.sample(&current.log)
+            {
+                debug!(
+                    target: "parser",
+                    listener = &*datagram.listener,
+                    exporter = %datagram.exporter.ip(),
+                    mac = client_mac,
+                    device = device_name,
+                    "{client} {arrow} {server} : [0x{protocol:02x}] {packets} packets, {bytes} bytes"
+                );
+            }
+
+            {
+                let mut labels = labels.clone();
+                let domain = observation_domain.unwrap_or_default().to_string();
+                labels.push(("observation_domain".to_owned(), domain));
+
+                count(&metrics.exporter_records, &labels, 1);
+                count(&metrics.exporter_bytes, &labels, bytes);
+            }
+
+            // Copies are counted per exporter above, but nowhere else.
+            if current.dedup.enabled {
+                let connection = Connection {
+                    protocol,
+                    src_addr,
+                    src_port,
+                    dst_addr,
+                    dst_port,
+                };
+
+                let exporter = datagram.exporter.ip().to_canonical();
+
+                if !dedup.keep(&current.dedup, exporter, connection, bytes) {
+                    count(&metrics.duplicate_records, &labels, 1);
+                    continue;
+                }
+            }
+
+            if !dry_run {
+                let (mac, device) =
+                    match device_series.admit(client_mac, current.metrics.max_devices) {
+                        true => (client_mac, device_name),
+                        false => {
+                            metrics.devices_overflow.inc();
+                            (OTHER_DEVICES, "")
                         }
+                    };
 
-                        inserter
-                            .write(&IpFixRow::new(
-                                client_mac,
-                                client_addr,
-                                client_port,
-                                server_addr,
-                                server_port,
-                                protocol,
-                                packets,
-                                bytes,
-                                is_download,
-                            ))
-                            .unwrap();
-
-                        inserter.commit().await.unwrap();
-                    }
+                // Every per-device family has the name next to the MAC,
+                // empty for devices missing from the config.
+                let device_labels = vec![
+                    ("mac".to_owned(), mac.to_owned()),
+                    ("device".to_owned(), device.to_owned()),
+                ];
+
+                let mut labels = device_labels.clone();
+
+                if current.metrics.vlan_label {
+                    let vlan = vlan.map(|vlan| vlan.to_string()).unwrap_or_default();
+                    labels.push(("vlan".to_owned(), vlan));
+                }
+
+                if current.metrics.observation_domain_label {
+                    let domain = observation_domain
+                        .map(|domain| domain.to_string())
+                        .unwrap_or_default();
+                    labels.push(("observation_domain".to_owned(), domain));
+                }
+
+                if current.metrics.country_label {
+                    labels.push(("country".to_owned(), location.country.to_string()));
+                }
+
+                if current.metrics.category_label {
+                    labels.push(("category".to_owned(), category.to_owned()));
+                }
+
+                if current.metrics.group_label {
+                    labels.push(("group".to_owned(), device_group.to_owned()));
+                }
+
+                // Downloads leave and uploads enter through the interface facing the client.
+                if current.metrics.interface_label {
+                    let interface = if is_download {
+                        out_interface
+                    } else {
+                        in_interface
+                    };
+                    let interface = interface
+                        .map(|index| {
+                            let index = index.to_string();
+                            current.interfaces.get(&index).cloned().unwrap_or(index)
+                        })
+                        .unwrap_or_default();
+                    labels.push(("interface".to_owned(), interface));
+                }
+
+                let (bytes_family, packets_family) = match is_download {
+                    true => (DeviceFamily::BytesReceived, DeviceFamily::PacketsReceived),
+                    false => (DeviceFamily::BytesSent, DeviceFamily::PacketsSent),
+                };
+
+                // Enough to find the rows of the flow in ClickHouse, with the bytes
+                // as the value and the device in the labels of the series. Both ends
+                // go in as address and port to stay within the 128 characters that
+                // OpenMetrics allows for exemplar labels, even with IPv6.
+                let exemplar = (current.metrics.exemplars
+                    && bytes >= current.metrics.exemplar_min_bytes)
+                    .then(|| {
+                        let time = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap()
+                            .as_secs();
+
+                        vec![
+                            ("time".to_owned(), time.to_string()),
+                            (
+                                "client".to_owned(),
+                                SocketAddr::new(client_addr, client_port).to_string(),
+                            ),
+                            (
+                                "server".to_owned(),
+                                SocketAddr::new(server_addr, server_port).to_string(),
+                            ),
+                        ]
+                    });
+
+                device_series.inc_by(&metrics, bytes_family, labels.clone(), bytes, exemplar);
+                device_series.inc_by(&metrics, packets_family, labels, packets, None);
+
+                let direction = if is_download { "download" } else { "upload" };
+
+                {
+                    let mut labels = device_labels.clone();
+                    labels.push(("direction".to_owned(), direction.to_owned()));
+
+                    let window = current.metrics.throughput_window;
+                    metrics.throughput.add(labels, bytes, window);
+                }
+
+                {
+                    let domain = domain.as_deref().unwrap_or_default();
+                    top.add(
+                        client_mac,
+                        device_name,
+                        server_addr,
+                        domain,
+                        protocol_name(protocol),
+                        bytes,
+                        is_download,
+                    );
+                }
+
+                if current.metrics.protocols {
+                    let mut labels = device_labels.clone();
+                    labels.push(("direction".to_owned(), direction.to_owned()));
+                    labels.push(("protocol".to_owned(), protocol_name(protocol).to_owned()));
+
+                    device_series.inc_by(
+                        &metrics,
+                        DeviceFamily::ProtocolBytes,
+                        labels,
+                        bytes,
+                        None,
+                    );
+                }
+
+                if let Some(vpn) = vpn {
+                    let mut labels = device_labels.clone();
+                    labels.push(("direction".to_owned(), direction.to_owned()));
+                    labels.push(("vpn".to_owned(), vpn.to_owned()));
+
+                    device_series.inc_by(&metrics, DeviceFamily::VpnBytes, labels, bytes, None);
+                }
+
+                if let Some(service) = services.configured(protocol, server_port) {
+                    let mut labels = device_labels;
+                    labels.push(("direction".to_owned(), direction.to_owned()));
+                    labels.push(("service".to_owned(), service.to_owned()));
+
+                    device_series.inc_by(&metrics, DeviceFamily::ServiceBytes, labels, bytes, None);
+                }
+
+                if let Some(asn) = server_as.filter(|_| current.metrics.top_asns > 0) {
+                    let size = current.metrics.top_asns;
+                    remote_asns.inc_by(&metrics, size, asn, direction, bytes);
+                }
+
+                if let Some(list) = threat {
+                    let labels = vec![
+                        ("list".to_owned(), list.to_owned()),
+                        ("direction".to_owned(), direction.to_owned()),
+                    ];
+                    metrics.threat_flows.get_or_create(&labels).inc();
+
+                    alerts.send(
+                        &current.threats,
+                        Alert {
+                            text: format!(
+                                "{client} ({client_mac}) {arrow} {server} on {list}: {bytes} bytes"
+                            ),
+                            list,
+                            client_mac,
+                            device_name,
+                            device_group,
+                            client_ip: client_addr,
+                            server_ip: server_addr,
+                            server_port,
+                            protocol,
+                            direction,
+                            packets,
+                            bytes,
+                        },
+                    );
+                }
+            }
+
+            if !sinks.is_empty() || live.is_watched() {
+                let mut record = FlowRecord::new(
+                    &datagram.listener,
+                    observation_domain.unwrap_or_default(),
+                    client_mac,
+                    device_name,
+                    device_group,
+                    client_addr,
+                    client_port,
+                    server_addr,
+                    server_port,
+                    protocol,
+                    icmp_type_code,
+                    tcp_flags,
+                    vlan.unwrap_or_default(),
+                    in_interface.unwrap_or_default(),
+                    out_interface.unwrap_or_default(),
+                    nat_src_addr,
+                    nat_src_port.unwrap_or_default(),
+                    tos,
+                    end_reason.unwrap_or_default(),
+                    mpls_labels,
+                    tunnel_id.unwrap_or_default(),
+                    &location.country,
+                    &location.city,
+                    server_as.unwrap_or_default(),
+                    &system.name,
+                    services.name(protocol, server_port),
+                    category,
+                    domain.as_deref().unwrap_or_default(),
+                    cloud.map(|cloud| cloud.provider).unwrap_or_default(),
+                    cloud
+                        .map(|cloud| cloud.service.as_str())
+                        .unwrap_or_default(),
+                    threat.unwrap_or_default(),
+                    vpn.unwrap_or_default(),
+                    enterprise_fields,
+                    packets,
+                    bytes,
+                    is_download,
+                );
+
+                if !rules.apply(&mut record) || !script.apply(&mut record) {
+                    continue;
+                }
+
+                live.send(&record);
+
+                // Sinks log and count the records they fail to write.
+                for sink in &mut sinks {
+                    let _ = sink.write(&record).await;
                 }
             }
         }
     }
+
+    if let Some(wal) = &mut wal {
+        checkpoint(&mut sinks, wal).await;
+    }
+
+    for sink in &mut sinks {
+        sink.flush().await;
+    }
+
+    local_ip_to_mac.save(&current.mac_cache);
+}
+
+/// Groups IP protocols into a few label values to keep cardinality down.
+fn protocol_name(protocol: u8) -> &'static str {
+    match protocol {
+        6 => "tcp",
+        17 => "udp",
+        1 | 58 => "icmp",
+        _ => "other",
+    }
 }
 
-async fn metrics(State(state): State<Arc<AppState>>) -> String {
-    let mut buffer = String::new();
+/// Removes the datagrams received so far from the write-ahead log once
+/// every sink has stored their records.
+async fn checkpoint(sinks: &mut [Box<dyn FlowSink>], wal: &mut Wal) {
+    let mut stored = true;
 
-    encode(&mut buffer, &state.registry).unwrap();
+    for sink in sinks {
+        if let Err(e) = sink.checkpoint().await {
+            debug!(target: "wal", "Cannot store flows for a checkpoint: {e}");
+            stored = false;
+        }
+    }
 
-    buffer
+    if let Err(e) = block_in_place(|| wal.checkpoint(stored)) {
+        error!(target: "wal", "Cannot remove checkpointed datagrams: {e}");
+    }
 }
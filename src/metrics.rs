@@ -0,0 +1,672 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::{
+        header::{HeaderName, ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use flate2::{write::GzEncoder, Compression};
+use prometheus_client::{
+    collector::Collector,
+    encoding::{text::encode, DescriptorEncoder, EncodeMetric},
+    metrics::{
+        counter::{Atomic, Counter},
+        exemplar::CounterWithExemplar,
+        family::Family,
+        gauge::{ConstGauge, Gauge},
+        MetricType,
+    },
+    registry::Registry,
+};
+use tracing::warn;
+
+use crate::{live::LiveFlows, top::Top};
+
+pub type Labels = Vec<(String, String)>;
+
+/// Counter of per-device families, with an exemplar of a large flow.
+pub type DeviceCounter = CounterWithExemplar<Labels>;
+
+/// MAC label of devices over the limit, counted together.
+pub const OTHER_DEVICES: &str = "other";
+
+/// ASN label of remote ASNs outside of the top, counted together.
+pub const OTHER_ASNS: &str = "other";
+
+/// Remote ASNs keeping their totals between picks of the top, per ASN in it.
+const ASN_CANDIDATES: usize = 10;
+
+/// Families with series per device, which come and go with devices.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceFamily {
+    BytesReceived,
+    BytesSent,
+    PacketsReceived,
+    PacketsSent,
+    ProtocolBytes,
+    ServiceBytes,
+    VpnBytes,
+}
+
+/// Counts traffic of devices, remembering when each series was last
+/// updated, so that series of devices gone for a while can be removed
+/// instead of showing up in every scrape forever.
+#[derive(Default)]
+pub struct DeviceSeries {
+    last_updated: HashMap<(DeviceFamily, Labels), Instant>,
+    /// MACs with series of their own.
+    devices: HashSet<String>,
+}
+
+impl DeviceSeries {
+    /// Whether the device gets series of its own, rather than being
+    /// counted under `OTHER_DEVICES` once there are `max_devices` of them
+    /// already. Zero means no limit.
+    pub fn admit(&mut self, mac: &str, max_devices: usize) -> bool {
+        if self.devices.contains(mac) {
+            return true;
+        }
+
+        if max_devices > 0 && self.devices.len() >= max_devices {
+            return false;
+        }
+
+        self.devices.insert(mac.to_owned());
+
+        true
+    }
+
+    /// Counts the value, replacing the exemplar of the series if there's one.
+    pub fn inc_by(
+        &mut self,
+        metrics: &Metrics,
+        family: DeviceFamily,
+        labels: Labels,
+        value: u64,
+        exemplar: Option<Labels>,
+    ) {
+        let counter = metrics.device_family(family).get_or_create(&labels);
+
+        match exemplar {
+            Some(exemplar) => {
+                counter.inc_by(value, Some(exemplar));
+            }
+            // Increments of the counter itself keep the previous exemplar.
+            None => {
+                counter.inner().inc_by(value);
+            }
+        }
+
+        self.last_updated.insert((family, labels), Instant::now());
+    }
+
+    /// Removes series not updated for longer than `expiry`, returning how many.
+    pub fn expire(&mut self, metrics: &Metrics, expiry: Duration) -> usize {
+        let before = self.last_updated.len();
+
+        self.last_updated.retain(|(family, labels), last_updated| {
+            if last_updated.elapsed() <= expiry {
+                return true;
+            }
+
+            metrics.device_family(*family).remove(labels);
+
+            false
+        });
+
+        // Devices without series left make room for new ones.
+        self.devices = self
+            .last_updated
+            .keys()
+            .filter_map(|(_, labels)| labels.iter().find(|(name, _)| name == "mac"))
+            .map(|(_, mac)| mac)
+            .filter(|mac| *mac != OTHER_DEVICES)
+            .cloned()
+            .collect();
+
+        before - self.last_updated.len()
+    }
+}
+
+/// Counts bytes exchanged with remote ASNs. Only the top ASNs by bytes
+/// have series of their own, the rest are counted together as `other`.
+/// The top is picked from the totals every now and then, the series of
+/// ASNs that drop out of it are removed. Only the ASNs closest to the top
+/// keep their totals, so that the rest of the Internet doesn't pile up.
+#[derive(Default)]
+pub struct RemoteAsns {
+    totals: HashMap<u32, u64>,
+    top: HashSet<u32>,
+}
+
+impl RemoteAsns {
+    /// Counts bytes of an ASN, which joins the top of `size` right away
+    /// while there's room.
+    pub fn inc_by(
+        &mut self,
+        metrics: &Metrics,
+        size: usize,
+        asn: u32,
+        direction: &str,
+        bytes: u64,
+    ) {
+        *self.totals.entry(asn).or_default() += bytes;
+
+        if self.top.len() < size {
+            self.top.insert(asn);
+        }
+
+        let asn = match self.top.contains(&asn) {
+            true => asn.to_string(),
+            false => OTHER_ASNS.to_owned(),
+        };
+
+        let labels = vec![
+            ("asn".to_owned(), asn),
+            ("direction".to_owned(), direction.to_owned()),
+        ];
+
+        metrics.asn_bytes.get_or_create(&labels).inc_by(bytes);
+    }
+
+    /// Picks the `size` ASNs with the most bytes so far, forgetting the
+    /// totals of ASNs too far behind to get into the top any time soon.
+    pub fn refresh(&mut self, metrics: &Metrics, size: usize) {
+        let mut totals = self.totals.drain().collect::<Vec<_>>();
+
+        totals.sort_unstable_by(|(_, a), (_, b)| b.cmp(a));
+        totals.truncate(size.saturating_mul(ASN_CANDIDATES));
+
+        let top = totals
+            .iter()
+            .take(size)
+            .map(|(asn, _)| *asn)
+            .collect::<HashSet<_>>();
+
+        for asn in self.top.difference(&top) {
+            for direction in ["download", "upload"] {
+                metrics.asn_bytes.remove(&vec![
+                    ("asn".to_owned(), asn.to_string()),
+                    ("direction".to_owned(), direction.to_owned()),
+                ]);
+            }
+        }
+
+        self.top = top;
+        self.totals = totals.into_iter().collect();
+    }
+}
+
+/// Bytes of devices over a sliding window, kept in buckets of a second,
+/// so that current throughput can be exported as a gauge computed on
+/// scrape, rather than left to `rate()` over counters of sparse flows.
+#[derive(Clone, Debug, Default)]
+pub struct Throughput {
+    series: Arc<Mutex<ThroughputSeries>>,
+}
+
+#[derive(Debug, Default)]
+struct ThroughputSeries {
+    window: Duration,
+    buckets: HashMap<Labels, VecDeque<(Instant, u64)>>,
+    /// When buckets of all series were last expired.
+    expired: Option<Instant>,
+}
+
+impl Throughput {
+    /// Adds bytes of a series, forgetting all of them if the window is zero.
+    pub fn add(&self, labels: Labels, bytes: u64, window: Duration) {
+        let mut series = self.series.lock().unwrap();
+
+        series.window = window;
+
+        if window.is_zero() {
+            series.buckets.clear();
+            return;
+        }
+
+        // Without scrapes nothing else expires buckets, series of devices
+        // that went quiet are swept once per window.
+        if series
+            .expired
+            .is_none_or(|expired| expired.elapsed() >= window)
+        {
+            series.expire();
+        }
+
+        let buckets = series.buckets.entry(labels).or_default();
+
+        expire_buckets(buckets, window);
+
+        match buckets.back_mut() {
+            Some((start, total)) if start.elapsed() < Duration::from_secs(1) => *total += bytes,
+            _ => buckets.push_back((Instant::now(), bytes)),
+        }
+    }
+
+    /// Bytes per second of every series over the window, dropping buckets
+    /// that fell out of it and series left without any.
+    fn rates(&self) -> Vec<(Labels, f64)> {
+        let mut series = self.series.lock().unwrap();
+
+        series.expire();
+
+        let window = series.window;
+
+        series
+            .buckets
+            .iter()
+            .map(|(labels, buckets)| {
+                let bytes = buckets.iter().map(|(_, bytes)| bytes).sum::<u64>();
+                (labels.clone(), bytes as f64 / window.as_secs_f64())
+            })
+            .collect()
+    }
+}
+
+impl ThroughputSeries {
+    /// Drops buckets that fell out of the window and series left without any.
+    fn expire(&mut self) {
+        let window = self.window;
+
+        self.buckets.retain(|_, buckets| {
+            expire_buckets(buckets, window);
+
+            !buckets.is_empty()
+        });
+
+        self.expired = Some(Instant::now());
+    }
+}
+
+fn expire_buckets(buckets: &mut VecDeque<(Instant, u64)>, window: Duration) {
+    while buckets
+        .front()
+        .is_some_and(|(start, _)| start.elapsed() >= window)
+    {
+        buckets.pop_front();
+    }
+}
+
+/// Exports `Throughput` on every scrape.
+#[derive(Debug)]
+struct ThroughputCollector {
+    name: String,
+    throughput: Throughput,
+}
+
+impl Collector for ThroughputCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> fmt::Result {
+        let rates = self.throughput.rates();
+
+        if rates.is_empty() {
+            return Ok(());
+        }
+
+        let mut family = encoder.encode_descriptor(
+            &self.name,
+            "Bytes per second of a local IP over the throughput window.",
+            None,
+            MetricType::Gauge,
+        )?;
+
+        for (labels, rate) in &rates {
+            ConstGauge::new(*rate).encode(family.encode_family(labels)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct AppState {
+    pub registry: Registry,
+    pub top: Top,
+    pub live: LiveFlows,
+}
+
+/// Metric families updated by the collector.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    pub bytes_received: Family<Labels, DeviceCounter>,
+    pub bytes_sent: Family<Labels, DeviceCounter>,
+    pub packets_received: Family<Labels, DeviceCounter>,
+    pub packets_sent: Family<Labels, DeviceCounter>,
+    pub protocol_bytes: Family<Labels, DeviceCounter>,
+    pub service_bytes: Family<Labels, DeviceCounter>,
+    pub vpn_bytes: Family<Labels, DeviceCounter>,
+    pub devices_overflow: Counter,
+    pub local_macs: Gauge,
+    pub asn_bytes: Family<Labels, Counter>,
+    pub threat_flows: Family<Labels, Counter>,
+    pub throughput: Throughput,
+    pub datagrams_dropped: Family<Labels, Counter>,
+    pub receive_drops: Family<Labels, Counter>,
+    pub packets_unsupported: Family<Labels, Counter>,
+    pub records_skipped: Family<Labels, Counter>,
+    pub records_missed: Family<Labels, Counter>,
+    pub exporter_datagrams: Family<Labels, Counter>,
+    pub exporter_records: Family<Labels, Counter>,
+    pub exporter_bytes: Family<Labels, Counter>,
+    pub duplicate_records: Family<Labels, Counter>,
+    pub script_errors: Counter,
+    pub sink_errors: Family<Labels, Counter>,
+    pub insert_retries: Counter,
+    pub insert_failures: Counter,
+    pub rows_spooled: Counter,
+    pub rows_replayed: Counter,
+    pub insert_bytes: Counter,
+    pub sent_bytes: Counter,
+}
+
+impl Metrics {
+    fn device_family(&self, family: DeviceFamily) -> &Family<Labels, DeviceCounter> {
+        match family {
+            DeviceFamily::BytesReceived => &self.bytes_received,
+            DeviceFamily::BytesSent => &self.bytes_sent,
+            DeviceFamily::PacketsReceived => &self.packets_received,
+            DeviceFamily::PacketsSent => &self.packets_sent,
+            DeviceFamily::ProtocolBytes => &self.protocol_bytes,
+            DeviceFamily::ServiceBytes => &self.service_bytes,
+            DeviceFamily::VpnBytes => &self.vpn_bytes,
+        }
+    }
+
+    /// Registers the families, with the prefix in front of every name.
+    pub fn register(registry: &mut Registry, prefix: &str) -> Self {
+        let metrics = Self::default();
+
+        registry.register(
+            format!("{prefix}bytes_received_total"),
+            "Total number of bytes received by a local IP.",
+            metrics.bytes_received.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}bytes_sent"),
+            "Total number of bytes sent by a local IP.",
+            metrics.bytes_sent.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}packets_received"),
+            "Total number of packets received by a local IP.",
+            metrics.packets_received.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}packets_sent"),
+            "Total number of packets sent by a local IP.",
+            metrics.packets_sent.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}protocol_bytes"),
+            "Total number of bytes per IP protocol and direction of a local IP.",
+            metrics.protocol_bytes.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}service_bytes"),
+            "Total number of bytes per service and direction of a local IP.",
+            metrics.service_bytes.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}vpn_bytes"),
+            "Total number of bytes per Tor or VPN and direction of a local IP.",
+            metrics.vpn_bytes.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}devices_overflow"),
+            "Flows of devices counted under mac=\"other\" because of the limit on devices.",
+            metrics.devices_overflow.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}local_macs"),
+            "MACs of local addresses learned from uploads and not expired yet.",
+            metrics.local_macs.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}asn_bytes"),
+            "Total number of bytes exchanged with the top remote ASNs, the rest are under asn=\"other\".",
+            metrics.asn_bytes.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}threat_flows"),
+            "Total number of flows with addresses on blocklists per list and direction.",
+            metrics.threat_flows.clone(),
+        );
+
+        registry.register_collector(Box::new(ThroughputCollector {
+            name: format!("{prefix}throughput_bytes_per_second"),
+            throughput: metrics.throughput.clone(),
+        }));
+
+        registry.register(
+            format!("{prefix}datagrams_dropped"),
+            "Datagrams ignored because the exporter is not allowed.",
+            metrics.datagrams_dropped.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}udp_receive_drops"),
+            "Datagrams dropped by the kernel because the receive queue of a listener was full.",
+            metrics.receive_drops.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}packets_unsupported"),
+            "Packets skipped because their version is not supported or they cannot be parsed.",
+            metrics.packets_unsupported.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}records_skipped"),
+            "Flow records skipped because addresses or counters are missing.",
+            metrics.records_skipped.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}missed_records"),
+            "IPFIX records lost on the way from the exporter according to sequence numbers.",
+            metrics.records_missed.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}exporter_datagrams"),
+            "Datagrams received from an exporter, including ones that cannot be parsed.",
+            metrics.exporter_datagrams.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}exporter_records"),
+            "Flow records received from an exporter per observation domain.",
+            metrics.exporter_records.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}exporter_bytes"),
+            "Total number of bytes in flows from an exporter per observation domain.",
+            metrics.exporter_bytes.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}duplicate_records"),
+            "Flow records from an exporter dropped as copies of ones from another exporter.",
+            metrics.duplicate_records.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}script_errors"),
+            "Records dropped because the script failed on them.",
+            metrics.script_errors.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}sink_errors"),
+            "Records a sink failed to write, per sink.",
+            metrics.sink_errors.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}clickhouse_insert_retries"),
+            "Attempts to insert a batch into ClickHouse again after a failure.",
+            metrics.insert_retries.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}clickhouse_insert_failures"),
+            "Batches dropped after running out of retries to insert them into ClickHouse.",
+            metrics.insert_failures.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}clickhouse_rows_spooled"),
+            "Rows written to the spool after running out of retries to insert them into ClickHouse.",
+            metrics.rows_spooled.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}clickhouse_rows_replayed"),
+            "Rows from the spool inserted into ClickHouse once it was back.",
+            metrics.rows_replayed.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}clickhouse_insert_bytes"),
+            "Bytes of rows inserted into ClickHouse before compression.",
+            metrics.insert_bytes.clone(),
+        );
+
+        registry.register(
+            format!("{prefix}clickhouse_sent_bytes"),
+            "Bytes sent to ClickHouse over the network, after compression and TLS.",
+            metrics.sent_bytes.clone(),
+        );
+
+        metrics
+    }
+}
+
+const OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Serves the registry in OpenMetrics to scrapers asking for it, and in
+/// the older Prometheus text format otherwise, compressed if they accept gzip.
+pub async fn handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let mut buffer = String::new();
+
+    if let Err(e) = encode(&mut buffer, &state.registry) {
+        warn!(target: "metrics", "Cannot encode metrics: {e}");
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Cannot encode metrics\n").into_response();
+    }
+
+    let content_type = match accepts(&headers, ACCEPT, "application/openmetrics-text") {
+        true => OPENMETRICS,
+        false => {
+            buffer = prometheus_text(&buffer);
+            PROMETHEUS_TEXT
+        }
+    };
+
+    if !accepts(&headers, ACCEPT_ENCODING, "gzip") {
+        return ([(CONTENT_TYPE, content_type)], buffer).into_response();
+    }
+
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+
+    match encoder
+        .write_all(buffer.as_bytes())
+        .and_then(|_| encoder.finish())
+    {
+        Ok(body) => (
+            [(CONTENT_TYPE, content_type), (CONTENT_ENCODING, "gzip")],
+            body,
+        )
+            .into_response(),
+        Err(e) => {
+            warn!(target: "metrics", "Cannot compress metrics: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Cannot compress metrics\n",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Whether the header lists the value, ignoring parameters, unless its
+/// quality is zero. Wildcards are left out, since they accept the default.
+fn accepts(headers: &HeaderMap, name: HeaderName, value: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .any(|entry| {
+            let mut parameters = entry.split(';').map(str::trim);
+
+            parameters
+                .next()
+                .is_some_and(|item| item.eq_ignore_ascii_case(value))
+                && !parameters.any(|parameter| {
+                    parameter
+                        .strip_prefix("q=")
+                        .and_then(|quality| quality.parse::<f32>().ok())
+                        .is_some_and(|quality| quality == 0.0)
+                })
+        })
+}
+
+/// Converts OpenMetrics to the Prometheus text format, where counters are
+/// described under the name of their samples, with the `_total` suffix,
+/// and there are neither exemplars, nor `UNIT` and `EOF`.
+fn prometheus_text(openmetrics: &str) -> String {
+    let counters = openmetrics
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE ")?.strip_suffix(" counter"))
+        .collect::<HashSet<_>>();
+
+    let mut text = String::with_capacity(openmetrics.len());
+
+    for line in openmetrics.lines() {
+        if line == "# EOF" || line.starts_with("# UNIT ") {
+            continue;
+        }
+
+        let descriptor = ["# HELP ", "# TYPE "]
+            .into_iter()
+            .find_map(|prefix| Some((prefix, line.strip_prefix(prefix)?)));
+
+        match descriptor {
+            Some((prefix, rest)) => {
+                let (name, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+
+                match counters.contains(name) {
+                    true => text.push_str(&format!("{prefix}{name}_total {rest}")),
+                    false => text.push_str(line),
+                }
+            }
+            // Exemplars are only a thing in OpenMetrics.
+            None => text.push_str(line.rsplit_once(" # {").map_or(line, |(sample, _)| sample)),
+        }
+
+        text.push('\n');
+    }
+
+    text
+}
@@ -0,0 +1,340 @@
+use std::{collections::BTreeMap, fs, io, net::IpAddr, path::Path};
+
+/// Marks the start of the metadata at the end of the file.
+const METADATA_START: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// Zeroes between the search tree and the data section.
+const DATA_SEPARATOR: usize = 16;
+
+/// Maps and arrays in maps and arrays are fine, but not to the point of
+/// exhausting the stack.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("invalid database: {0}")]
+    Invalid(&'static str),
+}
+
+/// Values of the data section, decoded as a whole.
+pub enum Value {
+    String(String),
+    Uint(u128),
+    Int(i32),
+    Map(BTreeMap<String, Value>),
+    /// Doubles, floats, bytes, booleans and arrays, which lookups don't need.
+    Other,
+}
+
+impl Value {
+    /// Follows keys of nested maps, like `["country", "iso_code"]`.
+    pub fn get(&self, path: &[&str]) -> Option<&Value> {
+        path.iter().try_fold(self, |value, key| match value {
+            Value::Map(map) => map.get(*key),
+            _ => None,
+        })
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Uint(value) => (*value).try_into().ok(),
+            Value::Int(value) => (*value).try_into().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Reads MaxMind DB files, like GeoLite2 or the free databases of DB-IP,
+/// see https://maxmind.github.io/MaxMind-DB/ for the format. The whole
+/// file is kept in memory.
+pub struct Mmdb {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    /// Where the data section starts.
+    data_start: usize,
+    /// Node to start IPv4 lookups from in IPv6 trees, past 96 zero bits.
+    ipv4_start: usize,
+    ip_version: u64,
+    pub database_type: String,
+}
+
+impl Mmdb {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Self::from_bytes(fs::read(path)?)
+    }
+
+    fn from_bytes(data: Vec<u8>) -> Result<Self, Error> {
+        let metadata_start = data
+            .windows(METADATA_START.len())
+            .rposition(|window| window == METADATA_START)
+            .ok_or(Error::Invalid("metadata not found"))?
+            + METADATA_START.len();
+
+        let (metadata, _) = Decoder::new(&data, metadata_start).decode(metadata_start, 0)?;
+
+        let field = |name| {
+            metadata
+                .get(&[name])
+                .and_then(Value::as_u64)
+                .ok_or(Error::Invalid("metadata is missing fields"))
+        };
+
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+
+        let database_type = metadata
+            .get(&["database_type"])
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned();
+
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(Error::Invalid("unsupported record size"));
+        }
+
+        let tree_size = node_count * record_size / 4;
+        let data_start = tree_size + DATA_SEPARATOR;
+
+        if data_start > metadata_start {
+            return Err(Error::Invalid("search tree is larger than the file"));
+        }
+
+        let mut mmdb = Self {
+            data,
+            node_count,
+            record_size,
+            data_start,
+            ipv4_start: 0,
+            ip_version,
+            database_type,
+        };
+
+        if ip_version == 6 {
+            let mut node = 0;
+
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+
+                node = mmdb.record(node, 0)?;
+            }
+
+            mmdb.ipv4_start = node;
+        }
+
+        Ok(mmdb)
+    }
+
+    /// Finds the data of the network the address is in, returning its
+    /// offset, which is the same for all the addresses of the network.
+    pub fn find(&self, ip: IpAddr) -> Result<Option<usize>, Error> {
+        let (bytes, mut node) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (ip.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(ip) if self.ip_version == 6 => (ip.octets().to_vec(), 0),
+            IpAddr::V6(_) => return Ok(None),
+        };
+
+        for bit in 0..bytes.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+
+            let direction = (bytes[bit / 8] >> (7 - bit % 8)) & 1;
+
+            node = self.record(node, direction as usize)?;
+        }
+
+        match node {
+            node if node == self.node_count => Ok(None),
+            node if node > self.node_count => Ok(Some(node - self.node_count - DATA_SEPARATOR)),
+            _ => Err(Error::Invalid("search tree is too deep")),
+        }
+    }
+
+    /// Decodes the data found for an address.
+    pub fn decode(&self, offset: usize) -> Result<Value, Error> {
+        let decoder = Decoder::new(&self.data, self.data_start);
+
+        Ok(decoder.decode(self.data_start + offset, 0)?.0)
+    }
+
+    /// Left (0) or right (1) record of a node.
+    fn record(&self, node: usize, direction: usize) -> Result<usize, Error> {
+        let size = self.record_size * 2 / 8;
+        let start = node * size;
+
+        let bytes = self
+            .data
+            .get(start..start + size)
+            .ok_or(Error::Invalid("node is out of bounds"))?;
+
+        let be = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .fold(0, |value, byte| value << 8 | *byte as usize)
+        };
+
+        let record = match (self.record_size, direction) {
+            (24, 0) => be(&bytes[..3]),
+            (24, _) => be(&bytes[3..]),
+            // The middle byte has the top bits of both records.
+            (28, 0) => (bytes[3] as usize & 0xf0) << 20 | be(&bytes[..3]),
+            (28, _) => (bytes[3] as usize & 0x0f) << 24 | be(&bytes[4..]),
+            (_, 0) => be(&bytes[..4]),
+            (_, _) => be(&bytes[4..]),
+        };
+
+        Ok(record)
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    /// Pointers are relative to the start of the section.
+    section_start: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8], section_start: usize) -> Self {
+        Self {
+            data,
+            section_start,
+        }
+    }
+
+    fn take(&self, offset: usize, length: usize) -> Result<&'a [u8], Error> {
+        self.data
+            .get(offset..offset + length)
+            .ok_or(Error::Invalid("data is out of bounds"))
+    }
+
+    fn uint(&self, offset: usize, length: usize) -> Result<u128, Error> {
+        if length > 16 {
+            return Err(Error::Invalid("integer is too large"));
+        }
+
+        let bytes = self.take(offset, length)?;
+
+        Ok(bytes
+            .iter()
+            .fold(0, |value, byte| value << 8 | *byte as u128))
+    }
+
+    /// Decodes the value at the offset, returning it with the offset of the
+    /// next one.
+    fn decode(&self, offset: usize, depth: usize) -> Result<(Value, usize), Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::Invalid("data is nested too deep"));
+        }
+
+        let control = *self.take(offset, 1)?.first().unwrap();
+        let mut offset = offset + 1;
+
+        let mut kind = control >> 5;
+
+        if kind == 1 {
+            let size = (control >> 3) & 0x3;
+            let high = (control & 0x7) as usize;
+            let bytes = size as usize + 1;
+
+            let pointer = self.uint(offset, bytes)? as usize;
+
+            let pointer = match size {
+                0 => high << 8 | pointer,
+                1 => (high << 16 | pointer) + 2048,
+                2 => (high << 24 | pointer) + 526_336,
+                _ => pointer,
+            };
+
+            // The value is where the pointer points, the next one is after it.
+            let (value, _) = self.decode(self.section_start + pointer, depth + 1)?;
+
+            return Ok((value, offset + bytes));
+        }
+
+        if kind == 0 {
+            kind = 7 + *self.take(offset, 1)?.first().unwrap();
+            offset += 1;
+        }
+
+        let mut size = (control & 0x1f) as usize;
+
+        match size {
+            29 => {
+                size = 29 + self.uint(offset, 1)? as usize;
+                offset += 1;
+            }
+            30 => {
+                size = 285 + self.uint(offset, 2)? as usize;
+                offset += 2;
+            }
+            31 => {
+                size = 65_821 + self.uint(offset, 3)? as usize;
+                offset += 3;
+            }
+            _ => {}
+        }
+
+        let value = match kind {
+            2 => {
+                let bytes = self.take(offset, size)?;
+                let value = std::str::from_utf8(bytes)
+                    .map_err(|_| Error::Invalid("string is not UTF-8"))?;
+
+                (Value::String(value.to_owned()), offset + size)
+            }
+            3 => (Value::Other, offset + 8),
+            4 => (Value::Other, offset + size),
+            5 | 6 | 9 | 10 => (Value::Uint(self.uint(offset, size)?), offset + size),
+            7 => {
+                let mut map = BTreeMap::new();
+
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset, depth + 1)?;
+
+                    let Value::String(key) = key else {
+                        return Err(Error::Invalid("map key is not a string"));
+                    };
+
+                    let (value, next) = self.decode(next, depth + 1)?;
+
+                    map.insert(key, value);
+                    offset = next;
+                }
+
+                (Value::Map(map), offset)
+            }
+            8 => {
+                let value = self.uint(offset, size)? as u32 as i32;
+
+                (Value::Int(value), offset + size)
+            }
+            11 => {
+                // Elements are decoded only to find where the array ends.
+                for _ in 0..size {
+                    let (_, next) = self.decode(offset, depth + 1)?;
+                    offset = next;
+                }
+
+                (Value::Other, offset)
+            }
+            14 => (Value::Other, offset),
+            15 => (Value::Other, offset + 4),
+            _ => return Err(Error::Invalid("unsupported data type")),
+        };
+
+        Ok(value)
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv6Addr},
+    os::fd::AsRawFd,
+    sync::Arc,
+};
+
+use nix::sys::socket::{
+    recv, sendto, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType,
+};
+use tokio::{sync::watch, task::spawn_blocking, time::sleep};
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+/// MAC addresses keyed by local address.
+pub type Neighbors = HashMap<IpAddr, String>;
+
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const RTM_NEWNEIGH: u16 = 28;
+const RTM_GETNEIGH: u16 = 30;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_DUMP: u16 = 0x300;
+
+const NDA_DST: u16 = 1;
+const NDA_LLADDR: u16 = 2;
+
+/// Entries that never got an answer, with no MAC or a stale one.
+const NUD_INCOMPLETE: u16 = 0x01;
+const NUD_FAILED: u16 = 0x20;
+/// Multicast and broadcast addresses, with MACs derived from them.
+const NUD_NOARP: u16 = 0x40;
+
+/// Sizes of `nlmsghdr` and `ndmsg`.
+const HEADER_LEN: usize = 16;
+const NDMSG_LEN: usize = 12;
+
+/// Dumps the ARP and NDP tables of the kernel every `interval`, so that
+/// downloads of clients that haven't uploaded anything yet get their MAC.
+/// This only helps when the collector runs on the router itself, other
+/// machines don't see the neighbors of the router.
+pub async fn scrape(
+    config: watch::Receiver<Arc<Config>>,
+    neighbors: watch::Sender<Arc<Neighbors>>,
+) {
+    loop {
+        let current = config.borrow().neighbors.clone();
+
+        if current.enabled {
+            match spawn_blocking(dump).await.map_err(io::Error::from) {
+                Ok(Ok(table)) => {
+                    debug!(target: "neighbors", "Found {} neighbors", table.len());
+
+                    neighbors.send_replace(Arc::new(table));
+                }
+                Ok(Err(e)) | Err(e) => {
+                    warn!(target: "neighbors", "Cannot dump the neighbor table: {e}")
+                }
+            }
+        } else if !neighbors.borrow().is_empty() {
+            neighbors.send_replace(Arc::default());
+        }
+
+        sleep(current.interval).await;
+    }
+}
+
+/// Asks the kernel for all the neighbors over rtnetlink, see rtnetlink(7).
+fn dump() -> io::Result<Neighbors> {
+    let socket = socket(
+        AddressFamily::Netlink,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::NetlinkRoute,
+    )?;
+
+    let mut request = Vec::with_capacity(HEADER_LEN + NDMSG_LEN);
+    request.extend_from_slice(&((HEADER_LEN + NDMSG_LEN) as u32).to_ne_bytes());
+    request.extend_from_slice(&RTM_GETNEIGH.to_ne_bytes());
+    request.extend_from_slice(&(NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    // Sequence number and port id, the kernel fills in the latter.
+    request.extend_from_slice(&[0; 8]);
+    // Neighbors of every family and interface.
+    request.extend_from_slice(&[0; NDMSG_LEN]);
+
+    sendto(
+        socket.as_raw_fd(),
+        &request,
+        &NetlinkAddr::new(0, 0),
+        MsgFlags::empty(),
+    )?;
+
+    let mut neighbors = Neighbors::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let length = recv(socket.as_raw_fd(), &mut buffer, MsgFlags::empty())?;
+        let mut messages = &buffer[..length];
+
+        while messages.len() >= HEADER_LEN {
+            let message_len = u32::from_ne_bytes(messages[..4].try_into().unwrap()) as usize;
+            let kind = u16::from_ne_bytes(messages[4..6].try_into().unwrap());
+
+            if message_len < HEADER_LEN || message_len > messages.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated netlink message",
+                ));
+            }
+
+            match kind {
+                NLMSG_DONE => return Ok(neighbors),
+                NLMSG_ERROR => {
+                    let code = messages
+                        .get(HEADER_LEN..HEADER_LEN + 4)
+                        .map_or(0, |code| i32::from_ne_bytes(code.try_into().unwrap()));
+
+                    return Err(io::Error::from_raw_os_error(-code));
+                }
+                RTM_NEWNEIGH => {
+                    if let Some((addr, mac)) = parse(&messages[HEADER_LEN..message_len]) {
+                        neighbors.insert(addr, mac);
+                    }
+                }
+                _ => {}
+            }
+
+            messages = &messages[align(message_len).min(messages.len())..];
+        }
+    }
+}
+
+/// Address and MAC of a neighbor, if it's known to be reachable at some MAC.
+fn parse(message: &[u8]) -> Option<(IpAddr, String)> {
+    let state = u16::from_ne_bytes(message.get(8..10)?.try_into().ok()?);
+
+    if state & (NUD_INCOMPLETE | NUD_FAILED | NUD_NOARP) != 0 {
+        return None;
+    }
+
+    let mut addr = None;
+    let mut mac = None;
+
+    let mut attributes = message.get(NDMSG_LEN..)?;
+
+    while attributes.len() >= 4 {
+        let length = u16::from_ne_bytes(attributes[..2].try_into().ok()?) as usize;
+        let kind = u16::from_ne_bytes(attributes[2..4].try_into().ok()?);
+
+        let value = attributes.get(4..length)?;
+
+        match (kind, value.len()) {
+            (NDA_DST, 4) => addr = Some(IpAddr::from(<[u8; 4]>::try_from(value).ok()?)),
+            (NDA_DST, 16) => {
+                let octets = <[u8; 16]>::try_from(value).ok()?;
+                addr = Some(Ipv6Addr::from(octets).to_canonical());
+            }
+            (NDA_LLADDR, 6) if value.iter().any(|byte| *byte != 0) => {
+                let octets = value.iter().map(|byte| format!("{byte:02X}"));
+                mac = Some(octets.collect::<Vec<_>>().join(":"));
+            }
+            _ => {}
+        }
+
+        attributes = &attributes[align(length).min(attributes.len())..];
+    }
+
+    // Point-to-point links and the like have no MAC.
+    Some((addr?, mac?))
+}
+
+/// Netlink messages and attributes are padded to 4 bytes.
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
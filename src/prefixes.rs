@@ -0,0 +1,71 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use ipnet::IpNet;
+
+/// Values keyed by networks, looked up by the most specific network with
+/// the address. Lookups try every prefix length with networks, of which
+/// there are few even with many networks.
+pub struct Prefixes<T> {
+    networks: HashMap<IpNet, T>,
+    /// Prefix lengths with networks, longest first.
+    v4_lengths: Vec<u8>,
+    v6_lengths: Vec<u8>,
+}
+
+impl<T> Default for Prefixes<T> {
+    fn default() -> Self {
+        Self {
+            networks: HashMap::new(),
+            v4_lengths: Vec::new(),
+            v6_lengths: Vec::new(),
+        }
+    }
+}
+
+impl<T> Prefixes<T> {
+    /// Adds the network unless it's there already, keeping the first value.
+    pub fn add(&mut self, network: IpNet, value: T) {
+        let network = self.track(network);
+        self.networks.entry(network).or_insert(value);
+    }
+
+    /// Adds the network, replacing the value it had.
+    pub fn replace(&mut self, network: IpNet, value: T) {
+        let network = self.track(network);
+        self.networks.insert(network, value);
+    }
+
+    /// Remembers the prefix length of the network, without host bits.
+    fn track(&mut self, network: IpNet) -> IpNet {
+        let network = network.trunc();
+
+        let lengths = match network {
+            IpNet::V4(_) => &mut self.v4_lengths,
+            IpNet::V6(_) => &mut self.v6_lengths,
+        };
+
+        if !lengths.contains(&network.prefix_len()) {
+            lengths.push(network.prefix_len());
+            lengths.sort_unstable_by(|a, b| b.cmp(a));
+        }
+
+        network
+    }
+
+    pub fn len(&self) -> usize {
+        self.networks.len()
+    }
+
+    /// Value of the most specific network with the address.
+    pub fn get(&self, addr: IpAddr) -> Option<&T> {
+        let lengths = match addr {
+            IpAddr::V4(_) => &self.v4_lengths,
+            IpAddr::V6(_) => &self.v6_lengths,
+        };
+
+        lengths.iter().find_map(|length| {
+            let network = IpNet::new(addr, *length).ok()?.trunc();
+            self.networks.get(&network)
+        })
+    }
+}
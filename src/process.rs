@@ -0,0 +1,236 @@
+use std::{fmt, fs, time::Duration};
+
+use nix::unistd::{sysconf, SysconfVar};
+use prometheus_client::{
+    collector::Collector,
+    encoding::{DescriptorEncoder, EncodeMetric},
+    metrics::{
+        counter::ConstCounter,
+        gauge::ConstGauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use tokio::{
+    runtime::Handle,
+    spawn,
+    time::{sleep_until, Instant},
+};
+
+/// How often the scheduler is checked for delays.
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Registers metrics of the process and the Tokio runtime, so that the box
+/// running out of memory, file descriptors or CPU shows up in graphs
+/// before the collector falls over. The prefix goes in front of the runtime
+/// metrics, the `process_` ones keep their usual names.
+pub fn register(registry: &mut Registry, prefix: &str) {
+    // From a millisecond, the resolution of timers, to half a second.
+    let delays = Histogram::new(exponential_buckets(0.001, 2.0, 10));
+
+    registry.register(
+        format!("{prefix}tokio_scheduler_delay_seconds"),
+        "How late a task woken up by a timer gets to run, high when the runtime is overloaded.",
+        delays.clone(),
+    );
+
+    registry.register_collector(Box::new(ProcessCollector {
+        runtime: Handle::current(),
+        prefix: prefix.to_owned(),
+    }));
+
+    spawn(probe(delays));
+}
+
+async fn probe(delays: Histogram) {
+    loop {
+        let deadline = Instant::now() + PROBE_INTERVAL;
+
+        sleep_until(deadline).await;
+
+        delays.observe(deadline.elapsed().as_secs_f64());
+    }
+}
+
+/// Reads the metrics from `/proc` and the runtime on every scrape.
+#[derive(Debug)]
+struct ProcessCollector {
+    runtime: Handle,
+    prefix: String,
+}
+
+impl Collector for ProcessCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> fmt::Result {
+        if let Some(stat) = Stat::read() {
+            counter(
+                &mut encoder,
+                "process_cpu_seconds",
+                "User and system CPU time spent.",
+                stat.cpu_seconds,
+            )?;
+
+            gauge(
+                &mut encoder,
+                "process_resident_memory_bytes",
+                "Resident memory size.",
+                stat.resident_memory_bytes,
+            )?;
+
+            gauge(
+                &mut encoder,
+                "process_virtual_memory_bytes",
+                "Virtual memory size.",
+                stat.virtual_memory_bytes,
+            )?;
+
+            gauge(
+                &mut encoder,
+                "process_threads",
+                "Number of OS threads.",
+                stat.threads,
+            )?;
+
+            if let Some(start_time) = stat.start_time_seconds {
+                gauge(
+                    &mut encoder,
+                    "process_start_time_seconds",
+                    "Start time of the process since the epoch.",
+                    start_time,
+                )?;
+            }
+        }
+
+        if let Ok(fds) = fs::read_dir("/proc/self/fd") {
+            gauge(
+                &mut encoder,
+                "process_open_fds",
+                "Number of open file descriptors.",
+                fds.count() as f64,
+            )?;
+        }
+
+        if let Some(max_fds) = max_fds() {
+            gauge(
+                &mut encoder,
+                "process_max_fds",
+                "Limit on open file descriptors.",
+                max_fds,
+            )?;
+        }
+
+        let runtime = self.runtime.metrics();
+        let workers = runtime.num_workers();
+
+        gauge(
+            &mut encoder,
+            &format!("{}tokio_workers", self.prefix),
+            "Worker threads of the runtime.",
+            workers as f64,
+        )?;
+
+        gauge(
+            &mut encoder,
+            &format!("{}tokio_alive_tasks", self.prefix),
+            "Tasks that are spawned and not finished yet.",
+            runtime.num_alive_tasks() as f64,
+        )?;
+
+        gauge(
+            &mut encoder,
+            &format!("{}tokio_global_queue_depth", self.prefix),
+            "Tasks waiting in the global queue of the runtime to be picked up by a worker.",
+            runtime.global_queue_depth() as f64,
+        )?;
+
+        counter(
+            &mut encoder,
+            &format!("{}tokio_worker_busy_seconds", self.prefix),
+            "Time worker threads spent running tasks, across all of them.",
+            (0..workers)
+                .map(|worker| runtime.worker_total_busy_duration(worker).as_secs_f64())
+                .sum(),
+        )?;
+
+        counter(
+            &mut encoder,
+            &format!("{}tokio_worker_parks", self.prefix),
+            "Times worker threads went to sleep for lack of tasks, across all of them.",
+            (0..workers)
+                .map(|worker| runtime.worker_park_count(worker) as f64)
+                .sum(),
+        )?;
+
+        Ok(())
+    }
+}
+
+fn gauge(encoder: &mut DescriptorEncoder, name: &str, help: &str, value: f64) -> fmt::Result {
+    let gauge = ConstGauge::new(value);
+
+    gauge.encode(encoder.encode_descriptor(name, help, None, gauge.metric_type())?)
+}
+
+fn counter(encoder: &mut DescriptorEncoder, name: &str, help: &str, value: f64) -> fmt::Result {
+    let counter = ConstCounter::new(value);
+
+    counter.encode(encoder.encode_descriptor(name, help, None, counter.metric_type())?)
+}
+
+struct Stat {
+    cpu_seconds: f64,
+    resident_memory_bytes: f64,
+    virtual_memory_bytes: f64,
+    threads: f64,
+    start_time_seconds: Option<f64>,
+}
+
+impl Stat {
+    /// Parses `/proc/self/stat`, see proc(5) for the fields.
+    fn read() -> Option<Self> {
+        let stat = fs::read_to_string("/proc/self/stat").ok()?;
+
+        // The name of the command can have spaces, the fields after it can't.
+        let (_, fields) = stat.rsplit_once(')')?;
+        let fields = fields.split_whitespace().collect::<Vec<_>>();
+
+        // Numbered from the state, which is the third field.
+        let field = |number: usize| fields.get(number - 3)?.parse::<f64>().ok();
+
+        let ticks = sysconf(SysconfVar::CLK_TCK).ok().flatten()? as f64;
+        let page_size = sysconf(SysconfVar::PAGE_SIZE).ok().flatten()? as f64;
+
+        let start_time_seconds = boot_time()
+            .zip(field(22))
+            .map(|(boot, start)| boot + start / ticks);
+
+        Some(Self {
+            cpu_seconds: (field(14)? + field(15)?) / ticks,
+            resident_memory_bytes: field(24)? * page_size,
+            virtual_memory_bytes: field(23)?,
+            threads: field(20)?,
+            start_time_seconds,
+        })
+    }
+}
+
+fn boot_time() -> Option<f64> {
+    fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// The soft limit on open files, none if unlimited.
+fn max_fds() -> Option<f64> {
+    fs::read_to_string("/proc/self/limits")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("Max open files"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
@@ -0,0 +1,81 @@
+use netflow_parser::variable_versions::ipfix::IPFixParser;
+
+use crate::sflow::Reader;
+
+const VARIABLE_LENGTH: u16 = 65535;
+
+/// Raw bytes of the fields of a data record, in the order of its template.
+pub type RawRecord<'a> = Vec<&'a [u8]>;
+
+/// Splits the data sets of an IPFIX message into records with the raw
+/// bytes of every field, for the fields that the parser decodes lossily.
+/// Sets are returned with their template id, the ones without a known
+/// template are left out, just like the parser does.
+pub fn data_sets<'a>(message: &'a [u8], parser: &IPFixParser) -> Vec<(u16, Vec<RawRecord<'a>>)> {
+    let mut sets = vec![];
+
+    let _ = walk(message, parser, &mut sets);
+
+    sets
+}
+
+fn walk<'a>(
+    message: &'a [u8],
+    parser: &IPFixParser,
+    sets: &mut Vec<(u16, Vec<RawRecord<'a>>)>,
+) -> Option<()> {
+    let length = Reader(message.get(2..)?).u16()?;
+
+    // Export time, sequence number and observation domain follow the length.
+    let mut reader = Reader(message.get(16..usize::from(length))?);
+
+    while !reader.0.is_empty() {
+        let id = reader.u16()?;
+        let length = usize::from(reader.u16()?).checked_sub(4)?;
+        let mut set = Reader(reader.take(length)?);
+
+        let Some(template) = parser.templates.peek(&id) else {
+            continue;
+        };
+
+        let mut records = vec![];
+
+        // Whatever doesn't fit into a record is padding.
+        'records: while !set.0.is_empty() {
+            let remaining = set.0.len();
+            let mut record = vec![];
+
+            for field in &template.template.fields {
+                let Some(value) = field_length(&mut set, field.field_length)
+                    .and_then(|length| set.take(length.into()))
+                else {
+                    break 'records;
+                };
+
+                record.push(value);
+            }
+
+            if set.0.len() == remaining {
+                break;
+            }
+
+            records.push(record);
+        }
+
+        sets.push((id, records));
+    }
+
+    Some(())
+}
+
+/// Reads the length of a variable length field (RFC 7011, section 7)
+/// or passes the length from the template through.
+pub fn field_length(reader: &mut Reader, length: u16) -> Option<u16> {
+    match length {
+        VARIABLE_LENGTH => match reader.u8()? {
+            255 => reader.u16(),
+            length => Some(length.into()),
+        },
+        length => Some(length),
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hyper::header::{HeaderName, CONTENT_ENCODING};
+use prometheus_client::encoding::text::encode;
+use tokio::{
+    sync::watch,
+    time::{sleep, timeout},
+};
+use tracing::{info, warn};
+
+use crate::{
+    config::Config,
+    metrics::AppState,
+    sink::{http, Error},
+};
+
+/// Pushes the metrics served on `/metrics` to a Prometheus remote_write
+/// endpoint every `remote_write_interval`, for collectors that cannot be
+/// scraped. Pushes that fail are not retried, counters catch up with the
+/// next one. The endpoint and labels are picked up on reloads.
+pub async fn push(state: Arc<AppState>, config: watch::Receiver<Arc<Config>>) {
+    let hostname = nix::unistd::gethostname()
+        .ok()
+        .and_then(|hostname| hostname.into_string().ok())
+        .unwrap_or_else(|| "-".to_owned());
+
+    let mut current = None;
+
+    loop {
+        let interval = config.borrow().metrics.remote_write_interval;
+
+        sleep(interval).await;
+
+        let (url, mut labels) = {
+            let config = config.borrow();
+            let metrics = &config.metrics;
+
+            (
+                metrics.remote_write_url.clone(),
+                metrics.remote_write_labels.clone(),
+            )
+        };
+
+        if url != current {
+            if let Some(url) = &url {
+                info!(target: "metrics", "Pushing metrics to {url}");
+            }
+
+            current.clone_from(&url);
+        }
+
+        let Some(url) = url else {
+            continue;
+        };
+
+        labels
+            .entry("job".to_owned())
+            .or_insert_with(|| "internet-hogs".to_owned());
+
+        labels
+            .entry("instance".to_owned())
+            .or_insert_with(|| hostname.clone());
+
+        match timeout(interval, send(&state, &url, &labels)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!(target: "metrics", "Cannot push metrics to {url}: {e}"),
+            Err(_) => warn!(target: "metrics", "Timed out pushing metrics to {url}"),
+        }
+    }
+}
+
+async fn send(state: &AppState, url: &str, labels: &BTreeMap<String, String>) -> Result<(), Error> {
+    let mut text = String::new();
+
+    encode(&mut text, &state.registry)?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+
+    let request = write_request(&text, labels, timestamp);
+    let body = snap::raw::Encoder::new().compress_vec(&request)?;
+
+    let version = HeaderName::from_static("x-prometheus-remote-write-version");
+    let headers = [(CONTENT_ENCODING, "snappy"), (version, "0.1.0")];
+
+    http::post(url, "application/x-protobuf", &headers, body).await?;
+
+    Ok(())
+}
+
+/// Encodes the samples of the text exposition as a `WriteRequest`,
+/// with every series carrying the given labels, unless it has them.
+fn write_request(text: &str, labels: &BTreeMap<String, String>, timestamp: i64) -> Vec<u8> {
+    let mut request = vec![];
+
+    for line in text.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((mut series, value)) = sample(line) else {
+            warn!(target: "metrics", "Cannot parse the sample {line:?}");
+            continue;
+        };
+
+        for (name, value) in labels {
+            series.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+
+        let mut time_series = vec![];
+
+        // Remote write wants labels sorted by name, which is how they're kept.
+        for (name, value) in &series {
+            let mut label = vec![];
+            length_delimited(&mut label, 1, name.as_bytes());
+            length_delimited(&mut label, 2, value.as_bytes());
+
+            length_delimited(&mut time_series, 1, &label);
+        }
+
+        let mut sample = vec![];
+        varint(&mut sample, 1 << 3 | 1);
+        sample.extend(value.to_le_bytes());
+        varint(&mut sample, 2 << 3);
+        varint(&mut sample, timestamp as u64);
+
+        length_delimited(&mut time_series, 2, &sample);
+
+        length_delimited(&mut request, 1, &time_series);
+    }
+
+    request
+}
+
+/// Parses a line like `name{label="value",...} 1`, with the name of the
+/// metric as the `__name__` label.
+fn sample(line: &str) -> Option<(BTreeMap<String, String>, f64)> {
+    let (name, mut rest) = line.split_at(line.find(['{', ' '])?);
+
+    let mut labels = BTreeMap::new();
+    labels.insert("__name__".to_owned(), name.to_owned());
+
+    if let Some(mut pairs) = rest.strip_prefix('{') {
+        loop {
+            pairs = pairs.trim_start_matches(',');
+
+            if let Some(after) = pairs.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+
+            let (name, after) = pairs.split_once("=\"")?;
+
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+
+            let end = loop {
+                match chars.next()? {
+                    (i, '"') => break i,
+                    (_, '\\') => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    (_, c) => value.push(c),
+                }
+            };
+
+            labels.insert(name.to_owned(), value);
+            pairs = &after[end + 1..];
+        }
+    }
+
+    let value = rest.split_whitespace().next()?.parse().ok()?;
+
+    Some((labels, value))
+}
+
+fn varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    buffer.push(value as u8);
+}
+
+fn length_delimited(buffer: &mut Vec<u8>, field: u64, data: &[u8]) {
+    varint(buffer, field << 3 | 2);
+    varint(buffer, data.len() as u64);
+    buffer.extend(data);
+}
@@ -0,0 +1,78 @@
+use std::{sync::Arc, time::Duration};
+
+use clickhouse::Client;
+use tokio::{sync::watch, time::interval};
+use tracing::{info, warn};
+
+use crate::{
+    config::{quote_identifier, ClickhouseConfig, Config},
+    schema::table_name,
+};
+
+/// How often partitions are checked against the retention.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Drops partitions of the ClickHouse table once all of their rows are
+/// older than `retention`, picking up the setting on reloads. This works
+/// for tables without a TTL, but partitions by month only go as a whole.
+pub async fn enforce(config: watch::Receiver<Arc<Config>>) {
+    let mut ticks = interval(CHECK_INTERVAL);
+
+    loop {
+        ticks.tick().await;
+
+        let config = config.borrow().clickhouse.clone();
+
+        let Some(retention) = config.retention else {
+            continue;
+        };
+
+        let client = match config.client() {
+            Ok(client) => client,
+            Err(e) => {
+                warn!(target: "clickhouse", "Cannot set up the client: {e}");
+                continue;
+            }
+        };
+
+        if let Err(e) = drop_partitions(&config, &client, retention).await {
+            warn!(target: "clickhouse", "Cannot drop old partitions: {e}");
+        }
+    }
+}
+
+async fn drop_partitions(
+    config: &ClickhouseConfig,
+    client: &Client,
+    retention: Duration,
+) -> Result<(), clickhouse::error::Error> {
+    let table = table_name(config);
+
+    let query = format!(
+        "SELECT _partition_id FROM {table} GROUP BY _partition_id \
+         HAVING max({}) < now() - toIntervalSecond(?)",
+        quote_identifier(config.column("insertionTime"))
+    );
+
+    let partitions = client
+        .query(&query)
+        .bind(retention.as_secs())
+        .fetch_all::<String>()
+        .await?;
+
+    for partition in partitions {
+        client
+            .query(&format!("ALTER TABLE {table} DROP PARTITION ID ?"))
+            .bind(&partition)
+            .execute()
+            .await?;
+
+        info!(
+            target: "clickhouse",
+            "Dropped partition {partition} older than {}",
+            humantime::format_duration(retention)
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::header::{HeaderName, AUTHORIZATION};
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::{
+    sync::watch,
+    time::{sleep, timeout},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    config::{normalize_mac, Config, RouterApi, RouterConfig},
+    sink::{http, Error},
+};
+
+/// Clients the router knows about, by MAC and by address.
+#[derive(Default)]
+pub struct Clients {
+    /// Names keyed by normalized MAC address.
+    names: HashMap<String, String>,
+    macs: HashMap<IpAddr, String>,
+}
+
+impl Clients {
+    /// Name of the device with the MAC address, as normalized in the config.
+    pub fn name(&self, mac: &str) -> Option<&str> {
+        self.names.get(mac).map(String::as_str)
+    }
+
+    /// MAC address of the device with the address.
+    pub fn mac(&self, addr: &IpAddr) -> Option<&str> {
+        self.macs.get(addr).map(String::as_str)
+    }
+
+    fn add(&mut self, mac: &str, addr: &str, name: &str) {
+        // Incomplete ARP entries and the like have no MAC.
+        let Ok(mac) = normalize_mac(mac) else {
+            return;
+        };
+
+        if let Ok(addr) = addr.parse::<IpAddr>() {
+            self.macs.insert(addr.to_canonical(), mac.clone());
+        }
+
+        if !name.is_empty() {
+            self.names.insert(mac, name.to_owned());
+        }
+    }
+}
+
+/// Polls the API of the router for its clients every `interval`, so that
+/// devices are named and downloads get the MAC of their client even if
+/// the client hasn't uploaded anything yet. The clients of the last
+/// successful poll are kept while the router cannot be reached.
+pub async fn poll(config: watch::Receiver<Arc<Config>>, clients: watch::Sender<Arc<Clients>>) {
+    loop {
+        let router = config.borrow().router.clone();
+
+        if router.enabled {
+            match timeout(router.interval, fetch(&router)).await {
+                Ok(Ok(fetched)) => {
+                    debug!(
+                        target: "router",
+                        "Fetched {} addresses and {} names from {}",
+                        fetched.macs.len(),
+                        fetched.names.len(),
+                        router.url
+                    );
+
+                    clients.send_replace(Arc::new(fetched));
+                }
+                Ok(Err(e)) => {
+                    warn!(target: "router", "Cannot fetch clients from {}: {e}", router.url)
+                }
+                Err(_) => warn!(target: "router", "Timed out fetching clients from {}", router.url),
+            }
+        } else if !clients.borrow().names.is_empty() || !clients.borrow().macs.is_empty() {
+            clients.send_replace(Arc::default());
+        }
+
+        sleep(router.interval).await;
+    }
+}
+
+async fn fetch(router: &RouterConfig) -> Result<Clients, Error> {
+    let base = router.url.trim_end_matches('/');
+
+    let mut clients = Clients::default();
+
+    match router.api {
+        RouterApi::Mikrotik => {
+            let credentials = format!("{}:{}", router.username, router.password);
+            let authorization = format!("Basic {}", STANDARD.encode(credentials));
+            let headers = [(AUTHORIZATION, authorization.as_str())];
+
+            // Neighbors first, so that leases have the last word on addresses.
+            for path in ["ipv6/neighbor", "ip/arp", "ip/dhcp-server/lease"] {
+                let url = format!("{base}/rest/{path}");
+
+                for entry in get::<Vec<MikrotikEntry>>(&url, &headers, router).await? {
+                    // Comments are what people name leases with in Winbox.
+                    let name = match entry.comment.is_empty() {
+                        true => &entry.host_name,
+                        false => &entry.comment,
+                    };
+
+                    clients.add(&entry.mac_address, &entry.address, name);
+                }
+            }
+        }
+        RouterApi::Unifi => {
+            let url = format!("{base}/proxy/network/api/s/{}/stat/sta", router.site);
+            let headers = [(
+                HeaderName::from_static("x-api-key"),
+                router.api_key.as_str(),
+            )];
+
+            for client in get::<UnifiClients>(&url, &headers, router).await?.data {
+                // Aliases set in the UniFi UI beat hostnames.
+                let name = client.name.or(client.hostname).unwrap_or_default();
+
+                clients.add(&client.mac, client.ip.as_deref().unwrap_or_default(), &name);
+            }
+        }
+    }
+
+    Ok(clients)
+}
+
+async fn get<T: DeserializeOwned>(
+    url: &str,
+    headers: &[(HeaderName, &str)],
+    router: &RouterConfig,
+) -> Result<T, Error> {
+    let body = http::get(url, headers, router.ca.as_deref()).await?;
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Entry of DHCP leases, ARP or IPv6 neighbors in RouterOS, which only
+/// have some of the fields each.
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct MikrotikEntry {
+    mac_address: String,
+    address: String,
+    host_name: String,
+    comment: String,
+}
+
+#[derive(Deserialize)]
+struct UnifiClients {
+    data: Vec<UnifiClient>,
+}
+
+#[derive(Deserialize)]
+struct UnifiClient {
+    mac: String,
+    ip: Option<String>,
+    hostname: Option<String>,
+    name: Option<String>,
+}
@@ -0,0 +1,332 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clickhouse::Row;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::config::parse_mac;
+
+/// Columns of the ClickHouse table with their types, in the order of `FlowRecord`.
+pub const SCHEMA: &[(&str, &str)] = &[
+    ("insertionTime", "DateTime64(0)"),
+    ("listener", "LowCardinality(String)"),
+    ("observationDomain", "UInt32"),
+    ("clientMac", "UInt64"),
+    ("deviceName", "LowCardinality(String)"),
+    ("deviceGroup", "LowCardinality(String)"),
+    ("clientIPv4", "IPv4"),
+    ("clientIPv6", "IPv6"),
+    ("clientPort", "UInt16"),
+    ("serverIPv4", "IPv4"),
+    ("serverIPv6", "IPv6"),
+    ("serverPort", "UInt16"),
+    ("protocol", "UInt8"),
+    ("icmpTypeCode", "Nullable(UInt16)"),
+    ("tcpFlags", "UInt16"),
+    ("vlan", "UInt16"),
+    ("inInterface", "UInt32"),
+    ("outInterface", "UInt32"),
+    ("natSourceIPv4", "IPv4"),
+    ("natSourceIPv6", "IPv6"),
+    ("natSourcePort", "UInt16"),
+    ("tos", "UInt8"),
+    ("flowEndReason", "UInt8"),
+    ("mplsLabels", "Array(UInt32)"),
+    ("tunnelId", "UInt64"),
+    ("serverCountry", "LowCardinality(String)"),
+    ("serverCity", "LowCardinality(String)"),
+    ("serverAsn", "UInt32"),
+    ("serverAsName", "LowCardinality(String)"),
+    ("service", "LowCardinality(String)"),
+    ("category", "LowCardinality(String)"),
+    ("serverDomain", "String"),
+    ("cloudProvider", "LowCardinality(String)"),
+    ("cloudService", "LowCardinality(String)"),
+    ("threat", "LowCardinality(String)"),
+    ("vpn", "LowCardinality(String)"),
+    ("tags", "Map(String, String)"),
+    ("enterpriseFields", "Map(String, String)"),
+    ("packets", "UInt64"),
+    ("bytes", "UInt64"),
+    ("is_download", "Bool"),
+];
+
+#[derive(Clone, Deserialize, Row, Serialize)]
+pub struct FlowRecord {
+    #[serde(rename = "insertionTime")]
+    insertion_time: i64,
+    listener: String,
+    #[serde(rename = "observationDomain")]
+    observation_domain: u32,
+    #[serde(rename = "clientMac")]
+    client_mac: u64,
+    #[serde(rename = "deviceName")]
+    device_name: String,
+    #[serde(rename = "deviceGroup")]
+    device_group: String,
+    #[serde(rename = "clientIPv4", with = "ipv4")]
+    client_ipv4: Ipv4Addr,
+    #[serde(rename = "clientIPv6")]
+    client_ipv6: Ipv6Addr,
+    #[serde(rename = "clientPort")]
+    client_port: u16,
+    #[serde(rename = "serverIPv4", with = "ipv4")]
+    server_ipv4: Ipv4Addr,
+    #[serde(rename = "serverIPv6")]
+    server_ipv6: Ipv6Addr,
+    #[serde(rename = "serverPort")]
+    server_port: u16,
+    protocol: u8,
+    #[serde(rename = "icmpTypeCode")]
+    icmp_type_code: Option<u16>,
+    #[serde(rename = "tcpFlags")]
+    tcp_flags: u16,
+    vlan: u16,
+    #[serde(rename = "inInterface")]
+    in_interface: u32,
+    #[serde(rename = "outInterface")]
+    out_interface: u32,
+    #[serde(rename = "natSourceIPv4", with = "ipv4")]
+    nat_source_ipv4: Ipv4Addr,
+    #[serde(rename = "natSourceIPv6")]
+    nat_source_ipv6: Ipv6Addr,
+    #[serde(rename = "natSourcePort")]
+    nat_source_port: u16,
+    tos: u8,
+    #[serde(rename = "flowEndReason")]
+    flow_end_reason: u8,
+    #[serde(rename = "mplsLabels")]
+    mpls_labels: Vec<u32>,
+    #[serde(rename = "tunnelId")]
+    tunnel_id: u64,
+    #[serde(rename = "serverCountry")]
+    server_country: String,
+    #[serde(rename = "serverCity")]
+    server_city: String,
+    #[serde(rename = "serverAsn")]
+    server_asn: u32,
+    #[serde(rename = "serverAsName")]
+    server_as_name: String,
+    service: String,
+    category: String,
+    #[serde(rename = "serverDomain")]
+    server_domain: String,
+    #[serde(rename = "cloudProvider")]
+    cloud_provider: String,
+    #[serde(rename = "cloudService")]
+    cloud_service: String,
+    threat: String,
+    vpn: String,
+    /// Added by `[rules]`.
+    #[serde(with = "pairs")]
+    tags: Vec<(String, String)>,
+    #[serde(rename = "enterpriseFields", with = "pairs")]
+    enterprise_fields: Vec<(String, String)>,
+    packets: u64,
+    bytes: u64,
+    is_download: bool,
+}
+
+impl FlowRecord {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        listener: &str,
+        observation_domain: u32,
+        client_mac: &str,
+        device_name: &str,
+        device_group: &str,
+        client_addr: IpAddr,
+        client_port: u16,
+        server_addr: IpAddr,
+        server_port: u16,
+        protocol: u8,
+        icmp_type_code: Option<u16>,
+        tcp_flags: u16,
+        vlan: u16,
+        in_interface: u32,
+        out_interface: u32,
+        nat_source_addr: Option<IpAddr>,
+        nat_source_port: u16,
+        tos: u8,
+        flow_end_reason: u8,
+        mpls_labels: Vec<u32>,
+        tunnel_id: u64,
+        server_country: &str,
+        server_city: &str,
+        server_asn: u32,
+        server_as_name: &str,
+        service: &str,
+        category: &str,
+        server_domain: &str,
+        cloud_provider: &str,
+        cloud_service: &str,
+        threat: &str,
+        vpn: &str,
+        enterprise_fields: Vec<(String, String)>,
+        packets: u64,
+        bytes: u64,
+        is_download: bool,
+    ) -> Self {
+        let insertion_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let (client_ipv4, client_ipv6) = match client_addr {
+            IpAddr::V4(ipv4_addr) => (ipv4_addr, Ipv6Addr::UNSPECIFIED),
+            IpAddr::V6(ipv6_addr) => (Ipv4Addr::UNSPECIFIED, ipv6_addr),
+        };
+
+        let (server_ipv4, server_ipv6) = match server_addr {
+            IpAddr::V4(ipv4_addr) => (ipv4_addr, Ipv6Addr::UNSPECIFIED),
+            IpAddr::V6(ipv6_addr) => (Ipv4Addr::UNSPECIFIED, ipv6_addr),
+        };
+
+        let (nat_source_ipv4, nat_source_ipv6) = match nat_source_addr {
+            Some(IpAddr::V4(ipv4_addr)) => (ipv4_addr, Ipv6Addr::UNSPECIFIED),
+            Some(IpAddr::V6(ipv6_addr)) => (Ipv4Addr::UNSPECIFIED, ipv6_addr),
+            None => (Ipv4Addr::UNSPECIFIED, Ipv6Addr::UNSPECIFIED),
+        };
+
+        // An invalid MAC is stored as zero, like an unknown one.
+        let client_mac = parse_mac(client_mac).unwrap_or_default();
+
+        Self {
+            insertion_time,
+            listener: listener.to_owned(),
+            observation_domain,
+            client_mac,
+            device_name: device_name.to_owned(),
+            device_group: device_group.to_owned(),
+            client_ipv4,
+            client_ipv6,
+            client_port,
+            server_ipv4,
+            server_ipv6,
+            server_port,
+            protocol,
+            icmp_type_code,
+            tcp_flags,
+            vlan,
+            in_interface,
+            out_interface,
+            nat_source_ipv4,
+            nat_source_ipv6,
+            nat_source_port,
+            tos,
+            flow_end_reason,
+            mpls_labels,
+            tunnel_id,
+            server_country: server_country.to_owned(),
+            server_city: server_city.to_owned(),
+            server_asn,
+            server_as_name: server_as_name.to_owned(),
+            service: service.to_owned(),
+            category: category.to_owned(),
+            server_domain: server_domain.to_owned(),
+            cloud_provider: cloud_provider.to_owned(),
+            cloud_service: cloud_service.to_owned(),
+            threat: threat.to_owned(),
+            vpn: vpn.to_owned(),
+            tags: vec![],
+            enterprise_fields,
+            is_download,
+            packets,
+            bytes,
+        }
+    }
+
+    pub fn client_mac(&self) -> u64 {
+        self.client_mac
+    }
+
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    pub fn set_device_name(&mut self, name: &str) {
+        name.clone_into(&mut self.device_name);
+    }
+
+    /// Sets the tag, replacing the value it had.
+    pub fn tag(&mut self, name: &str, value: &str) {
+        match self.tags.iter_mut().find(|(tag, _)| tag == name) {
+            Some((_, tagged)) => value.clone_into(tagged),
+            None => self.tags.push((name.to_owned(), value.to_owned())),
+        }
+    }
+}
+
+/// Addresses are numbers in RowBinary for ClickHouse and text in JSON.
+mod ipv4 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(addr: &Ipv4Addr, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            addr.serialize(serializer)
+        } else {
+            clickhouse::serde::ipv4::serialize(addr, serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Ipv4Addr, D::Error> {
+        if deserializer.is_human_readable() {
+            Ipv4Addr::deserialize(deserializer)
+        } else {
+            clickhouse::serde::ipv4::deserialize(deserializer)
+        }
+    }
+}
+
+/// ClickHouse maps are arrays of pairs in RowBinary, but objects in JSON.
+mod pairs {
+    use std::fmt;
+
+    use serde::de::{MapAccess, Visitor};
+
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        pairs: &[(String, String)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_map(pairs.iter().map(|(key, value)| (key, value)))
+        } else {
+            pairs.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(String, String)>, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_map(PairsVisitor)
+        } else {
+            Vec::deserialize(deserializer)
+        }
+    }
+
+    /// Collects the entries of a map in their order.
+    struct PairsVisitor;
+
+    impl<'de> Visitor<'de> for PairsVisitor {
+        type Value = Vec<(String, String)>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a map of strings")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut pairs = vec![];
+
+            while let Some(pair) = map.next_entry()? {
+                pairs.push(pair);
+            }
+
+            Ok(pairs)
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Deserialize;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::{config::RulesConfig, filter::Filter, row::FlowRecord};
+
+/// Site specific logic for records, from the rules in a TOML file, which is
+/// checked every now and then and read again once it changes. Every rule
+/// matching the record applies, in order, with the filter expressions of
+/// sinks in `when`:
+///
+/// ```toml
+/// [[rule]]
+/// when = "serverPort == 22 and clientIP in [192.168.1.0/24]"
+/// device_name = "admin laptop"
+/// tags = { team = "ops" }
+///
+/// [[rule]]
+/// when = "serverIP in [192.168.1.10/32]"
+/// drop = true
+/// ```
+///
+/// Dropped records go to no sink and skip the rules after.
+pub struct Rules {
+    enabled: bool,
+    path: PathBuf,
+    reload: Duration,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RawRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    #[serde(default)]
+    when: String,
+    device_name: Option<String>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    #[serde(default)]
+    drop: bool,
+}
+
+struct Rule {
+    when: Filter,
+    device_name: Option<String>,
+    tags: BTreeMap<String, String>,
+    drop: bool,
+}
+
+impl Rules {
+    pub fn new(config: &RulesConfig) -> Self {
+        let mut rules = Self {
+            enabled: config.enabled,
+            path: config.path.clone(),
+            reload: config.reload,
+            modified: None,
+            last_check: Instant::now(),
+            rules: Vec::new(),
+        };
+
+        rules.load();
+
+        rules
+    }
+
+    pub fn reconfigure(&mut self, config: &RulesConfig) {
+        let changed = config.enabled != self.enabled || config.path != self.path;
+
+        self.enabled = config.enabled;
+        self.path.clone_from(&config.path);
+        self.reload = config.reload;
+
+        if changed {
+            self.modified = None;
+            self.load();
+        }
+    }
+
+    /// Reads the rules again if the file changed since the last check.
+    pub fn refresh(&mut self) {
+        if !self.enabled || self.last_check.elapsed() < self.reload {
+            return;
+        }
+
+        self.load();
+    }
+
+    /// Applies the matching rules to the record, returning whether to keep it.
+    pub fn apply(&self, record: &mut FlowRecord) -> bool {
+        for rule in &self.rules {
+            if !rule.when.matches(record) {
+                continue;
+            }
+
+            if rule.drop {
+                return false;
+            }
+
+            if let Some(name) = &rule.device_name {
+                record.set_device_name(name);
+            }
+
+            for (name, value) in &rule.tags {
+                record.tag(name, value);
+            }
+        }
+
+        true
+    }
+
+    fn load(&mut self) {
+        self.last_check = Instant::now();
+
+        if !self.enabled {
+            self.rules.clear();
+            return;
+        }
+
+        let path = &self.path;
+
+        let modified = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                warn!(target: "rules", "Cannot read {}: {e}", path.display());
+                return;
+            }
+        };
+
+        if self.modified == Some(modified) {
+            return;
+        }
+
+        self.modified = Some(modified);
+
+        // Rules of the last good file are kept until the mistake is fixed.
+        let rules = block_in_place(|| fs::read_to_string(path))
+            .map_err(|e| e.to_string())
+            .and_then(|contents| parse(&contents));
+
+        match rules {
+            Ok(rules) => {
+                info!(target: "rules", "Loaded {} rules from {}", rules.len(), path.display());
+
+                self.rules = rules;
+            }
+            Err(e) => warn!(target: "rules", "Cannot load {}: {e}", path.display()),
+        }
+    }
+}
+
+fn parse(contents: &str) -> Result<Vec<Rule>, String> {
+    let file = toml::from_str::<RulesFile>(contents).map_err(|e| e.to_string())?;
+
+    file.rule
+        .into_iter()
+        .map(|rule| {
+            let when = Filter::parse(&rule.when)
+                .map_err(|e| format!("invalid filter {:?}: {e}", rule.when))?;
+
+            Ok(Rule {
+                when,
+                device_name: rule.device_name,
+                tags: rule.tags,
+                drop: rule.drop,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+
+use clickhouse::Client;
+
+use crate::{
+    config::{quote_identifier, ClickhouseConfig, Config},
+    row::SCHEMA,
+};
+
+/// Creates the ClickHouse table if it doesn't exist, or adds the columns
+/// it's missing, printing what was done. Returns the process exit code.
+pub async fn init(config: &Config) -> i32 {
+    let config = &config.clickhouse;
+    let table = format!("{}.{}", config.database, config.table);
+
+    let client = match config.client() {
+        Ok(client) => client,
+        Err(e) => {
+            println!("FAILED clickhouse table {table}: cannot set up the client: {e}");
+            return 1;
+        }
+    };
+
+    match init_table(config, &client).await {
+        Ok(changes) if changes.is_empty() => {
+            println!("ok     clickhouse table {table}: up to date");
+            0
+        }
+        Ok(changes) => {
+            for change in changes {
+                println!("ok     clickhouse table {table}: {change}");
+            }
+
+            0
+        }
+        Err(e) => {
+            println!("FAILED clickhouse table {table}: {e}");
+            1
+        }
+    }
+}
+
+async fn init_table(
+    config: &ClickhouseConfig,
+    client: &Client,
+) -> Result<Vec<String>, clickhouse::error::Error> {
+    let columns = client
+        .query("SELECT name, type FROM system.columns WHERE database = ? AND table = ?")
+        .bind(&config.database)
+        .bind(&config.table)
+        .fetch_all::<(String, String)>()
+        .await?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    let mut changes = vec![];
+
+    if columns.is_empty() {
+        client.query(&create_table(config)).execute().await?;
+
+        changes.push(format!("created with {} columns", SCHEMA.len()));
+    }
+
+    // Columns go after the ones preceding them in the schema, which
+    // keeps the order of the table for columns added over time.
+    for (index, (name, column_type)) in SCHEMA.iter().enumerate() {
+        let name = config.column(name);
+
+        if columns.is_empty() || columns.contains_key(name) {
+            continue;
+        }
+
+        let position = match index {
+            0 => "FIRST".to_owned(),
+            index => format!(
+                "AFTER {}",
+                quote_identifier(config.column(SCHEMA[index - 1].0))
+            ),
+        };
+
+        let statement = format!(
+            "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {position}",
+            table_name(config),
+            column_definition(name, column_type),
+        );
+
+        client.query(&statement).execute().await?;
+
+        changes.push(format!("added column {name} {column_type}"));
+    }
+
+    if config.rollups {
+        for rollup in ROLLUPS {
+            if init_rollup(config, client, rollup).await? {
+                changes.push(format!("created rollup {}_{}", config.table, rollup.suffix));
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Totals of a few columns over a period, kept up to date by a
+/// materialized view and never expiring with the raw rows.
+struct Rollup {
+    suffix: &'static str,
+    /// Column with the start of the period, its type and how it's calculated.
+    period: (&'static str, &'static str, &'static str),
+    keys: &'static [&'static str],
+}
+
+/// Flow records carry no ASNs, so servers are rolled up by address.
+const ROLLUPS: &[Rollup] = &[
+    Rollup {
+        suffix: "hourly_devices",
+        period: ("hour", "DateTime", "toStartOfHour"),
+        keys: &["clientMac", "deviceName", "is_download"],
+    },
+    Rollup {
+        suffix: "daily_servers",
+        period: ("day", "Date", "toDate"),
+        keys: &["serverIPv4", "serverIPv6", "is_download"],
+    },
+];
+
+/// Creates the rollup table and its view, returning whether they're new.
+async fn init_rollup(
+    config: &ClickhouseConfig,
+    client: &Client,
+    rollup: &Rollup,
+) -> Result<bool, clickhouse::error::Error> {
+    let name = format!("{}_{}", config.table, rollup.suffix);
+    let view = format!("{name}_mv");
+
+    // The view goes last, so a table without it is finished off.
+    let exists = client
+        .query("SELECT count() FROM system.tables WHERE database = ? AND name = ?")
+        .bind(&config.database)
+        .bind(&view)
+        .fetch_one::<u64>()
+        .await?
+        > 0;
+
+    if exists {
+        return Ok(false);
+    }
+
+    let (period, period_type, period_function) = rollup.period;
+
+    let keys = rollup
+        .keys
+        .iter()
+        .map(|key| {
+            let (_, key_type) = SCHEMA.iter().find(|(name, _)| name == key).unwrap();
+            format!("{} {key_type}", quote_identifier(key))
+        })
+        .collect::<Vec<_>>()
+        .join(",\n    ");
+
+    let order = std::iter::once(period)
+        .chain(rollup.keys.iter().copied())
+        .map(quote_identifier)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let database = quote_identifier(&config.database);
+    let target = format!("{database}.{}", quote_identifier(&name));
+
+    // Totals of rows with the same key are summed up as parts get merged.
+    let statement = format!(
+        "CREATE TABLE IF NOT EXISTS {target}\n(\n    \
+         {} {period_type},\n    {keys},\n    \
+         `bytes` UInt64,\n    `packets` UInt64,\n    `flows` UInt64\n)\n\
+         ENGINE = SummingMergeTree\n\
+         PARTITION BY toYYYYMM({})\n\
+         ORDER BY ({order})",
+        quote_identifier(period),
+        quote_identifier(period),
+    );
+
+    client.query(&statement).execute().await?;
+
+    let mut columns = vec![format!(
+        "{period_function}({}) AS {}",
+        quote_identifier(config.column("insertionTime")),
+        quote_identifier(period)
+    )];
+
+    for key in rollup.keys {
+        columns.push(format!(
+            "{} AS {}",
+            quote_identifier(config.column(key)),
+            quote_identifier(key)
+        ));
+    }
+
+    for counter in ["bytes", "packets"] {
+        columns.push(format!(
+            "sum({}) AS {}",
+            quote_identifier(config.column(counter)),
+            quote_identifier(counter)
+        ));
+    }
+
+    columns.push("count() AS `flows`".to_owned());
+
+    let statement = format!(
+        "CREATE MATERIALIZED VIEW IF NOT EXISTS {database}.{} TO {target} AS \
+         SELECT {} FROM {} GROUP BY {order}",
+        quote_identifier(&view),
+        columns.join(", "),
+        table_name(config),
+    );
+
+    client.query(&statement).execute().await?;
+
+    Ok(true)
+}
+
+/// Table partitioned by day, with old rows expiring after `retention` if it's set.
+fn create_table(config: &ClickhouseConfig) -> String {
+    let columns = SCHEMA
+        .iter()
+        .map(|(name, column_type)| column_definition(config.column(name), column_type))
+        .collect::<Vec<_>>()
+        .join(",\n    ");
+
+    let insertion_time = quote_identifier(config.column("insertionTime"));
+
+    let order = ["insertionTime", "clientIPv4", "clientIPv6"]
+        .map(|name| quote_identifier(config.column(name)))
+        .join(", ");
+
+    let mut statement = format!(
+        "CREATE TABLE IF NOT EXISTS {}\n(\n    {columns}\n)\n\
+         ENGINE = MergeTree\n\
+         PARTITION BY toYYYYMMDD({insertion_time})\n\
+         ORDER BY ({order})",
+        table_name(config),
+    );
+
+    if let Some(retention) = config.retention {
+        statement.push_str(&format!(
+            "\nTTL toDateTime({insertion_time}) + INTERVAL {} SECOND",
+            retention.as_secs()
+        ));
+    }
+
+    statement
+}
+
+pub fn table_name(config: &ClickhouseConfig) -> String {
+    format!(
+        "{}.{}",
+        quote_identifier(&config.database),
+        quote_identifier(&config.table)
+    )
+}
+
+/// Column with a codec suited to how its values change from row to row.
+fn column_definition(name: &str, column_type: &str) -> String {
+    let codec = match column_type {
+        "DateTime64(0)" => " CODEC(Delta, ZSTD(1))",
+        "UInt8" | "UInt16" | "UInt32" | "UInt64" => " CODEC(T64, ZSTD(1))",
+        "LowCardinality(String)" | "Bool" => "",
+        _ => " CODEC(ZSTD(1))",
+    };
+
+    format!("{} {column_type}{codec}", quote_identifier(name))
+}
@@ -0,0 +1,193 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use prometheus_client::metrics::counter::Counter;
+use rhai::{Engine, Map, Scope, AST};
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::{config::ScriptConfig, row::FlowRecord};
+
+/// Site specific logic for records that the rules can't express, in a Rhai
+/// script after the rules. The script runs for every record, which it gets
+/// as the `record` map with the columns of the file sink, and tags, renames
+/// or drops it by changing the variables it starts with:
+///
+/// ```rhai
+/// if record.serverPort == 3389 {
+///     tags.remote = "rdp";
+/// }
+///
+/// if record.clientIPv4.starts_with("192.168.122.") {
+///     device_name = "vm " + record.clientIPv4;
+/// }
+///
+/// drop = record.serverPort == 5353;
+/// ```
+///
+/// The file is checked every now and then and compiled again once it
+/// changes, keeping the last good script meanwhile. Records the script
+/// fails on are dropped, so that a broken script doesn't let through
+/// what it's meant to filter out.
+pub struct Script {
+    enabled: bool,
+    path: PathBuf,
+    reload: Duration,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+    engine: Engine,
+    ast: Option<AST>,
+    errors: Counter,
+    /// Whether the last run failed, to only warn once in a row.
+    failing: bool,
+}
+
+impl Script {
+    pub fn new(config: &ScriptConfig, errors: Counter) -> Self {
+        let mut script = Self {
+            enabled: config.enabled,
+            path: config.path.clone(),
+            reload: config.reload,
+            modified: None,
+            last_check: Instant::now(),
+            engine: engine(config),
+            ast: None,
+            errors,
+            failing: false,
+        };
+
+        script.load();
+
+        script
+    }
+
+    pub fn reconfigure(&mut self, config: &ScriptConfig) {
+        let changed = config.enabled != self.enabled || config.path != self.path;
+
+        self.enabled = config.enabled;
+        self.path.clone_from(&config.path);
+        self.reload = config.reload;
+        self.engine = engine(config);
+
+        if changed {
+            self.modified = None;
+            self.load();
+        }
+    }
+
+    /// Compiles the script again if the file changed since the last check.
+    pub fn refresh(&mut self) {
+        if !self.enabled || self.last_check.elapsed() < self.reload {
+            return;
+        }
+
+        self.load();
+    }
+
+    /// Runs the script for the record, returning whether to keep it.
+    pub fn apply(&mut self, record: &mut FlowRecord) -> bool {
+        let Some(ast) = &self.ast else {
+            return true;
+        };
+
+        let mut scope = Scope::new();
+
+        let values = rhai::serde::to_dynamic(&*record);
+
+        let result = values.and_then(|values| {
+            scope.push_constant_dynamic("record", values);
+            scope.push("device_name", record.device_name().to_owned());
+            scope.push("tags", Map::new());
+            scope.push("drop", false);
+
+            self.engine.run_ast_with_scope(&mut scope, ast)
+        });
+
+        if let Err(e) = result {
+            self.errors.inc();
+
+            if !self.failing {
+                warn!(target: "script", "Dropping records the script fails on: {e}");
+            }
+
+            self.failing = true;
+
+            return false;
+        }
+
+        if self.failing {
+            info!(target: "script", "Script runs again");
+        }
+
+        self.failing = false;
+
+        if scope.get_value::<bool>("drop").unwrap_or_default() {
+            return false;
+        }
+
+        if let Some(name) = scope.get_value::<String>("device_name") {
+            record.set_device_name(&name);
+        }
+
+        for (name, value) in scope.get_value::<Map>("tags").unwrap_or_default() {
+            // Numbers and the like go in as text, like they read.
+            record.tag(&name, &value.to_string());
+        }
+
+        true
+    }
+
+    fn load(&mut self) {
+        self.last_check = Instant::now();
+
+        if !self.enabled {
+            self.ast = None;
+            return;
+        }
+
+        let path = &self.path;
+
+        let modified = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                warn!(target: "script", "Cannot read {}: {e}", path.display());
+                return;
+            }
+        };
+
+        if self.modified == Some(modified) {
+            return;
+        }
+
+        self.modified = Some(modified);
+
+        // The last good script is kept until the mistake is fixed.
+        let ast = block_in_place(|| fs::read_to_string(path))
+            .map_err(|e| e.to_string())
+            .and_then(|contents| self.engine.compile(contents).map_err(|e| e.to_string()));
+
+        match ast {
+            Ok(ast) => {
+                info!(target: "script", "Loaded {}", path.display());
+
+                self.ast = Some(ast);
+            }
+            Err(e) => warn!(target: "script", "Cannot load {}: {e}", path.display()),
+        }
+    }
+}
+
+/// Engine with a limit on the work per record, so that a script stuck in
+/// a loop fails instead of stalling the collector.
+fn engine(config: &ScriptConfig) -> Engine {
+    let mut engine = Engine::new();
+
+    engine.set_max_operations(config.max_operations);
+    engine.on_print(|text| info!(target: "script", "{text}"));
+    engine.on_debug(|text, _, _| info!(target: "script", "{text}"));
+
+    engine
+}
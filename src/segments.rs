@@ -0,0 +1,178 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Segments are closed once they reach this size, so that they can be
+/// replayed and removed one at a time.
+const SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Append-only files in a directory, numbered in the order they were
+/// written, for the write-ahead log and the spool of ClickHouse. The oldest
+/// segments are removed to stay under the size limit.
+pub struct Segments {
+    directory: PathBuf,
+    extension: &'static str,
+    max_bytes: u64,
+    /// Numbers and sizes of the segments, oldest first.
+    segments: Vec<(u64, u64)>,
+    /// The last segment, while it's open for appending.
+    writer: Option<BufWriter<File>>,
+}
+
+impl Segments {
+    /// Picks up the segments with the extension left over from previous runs.
+    pub fn open(directory: &Path, extension: &'static str, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+
+        let mut segments = vec![];
+
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            let name = entry.file_name();
+
+            let Some(number) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(extension))
+                .and_then(|name| name.strip_suffix('.'))
+                .and_then(|number| number.parse().ok())
+            else {
+                continue;
+            };
+
+            segments.push((number, entry.metadata()?.len()));
+        }
+
+        segments.sort_unstable();
+
+        Ok(Self {
+            directory: directory.to_owned(),
+            extension,
+            max_bytes,
+            segments,
+            writer: None,
+        })
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = max_bytes;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.segments.iter().map(|(_, size)| size).sum()
+    }
+
+    /// Numbers of the segments, oldest first.
+    pub fn numbers(&self) -> Vec<u64> {
+        self.segments.iter().map(|(number, _)| *number).collect()
+    }
+
+    pub fn path(&self, number: u64) -> PathBuf {
+        self.directory
+            .join(format!("{number:020}.{}", self.extension))
+    }
+
+    /// Appends data to the last segment, starting a new one when it's full,
+    /// and returns the paths of the oldest segments removed to make room.
+    pub fn append(&mut self, data: &[u8]) -> io::Result<Vec<PathBuf>> {
+        let size = data.len() as u64;
+
+        let full = self
+            .segments
+            .last()
+            .is_some_and(|(_, last)| last + size > SEGMENT_BYTES);
+
+        if full || self.writer.is_none() {
+            self.close()?;
+
+            let number = self.segments.last().map_or(0, |(number, _)| number + 1);
+
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(self.path(number))?;
+
+            self.writer = Some(BufWriter::new(file));
+            self.segments.push((number, 0));
+        }
+
+        let mut removed = vec![];
+
+        // Only closed segments go, the one being written is the newest.
+        while self.segments.len() > 1 && self.size() + size > self.max_bytes {
+            let (number, _) = self.segments.remove(0);
+            let path = self.path(number);
+
+            fs::remove_file(&path)?;
+
+            removed.push(path);
+        }
+
+        let writer = self.writer.as_mut().unwrap();
+
+        writer.write_all(data)?;
+        writer.flush()?;
+
+        self.segments.last_mut().unwrap().1 += size;
+
+        Ok(removed)
+    }
+
+    /// Closes the last segment once it's on the disk, so that the next
+    /// append starts a new one.
+    pub fn close(&mut self) -> io::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a segment, closing it if it's being written.
+    pub fn read(&mut self, number: u64) -> io::Result<Vec<u8>> {
+        if self
+            .segments
+            .last()
+            .is_some_and(|(last, _)| *last == number)
+        {
+            self.close()?;
+        }
+
+        let mut data = vec![];
+
+        File::open(self.path(number))?.read_to_end(&mut data)?;
+
+        Ok(data)
+    }
+
+    /// Removes a segment, closing it if it's being written.
+    pub fn remove(&mut self, number: u64) -> io::Result<()> {
+        let index = self
+            .segments
+            .iter()
+            .position(|(segment, _)| *segment == number)
+            .expect("segments are removed once");
+
+        if index == self.segments.len() - 1 {
+            self.writer = None;
+        }
+
+        self.segments.remove(index);
+
+        fs::remove_file(self.path(number))
+    }
+}
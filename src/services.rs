@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, HashMap};
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const SCTP: u8 = 132;
+
+/// Names services by the port on the server side of flows, with the rules
+/// of `[services]` first and the IANA names of well known ports after.
+pub struct Services {
+    /// Names keyed by port and protocol, any protocol if `None`.
+    configured: HashMap<(u16, Option<u8>), String>,
+}
+
+impl Services {
+    /// Takes the rules normalized by the config.
+    pub fn new(rules: &BTreeMap<String, String>) -> Self {
+        let configured = rules
+            .iter()
+            .filter_map(|(key, name)| Some((parse_rule(key)?, name.clone())))
+            .collect();
+
+        Self { configured }
+    }
+
+    /// Service from `[services]`, which gets series of its own.
+    pub fn configured(&self, protocol: u8, port: u16) -> Option<&str> {
+        self.configured
+            .get(&(port, Some(protocol)))
+            .or_else(|| self.configured.get(&(port, None)))
+            .map(String::as_str)
+    }
+
+    /// Service of the flow, empty if the port is not known.
+    pub fn name(&self, protocol: u8, port: u16) -> &str {
+        self.configured(protocol, port)
+            .or_else(|| well_known(protocol, port))
+            .unwrap_or_default()
+    }
+}
+
+/// Parses a rule like `443` or `3074/udp` into the port and protocol.
+pub fn parse_rule(key: &str) -> Option<(u16, Option<u8>)> {
+    let (port, protocol) = match key.split_once('/') {
+        Some((port, protocol)) => (port, Some(protocol)),
+        None => (key, None),
+    };
+
+    let protocol = match protocol.map(str::to_ascii_lowercase).as_deref() {
+        Some("tcp") => Some(TCP),
+        Some("udp") => Some(UDP),
+        Some("sctp") => Some(SCTP),
+        Some(_) => return None,
+        None => None,
+    };
+
+    Some((port.parse().ok()?, protocol))
+}
+
+/// Formats a rule the way `parse_rule` takes it.
+pub fn format_rule((port, protocol): (u16, Option<u8>)) -> String {
+    match protocol {
+        Some(TCP) => format!("{port}/tcp"),
+        Some(UDP) => format!("{port}/udp"),
+        Some(_) => format!("{port}/sctp"),
+        None => port.to_string(),
+    }
+}
+
+/// Service names from the IANA registry for ports seen on home and office
+/// networks, only for protocols that have ports.
+fn well_known(protocol: u8, port: u16) -> Option<&'static str> {
+    if ![TCP, UDP, SCTP].contains(&protocol) {
+        return None;
+    }
+
+    let name = match (port, protocol) {
+        (20, _) => "ftp-data",
+        (21, _) => "ftp",
+        (22, _) => "ssh",
+        (23, _) => "telnet",
+        (25, _) => "smtp",
+        (53, _) => "domain",
+        (67, _) => "bootps",
+        (68, _) => "bootpc",
+        (69, _) => "tftp",
+        (80, _) => "http",
+        (110, _) => "pop3",
+        (123, _) => "ntp",
+        (137, _) => "netbios-ns",
+        (138, _) => "netbios-dgm",
+        (139, _) => "netbios-ssn",
+        (143, _) => "imap",
+        (161, _) => "snmp",
+        (162, _) => "snmptrap",
+        (179, _) => "bgp",
+        (389, _) => "ldap",
+        (443, _) => "https",
+        (445, _) => "microsoft-ds",
+        (465, _) => "submissions",
+        (500, _) => "isakmp",
+        (514, UDP) => "syslog",
+        (514, _) => "shell",
+        (554, _) => "rtsp",
+        (587, _) => "submission",
+        (631, _) => "ipp",
+        (636, _) => "ldaps",
+        (853, _) => "domain-s",
+        (873, _) => "rsync",
+        (993, _) => "imaps",
+        (995, _) => "pop3s",
+        (1194, _) => "openvpn",
+        (1723, _) => "pptp",
+        (1883, _) => "mqtt",
+        (1900, _) => "ssdp",
+        (3074, _) => "xbox",
+        (3306, _) => "mysql",
+        (3389, _) => "ms-wbt-server",
+        (3478, _) => "stun",
+        (4500, _) => "ipsec-nat-t",
+        (5060, _) => "sip",
+        (5061, _) => "sips",
+        (5222, _) => "xmpp-client",
+        (5353, _) => "mdns",
+        (5432, _) => "postgresql",
+        (5900, _) => "rfb",
+        (8080, _) => "http-alt",
+        (8883, _) => "secure-mqtt",
+        _ => return None,
+    };
+
+    Some(name)
+}
@@ -0,0 +1,396 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::flow::Flow;
+
+const FLOW_SAMPLE: u32 = 1;
+const EXPANDED_FLOW_SAMPLE: u32 = 3;
+
+const RAW_PACKET_HEADER: u32 = 1;
+const ETHERNET_FRAME_DATA: u32 = 2;
+const IPV4_DATA: u32 = 3;
+const IPV6_DATA: u32 = 4;
+const EXTENDED_SWITCH_DATA: u32 = 1001;
+const EXTENDED_GATEWAY_DATA: u32 = 1003;
+
+const HEADER_PROTOCOL_ETHERNET: u32 = 1;
+const HEADER_PROTOCOL_IPV4: u32 = 11;
+const HEADER_PROTOCOL_IPV6: u32 = 12;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+/// Decodes the flow samples of an sFlow v5 datagram, with counters scaled up
+/// by the sampling rate. Counter samples and unknown records are skipped.
+/// Returns `None` if the datagram is malformed.
+pub fn parse(data: &[u8]) -> Option<Vec<Flow>> {
+    let mut reader = Reader(data);
+
+    if reader.u32()? != 5 {
+        return None;
+    }
+
+    // The agent address, which is empty if unknown.
+    match reader.u32()? {
+        0 => {}
+        1 => reader.skip(4)?,
+        2 => reader.skip(16)?,
+        _ => return None,
+    }
+
+    // Sub agent id, sequence number and uptime.
+    reader.skip(12)?;
+
+    let mut flows = vec![];
+
+    for _ in 0..reader.u32()? {
+        let format = reader.u32()?;
+        let mut sample = Reader(reader.opaque()?);
+
+        match format {
+            FLOW_SAMPLE => {
+                // Sequence number and source id.
+                sample.skip(8)?;
+            }
+            EXPANDED_FLOW_SAMPLE => {
+                // Sequence number, source id type and index.
+                sample.skip(12)?;
+            }
+            _ => continue,
+        }
+
+        let sampling_rate = sample.u32()?.max(1);
+
+        // Sample pool and drops.
+        sample.skip(8)?;
+
+        let mut packet = Packet::default();
+
+        if format == FLOW_SAMPLE {
+            // The format lives in the top two bits.
+            let (input, output) = (sample.u32()?, sample.u32()?);
+
+            packet.in_interface = interface(input >> 30, input & 0x3fff_ffff);
+            packet.out_interface = interface(output >> 30, output & 0x3fff_ffff);
+        } else {
+            packet.in_interface = interface(sample.u32()?, sample.u32()?);
+            packet.out_interface = interface(sample.u32()?, sample.u32()?);
+        }
+
+        for _ in 0..sample.u32()? {
+            let format = sample.u32()?;
+            let mut record = Reader(sample.opaque()?);
+
+            match format {
+                RAW_PACKET_HEADER => packet.raw_header(&mut record)?,
+                ETHERNET_FRAME_DATA => packet.ethernet_data(&mut record)?,
+                IPV4_DATA => packet.ip_data(&mut record, false)?,
+                IPV6_DATA => packet.ip_data(&mut record, true)?,
+                EXTENDED_SWITCH_DATA => packet.switch_data(&mut record)?,
+                EXTENDED_GATEWAY_DATA => packet.gateway_data(&mut record)?,
+                _ => {}
+            }
+        }
+
+        if let Some(flow) = packet.into_flow(sampling_rate) {
+            flows.push(flow);
+        }
+    }
+
+    Some(flows)
+}
+
+/// What is known about a sampled packet from the records of a sample.
+#[derive(Default)]
+struct Packet {
+    length: u32,
+    src_mac: Option<String>,
+    src_addr: Option<IpAddr>,
+    dst_addr: Option<IpAddr>,
+    protocol: u8,
+    icmp_type_code: Option<u16>,
+    src_port: u16,
+    dst_port: u16,
+    tcp_flags: u16,
+    tos: u8,
+    vlan: Option<u16>,
+    in_interface: Option<u32>,
+    out_interface: Option<u32>,
+    src_as: Option<u32>,
+    dst_as: Option<u32>,
+}
+
+impl Packet {
+    fn raw_header(&mut self, record: &mut Reader) -> Option<()> {
+        let protocol = record.u32()?;
+
+        self.length = record.u32()?;
+
+        // Bytes stripped from the frame, the checksum for Ethernet.
+        record.skip(4)?;
+
+        let header = record.opaque()?;
+
+        // Truncated headers are fine, whatever is there gets used.
+        let _ = match protocol {
+            HEADER_PROTOCOL_ETHERNET => self.ethernet(Reader(header)),
+            HEADER_PROTOCOL_IPV4 => self.ipv4(Reader(header)),
+            HEADER_PROTOCOL_IPV6 => self.ipv6(Reader(header)),
+            _ => None,
+        };
+
+        Some(())
+    }
+
+    fn ethernet_data(&mut self, record: &mut Reader) -> Option<()> {
+        self.length = record.u32()?;
+
+        // Addresses are padded to 8 bytes.
+        self.src_mac = Some(format_mac(record.take(8)?));
+
+        Some(())
+    }
+
+    fn ip_data(&mut self, record: &mut Reader, ipv6: bool) -> Option<()> {
+        self.length = record.u32()?;
+        self.protocol = record.u32()? as u8;
+
+        if ipv6 {
+            self.src_addr = Some(record.ipv6()?.into());
+            self.dst_addr = Some(record.ipv6()?.into());
+        } else {
+            self.src_addr = Some(record.ipv4()?.into());
+            self.dst_addr = Some(record.ipv4()?.into());
+        }
+
+        self.src_port = record.u32()? as u16;
+        self.dst_port = record.u32()? as u16;
+        self.tcp_flags = record.u32()? as u16;
+
+        // Type of service for IPv4, priority for IPv6.
+        self.tos = record.u32()? as u8;
+
+        Some(())
+    }
+
+    fn switch_data(&mut self, record: &mut Reader) -> Option<()> {
+        // The incoming VLAN is followed by the priority and outgoing ones.
+        self.vlan = Some(record.u32()? as u16);
+
+        Some(())
+    }
+
+    fn gateway_data(&mut self, record: &mut Reader) -> Option<()> {
+        // The next hop, then the AS of the router itself. An unknown next
+        // hop has no address, other types can't be read past, so the record
+        // is skipped like records of unknown formats.
+        match record.u32()? {
+            0 => {}
+            1 => record.skip(4)?,
+            2 => record.skip(16)?,
+            _ => return Some(()),
+        }
+
+        let router_as = record.u32()?;
+
+        self.src_as = Some(record.u32()?).filter(|asn| *asn > 0);
+
+        // Peer AS, then the path to the destination, which is local to
+        // the router if empty. The destination AS is the last one.
+        record.skip(4)?;
+
+        self.dst_as = Some(router_as);
+
+        for _ in 0..record.u32()? {
+            // Segment type, AS_SET or AS_SEQUENCE.
+            record.skip(4)?;
+
+            for _ in 0..record.u32()? {
+                self.dst_as = Some(record.u32()?);
+            }
+        }
+
+        self.dst_as = self.dst_as.filter(|asn| *asn > 0);
+
+        Some(())
+    }
+
+    fn ethernet(&mut self, mut header: Reader) -> Option<()> {
+        header.skip(6)?;
+
+        self.src_mac = Some(format_mac(header.take(6)?));
+
+        let mut ethertype = header.u16()?;
+
+        while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+            let tag = header.u16()? & 0x0fff;
+
+            // The outer tag wins, switch data takes precedence over both.
+            self.vlan.get_or_insert(tag);
+
+            ethertype = header.u16()?;
+        }
+
+        match ethertype {
+            ETHERTYPE_IPV4 => self.ipv4(header),
+            ETHERTYPE_IPV6 => self.ipv6(header),
+            _ => None,
+        }
+    }
+
+    pub fn ipv4(&mut self, mut header: Reader) -> Option<()> {
+        let header_length = usize::from(header.u8()? & 0x0f) * 4;
+
+        self.tos = header.u8()?;
+
+        header.skip(7)?;
+
+        self.protocol = header.u8()?;
+
+        header.skip(2)?;
+
+        self.src_addr = Some(header.ipv4()?.into());
+        self.dst_addr = Some(header.ipv4()?.into());
+
+        header.skip(header_length.checked_sub(20)?)?;
+
+        self.transport(header)
+    }
+
+    pub fn ipv6(&mut self, mut header: Reader) -> Option<()> {
+        // The traffic class sits between the version and the flow label.
+        self.tos = (header.u16()? >> 4) as u8;
+
+        header.skip(4)?;
+
+        self.protocol = header.u8()?;
+
+        header.skip(1)?;
+
+        self.src_addr = Some(header.ipv6()?.into());
+        self.dst_addr = Some(header.ipv6()?.into());
+
+        self.transport(header)
+    }
+
+    fn transport(&mut self, mut header: Reader) -> Option<()> {
+        // TCP, UDP and SCTP all start with the ports.
+        if matches!(self.protocol, 6 | 17 | 132) {
+            self.src_port = header.u16()?;
+            self.dst_port = header.u16()?;
+        }
+
+        // ICMP and ICMPv6 both start with the type and code.
+        if matches!(self.protocol, 1 | 58) {
+            self.icmp_type_code = Some(header.u16()?);
+        }
+
+        if self.protocol == 6 {
+            // Sequence and acknowledgment numbers, then the data offset
+            // shares 16 bits with the flags.
+            header.skip(8)?;
+            self.tcp_flags = header.u16()? & 0x0fff;
+        }
+
+        Some(())
+    }
+
+    fn into_flow(self, sampling_rate: u32) -> Option<Flow> {
+        Some(Flow {
+            observation_domain: None,
+            src_mac: self.src_mac,
+            src_addr: self.src_addr?,
+            src_port: self.src_port,
+            dst_addr: self.dst_addr?,
+            dst_port: self.dst_port,
+            protocol: self.protocol,
+            icmp_type_code: self.icmp_type_code,
+            tcp_flags: self.tcp_flags,
+            tos: self.tos,
+            end_reason: None,
+            vlan: self.vlan,
+            in_interface: self.in_interface,
+            out_interface: self.out_interface,
+            nat_src_addr: None,
+            nat_src_port: None,
+            packets: sampling_rate.into(),
+            bytes: u64::from(self.length) * u64::from(sampling_rate),
+            direction: None,
+            mpls_labels: vec![],
+            tunnel_id: None,
+            src_as: self.src_as,
+            dst_as: self.dst_as,
+            enterprise_fields: vec![],
+        })
+    }
+}
+
+/// Only format zero is a single ifIndex, where zero means unknown. The other
+/// formats are for dropped packets and ones sent out of multiple interfaces.
+fn interface(format: u32, value: u32) -> Option<u32> {
+    (format == 0 && value != 0).then_some(value)
+}
+
+fn format_mac(octets: &[u8]) -> String {
+    octets[..6]
+        .iter()
+        .map(|octet| format!("{octet:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Reads big endian XDR encoded values, which is what IPFIX uses as well.
+pub struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+
+        let (taken, rest) = self.0.split_at(n);
+
+        self.0 = rest;
+
+        Some(taken)
+    }
+
+    pub fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn ipv4(&mut self) -> Option<Ipv4Addr> {
+        self.take(4)
+            .map(|bytes| <[u8; 4]>::try_from(bytes).unwrap().into())
+    }
+
+    pub fn ipv6(&mut self) -> Option<Ipv6Addr> {
+        self.take(16)
+            .map(|bytes| <[u8; 16]>::try_from(bytes).unwrap().into())
+    }
+
+    /// Reads variable length opaque data, which is padded to 4 bytes.
+    pub fn opaque(&mut self) -> Option<&'a [u8]> {
+        let length = self.u32()? as usize;
+        let data = self.take(length)?;
+
+        self.skip((4 - length % 4) % 4)?;
+
+        Some(data)
+    }
+}
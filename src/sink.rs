@@ -0,0 +1,183 @@
+use async_trait::async_trait;
+use prometheus_client::metrics::counter::Counter;
+use tracing::warn;
+
+use crate::{config::Config, filter::Filter, metrics::Metrics, row::FlowRecord};
+
+mod clickhouse;
+mod file;
+pub mod http;
+mod influxdb;
+mod kafka;
+mod otlp;
+mod parquet;
+mod redis;
+mod spool;
+mod sqlite;
+mod syslog;
+
+pub use self::{
+    clickhouse::ClickhouseSink, file::FileSink, influxdb::InfluxdbSink, kafka::KafkaSink,
+    otlp::OtlpSink, parquet::ParquetSink, redis::RedisSink, sqlite::SqliteSink, syslog::SyslogSink,
+};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Destination for flow records. Sinks batch records as they see fit,
+/// the receive loop only hands records over and flushes on shutdown.
+#[async_trait]
+pub trait FlowSink: Send {
+    /// Applies the settings of a reloaded configuration that don't need a restart.
+    fn reconfigure(&mut self, _config: &Config) {}
+
+    /// Queues a record, sending out a batch once the limits are reached.
+    async fn write(&mut self, record: &FlowRecord) -> Result<(), Error>;
+
+    /// Sends out the pending records and waits until they are stored,
+    /// failing if any record written so far didn't make it.
+    async fn checkpoint(&mut self) -> Result<(), Error>;
+
+    /// Sends out all the pending records.
+    async fn flush(&mut self);
+}
+
+/// Instantiates the sinks enabled in the configuration.
+pub fn from_config(config: &Config, metrics: &Metrics) -> Result<Vec<Box<dyn FlowSink>>, Error> {
+    let mut sinks = Vec::<(&'static str, Box<dyn FlowSink>)>::new();
+
+    if config.clickhouse.enabled {
+        sinks.push((
+            "clickhouse",
+            Box::new(ClickhouseSink::new(&config.clickhouse, metrics)?),
+        ));
+    }
+
+    if config.kafka.enabled {
+        sinks.push(("kafka", Box::new(KafkaSink::new(&config.kafka))));
+    }
+
+    if config.sqlite.enabled {
+        sinks.push((
+            "sqlite",
+            Box::new(SqliteSink::new(&config.sqlite, metrics)?),
+        ));
+    }
+
+    if config.parquet.enabled {
+        sinks.push((
+            "parquet",
+            Box::new(ParquetSink::new(&config.parquet, metrics)?),
+        ));
+    }
+
+    if config.file.enabled {
+        sinks.push(("file", Box::new(FileSink::new(&config.file, metrics)?)));
+    }
+
+    if config.influxdb.enabled {
+        sinks.push(("influxdb", Box::new(InfluxdbSink::new(&config.influxdb))));
+    }
+
+    if config.otlp.enabled {
+        sinks.push(("otlp", Box::new(OtlpSink::new(&config.otlp))));
+    }
+
+    if config.syslog.enabled {
+        sinks.push((
+            "syslog",
+            Box::new(SyslogSink::new(&config.syslog, metrics)?),
+        ));
+    }
+
+    if config.redis.enabled {
+        sinks.push(("redis", Box::new(RedisSink::new(&config.redis)?)));
+    }
+
+    Ok(sinks
+        .into_iter()
+        .map(|(section, sink)| {
+            Box::new(Filtered::new(section, sink, config, metrics)) as Box<dyn FlowSink>
+        })
+        .collect())
+}
+
+/// Hands over only the records matching the filter of the section. Records
+/// the sink fails to write are logged and counted rather than passed on,
+/// so that one sink in trouble doesn't stop the others or the collector.
+struct Filtered {
+    section: &'static str,
+    sink: Box<dyn FlowSink>,
+    filter: Filter,
+    metrics: Metrics,
+}
+
+impl Filtered {
+    fn new(
+        section: &'static str,
+        sink: Box<dyn FlowSink>,
+        config: &Config,
+        metrics: &Metrics,
+    ) -> Self {
+        Self {
+            section,
+            sink,
+            filter: filter(section, config),
+            metrics: metrics.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl FlowSink for Filtered {
+    fn reconfigure(&mut self, config: &Config) {
+        self.filter = filter(self.section, config);
+        self.sink.reconfigure(config);
+    }
+
+    async fn write(&mut self, record: &FlowRecord) -> Result<(), Error> {
+        if !self.filter.matches(record) {
+            return Ok(());
+        }
+
+        if let Err(e) = self.sink.write(record).await {
+            warn!(target: "sink", sink = self.section, "Cannot write a record: {e}");
+
+            errors(&self.metrics, self.section).inc();
+        }
+
+        Ok(())
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Error> {
+        self.sink.checkpoint().await
+    }
+
+    async fn flush(&mut self) {
+        self.sink.flush().await;
+    }
+}
+
+fn filter(section: &str, config: &Config) -> Filter {
+    let (_, filter) = config
+        .filters()
+        .into_iter()
+        .find(|(name, _)| *name == section)
+        .expect("every sink has a filter");
+
+    Filter::parse(filter).expect("filters are validated when loading")
+}
+
+/// Counter of the records the sink of the section failed to write.
+fn errors(metrics: &Metrics, section: &str) -> Counter {
+    let labels = vec![("sink".to_owned(), section.to_owned())];
+    metrics.sink_errors.get_or_create(&labels).clone()
+}
+
+/// Formats the MAC address the way it's written in the configuration.
+fn format_mac(mac: u64) -> String {
+    mac.to_be_bytes()[2..]
+        .iter()
+        .map(|octet| format!("{octet:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
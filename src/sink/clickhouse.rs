@@ -0,0 +1,455 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use clickhouse::{
+    inserter::{Inserter, Quantities},
+    Client,
+};
+use rand::Rng;
+use tokio::{
+    select,
+    sync::{mpsc, oneshot, watch},
+    task::{block_in_place, spawn, JoinHandle},
+    time::sleep,
+};
+use tracing::{error, info, warn};
+
+use super::{spool::Spool, Error, FlowSink};
+use crate::{
+    config::{ClickhouseConfig, Config},
+    metrics::Metrics,
+    row::FlowRecord,
+};
+
+/// Records queued for the writer before the receive loop has to wait.
+const QUEUE_CAPACITY: usize = 64 * 1024;
+
+/// Hands records over to a task inserting them into ClickHouse, so that
+/// the receive loop doesn't wait for inserts.
+pub struct ClickhouseSink {
+    messages: Option<mpsc::Sender<Message>>,
+    config: watch::Sender<ClickhouseConfig>,
+    writer: Option<JoinHandle<()>>,
+}
+
+// Records are the bulk of messages, boxing them would cost an allocation each.
+#[allow(clippy::large_enum_variant)]
+enum Message {
+    Record(FlowRecord),
+    /// Asks to insert the batch, replying whether everything written
+    /// so far is inserted or spooled.
+    Checkpoint(oneshot::Sender<bool>),
+}
+
+/// Inserts records into the ClickHouse table in batches. Rows are kept
+/// until their batch is inserted, so that a failed batch can be inserted
+/// again. Meanwhile new rows join the failed batch, until it's either
+/// inserted or, after the last retry or once it has `max_pending` rows,
+/// spooled to disk or dropped. Spooled rows are inserted after the next
+/// successful batch.
+struct Writer {
+    config: ClickhouseConfig,
+    client: Client,
+    inserter: Inserter<FlowRecord>,
+    metrics: Metrics,
+    /// Rows written since the last successful insert.
+    batch: Vec<FlowRecord>,
+    /// Failed attempts to insert the batch.
+    failures: u32,
+    /// When to try again, if the last attempt failed.
+    retry_at: Option<Instant>,
+    spool: Option<Spool>,
+    /// Whether rows were dropped since the last checkpoint.
+    dropped: bool,
+}
+
+impl ClickhouseSink {
+    pub fn new(config: &ClickhouseConfig, metrics: &Metrics) -> Result<Self, Error> {
+        let writer = Writer::new(config, metrics)?;
+
+        let (messages, messages_receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let (config, config_receiver) = watch::channel(config.clone());
+
+        Ok(Self {
+            messages: Some(messages),
+            config,
+            writer: Some(spawn(writer.run(messages_receiver, config_receiver))),
+        })
+    }
+
+    async fn send(&self, message: Message) -> Result<(), Error> {
+        let messages = self
+            .messages
+            .as_ref()
+            .expect("records are not written after a flush");
+
+        messages
+            .send(message)
+            .await
+            .map_err(|_| "ClickHouse writer is gone".into())
+    }
+}
+
+impl Writer {
+    fn new(config: &ClickhouseConfig, metrics: &Metrics) -> Result<Self, Error> {
+        let client = config.counting_client(metrics.sent_bytes.clone())?;
+
+        let spool = match &config.spool_directory {
+            Some(directory) => {
+                info!(
+                    target: "clickhouse",
+                    "Spooling rows to {} when out of retries",
+                    directory.display()
+                );
+
+                Some(Spool::open(directory, config.spool_max_bytes)?)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            config: config.clone(),
+            inserter: inserter(&client, config)?,
+            client,
+            metrics: metrics.clone(),
+            batch: vec![],
+            failures: 0,
+            retry_at: None,
+            spool,
+            dropped: false,
+        })
+    }
+
+    /// Inserts records until the sink is flushed, committing batches once
+    /// they reach `max_rows` or `max_bytes` or get older than `period`.
+    async fn run(
+        mut self,
+        mut messages: mpsc::Receiver<Message>,
+        mut config: watch::Receiver<ClickhouseConfig>,
+    ) {
+        loop {
+            let due = match self.retry_at {
+                Some(retry_at) => retry_at.saturating_duration_since(Instant::now()),
+                None => self.inserter.time_left().unwrap_or(self.config.period),
+            };
+
+            select! {
+                message = messages.recv() => match message {
+                    Some(Message::Record(record)) => self.write(record).await,
+                    Some(Message::Checkpoint(reply)) => {
+                        let stored = self.checkpoint().await;
+                        reply.send(stored).ok();
+                    }
+                    None => break,
+                },
+                Ok(()) = config.changed() => {
+                    let config = config.borrow_and_update().clone();
+                    self.reconfigure(&config);
+                }
+                _ = sleep(due) => self.commit().await,
+            }
+        }
+
+        self.flush().await;
+    }
+
+    fn reconfigure(&mut self, config: &ClickhouseConfig) {
+        self.config.max_bytes = config.max_bytes;
+        self.config.max_rows = config.max_rows;
+        self.config.period = config.period;
+        self.config.send_timeout = config.send_timeout;
+        self.config.end_timeout = config.end_timeout;
+        self.config.max_retries = config.max_retries;
+        self.config.retry_backoff = config.retry_backoff;
+        self.config.max_retry_backoff = config.max_retry_backoff;
+        self.config.max_pending = config.max_pending;
+        self.config.spool_max_bytes = config.spool_max_bytes;
+
+        if let Some(spool) = &mut self.spool {
+            spool.set_max_bytes(self.config.spool_max_bytes);
+        }
+
+        apply(&mut self.inserter, &self.config);
+    }
+
+    async fn write(&mut self, record: FlowRecord) {
+        // While retrying, rows only join the batch until the next attempt.
+        if self.retry_at.is_none() {
+            if let Err(e) = self.inserter.write(&record) {
+                self.batch.push(record);
+                self.fail(e);
+                return;
+            }
+        }
+
+        self.batch.push(record);
+
+        // Rows keep coming while ClickHouse is down, they can't all wait in memory.
+        if self.retry_at.is_some() && self.batch.len() as u64 >= self.config.max_pending {
+            let max_pending = self.config.max_pending;
+            self.spill(format_args!("{max_pending} rows waiting for a retry"));
+
+            return;
+        }
+
+        let pending = self.inserter.pending();
+
+        if pending.rows >= self.config.max_rows || pending.bytes >= self.config.max_bytes {
+            self.commit().await;
+        }
+    }
+
+    /// Commits the batch if it's due, or retries it.
+    async fn commit(&mut self) {
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() >= retry_at {
+                self.retry().await;
+            }
+
+            return;
+        }
+
+        match self.inserter.commit().await {
+            Ok(quantities) if quantities.rows > 0 => {
+                self.metrics.insert_bytes.inc_by(quantities.bytes);
+                self.batch.clear();
+                self.replay().await;
+            }
+            Ok(_) => {}
+            Err(e) => self.fail(e),
+        }
+    }
+
+    /// Inserts the batch unless it's waiting for a retry, returning
+    /// whether no rows written so far are pending or dropped.
+    async fn checkpoint(&mut self) -> bool {
+        if self.retry_at.is_none() {
+            match self.inserter.force_commit().await {
+                Ok(quantities) => {
+                    self.metrics.insert_bytes.inc_by(quantities.bytes);
+                    self.batch.clear();
+                }
+                Err(e) => self.fail(e),
+            }
+        }
+
+        let stored = self.batch.is_empty() && !self.dropped;
+
+        self.dropped = false;
+
+        stored
+    }
+
+    async fn flush(&mut self) {
+        let result = match self.retry_at {
+            Some(_) => self.insert(&self.batch).await,
+            None => self.inserter.force_commit().await.inspect(|quantities| {
+                self.metrics.insert_bytes.inc_by(quantities.bytes);
+            }),
+        };
+
+        match result {
+            Ok(quantities) => {
+                info!(target: "clickhouse", "Flushed {} pending rows", quantities.rows)
+            }
+            Err(e) => self.give_up(format_args!("cannot flush: {e}")),
+        }
+    }
+
+    /// Inserts rows in one go, bypassing the batching of the inserter.
+    async fn insert(&self, rows: &[FlowRecord]) -> Result<Quantities, clickhouse::error::Error> {
+        let mut inserter = inserter(&self.client, &self.config)?;
+
+        for row in rows {
+            inserter.write(row)?;
+        }
+
+        let quantities = inserter.end().await?;
+
+        self.metrics.insert_bytes.inc_by(quantities.bytes);
+
+        Ok(quantities)
+    }
+
+    async fn retry(&mut self) {
+        match self.insert(&self.batch).await {
+            Ok(_) => {
+                // The batch may have been spilled while waiting.
+                if !self.batch.is_empty() {
+                    info!(
+                        target: "clickhouse",
+                        "Inserted {} rows after {} retries",
+                        self.batch.len(),
+                        self.failures
+                    );
+                }
+
+                self.batch.clear();
+                self.failures = 0;
+                self.retry_at = None;
+
+                self.replay().await;
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+
+    /// Inserts the oldest segment of the spool, if there's any.
+    async fn replay(&mut self) {
+        let Some(spool) = &mut self.spool else {
+            return;
+        };
+
+        let (segment, rows) = match block_in_place(|| spool.oldest()) {
+            Ok(Some(oldest)) => oldest,
+            Ok(None) => return,
+            Err(e) => {
+                error!(target: "clickhouse", "Cannot read spooled rows: {e}");
+                return;
+            }
+        };
+
+        if let Err(e) = self.insert(&rows).await {
+            warn!(target: "clickhouse", "Cannot insert {} spooled rows: {e}", rows.len());
+            return;
+        }
+
+        self.metrics.rows_replayed.inc_by(rows.len() as u64);
+
+        info!(target: "clickhouse", "Inserted {} spooled rows", rows.len());
+
+        let spool = self.spool.as_mut().unwrap();
+
+        if let Err(e) = block_in_place(|| spool.remove(segment)) {
+            error!(target: "clickhouse", "Cannot remove spooled rows: {e}");
+        }
+    }
+
+    /// Keeps the batch in the spool if there's one, or drops it, and
+    /// stops retrying.
+    fn give_up(&mut self, e: impl std::fmt::Display) {
+        self.spill(e);
+        self.failures = 0;
+        self.retry_at = None;
+    }
+
+    /// Keeps the batch in the spool if there's one, or drops it.
+    fn spill(&mut self, e: impl std::fmt::Display) {
+        let rows = self.batch.len();
+
+        let spooled = match &mut self.spool {
+            Some(spool) => block_in_place(|| spool.append(&self.batch)).map_err(|spool_error| {
+                error!(target: "clickhouse", "Cannot spool {rows} rows: {spool_error}");
+            }),
+            None => Err(()),
+        };
+
+        match spooled {
+            Ok(()) => {
+                self.metrics.rows_spooled.inc_by(rows as u64);
+
+                warn!(target: "clickhouse", "Spooled {rows} rows: {e}");
+            }
+            Err(()) => {
+                self.metrics.insert_failures.inc();
+                self.dropped = true;
+
+                error!(target: "clickhouse", "Dropping {rows} rows: {e}");
+            }
+        }
+
+        self.batch.clear();
+    }
+
+    /// Schedules the next attempt with exponential backoff and jitter,
+    /// or drops the batch once out of retries.
+    fn fail(&mut self, e: clickhouse::error::Error) {
+        // Whatever the inserter had is in the batch.
+        match inserter(&self.client, &self.config) {
+            Ok(inserter) => self.inserter = inserter,
+            Err(e) => error!(target: "clickhouse", "Cannot start a new insert: {e}"),
+        }
+
+        self.failures += 1;
+
+        if self.failures > self.config.max_retries {
+            let retries = self.config.max_retries;
+            self.give_up(format_args!("out of {retries} retries: {e}"));
+
+            return;
+        }
+
+        self.metrics.insert_retries.inc();
+
+        let backoff = self
+            .config
+            .retry_backoff
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(self.config.max_retry_backoff);
+
+        // Up to a half of the backoff is random, so that collectors
+        // don't all come back at the same time.
+        let backoff = backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+
+        warn!(
+            target: "clickhouse",
+            "Cannot insert {} rows, retry {} of {} in {}: {e}",
+            self.batch.len(),
+            self.failures,
+            self.config.max_retries,
+            humantime::format_duration(Duration::from_millis(backoff.as_millis() as u64))
+        );
+
+        self.retry_at = Some(Instant::now() + backoff);
+    }
+}
+
+#[async_trait]
+impl FlowSink for ClickhouseSink {
+    fn reconfigure(&mut self, config: &Config) {
+        self.config.send_replace(config.clickhouse.clone());
+    }
+
+    async fn write(&mut self, record: &FlowRecord) -> Result<(), Error> {
+        self.send(Message::Record(record.clone())).await
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Error> {
+        let (reply, stored) = oneshot::channel();
+
+        self.send(Message::Checkpoint(reply)).await?;
+
+        match stored.await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("rows are waiting for a retry or were dropped".into()),
+            Err(_) => Err("ClickHouse writer is gone".into()),
+        }
+    }
+
+    async fn flush(&mut self) {
+        // The writer flushes once it's done with the queued messages.
+        self.messages = None;
+
+        if let Some(writer) = self.writer.take() {
+            writer.await.unwrap();
+        }
+    }
+}
+
+fn inserter(
+    client: &Client,
+    config: &ClickhouseConfig,
+) -> Result<Inserter<FlowRecord>, clickhouse::error::Error> {
+    let mut inserter = client.inserter(&config.insert_target())?;
+
+    apply(&mut inserter, config);
+
+    Ok(inserter)
+}
+
+fn apply(inserter: &mut Inserter<FlowRecord>, config: &ClickhouseConfig) {
+    inserter.set_timeouts(Some(config.send_timeout), Some(config.end_timeout));
+    inserter.set_max_bytes(config.max_bytes);
+    inserter.set_max_rows(config.max_rows);
+    inserter.set_period(Some(config.period));
+}
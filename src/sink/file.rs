@@ -0,0 +1,283 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use flate2::{write::GzEncoder, Compression};
+use prometheus_client::metrics::counter::Counter;
+use tokio::task::block_in_place;
+use tracing::{error, info, warn};
+
+use super::{errors, Error, FlowSink};
+use crate::{
+    config::{Config, FileConfig, FileFormat},
+    metrics::Metrics,
+    row::{FlowRecord, SCHEMA},
+};
+
+/// Appends records to JSON Lines or CSV files, starting a new file once
+/// the current one gets too old or too large, or can't be written to.
+pub struct FileSink {
+    config: FileConfig,
+    file: Option<OpenFile>,
+    last_flush: Instant,
+    /// Lines appended since the last flush, which an error loses.
+    unflushed: u64,
+    errors: Counter,
+}
+
+struct OpenFile {
+    output: Output,
+    path: PathBuf,
+    /// Uncompressed bytes written so far.
+    size: u64,
+    /// Seconds since the epoch when the file is due to be closed, if ever.
+    ends: Option<u64>,
+}
+
+enum Output {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Output {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.write_all(data),
+            Self::Gzip(writer) => writer.write_all(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            Self::Gzip(writer) => writer.flush(),
+        }
+    }
+
+    /// Writes out everything, including the gzip trailer.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Plain(mut writer) => writer.flush(),
+            Self::Gzip(writer) => writer.finish()?.flush(),
+        }
+    }
+}
+
+impl FileSink {
+    pub fn new(config: &FileConfig, metrics: &Metrics) -> Result<Self, Error> {
+        fs::create_dir_all(&config.directory)?;
+
+        info!(target: "file", "Writing flows to {}", config.directory.display());
+
+        Ok(Self {
+            config: config.clone(),
+            file: None,
+            last_flush: Instant::now(),
+            unflushed: 0,
+            errors: errors(metrics, "file"),
+        })
+    }
+
+    fn open(&self) -> io::Result<OpenFile> {
+        let now = SystemTime::now();
+        let seconds = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let rotation = self.config.rotation.as_secs();
+
+        let name = humantime::format_rfc3339_seconds(now)
+            .to_string()
+            .replace(':', "");
+
+        let extension = match (self.config.format, self.config.gzip) {
+            (FileFormat::Json, false) => "jsonl",
+            (FileFormat::Json, true) => "jsonl.gz",
+            (FileFormat::Csv, false) => "csv",
+            (FileFormat::Csv, true) => "csv.gz",
+        };
+
+        // Files filling up within a second get a counter to tell them apart.
+        let path = (0..)
+            .map(|n| match n {
+                0 => format!("flows-{name}.{extension}"),
+                n => format!("flows-{name}-{n}.{extension}"),
+            })
+            .map(|file_name| self.config.directory.join(file_name))
+            .find(|path| !path.exists())
+            .unwrap();
+
+        let writer = BufWriter::new(
+            OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .open(&path)?,
+        );
+
+        let mut file = OpenFile {
+            output: if self.config.gzip {
+                Output::Gzip(GzEncoder::new(writer, Compression::default()))
+            } else {
+                Output::Plain(writer)
+            },
+            path,
+            size: 0,
+            ends: (rotation > 0).then(|| seconds - seconds % rotation + rotation),
+        };
+
+        if self.config.format == FileFormat::Csv {
+            let header = SCHEMA
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(",");
+
+            file.append(format!("{header}\n").as_bytes())?;
+        }
+
+        Ok(file)
+    }
+
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let full = self.file.as_ref().is_some_and(|file| {
+            file.ends.is_some_and(|ends| seconds >= ends)
+                || (self.config.max_size > 0 && file.size >= self.config.max_size)
+        });
+
+        if full {
+            self.close()?;
+        }
+
+        self.unflushed += 1;
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(self.open()?),
+        };
+
+        file.append(line)?;
+
+        if self.last_flush.elapsed() >= self.config.period {
+            self.last_flush = Instant::now();
+
+            file.output.flush()?;
+            self.unflushed = 0;
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<Option<PathBuf>> {
+        let Some(file) = self.file.take() else {
+            return Ok(None);
+        };
+
+        file.output.finish()?;
+        self.unflushed = 0;
+
+        Ok(Some(file.path))
+    }
+
+    /// Gives up on the open file after an error, like with the disk full,
+    /// counting the lines not flushed yet as lost, so that the next record
+    /// starts a new one.
+    fn abandon(&mut self, e: io::Error) {
+        let path = match &self.file {
+            Some(file) => file.path.clone(),
+            None => self.config.directory.clone(),
+        };
+
+        warn!(
+            target: "file",
+            "Cannot write {} lines to {}, starting a new file: {e}",
+            self.unflushed,
+            path.display()
+        );
+
+        self.errors.inc_by(self.unflushed);
+        self.unflushed = 0;
+        self.file = None;
+    }
+}
+
+impl OpenFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.output.write_all(data)?;
+        self.size += data.len() as u64;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FlowSink for FileSink {
+    fn reconfigure(&mut self, config: &Config) {
+        self.config.rotation = config.file.rotation;
+        self.config.max_size = config.file.max_size;
+        self.config.period = config.file.period;
+    }
+
+    async fn write(&mut self, record: &FlowRecord) -> Result<(), Error> {
+        let mut line = match self.config.format {
+            FileFormat::Json => serde_json::to_string(record)?,
+            FileFormat::Csv => csv_line(record)?,
+        };
+
+        line.push('\n');
+
+        if let Err(e) = block_in_place(|| self.append(line.as_bytes())) {
+            self.abandon(e);
+        }
+
+        Ok(())
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Error> {
+        if let Some(file) = &mut self.file {
+            block_in_place(|| file.output.flush())?;
+            self.unflushed = 0;
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) {
+        match block_in_place(|| self.close()) {
+            Ok(Some(path)) => info!(target: "file", "Closed {}", path.display()),
+            Ok(None) => {}
+            Err(e) => error!(target: "file", "Cannot close the file: {e}"),
+        }
+    }
+}
+
+/// Formats the columns as CSV, with arrays and maps as JSON.
+fn csv_line(record: &FlowRecord) -> Result<String, Error> {
+    let serde_json::Value::Object(columns) = serde_json::to_value(record)? else {
+        unreachable!("records are serialized as objects");
+    };
+
+    Ok(columns
+        .into_iter()
+        .map(|(_, value)| match value {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(value) => csv_field(value),
+            value => csv_field(value.to_string()),
+        })
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+/// Quotes fields with separators, quotes or line breaks (RFC 4180).
+fn csv_field(value: String) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::Path;
+
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::{
+    body::Bytes,
+    header::{HeaderName, CONTENT_TYPE},
+    Request, Response,
+};
+use hyper_rustls::HttpsConnectorBuilder;
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+
+use super::Error;
+use crate::tls;
+
+/// Sends a POST request over HTTP or HTTPS, with certificates verified
+/// against the system roots. Returns the body of successful responses.
+pub async fn post(
+    url: &str,
+    content_type: &str,
+    headers: &[(HeaderName, &str)],
+    body: Vec<u8>,
+) -> Result<Bytes, Error> {
+    let connector = HttpsConnectorBuilder::new()
+        .with_native_roots()?
+        .https_or_http()
+        .enable_http1()
+        .build();
+
+    let mut request = Request::post(url).header(CONTENT_TYPE, content_type);
+
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+
+    let response = Client::builder(TokioExecutor::new())
+        .build(connector)
+        .request(request.body(Full::new(Bytes::from(body)))?)
+        .await?;
+
+    read(url, response).await
+}
+
+/// Sends a GET request over HTTP or HTTPS, with certificates verified
+/// against the given CA or the system roots. Returns the body of
+/// successful responses.
+pub async fn get(
+    url: &str,
+    headers: &[(HeaderName, &str)],
+    ca: Option<&Path>,
+) -> Result<Bytes, Error> {
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(tls::client_config(ca, None)?)
+        .https_or_http()
+        .enable_http1()
+        .build();
+
+    let mut request = Request::get(url);
+
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+
+    let response = Client::builder(TokioExecutor::new())
+        .build(connector)
+        .request(request.body(Empty::<Bytes>::new())?)
+        .await?;
+
+    read(url, response).await
+}
+
+async fn read(url: &str, response: Response<hyper::body::Incoming>) -> Result<Bytes, Error> {
+    let status = response.status();
+    let body = response.into_body().collect().await?.to_bytes();
+
+    if !status.is_success() {
+        return Err(format!(
+            "{url} responded with {status}: {}",
+            String::from_utf8_lossy(&body)
+        )
+        .into());
+    }
+
+    Ok(body)
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use hyper::header::AUTHORIZATION;
+use serde_json::Value;
+use tracing::{error, info, warn};
+
+use super::{format_mac, http, Error, FlowSink};
+use crate::{
+    config::{Config, InfluxdbConfig, InfluxdbMode},
+    row::FlowRecord,
+};
+
+/// Columns that become tags rather than fields, by the name of the tag.
+const TAGS: &[(&str, &str)] = &[
+    ("listener", "listener"),
+    ("deviceName", "device"),
+    ("clientMac", "mac"),
+    ("is_download", "direction"),
+];
+
+/// Longest wait between attempts to reach InfluxDB.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Writes records to InfluxDB v2 in the line protocol, either as a point
+/// per flow or as totals per device and direction for every period. Lines
+/// are kept while InfluxDB is unavailable, up to a limit, and sent once
+/// it's back.
+pub struct InfluxdbSink {
+    config: InfluxdbConfig,
+    lines: VecDeque<String>,
+    /// Totals keyed by the tag set of the series.
+    totals: BTreeMap<String, Totals>,
+    last_send: Instant,
+    backoff: Duration,
+    retry_at: Instant,
+    /// Lines dropped because too many were pending.
+    dropped: u64,
+}
+
+#[derive(Default)]
+struct Totals {
+    bytes: u64,
+    packets: u64,
+    flows: u64,
+}
+
+impl InfluxdbSink {
+    pub fn new(config: &InfluxdbConfig) -> Self {
+        Self {
+            config: config.clone(),
+            lines: VecDeque::new(),
+            totals: BTreeMap::new(),
+            last_send: Instant::now(),
+            backoff: Duration::ZERO,
+            retry_at: Instant::now(),
+            dropped: 0,
+        }
+    }
+
+    /// Drops the oldest lines beyond `max_pending`.
+    fn trim(&mut self) {
+        while self.lines.len() as u64 > self.config.max_pending {
+            self.lines.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    async fn send(&mut self) -> Result<usize, Error> {
+        self.last_send = Instant::now();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        for (tags, totals) in std::mem::take(&mut self.totals) {
+            self.lines.push_back(format!(
+                "{}{tags} bytes={}u,packets={}u,flows={}u {timestamp}",
+                escape(&self.config.measurement, &[',', ' ']),
+                totals.bytes,
+                totals.packets,
+                totals.flows
+            ));
+        }
+
+        self.trim();
+
+        if self.lines.is_empty() {
+            return Ok(0);
+        }
+
+        let url = format!(
+            "{}/api/v2/write?org={}&bucket={}&precision=s",
+            self.config.url.trim_end_matches('/'),
+            encode(&self.config.org),
+            encode(&self.config.bucket)
+        );
+
+        let token = format!("Token {}", self.config.token);
+        let headers = [(AUTHORIZATION, token.as_str())];
+        let body = Vec::from(self.lines.make_contiguous().join("\n"));
+        let request = http::post(&url, "text/plain; charset=utf-8", &headers, body);
+
+        match tokio::time::timeout(self.config.timeout, request).await {
+            Ok(result) => result?,
+            Err(_) => return Err("timed out sending points to InfluxDB".into()),
+        };
+
+        if self.dropped > 0 {
+            warn!(target: "influxdb", "Dropped {} points while InfluxDB was unavailable", self.dropped);
+            self.dropped = 0;
+        }
+
+        Ok(self.lines.drain(..).count())
+    }
+}
+
+#[async_trait]
+impl FlowSink for InfluxdbSink {
+    fn reconfigure(&mut self, config: &Config) {
+        self.config.max_rows = config.influxdb.max_rows;
+        self.config.period = config.influxdb.period;
+        self.config.max_pending = config.influxdb.max_pending;
+        self.config.timeout = config.influxdb.timeout;
+    }
+
+    async fn write(&mut self, record: &FlowRecord) -> Result<(), Error> {
+        let Value::Object(mut columns) = serde_json::to_value(record)? else {
+            unreachable!("records are serialized as objects");
+        };
+
+        let mut tags = TAGS
+            .iter()
+            .filter_map(|(column, tag)| {
+                let value = match columns.shift_remove(*column)? {
+                    Value::Bool(true) => "download".to_owned(),
+                    Value::Bool(false) => "upload".to_owned(),
+                    Value::Number(mac) => format_mac(mac.as_u64()?),
+                    Value::String(value) if !value.is_empty() => value,
+                    _ => return None,
+                };
+
+                Some((*tag, value))
+            })
+            .collect::<Vec<_>>();
+
+        // Tags sorted by key are what InfluxDB prefers.
+        tags.sort();
+
+        let tags = tags
+            .iter()
+            .map(|(tag, value)| format!(",{tag}={}", escape(value, &[',', '=', ' '])))
+            .collect::<String>();
+
+        match self.config.mode {
+            InfluxdbMode::Flows => {
+                let timestamp = columns.shift_remove("insertionTime");
+
+                let fields = columns
+                    .into_iter()
+                    .filter_map(|(name, value)| Some(format!("{name}={}", field(value)?)))
+                    .collect::<Vec<_>>()
+                    .join(",");
+
+                self.lines.push_back(format!(
+                    "{}{tags} {fields} {}",
+                    escape(&self.config.measurement, &[',', ' ']),
+                    timestamp.unwrap_or_default()
+                ));
+            }
+            InfluxdbMode::Aggregated => {
+                let totals = self.totals.entry(tags).or_default();
+                let counter = |name| columns.get(name).and_then(Value::as_u64).unwrap_or(0);
+
+                totals.bytes += counter("bytes");
+                totals.packets += counter("packets");
+                totals.flows += 1;
+            }
+        }
+
+        self.trim();
+
+        let due = self.lines.len() as u64 >= self.config.max_rows
+            || self.last_send.elapsed() >= self.config.period;
+
+        if !due || Instant::now() < self.retry_at {
+            return Ok(());
+        }
+
+        // Unavailable InfluxDB is waited out rather than treated as fatal.
+        match self.send().await {
+            Ok(_) => self.backoff = Duration::ZERO,
+            Err(e) => {
+                self.backoff = (self.backoff * 2).clamp(self.config.period, MAX_BACKOFF);
+                self.retry_at = Instant::now() + self.backoff;
+
+                warn!(
+                    target: "influxdb",
+                    "Cannot send {} pending points, retrying in {}: {e}",
+                    self.lines.len(),
+                    humantime::format_duration(self.backoff)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Error> {
+        let dropped = self.dropped;
+
+        self.send().await?;
+
+        if dropped > 0 {
+            return Err(format!("dropped {dropped} points while InfluxDB was unavailable").into());
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) {
+        match self.send().await {
+            Ok(lines) => info!(target: "influxdb", "Flushed {lines} pending points"),
+            Err(e) => error!(target: "influxdb", "Cannot flush pending points: {e}"),
+        }
+    }
+}
+
+/// Formats a field value, numbers are all unsigned integers. Empty lists
+/// and maps are left out along with nulls, the rest ends up as JSON.
+fn field(value: Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Number(number) => Some(format!("{number}u")),
+        Value::String(value) => Some(format!("\"{}\"", escape(&value, &['"', '\\']))),
+        Value::Array(items) if items.is_empty() => None,
+        Value::Object(items) if items.is_empty() => None,
+        value => field(Value::String(value.to_string())),
+    }
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Percent-encodes a query parameter.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use apache_avro::{types::Value, writer::datum::GenericDatumWriter, Schema};
+use async_trait::async_trait;
+use rskafka::{
+    chrono::{DateTime, Utc},
+    client::{
+        partition::{Compression, PartitionClient, UnknownTopicHandling},
+        ClientBuilder,
+    },
+    record::Record,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use super::{format_mac, http, Error, FlowSink};
+use crate::{
+    config::{Config, KafkaConfig, KafkaFormat},
+    row::{FlowRecord, SCHEMA},
+};
+
+/// Longest wait between attempts to reach Kafka.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Publishes records to a Kafka topic, keyed and partitioned by client MAC,
+/// so that all flows of a device end up in the same partition in order.
+/// Records are kept while Kafka is unavailable, up to a limit, and sent
+/// once it's back, so a record can be published twice if a batch fails
+/// halfway.
+pub struct KafkaSink {
+    config: KafkaConfig,
+    /// Avro schema of the records, if they are sent as Avro.
+    schema: Option<Schema>,
+    /// Connected on the first batch, so that startup doesn't wait for Kafka,
+    /// and dropped on errors, so that the next try reconnects.
+    producer: Option<Producer>,
+    /// Client MAC and the encoded record, without the header of Avro.
+    pending: VecDeque<(u64, Vec<u8>)>,
+    last_send: Instant,
+    backoff: Duration,
+    retry_at: Instant,
+    /// Records dropped because too many were pending.
+    dropped: u64,
+}
+
+struct Producer {
+    partitions: Vec<PartitionClient>,
+    /// Id the schema registry assigned to the Avro schema.
+    schema_id: Option<u32>,
+}
+
+impl KafkaSink {
+    pub fn new(config: &KafkaConfig) -> Self {
+        let schema = (config.format == KafkaFormat::Avro)
+            .then(|| Schema::parse(&avro_schema()).expect("Avro schema is valid"));
+
+        Self {
+            config: config.clone(),
+            schema,
+            producer: None,
+            pending: VecDeque::new(),
+            last_send: Instant::now(),
+            backoff: Duration::ZERO,
+            retry_at: Instant::now(),
+            dropped: 0,
+        }
+    }
+
+    async fn connect(&self) -> Result<Producer, Error> {
+        let client = ClientBuilder::new(self.config.brokers.clone())
+            .build()
+            .await?;
+
+        let topic = client
+            .list_topics()
+            .await?
+            .into_iter()
+            .find(|topic| topic.name == self.config.topic && !topic.partitions.is_empty())
+            .ok_or_else(|| format!("topic {} does not exist", self.config.topic))?;
+
+        let mut partitions = vec![];
+
+        for partition in topic.partitions {
+            partitions.push(
+                client
+                    .partition_client(
+                        self.config.topic.clone(),
+                        partition,
+                        UnknownTopicHandling::Error,
+                    )
+                    .await?,
+            );
+        }
+
+        let schema_id = match (&self.schema, &self.config.schema_registry) {
+            (Some(schema), Some(registry)) => {
+                Some(register(registry, &format!("{}-value", self.config.topic), schema).await?)
+            }
+            _ => None,
+        };
+
+        info!(
+            target: "kafka",
+            "Publishing to topic {} with {} partitions",
+            self.config.topic,
+            partitions.len()
+        );
+
+        Ok(Producer {
+            partitions,
+            schema_id,
+        })
+    }
+
+    /// Encodes the record as JSON or as an Avro datum.
+    fn encode(&self, record: &FlowRecord) -> Result<Vec<u8>, Error> {
+        let value = serde_json::to_value(record)?;
+
+        let Some(schema) = &self.schema else {
+            return Ok(serde_json::to_vec(&value)?);
+        };
+
+        let writer = GenericDatumWriter::builder(schema).build()?;
+        let mut datum = vec![];
+
+        writer.write_value(&mut datum, Value::try_from(value)?.resolve(schema)?)?;
+
+        Ok(datum)
+    }
+
+    /// Sends out the pending records, connecting first if needed. Records
+    /// are only removed once their partition took them.
+    async fn send(&mut self) -> Result<(), Error> {
+        self.last_send = Instant::now();
+
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        if self.producer.is_none() {
+            self.producer = Some(self.connect().await?);
+        }
+
+        let producer = self.producer.as_ref().unwrap();
+
+        // Magic byte and schema id in front of Avro datums.
+        let header = producer.schema_id.map(|id| {
+            let mut header = vec![0];
+            header.extend_from_slice(&id.to_be_bytes());
+            header
+        });
+
+        let mut batches = vec![(vec![], vec![]); producer.partitions.len()];
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let timestamp = DateTime::<Utc>::from_timestamp_millis(millis as i64).unwrap();
+
+        for (index, (mac, payload)) in self.pending.iter().enumerate() {
+            let payload = match &header {
+                Some(header) => [header.as_slice(), payload].concat(),
+                None => payload.clone(),
+            };
+
+            let key = format_mac(*mac);
+
+            let partition = mac % producer.partitions.len() as u64;
+            let (indices, records) = &mut batches[partition as usize];
+
+            indices.push(index);
+            records.push(Record {
+                key: Some(key.into_bytes()),
+                value: Some(payload),
+                headers: Default::default(),
+                timestamp,
+            });
+        }
+
+        let mut sent = vec![false; self.pending.len()];
+        let mut result = Ok(());
+
+        for (partition, (indices, records)) in producer.partitions.iter().zip(batches) {
+            if records.is_empty() {
+                continue;
+            }
+
+            if let Err(e) = partition.produce(records, Compression::NoCompression).await {
+                result = Err(e);
+                break;
+            }
+
+            for index in indices {
+                sent[index] = true;
+            }
+        }
+
+        let mut sent = sent.into_iter();
+        self.pending.retain(|_| !sent.next().unwrap());
+
+        if let Err(e) = result {
+            self.producer = None;
+            return Err(e.into());
+        }
+
+        if self.dropped > 0 {
+            warn!(target: "kafka", "Dropped {} flows while Kafka was unavailable", self.dropped);
+            self.dropped = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Sends out the pending records, returning how many were sent.
+    async fn send_with_timeout(&mut self) -> Result<usize, Error> {
+        let pending = self.pending.len();
+
+        match tokio::time::timeout(self.config.timeout, self.send()).await {
+            Ok(result) => result.map(|_| pending - self.pending.len()),
+            Err(_) => {
+                self.producer = None;
+                Err("timed out sending records to Kafka".into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl FlowSink for KafkaSink {
+    fn reconfigure(&mut self, config: &Config) {
+        self.config.max_rows = config.kafka.max_rows;
+        self.config.period = config.kafka.period;
+        self.config.max_pending = config.kafka.max_pending;
+        self.config.timeout = config.kafka.timeout;
+    }
+
+    async fn write(&mut self, record: &FlowRecord) -> Result<(), Error> {
+        self.pending
+            .push_back((record.client_mac(), self.encode(record)?));
+
+        while self.pending.len() as u64 > self.config.max_pending {
+            self.pending.pop_front();
+            self.dropped += 1;
+        }
+
+        let due = self.pending.len() as u64 >= self.config.max_rows
+            || self.last_send.elapsed() >= self.config.period;
+
+        if !due || Instant::now() < self.retry_at {
+            return Ok(());
+        }
+
+        // Unavailable Kafka is waited out rather than treated as fatal.
+        match self.send_with_timeout().await {
+            Ok(_) => self.backoff = Duration::ZERO,
+            Err(e) => {
+                self.backoff = (self.backoff * 2).clamp(self.config.period, MAX_BACKOFF);
+                self.retry_at = Instant::now() + self.backoff;
+
+                warn!(
+                    target: "kafka",
+                    "Cannot send {} pending records, retrying in {}: {e}",
+                    self.pending.len(),
+                    humantime::format_duration(self.backoff)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Error> {
+        let dropped = self.dropped;
+
+        self.send_with_timeout().await?;
+
+        if dropped > 0 {
+            return Err(format!("dropped {dropped} flows while Kafka was unavailable").into());
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) {
+        match self.send_with_timeout().await {
+            Ok(sent) => info!(target: "kafka", "Flushed {sent} pending records"),
+            Err(e) => error!(target: "kafka", "Cannot flush pending records: {e}"),
+        }
+    }
+}
+
+/// Avro schema with the columns of the ClickHouse table as fields.
+fn avro_schema() -> serde_json::Value {
+    let fields = SCHEMA
+        .iter()
+        .map(|(name, column_type)| {
+            let avro_type = match *column_type {
+                "LowCardinality(String)" | "IPv4" | "IPv6" => json!("string"),
+                "UInt8" | "UInt16" => json!("int"),
+                "DateTime64(0)" | "UInt32" | "UInt64" => json!("long"),
+                "Nullable(UInt16)" => json!(["null", "int"]),
+                "Array(UInt32)" => json!({"type": "array", "items": "long"}),
+                "Map(String, String)" => json!({"type": "map", "values": "string"}),
+                "Bool" => json!("boolean"),
+                _ => unreachable!("no Avro type for {column_type}"),
+            };
+
+            json!({"name": name, "type": avro_type})
+        })
+        .collect::<Vec<_>>();
+
+    json!({"type": "record", "name": "FlowRecord", "fields": fields})
+}
+
+/// Registers the schema under the subject, which is a no-op returning
+/// the same id if the schema is already there.
+async fn register(registry: &str, subject: &str, schema: &Schema) -> Result<u32, Error> {
+    #[derive(Deserialize)]
+    struct Registered {
+        id: u32,
+    }
+
+    let body = http::post(
+        &format!(
+            "{}/subjects/{subject}/versions",
+            registry.trim_end_matches('/')
+        ),
+        "application/vnd.schemaregistry.v1+json",
+        &[],
+        json!({"schema": schema.canonical_form()})
+            .to_string()
+            .into_bytes(),
+    )
+    .await?;
+
+    Ok(serde_json::from_slice::<Registered>(&body)?.id)
+}
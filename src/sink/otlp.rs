@@ -0,0 +1,391 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use hyper::header::HeaderName;
+use serde_json::{json, Map, Value};
+use tracing::{error, info, warn};
+
+use super::{format_mac, http, Error, FlowSink};
+use crate::{
+    config::{Config, OtlpConfig},
+    row::FlowRecord,
+};
+
+/// Longest wait between attempts to reach the collector.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Exports traffic counters to an OpenTelemetry collector over OTLP/HTTP
+/// with JSON encoding, and optionally every flow as a log record. Log
+/// records are kept while the collector is unavailable, up to a limit,
+/// while counters being cumulative only need the next export to succeed.
+pub struct OtlpSink {
+    config: OtlpConfig,
+    /// Totals since the start, which is when the counters were reset.
+    counters: BTreeMap<Series, Totals>,
+    started: SystemTime,
+    last_export: Instant,
+    logs: VecDeque<Value>,
+    last_send: Instant,
+    backoff: Duration,
+    retry_at: Instant,
+    /// Log records dropped because too many were pending.
+    dropped: u64,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Series {
+    listener: String,
+    device: String,
+    mac: String,
+    direction: &'static str,
+}
+
+#[derive(Default)]
+struct Totals {
+    bytes: u64,
+    packets: u64,
+    flows: u64,
+}
+
+impl OtlpSink {
+    pub fn new(config: &OtlpConfig) -> Self {
+        info!(
+            target: "otlp",
+            "Exporting counters to {} every {}",
+            config.endpoint,
+            humantime::format_duration(config.interval)
+        );
+
+        Self {
+            config: config.clone(),
+            counters: BTreeMap::new(),
+            started: SystemTime::now(),
+            last_export: Instant::now(),
+            logs: VecDeque::new(),
+            last_send: Instant::now(),
+            backoff: Duration::ZERO,
+            retry_at: Instant::now(),
+            dropped: 0,
+        }
+    }
+
+    /// Sends the current value of every counter as a cumulative sum.
+    async fn export_metrics(&mut self) -> Result<usize, Error> {
+        self.last_export = Instant::now();
+
+        if self.counters.is_empty() {
+            return Ok(0);
+        }
+
+        let start = nanos(self.started);
+        let now = nanos(SystemTime::now());
+
+        let sum = |name, description, unit, value: fn(&Totals) -> u64| {
+            let points = self
+                .counters
+                .iter()
+                .map(|(series, totals)| {
+                    json!({
+                        "attributes": [
+                            attribute("listener", json!({ "stringValue": series.listener })),
+                            attribute("device", json!({ "stringValue": series.device })),
+                            attribute("mac", json!({ "stringValue": series.mac })),
+                            attribute("direction", json!({ "stringValue": series.direction })),
+                        ],
+                        "startTimeUnixNano": start,
+                        "timeUnixNano": now,
+                        "asInt": value(totals).to_string(),
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            json!({
+                "name": name,
+                "description": description,
+                "unit": unit,
+                "sum": {
+                    // Cumulative.
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                    "dataPoints": points,
+                },
+            })
+        };
+
+        let metrics = vec![
+            sum(
+                "ipfix.bytes",
+                "Bytes transferred by a local device.",
+                "By",
+                |totals| totals.bytes,
+            ),
+            sum(
+                "ipfix.packets",
+                "Packets transferred by a local device.",
+                "{packet}",
+                |totals| totals.packets,
+            ),
+            sum(
+                "ipfix.flows",
+                "Flow records received for a local device.",
+                "{flow}",
+                |totals| totals.flows,
+            ),
+        ];
+
+        let body = json!({
+            "resourceMetrics": [{
+                "resource": self.resource(),
+                "scopeMetrics": [{ "scope": scope(), "metrics": metrics }],
+            }],
+        });
+
+        self.post("metrics", body).await?;
+
+        Ok(self.counters.len())
+    }
+
+    async fn send_logs(&mut self) -> Result<usize, Error> {
+        self.last_send = Instant::now();
+
+        if self.logs.is_empty() {
+            return Ok(0);
+        }
+
+        let body = json!({
+            "resourceLogs": [{
+                "resource": self.resource(),
+                "scopeLogs": [{ "scope": scope(), "logRecords": self.logs }],
+            }],
+        });
+
+        self.post("logs", body).await?;
+
+        if self.dropped > 0 {
+            warn!(target: "otlp", "Dropped {} log records while the collector was unavailable", self.dropped);
+            self.dropped = 0;
+        }
+
+        Ok(self.logs.drain(..).count())
+    }
+
+    async fn post(&self, signal: &str, body: Value) -> Result<(), Error> {
+        let url = format!("{}/v1/{signal}", self.config.endpoint.trim_end_matches('/'));
+
+        let headers = self
+            .config
+            .headers
+            .iter()
+            .map(|(name, value)| Ok((HeaderName::from_bytes(name.as_bytes())?, value.as_str())))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let request = http::post(
+            &url,
+            "application/json",
+            &headers,
+            serde_json::to_vec(&body)?,
+        );
+
+        match tokio::time::timeout(self.config.timeout, request).await {
+            Ok(result) => result?,
+            Err(_) => return Err(format!("timed out exporting {signal}").into()),
+        };
+
+        Ok(())
+    }
+
+    fn resource(&self) -> Value {
+        json!({
+            "attributes": [
+                attribute("service.name", json!({ "stringValue": self.config.service_name })),
+            ],
+        })
+    }
+}
+
+#[async_trait]
+impl FlowSink for OtlpSink {
+    fn reconfigure(&mut self, config: &Config) {
+        self.config.interval = config.otlp.interval;
+        self.config.max_rows = config.otlp.max_rows;
+        self.config.period = config.otlp.period;
+        self.config.max_pending = config.otlp.max_pending;
+        self.config.timeout = config.otlp.timeout;
+    }
+
+    async fn write(&mut self, record: &FlowRecord) -> Result<(), Error> {
+        let Value::Object(columns) = serde_json::to_value(record)? else {
+            unreachable!("records are serialized as objects");
+        };
+
+        let text = |name| columns[name].as_str().unwrap_or_default().to_owned();
+        let number = |name| columns[name].as_u64().unwrap_or_default();
+        let is_download = columns["is_download"].as_bool().unwrap_or_default();
+
+        let series = Series {
+            listener: text("listener"),
+            device: text("deviceName"),
+            mac: format_mac(number("clientMac")),
+            direction: if is_download { "download" } else { "upload" },
+        };
+
+        let totals = self.counters.entry(series).or_default();
+
+        totals.bytes += number("bytes");
+        totals.packets += number("packets");
+        totals.flows += 1;
+
+        if self.config.logs {
+            self.logs.push_back(log_record(columns));
+        }
+
+        while self.logs.len() as u64 > self.config.max_pending {
+            self.logs.pop_front();
+            self.dropped += 1;
+        }
+
+        let logs_due = self.logs.len() as u64 >= self.config.max_rows
+            || self.last_send.elapsed() >= self.config.period;
+        let metrics_due = self.last_export.elapsed() >= self.config.interval;
+
+        if !(logs_due || metrics_due) || Instant::now() < self.retry_at {
+            return Ok(());
+        }
+
+        let mut result = Ok(0);
+
+        if logs_due {
+            result = self.send_logs().await;
+        }
+
+        if metrics_due && result.is_ok() {
+            result = self.export_metrics().await;
+        }
+
+        // Unavailable collector is waited out rather than treated as fatal.
+        match result {
+            Ok(_) => self.backoff = Duration::ZERO,
+            Err(e) => {
+                self.backoff = (self.backoff * 2).clamp(self.config.period, MAX_BACKOFF);
+                self.retry_at = Instant::now() + self.backoff;
+
+                warn!(
+                    target: "otlp",
+                    "Cannot export to {}, retrying in {}: {e}",
+                    self.config.endpoint,
+                    humantime::format_duration(self.backoff)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Error> {
+        let dropped = self.dropped;
+
+        self.send_logs().await?;
+        self.export_metrics().await?;
+
+        if dropped > 0 {
+            return Err(format!(
+                "dropped {dropped} log records while the collector was unavailable"
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) {
+        match self.send_logs().await {
+            Ok(records) => info!(target: "otlp", "Flushed {records} pending log records"),
+            Err(e) => error!(target: "otlp", "Cannot flush pending log records: {e}"),
+        }
+
+        match self.export_metrics().await {
+            Ok(series) => info!(target: "otlp", "Exported {series} series of counters"),
+            Err(e) => error!(target: "otlp", "Cannot export counters: {e}"),
+        }
+    }
+}
+
+/// Log record of a flow with the columns as attributes, leaving out empty ones.
+fn log_record(columns: Map<String, Value>) -> Value {
+    let address = |v4: &str, v6: &str, port: &str| match columns[v6].as_str() {
+        Some("::") | None => format!(
+            "{}:{}",
+            columns[v4].as_str().unwrap_or_default(),
+            columns[port]
+        ),
+        Some(v6) => format!("[{v6}]:{}", columns[port]),
+    };
+
+    let client = address("clientIPv4", "clientIPv6", "clientPort");
+    let server = address("serverIPv4", "serverIPv6", "serverPort");
+    let arrow = if columns["is_download"] == true {
+        "<-"
+    } else {
+        "->"
+    };
+
+    let time = columns["insertionTime"].as_u64().unwrap_or_default() * 1_000_000_000;
+
+    let attributes = columns
+        .into_iter()
+        .filter_map(|(name, value)| Some(attribute(&name, any_value(value)?)))
+        .collect::<Vec<_>>();
+
+    json!({
+        "timeUnixNano": time.to_string(),
+        "observedTimeUnixNano": nanos(SystemTime::now()),
+        "severityNumber": 9,
+        "severityText": "INFO",
+        "body": { "stringValue": format!("{client} {arrow} {server}") },
+        "attributes": attributes,
+    })
+}
+
+fn any_value(value: Value) -> Option<Value> {
+    match value {
+        Value::Null => None,
+        Value::Bool(value) => Some(json!({ "boolValue": value })),
+        // 64-bit integers are strings in the JSON encoding of protobuf.
+        Value::Number(number) => Some(json!({ "intValue": number.to_string() })),
+        Value::String(value) => Some(json!({ "stringValue": value })),
+        Value::Array(items) if items.is_empty() => None,
+        Value::Array(items) => {
+            let values = items.into_iter().filter_map(any_value).collect::<Vec<_>>();
+            Some(json!({ "arrayValue": { "values": values } }))
+        }
+        Value::Object(items) if items.is_empty() => None,
+        Value::Object(items) => {
+            let values = items
+                .into_iter()
+                .filter_map(|(key, value)| Some(attribute(&key, any_value(value)?)))
+                .collect::<Vec<_>>();
+            Some(json!({ "kvlistValue": { "values": values } }))
+        }
+    }
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+fn scope() -> Value {
+    json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+    })
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos()
+        .to_string()
+}
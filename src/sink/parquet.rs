@@ -0,0 +1,226 @@
+use std::{
+    fs::{self, File},
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use arrow_json::reader::{Decoder, ReaderBuilder};
+use arrow_schema::{DataType, Field, Fields, Schema, SchemaRef, TimeUnit};
+use async_trait::async_trait;
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use prometheus_client::metrics::counter::Counter;
+use tokio::task::block_in_place;
+use tracing::{error, info, warn};
+
+use super::{errors, Error, FlowSink};
+use crate::{
+    config::{Config, ParquetConfig},
+    metrics::Metrics,
+    row::{FlowRecord, SCHEMA},
+};
+
+/// Writes records to Parquet files in a directory, a new one for every
+/// rotation period. Files are written under a temporary name and renamed
+/// once complete, so that readers globbing `*.parquet` never see partial ones.
+/// A file that fails to be written is given up on and a new one started.
+pub struct ParquetSink {
+    config: ParquetConfig,
+    schema: SchemaRef,
+    decoder: Decoder,
+    file: Option<OpenFile>,
+    /// Rows handed to the writer of the open file.
+    rows: u64,
+    errors: Counter,
+}
+
+struct OpenFile {
+    writer: ArrowWriter<File>,
+    path: PathBuf,
+    /// Seconds since the epoch when the file is due to be closed.
+    ends: u64,
+}
+
+impl ParquetSink {
+    pub fn new(config: &ParquetConfig, metrics: &Metrics) -> Result<Self, Error> {
+        fs::create_dir_all(&config.directory)?;
+
+        let schema = Arc::new(arrow_schema());
+
+        info!(
+            target: "parquet",
+            "Writing flows to {}, a file every {}",
+            config.directory.display(),
+            humantime::format_duration(config.rotation)
+        );
+
+        Ok(Self {
+            config: config.clone(),
+            decoder: ReaderBuilder::new(schema.clone()).build_decoder()?,
+            schema,
+            file: None,
+            rows: 0,
+            errors: errors(metrics, "parquet"),
+        })
+    }
+
+    /// Encodes the buffered rows into the open file.
+    fn write_rows(&mut self) -> Result<(), Error> {
+        if let (Some(file), Some(batch)) = (&mut self.file, self.decoder.flush()?) {
+            self.rows += batch.num_rows() as u64;
+            file.writer.write(&batch)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives up on the open file after an error, counting its rows as lost,
+    /// so that the next record starts a new one. The partial file is left
+    /// under its temporary name.
+    fn abandon(&mut self, e: Error) {
+        warn!(
+            target: "parquet",
+            "Cannot write {} rows to {}, starting a new file: {e}",
+            self.rows,
+            self.config.directory.display()
+        );
+
+        self.errors.inc_by(self.rows);
+        self.rows = 0;
+        self.file = None;
+    }
+
+    fn open(&mut self) -> Result<OpenFile, Error> {
+        let now = SystemTime::now();
+        let seconds = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let rotation = self.config.rotation.as_secs();
+
+        let name = humantime::format_rfc3339_seconds(now)
+            .to_string()
+            .replace(':', "");
+
+        let path = self.config.directory.join(format!("flows-{name}.parquet"));
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+
+        let writer = ArrowWriter::try_new(
+            File::create(path.with_extension("parquet.tmp"))?,
+            self.schema.clone(),
+            Some(properties),
+        )?;
+
+        self.rows = 0;
+
+        Ok(OpenFile {
+            writer,
+            path,
+            ends: seconds - seconds % rotation + rotation,
+        })
+    }
+
+    /// Writes out the buffered rows and the footer, then moves the file
+    /// into place.
+    fn close(&mut self) -> Result<usize, Error> {
+        self.write_rows()?;
+
+        let Some(file) = self.file.take() else {
+            return Ok(0);
+        };
+
+        let metadata = file.writer.close()?;
+
+        fs::rename(file.path.with_extension("parquet.tmp"), &file.path)?;
+
+        self.rows = 0;
+
+        Ok(metadata.file_metadata().num_rows() as usize)
+    }
+
+    fn due(&self) -> bool {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        self.file.as_ref().is_some_and(|file| seconds >= file.ends)
+    }
+}
+
+#[async_trait]
+impl FlowSink for ParquetSink {
+    fn reconfigure(&mut self, config: &Config) {
+        self.config.rotation = config.parquet.rotation;
+        self.config.max_rows = config.parquet.max_rows;
+    }
+
+    async fn write(&mut self, record: &FlowRecord) -> Result<(), Error> {
+        if self.due() {
+            if let Err(e) = block_in_place(|| self.close()) {
+                self.abandon(e);
+            }
+        }
+
+        if self.file.is_none() {
+            self.file = Some(block_in_place(|| self.open())?);
+        }
+
+        self.decoder.serialize(&[record])?;
+
+        if self.decoder.len() as u64 >= self.config.max_rows {
+            if let Err(e) = block_in_place(|| self.write_rows()) {
+                self.abandon(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Error> {
+        Err("Parquet files are only complete once closed".into())
+    }
+
+    async fn flush(&mut self) {
+        match block_in_place(|| self.close()) {
+            Ok(rows) => info!(target: "parquet", "Closed the file with {rows} rows"),
+            Err(e) => error!(target: "parquet", "Cannot close the file: {e}"),
+        }
+    }
+}
+
+/// Arrow schema with the columns of the ClickHouse table, which the JSON
+/// representation of the records is decoded into.
+fn arrow_schema() -> Schema {
+    let fields = SCHEMA
+        .iter()
+        .map(|(name, column_type)| {
+            let data_type = match *column_type {
+                "DateTime64(0)" => DataType::Timestamp(TimeUnit::Second, Some("+00:00".into())),
+                "LowCardinality(String)" | "IPv4" | "IPv6" => DataType::Utf8,
+                "UInt8" => DataType::UInt8,
+                "UInt16" | "Nullable(UInt16)" => DataType::UInt16,
+                "UInt32" => DataType::UInt32,
+                "UInt64" => DataType::UInt64,
+                "Array(UInt32)" => DataType::new_list(DataType::UInt32, false),
+                "Map(String, String)" => DataType::Map(
+                    Arc::new(Field::new_struct(
+                        "entries",
+                        Fields::from(vec![
+                            Field::new("keys", DataType::Utf8, false),
+                            Field::new("values", DataType::Utf8, false),
+                        ]),
+                        false,
+                    )),
+                    false,
+                ),
+                "Bool" => DataType::Boolean,
+                _ => unreachable!("no Arrow type for {column_type}"),
+            };
+
+            Field::new(*name, data_type, column_type.starts_with("Nullable"))
+        })
+        .collect::<Vec<_>>();
+
+    Schema::new(fields)
+}
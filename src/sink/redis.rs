@@ -0,0 +1,196 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use redis::{aio::MultiplexedConnection, Client};
+use serde_json::Value;
+use tracing::{error, info, warn};
+
+use super::{Error, FlowSink};
+use crate::{
+    config::{Config, RedisConfig},
+    row::FlowRecord,
+};
+
+/// Longest wait between attempts to reach Redis.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Adds records to a Redis stream with the columns as fields. Entries
+/// are kept while Redis is unavailable, up to a limit, and added once
+/// it's back, so an entry can end up in the stream twice if a pipeline
+/// fails halfway.
+pub struct RedisSink {
+    config: RedisConfig,
+    client: Client,
+    connection: Option<MultiplexedConnection>,
+    pending: VecDeque<Vec<(String, String)>>,
+    last_send: Instant,
+    backoff: Duration,
+    retry_at: Instant,
+    /// Entries dropped because too many were pending.
+    dropped: u64,
+}
+
+impl RedisSink {
+    pub fn new(config: &RedisConfig) -> Result<Self, Error> {
+        let client = Client::open(config.url.as_str())?;
+
+        info!(target: "redis", "Adding flows to the {} stream", config.stream);
+
+        Ok(Self {
+            config: config.clone(),
+            client,
+            connection: None,
+            pending: VecDeque::new(),
+            last_send: Instant::now(),
+            backoff: Duration::ZERO,
+            retry_at: Instant::now(),
+            dropped: 0,
+        })
+    }
+
+    /// Adds the pending entries in pipelines of up to `max_rows`. The
+    /// connection is dropped on errors, so that the next try reconnects.
+    async fn send(&mut self) -> Result<usize, Error> {
+        self.last_send = Instant::now();
+
+        let timeout = self.config.timeout;
+        let mut sent = 0;
+
+        while !self.pending.is_empty() {
+            if self.connection.is_none() {
+                let connecting = self.client.get_multiplexed_async_connection();
+
+                let connection = match tokio::time::timeout(timeout, connecting).await {
+                    Ok(connection) => connection?,
+                    Err(_) => return Err("timed out connecting".into()),
+                };
+
+                self.connection = Some(connection);
+            }
+
+            let batch = self.pending.len().min(self.config.max_rows as usize);
+            let mut pipeline = redis::pipe();
+
+            for fields in self.pending.range(..batch) {
+                let command = pipeline.cmd("XADD").arg(&self.config.stream);
+
+                if self.config.max_len > 0 {
+                    command.arg("MAXLEN").arg("~").arg(self.config.max_len);
+                }
+
+                command.arg("*").arg(fields).ignore();
+            }
+
+            let connection = self.connection.as_mut().unwrap();
+
+            match tokio::time::timeout(timeout, pipeline.exec_async(connection)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    self.connection = None;
+                    return Err(e.into());
+                }
+                Err(_) => {
+                    self.connection = None;
+                    return Err("timed out adding entries".into());
+                }
+            }
+
+            self.pending.drain(..batch);
+            sent += batch;
+        }
+
+        if self.dropped > 0 {
+            warn!(target: "redis", "Dropped {} flows while Redis was unavailable", self.dropped);
+            self.dropped = 0;
+        }
+
+        Ok(sent)
+    }
+}
+
+#[async_trait]
+impl FlowSink for RedisSink {
+    fn reconfigure(&mut self, config: &Config) {
+        self.config.max_len = config.redis.max_len;
+        self.config.max_rows = config.redis.max_rows;
+        self.config.period = config.redis.period;
+        self.config.max_pending = config.redis.max_pending;
+        self.config.timeout = config.redis.timeout;
+    }
+
+    async fn write(&mut self, record: &FlowRecord) -> Result<(), Error> {
+        self.pending.push_back(fields(record)?);
+
+        while self.pending.len() as u64 > self.config.max_pending {
+            self.pending.pop_front();
+            self.dropped += 1;
+        }
+
+        let due = self.pending.len() as u64 >= self.config.max_rows
+            || self.last_send.elapsed() >= self.config.period;
+
+        if !due || Instant::now() < self.retry_at {
+            return Ok(());
+        }
+
+        // Unavailable Redis is waited out rather than treated as fatal.
+        match self.send().await {
+            Ok(_) => self.backoff = Duration::ZERO,
+            Err(e) => {
+                self.backoff = (self.backoff * 2).clamp(self.config.period, MAX_BACKOFF);
+                self.retry_at = Instant::now() + self.backoff;
+
+                warn!(
+                    target: "redis",
+                    "Cannot add {} pending entries, retrying in {}: {e}",
+                    self.pending.len(),
+                    humantime::format_duration(self.backoff)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Error> {
+        let dropped = self.dropped;
+
+        self.send().await?;
+
+        if dropped > 0 {
+            return Err(format!("dropped {dropped} flows while Redis was unavailable").into());
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) {
+        match self.send().await {
+            Ok(entries) => info!(target: "redis", "Flushed {entries} pending entries"),
+            Err(e) => error!(
+                target: "redis",
+                "Cannot flush {} pending entries: {e}",
+                self.pending.len()
+            ),
+        }
+    }
+}
+
+/// Field and value pairs of an entry, with values other than strings as JSON.
+fn fields(record: &FlowRecord) -> Result<Vec<(String, String)>, Error> {
+    let Value::Object(columns) = serde_json::to_value(record)? else {
+        unreachable!("records are serialized as objects");
+    };
+
+    Ok(columns
+        .into_iter()
+        .filter_map(|(name, value)| match value {
+            Value::Null => None,
+            Value::String(value) => Some((name, value)),
+            value => Some((name, value.to_string())),
+        })
+        .collect())
+}
@@ -0,0 +1,84 @@
+use std::{
+    io::{self, BufRead},
+    path::Path,
+};
+
+use tracing::{info, warn};
+
+use crate::{row::FlowRecord, segments::Segments};
+
+/// Rows that couldn't be inserted, kept in a directory of append-only
+/// JSON Lines segments numbered in the order they were written. The
+/// oldest segments are removed to stay under the size limit.
+pub struct Spool {
+    segments: Segments,
+}
+
+impl Spool {
+    /// Picks up the segments left over from previous runs.
+    pub fn open(directory: &Path, max_bytes: u64) -> io::Result<Self> {
+        let segments = Segments::open(directory, "jsonl", max_bytes)?;
+
+        if !segments.is_empty() {
+            info!(
+                target: "clickhouse",
+                "Found {} bytes of spooled rows in {}",
+                segments.size(),
+                directory.display()
+            );
+        }
+
+        Ok(Self { segments })
+    }
+
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.segments.set_max_bytes(max_bytes);
+    }
+
+    /// Appends rows to the last segment, starting a new one when it's full.
+    pub fn append(&mut self, rows: &[FlowRecord]) -> io::Result<()> {
+        let mut data = vec![];
+
+        for row in rows {
+            serde_json::to_writer(&mut data, row)?;
+            data.push(b'\n');
+        }
+
+        for path in self.segments.append(&data)? {
+            warn!(
+                target: "clickhouse",
+                "Spool is over {} bytes, dropped {}",
+                self.segments.max_bytes(),
+                path.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Reads the rows of the oldest segment, closing it if it's being written.
+    pub fn oldest(&mut self) -> io::Result<Option<(u64, Vec<FlowRecord>)>> {
+        let Some(&number) = self.segments.numbers().first() else {
+            return Ok(None);
+        };
+
+        let mut rows = vec![];
+
+        for line in self.segments.read(number)?.lines() {
+            let line = line?;
+
+            // A line cut short by a crash is not worth the whole segment.
+            match serde_json::from_str(&line) {
+                Ok(row) => rows.push(row),
+                Err(e) => warn!(target: "clickhouse", "Skipping a spooled row: {e}"),
+            }
+        }
+
+        Ok(Some((number, rows)))
+    }
+
+    /// Removes a segment once its rows are inserted.
+    pub fn remove(&mut self, number: u64) -> io::Result<()> {
+        self.segments.remove(number)
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::VecDeque,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use prometheus_client::metrics::counter::Counter;
+use rusqlite::{params_from_iter, types::Value, Connection};
+use tokio::task::block_in_place;
+use tracing::{error, info, warn};
+
+use super::{errors, Error, FlowSink};
+use crate::{
+    config::{Config, SqliteConfig},
+    metrics::Metrics,
+    row::{FlowRecord, SCHEMA},
+};
+
+const TABLE: &str = "ipfix";
+
+/// Keeps records in an SQLite database, for when a database server is too
+/// much. Batches are inserted in a transaction, which also prunes old rows.
+/// Rows of a failed transaction, like with the database locked or the disk
+/// full, are tried again every period, up to a limit.
+pub struct SqliteSink {
+    config: SqliteConfig,
+    connection: Connection,
+    pending: VecDeque<Vec<Value>>,
+    last_insert: Instant,
+    /// Rows dropped because too many were pending.
+    dropped: u64,
+    errors: Counter,
+}
+
+impl SqliteSink {
+    pub fn new(config: &SqliteConfig, metrics: &Metrics) -> Result<Self, Error> {
+        let connection = Connection::open(&config.path)?;
+
+        // Readers don't block the collector and fsync happens on checkpoints only.
+        connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+
+        connection.execute_batch(&create_table())?;
+
+        info!(
+            target: "sqlite",
+            "Writing flows to {}, retention: {}",
+            config.path.display(),
+            humantime::format_duration(config.retention)
+        );
+
+        Ok(Self {
+            config: config.clone(),
+            connection,
+            pending: VecDeque::new(),
+            last_insert: Instant::now(),
+            dropped: 0,
+            errors: errors(metrics, "sqlite"),
+        })
+    }
+
+    /// Inserts the pending rows and deletes the ones past the retention.
+    fn insert(&mut self) -> Result<usize, Error> {
+        self.last_insert = Instant::now();
+
+        if self.pending.is_empty() {
+            return Ok(0);
+        }
+
+        let transaction = self.connection.transaction()?;
+
+        {
+            let mut statement = transaction.prepare_cached(&insert_statement())?;
+
+            for row in &self.pending {
+                statement.execute(params_from_iter(row))?;
+            }
+        }
+
+        if !self.config.retention.is_zero() {
+            let cutoff = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .saturating_sub(self.config.retention)
+                .as_secs() as i64;
+
+            transaction.execute(
+                &format!("DELETE FROM {TABLE} WHERE insertionTime < ?1"),
+                [cutoff],
+            )?;
+        }
+
+        transaction.commit()?;
+
+        if self.dropped > 0 {
+            warn!(target: "sqlite", "Dropped {} rows while the database was unavailable", self.dropped);
+            self.dropped = 0;
+        }
+
+        Ok(self.pending.drain(..).count())
+    }
+}
+
+#[async_trait]
+impl FlowSink for SqliteSink {
+    fn reconfigure(&mut self, config: &Config) {
+        self.config.retention = config.sqlite.retention;
+        self.config.max_rows = config.sqlite.max_rows;
+        self.config.period = config.sqlite.period;
+        self.config.max_pending = config.sqlite.max_pending;
+    }
+
+    async fn write(&mut self, record: &FlowRecord) -> Result<(), Error> {
+        self.pending.push_back(row(record)?);
+
+        while self.pending.len() as u64 > self.config.max_pending {
+            self.pending.pop_front();
+            self.dropped += 1;
+            self.errors.inc();
+        }
+
+        let due = self.pending.len() as u64 >= self.config.max_rows
+            || self.last_insert.elapsed() >= self.config.period;
+
+        if !due {
+            return Ok(());
+        }
+
+        // The rows stay pending and are tried again after the period.
+        if let Err(e) = block_in_place(|| self.insert()) {
+            warn!(
+                target: "sqlite",
+                "Cannot insert {} pending rows, retrying in {}: {e}",
+                self.pending.len(),
+                humantime::format_duration(self.config.period)
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Error> {
+        let dropped = self.dropped;
+
+        block_in_place(|| self.insert())?;
+
+        if dropped > 0 {
+            return Err(
+                format!("dropped {dropped} rows while the database was unavailable").into(),
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) {
+        match block_in_place(|| self.insert()) {
+            Ok(rows) => info!(target: "sqlite", "Flushed {rows} pending rows"),
+            Err(e) => error!(target: "sqlite", "Cannot flush pending rows: {e}"),
+        }
+    }
+}
+
+/// Values of the columns, with addresses as text and arrays and maps as JSON.
+fn row(record: &FlowRecord) -> Result<Vec<Value>, Error> {
+    let serde_json::Value::Object(columns) = serde_json::to_value(record)? else {
+        unreachable!("records are serialized as objects");
+    };
+
+    Ok(columns
+        .into_iter()
+        .map(|(_, value)| match value {
+            serde_json::Value::Null => Value::Null,
+            serde_json::Value::Bool(value) => Value::Integer(value.into()),
+            serde_json::Value::Number(number) => match number.as_i64() {
+                Some(number) => Value::Integer(number),
+                None => Value::Integer(number.as_u64().unwrap_or_default() as i64),
+            },
+            serde_json::Value::String(value) => Value::Text(value),
+            value => Value::Text(value.to_string()),
+        })
+        .collect())
+}
+
+fn create_table() -> String {
+    let columns = SCHEMA
+        .iter()
+        .map(|(name, column_type)| {
+            let sqlite_type = match *column_type {
+                "LowCardinality(String)" | "IPv4" | "IPv6" => "TEXT",
+                "Array(UInt32)" | "Map(String, String)" => "TEXT",
+                _ => "INTEGER",
+            };
+
+            format!("{name} {sqlite_type}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "CREATE TABLE IF NOT EXISTS {TABLE} ({columns});
+         CREATE INDEX IF NOT EXISTS {TABLE}_insertionTime ON {TABLE} (insertionTime);"
+    )
+}
+
+fn insert_statement() -> String {
+    let columns = SCHEMA
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let placeholders = vec!["?"; SCHEMA.len()].join(", ");
+
+    format!("INSERT INTO {TABLE} ({columns}) VALUES ({placeholders})")
+}
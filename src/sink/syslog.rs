@@ -0,0 +1,403 @@
+use std::{
+    io,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use prometheus_client::metrics::counter::Counter;
+use serde_json::{Map, Value};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::{lookup_host, TcpStream, UdpSocket},
+};
+use tokio_rustls::{client::TlsStream, rustls::pki_types::ServerName, TlsConnector};
+use tracing::{error, info, warn};
+
+use super::{errors, format_mac, Error, FlowSink};
+use crate::{
+    config::{Config, SyslogConfig, SyslogFormat, SyslogTransport},
+    metrics::Metrics,
+    row::FlowRecord,
+    tls,
+};
+
+/// Severity of every message, which is informational.
+const SEVERITY: u8 = 6;
+
+/// Shortest and longest wait between attempts to reach the server.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Sends a syslog message per flow, for SIEMs and the like. Flows over
+/// the rate limit are dropped rather than queued, and so are flows while
+/// the server is unavailable, until a reconnect after a backoff succeeds.
+pub struct SyslogSink {
+    config: SyslogConfig,
+    hostname: String,
+    connector: Option<TlsConnector>,
+    connection: Option<Connection>,
+    second: Instant,
+    sent: u64,
+    suppressed: u64,
+    backoff: Duration,
+    retry_at: Instant,
+    /// Flows dropped since the server became unavailable.
+    dropped: u64,
+    /// Flows dropped since the last checkpoint.
+    undelivered: u64,
+    errors: Counter,
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    async fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send(message).await.map(|_| ()),
+            Self::Tcp(stream) => send_framed(stream, message).await,
+            Self::Tls(stream) => send_framed(stream.as_mut(), message).await,
+        }
+    }
+
+    async fn close(self) -> io::Result<()> {
+        match self {
+            Self::Udp(_) => Ok(()),
+            Self::Tcp(mut stream) => stream.shutdown().await,
+            Self::Tls(mut stream) => stream.shutdown().await,
+        }
+    }
+}
+
+impl SyslogSink {
+    pub fn new(config: &SyslogConfig, metrics: &Metrics) -> Result<Self, Error> {
+        let connector = match config.transport {
+            SyslogTransport::Tls => Some(tls::connector(config.ca.as_deref())?),
+            SyslogTransport::Udp | SyslogTransport::Tcp => None,
+        };
+
+        let hostname = match config.hostname.as_str() {
+            "" => nix::unistd::gethostname()?
+                .into_string()
+                .unwrap_or_else(|_| "-".to_owned()),
+            hostname => hostname.to_owned(),
+        };
+
+        info!(target: "syslog", "Sending flows to {}", config.address);
+
+        Ok(Self {
+            config: config.clone(),
+            hostname,
+            connector,
+            connection: None,
+            second: Instant::now(),
+            sent: 0,
+            suppressed: 0,
+            backoff: Duration::ZERO,
+            retry_at: Instant::now(),
+            dropped: 0,
+            undelivered: 0,
+            errors: errors(metrics, "syslog"),
+        })
+    }
+
+    async fn connect(&self) -> Result<Connection, Error> {
+        let address = self.config.address.as_str();
+
+        Ok(match &self.connector {
+            Some(connector) => {
+                let (host, _) = address.rsplit_once(':').unwrap_or_default();
+                let name = ServerName::try_from(host.trim_matches(['[', ']']).to_owned())?;
+                let stream = connector.connect(name, TcpStream::connect(address).await?);
+
+                Connection::Tls(Box::new(stream.await?))
+            }
+            None if self.config.transport == SyslogTransport::Tcp => {
+                Connection::Tcp(TcpStream::connect(address).await?)
+            }
+            None => {
+                let server = lookup_host(address)
+                    .await?
+                    .next()
+                    .ok_or_else(|| format!("{address} does not resolve"))?;
+
+                let socket = match server.is_ipv4() {
+                    true => UdpSocket::bind("0.0.0.0:0").await?,
+                    false => UdpSocket::bind("[::]:0").await?,
+                };
+
+                socket.connect(server).await?;
+
+                Connection::Udp(socket)
+            }
+        })
+    }
+
+    /// Sends a message, connecting first if there is no connection yet.
+    async fn send(&mut self, message: &[u8]) -> Result<(), Error> {
+        let timeout = self.config.timeout;
+
+        if self.connection.is_none() {
+            let connection = match tokio::time::timeout(timeout, self.connect()).await {
+                Ok(connection) => connection?,
+                Err(_) => return Err("timed out connecting".into()),
+            };
+
+            self.connection = Some(connection);
+        }
+
+        let connection = self.connection.as_mut().unwrap();
+
+        match tokio::time::timeout(timeout, connection.send(message)).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err("timed out sending".into()),
+        }
+    }
+
+    /// Counts a flow that didn't make it to the server.
+    fn drop_flow(&mut self) {
+        self.dropped += 1;
+        self.undelivered += 1;
+        self.errors.inc();
+    }
+
+    /// Counts a message against the rate limit and tells whether it fits.
+    fn allow(&mut self) -> bool {
+        if self.config.rate_limit == 0 {
+            return true;
+        }
+
+        if self.second.elapsed() >= Duration::from_secs(1) {
+            if self.suppressed > 0 {
+                warn!(
+                    target: "syslog",
+                    "Rate limit of {} messages per second dropped {} flows",
+                    self.config.rate_limit,
+                    self.suppressed
+                );
+            }
+
+            self.second = Instant::now();
+            self.sent = 0;
+            self.suppressed = 0;
+        }
+
+        if self.sent >= self.config.rate_limit {
+            self.suppressed += 1;
+            return false;
+        }
+
+        self.sent += 1;
+
+        true
+    }
+
+    /// Formats the RFC 5424 message with the flow in the chosen format.
+    fn message(&self, record: &FlowRecord) -> Result<Vec<u8>, Error> {
+        let Value::Object(columns) = serde_json::to_value(record)? else {
+            unreachable!("records are serialized as objects");
+        };
+
+        let seconds = columns["insertionTime"].as_u64().unwrap_or_default();
+        let timestamp =
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(seconds));
+
+        let body = match self.config.format {
+            SyslogFormat::Rfc5424 => serde_json::to_string(&columns)?,
+            SyslogFormat::Cef => cef(&columns),
+        };
+
+        Ok(format!(
+            "<{}>1 {timestamp} {} {} {} flow - {body}",
+            self.config.facility * 8 + SEVERITY,
+            self.hostname,
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        )
+        .into_bytes())
+    }
+}
+
+#[async_trait]
+impl FlowSink for SyslogSink {
+    fn reconfigure(&mut self, config: &Config) {
+        self.config.rate_limit = config.syslog.rate_limit;
+        self.config.timeout = config.syslog.timeout;
+    }
+
+    async fn write(&mut self, record: &FlowRecord) -> Result<(), Error> {
+        if !self.allow() {
+            return Ok(());
+        }
+
+        let message = self.message(record)?;
+
+        if Instant::now() < self.retry_at {
+            self.drop_flow();
+            return Ok(());
+        }
+
+        let mut result = self.send(&message).await;
+
+        // Streams get closed by the server every now and then, so give
+        // a fresh connection a try before backing off.
+        if let Err(e) = &result {
+            if self.connection.take().is_some() {
+                warn!(target: "syslog", "Reconnecting to {}: {e}", self.config.address);
+
+                result = self.send(&message).await;
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                if self.dropped > 0 {
+                    warn!(
+                        target: "syslog",
+                        "Dropped {} flows while {} was unavailable",
+                        self.dropped,
+                        self.config.address
+                    );
+                    self.dropped = 0;
+                }
+
+                self.backoff = Duration::ZERO;
+            }
+            Err(e) => {
+                self.connection = None;
+                self.drop_flow();
+
+                self.backoff = (self.backoff * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+                self.retry_at = Instant::now() + self.backoff;
+
+                warn!(
+                    target: "syslog",
+                    "Cannot send to {}, retrying in {}: {e}",
+                    self.config.address,
+                    humantime::format_duration(self.backoff)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Error> {
+        // Messages go out as records are written.
+        let undelivered = std::mem::take(&mut self.undelivered);
+
+        if undelivered > 0 {
+            return Err(
+                format!("dropped {undelivered} flows while the server was unavailable").into(),
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) {
+        let Some(connection) = self.connection.take() else {
+            return;
+        };
+
+        match connection.close().await {
+            Ok(()) => info!(target: "syslog", "Closed the connection to {}", self.config.address),
+            Err(e) => error!(target: "syslog", "Cannot close the connection: {e}"),
+        }
+    }
+}
+
+/// Writes a message with its length in front (RFC 6587 octet counting).
+async fn send_framed(stream: &mut (impl AsyncWrite + Unpin), message: &[u8]) -> io::Result<()> {
+    let mut frame = format!("{} ", message.len()).into_bytes();
+    frame.extend_from_slice(message);
+
+    stream.write_all(&frame).await?;
+    stream.flush().await
+}
+
+/// Formats the flow as a CEF event, with the direction of the traffic
+/// deciding which end is the source.
+fn cef(columns: &Map<String, Value>) -> String {
+    let text = |name: &str| columns[name].as_str().unwrap_or_default().to_owned();
+    let number = |name: &str| columns[name].as_u64().unwrap_or_default();
+
+    let address = |v4, v6, port| match text(v6).as_str() {
+        "::" => (text(v4), number(port)),
+        _ => (text(v6), number(port)),
+    };
+
+    let client = address("clientIPv4", "clientIPv6", "clientPort");
+    let server = address("serverIPv4", "serverIPv6", "serverPort");
+
+    let is_download = columns["is_download"] == true;
+
+    let ((src, spt), (dst, dpt)) = match is_download {
+        true => (server, client),
+        false => (client, server),
+    };
+
+    let protocol = match number("protocol") {
+        1 => "ICMP".to_owned(),
+        6 => "TCP".to_owned(),
+        17 => "UDP".to_owned(),
+        58 => "IPv6-ICMP".to_owned(),
+        protocol => protocol.to_string(),
+    };
+
+    let mut extension = vec![
+        ("rt", (number("insertionTime") * 1000).to_string()),
+        ("src", src),
+        ("spt", spt.to_string()),
+        ("dst", dst),
+        ("dpt", dpt.to_string()),
+        ("proto", protocol),
+        (
+            if is_download { "dmac" } else { "smac" },
+            format_mac(number("clientMac")),
+        ),
+        (
+            if is_download { "in" } else { "out" },
+            number("bytes").to_string(),
+        ),
+        ("deviceDirection", u8::from(!is_download).to_string()),
+        ("cn1Label", "packets".to_owned()),
+        ("cn1", number("packets").to_string()),
+        ("cs1Label", "deviceName".to_owned()),
+        ("cs1", text("deviceName")),
+        ("cs2Label", "listener".to_owned()),
+        ("cs2", text("listener")),
+    ];
+
+    for (key, column) in [
+        ("deviceInboundInterface", "inInterface"),
+        ("deviceOutboundInterface", "outInterface"),
+    ] {
+        if number(column) != 0 {
+            extension.push((key, number(column).to_string()));
+        }
+    }
+
+    let extension = extension
+        .into_iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('=', "\\=")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r");
+
+            format!("{key}={value}")
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    format!(
+        "CEF:0|{name}|{name}|{}|flow|Flow|1|{extension}",
+        env!("CARGO_PKG_VERSION"),
+        name = env!("CARGO_PKG_NAME")
+    )
+}
@@ -0,0 +1,153 @@
+use netflow_parser::variable_versions::{
+    data_number::FieldValue, ipfix::IPFixParser, ipfix_lookup::IPFixField,
+};
+
+use crate::{records::field_length, sflow::Reader};
+
+const BASIC_LIST: u16 = 291;
+const SUB_TEMPLATE_LIST: u16 = 292;
+const SUB_TEMPLATE_MULTI_LIST: u16 = 293;
+
+/// Lists in lists are fine, but not to the point of exhausting the stack.
+const MAX_DEPTH: usize = 8;
+
+/// A field of an IPFIX record as enterprise number (0 for IANA), id and value.
+pub type NestedField = (u32, u16, FieldValue);
+
+/// Returns the fields nested in IANA structured data (RFC 6313), which the
+/// parser leaves as raw bytes, with lists in lists flattened as well.
+/// Sub-templates are looked up in the templates of the parser. Data that
+/// ends prematurely yields whatever was decoded up to that point.
+pub fn flatten(
+    enterprise: Option<u32>,
+    id: u16,
+    value: &FieldValue,
+    parser: &IPFixParser,
+) -> Vec<NestedField> {
+    let mut flattener = Flattener {
+        parser,
+        fields: vec![],
+        depth: 0,
+    };
+
+    flattener.list(enterprise, id, value);
+
+    flattener.fields
+}
+
+struct Flattener<'a> {
+    parser: &'a IPFixParser,
+    fields: Vec<NestedField>,
+    depth: usize,
+}
+
+impl Flattener<'_> {
+    fn list(&mut self, enterprise: Option<u32>, id: u16, value: &FieldValue) {
+        let (None, FieldValue::Vec(data)) = (enterprise, value) else {
+            return;
+        };
+
+        if self.depth == MAX_DEPTH {
+            return;
+        }
+
+        self.depth += 1;
+
+        let mut reader = Reader(data);
+
+        // The semantic of the list, e.g. allOf or exactlyOneOf, doesn't matter.
+        let _ = reader.u8().and_then(|_| match id {
+            BASIC_LIST => self.basic_list(reader),
+            SUB_TEMPLATE_LIST => {
+                let template = reader.u16()?;
+                self.records(reader, template)
+            }
+            SUB_TEMPLATE_MULTI_LIST => self.multi_list(reader),
+            _ => None,
+        });
+
+        self.depth -= 1;
+    }
+
+    fn basic_list(&mut self, mut reader: Reader) -> Option<()> {
+        let id = reader.u16()?;
+        let length = reader.u16()?;
+
+        let (id, enterprise) = if id & 0x8000 != 0 {
+            (id & 0x7fff, Some(reader.u32()?))
+        } else {
+            (id, None)
+        };
+
+        if length == 0 {
+            return None;
+        }
+
+        while !reader.0.is_empty() {
+            self.field(&mut reader, enterprise, id, length)?;
+        }
+
+        Some(())
+    }
+
+    fn multi_list(&mut self, mut reader: Reader) -> Option<()> {
+        while !reader.0.is_empty() {
+            let template = reader.u16()?;
+
+            // The length covers the template id and itself.
+            let length = usize::from(reader.u16()?).checked_sub(4)?;
+
+            self.records(Reader(reader.take(length)?), template)?;
+        }
+
+        Some(())
+    }
+
+    /// Decodes records of a sub-template until the data runs out.
+    fn records(&mut self, mut reader: Reader, template: u16) -> Option<()> {
+        let parser = self.parser;
+        let template = &parser.templates.peek(&template)?.template;
+
+        while !reader.0.is_empty() {
+            let remaining = reader.0.len();
+
+            for field in &template.fields {
+                self.field(
+                    &mut reader,
+                    field.enterprise_number,
+                    field.field_type_number,
+                    field.field_length,
+                )?;
+            }
+
+            // Templates of nothing but empty fields would never get anywhere.
+            if reader.0.len() == remaining {
+                break;
+            }
+        }
+
+        Some(())
+    }
+
+    fn field(
+        &mut self,
+        reader: &mut Reader,
+        enterprise: Option<u32>,
+        id: u16,
+        length: u16,
+    ) -> Option<()> {
+        let length = field_length(reader, length)?;
+
+        let data = reader.take(length.into())?;
+
+        let (_, value) =
+            FieldValue::from_field_type(data, IPFixField::new(id, enterprise).into(), length)
+                .ok()?;
+
+        self.list(enterprise, id, &value);
+        self.fields
+            .push((enterprise.unwrap_or_default(), id, value));
+
+        Some(())
+    }
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use clickhouse::Client;
+use sd_notify::NotifyState;
+use tokio::time::sleep;
+use tracing::warn;
+
+/// Tells systemd that the service is ready once ClickHouse is reachable.
+/// Does nothing if the service is not managed by systemd.
+pub async fn notify_ready(client: Option<Client>) {
+    if let Some(client) = client {
+        while let Err(e) = client.query("SELECT 1").execute().await {
+            warn!(target: "clickhouse", "Waiting for ClickHouse to become reachable: {e}");
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    let _ = sd_notify::notify(false, &[NotifyState::Ready]);
+}
+
+pub fn notify_stopping() {
+    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+}
+
+/// Interval to pet the watchdog at, if it's enabled for the service.
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+
+    sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec) / 2)
+}
+
+pub fn pet_watchdog() {
+    let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+}
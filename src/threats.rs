@@ -0,0 +1,214 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use serde::Serialize;
+use tokio::{
+    select, spawn,
+    sync::watch,
+    task::block_in_place,
+    time::{sleep, timeout},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    config::{Config, ThreatsConfig},
+    prefixes::Prefixes,
+    sink::{http, Error},
+};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Lists that cannot be read are tried again sooner than `refresh`.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Addresses on blocklists, with the name of the first list they are on.
+#[derive(Default)]
+pub struct Threats {
+    networks: Prefixes<Arc<str>>,
+}
+
+impl Threats {
+    fn new(fetched: &HashMap<String, Vec<IpNet>>, config: &ThreatsConfig) -> Self {
+        let mut networks = Prefixes::default();
+
+        // Lists in the config order, which is by name.
+        for name in config.lists.keys() {
+            let name = Arc::<str>::from(name.as_str());
+
+            for network in fetched.get(&*name).into_iter().flatten() {
+                networks.add(*network, name.clone());
+            }
+        }
+
+        Self { networks }
+    }
+
+    pub fn len(&self) -> usize {
+        self.networks.len()
+    }
+
+    /// Name of the list with the address, if any.
+    pub fn list(&self, addr: IpAddr) -> Option<&str> {
+        self.networks.get(addr).map(|name| &**name)
+    }
+}
+
+/// Reads the blocklists every `refresh`, keeping the last good copy of the
+/// lists that cannot be read.
+pub async fn poll(mut config: watch::Receiver<Arc<Config>>, threats: watch::Sender<Arc<Threats>>) {
+    let mut fetched = HashMap::new();
+
+    loop {
+        let current = config.borrow().threats.clone();
+
+        let mut interval = current.refresh;
+
+        fetched.retain(|name, _| current.enabled && current.lists.contains_key(name));
+
+        if current.enabled {
+            for (name, source) in &current.lists {
+                match timeout(FETCH_TIMEOUT, fetch(source)).await {
+                    Ok(Ok(networks)) => {
+                        debug!(target: "threats", "Read {} networks of {name} from {source}", networks.len());
+
+                        fetched.insert(name.clone(), networks);
+                    }
+                    Ok(Err(e)) => {
+                        warn!(target: "threats", "Cannot read {name} from {source}: {e}");
+                        interval = interval.min(RETRY_INTERVAL);
+                    }
+                    Err(_) => {
+                        warn!(target: "threats", "Timed out reading {name} from {source}");
+                        interval = interval.min(RETRY_INTERVAL);
+                    }
+                }
+            }
+        }
+
+        if !fetched.is_empty() || threats.borrow().len() > 0 {
+            threats.send_replace(Arc::new(Threats::new(&fetched, &current)));
+        }
+
+        select! {
+            _ = sleep(interval) => {}
+            Ok(_) = config.wait_for(|config| config.threats != current) => {}
+        }
+    }
+}
+
+/// Reads a list of addresses from a file or an http(s) URL.
+pub async fn fetch(source: &str) -> Result<Vec<IpNet>, Error> {
+    let contents = match source.starts_with("http://") || source.starts_with("https://") {
+        true => http::get(source, &[], None).await?.to_vec(),
+        false => block_in_place(|| fs::read(source))?,
+    };
+
+    Ok(parse(&String::from_utf8_lossy(&contents)))
+}
+
+/// Takes the first word of every line, skipping comments after `#` or `;`
+/// and lines that aren't addresses or networks, like headers of the
+/// FireHOL and Spamhaus lists.
+fn parse(contents: &str) -> Vec<IpNet> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let line = line.split(['#', ';']).next()?;
+            let word = line.split_whitespace().next()?;
+
+            word.parse::<IpNet>()
+                .ok()
+                .or_else(|| word.parse::<IpAddr>().ok().map(IpNet::from))
+        })
+        .collect()
+}
+
+/// Flow of a listed address, as posted to the webhook.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert<'a> {
+    /// Summary for chat webhooks like the ones of Slack or Mattermost.
+    pub text: String,
+    pub list: &'a str,
+    pub client_mac: &'a str,
+    pub device_name: &'a str,
+    pub device_group: &'a str,
+    #[serde(rename = "clientIP")]
+    pub client_ip: IpAddr,
+    #[serde(rename = "serverIP")]
+    pub server_ip: IpAddr,
+    pub server_port: u16,
+    pub protocol: u8,
+    pub direction: &'a str,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Posts alerts to the webhook, once per local and remote address for
+/// every `alert_interval`, so that a chatty connection is one alert.
+#[derive(Default)]
+pub struct Alerts {
+    sent: HashMap<(IpAddr, IpAddr), Instant>,
+}
+
+impl Alerts {
+    pub fn send(&mut self, config: &ThreatsConfig, alert: Alert) {
+        if config.webhook.is_empty() {
+            return;
+        }
+
+        if !config.alert_groups.is_empty()
+            && !config
+                .alert_groups
+                .iter()
+                .any(|group| group == alert.device_group)
+        {
+            return;
+        }
+
+        let key = (alert.client_ip, alert.server_ip);
+
+        if self
+            .sent
+            .get(&key)
+            .is_some_and(|sent| sent.elapsed() < config.alert_interval)
+        {
+            return;
+        }
+
+        self.sent.insert(key, Instant::now());
+
+        let body = match serde_json::to_vec(&alert) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!(target: "threats", "Cannot serialize alert: {e}");
+                return;
+            }
+        };
+
+        let url = config.webhook.clone();
+
+        // Flows are not held up by a slow webhook.
+        spawn(async move {
+            let request = http::post(&url, "application/json", &[], body);
+
+            match timeout(FETCH_TIMEOUT, request).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!(target: "threats", "Cannot send alert to {url}: {e}"),
+                Err(_) => warn!(target: "threats", "Timed out sending alert to {url}"),
+            }
+        });
+    }
+
+    /// Forgets alerts sent longer than `alert_interval` ago.
+    pub fn expire(&mut self, config: &ThreatsConfig) {
+        self.sent
+            .retain(|_, sent| sent.elapsed() < config.alert_interval);
+    }
+}
@@ -0,0 +1,303 @@
+use std::{fs, io, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use rcgen::KeyPair;
+use tokio::{
+    select, spawn,
+    sync::{mpsc, watch},
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::CertificateDer, server::WebPkiClientVerifier, ClientConfig, RootCertStore,
+        ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+use tracing::warn;
+use webrtc_dtls::{
+    config::{ClientAuthType, Config as DtlsConfig, ExtendedMasterSecretType},
+    conn::DTLSConn,
+    content::ContentType,
+    crypto::{Certificate, CryptoPrivateKey},
+};
+use webrtc_util::conn::{conn_udp_listener::ListenConfig, Conn, Listener};
+
+use crate::{
+    config::{Config, TlsConfig},
+    listener::{Datagram, Format},
+    metrics::Metrics,
+};
+
+/// Builds the acceptor for IPFIX over TLS. Exporters have to present
+/// a certificate signed by the client CA if one is configured.
+pub fn acceptor(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let certificates = read_certificates(config.certificate.as_deref())?;
+
+    let key = rustls_pemfile::private_key(&mut read(config.private_key.as_deref())?.as_slice())?
+        .ok_or_else(|| invalid("no private key found"))?;
+
+    let builder = match &config.client_ca {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(read_roots(path)?))
+                .build()
+                .map_err(invalid)?;
+
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+
+    let server_config = builder
+        .with_single_cert(certificates, key)
+        .map_err(invalid)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Builds the connector for sending to TLS servers, which are verified
+/// against the given CA or the system roots.
+pub fn connector(ca: Option<&Path>) -> io::Result<TlsConnector> {
+    Ok(TlsConnector::from(Arc::new(client_config(ca, None)?)))
+}
+
+/// Client configuration verifying servers against the given CA or the
+/// system roots, presenting the certificate and key if given.
+pub fn client_config(
+    ca: Option<&Path>,
+    identity: Option<(&Path, &Path)>,
+) -> io::Result<ClientConfig> {
+    let roots = match ca {
+        Some(path) => read_roots(path)?,
+        None => {
+            let mut roots = RootCertStore::empty();
+            roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+            roots
+        }
+    };
+
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+
+    let Some((certificate, private_key)) = identity else {
+        return Ok(builder.with_no_client_auth());
+    };
+
+    let key = rustls_pemfile::private_key(&mut read(Some(private_key))?.as_slice())?
+        .ok_or_else(|| invalid("no private key found"))?;
+
+    builder
+        .with_client_auth_cert(read_certificates(Some(certificate))?, key)
+        .map_err(invalid)
+}
+
+/// UDP socket for IPFIX over DTLS. Associations are accepted before their
+/// handshake, which is done in the background, so that an exporter that
+/// never finishes it doesn't hold up the others.
+pub struct DtlsSocket {
+    associations: Arc<dyn Listener + Send + Sync>,
+    config: DtlsConfig,
+}
+
+/// Binds a UDP socket for IPFIX over DTLS with the same certificates as TLS.
+/// The private key has to be in the PKCS#8 format.
+pub async fn bind_dtls(addr: &str, config: &TlsConfig) -> io::Result<DtlsSocket> {
+    let certificate = read_certificates(config.certificate.as_deref())?;
+
+    let key = String::from_utf8(read(config.private_key.as_deref())?).map_err(invalid)?;
+    let key = KeyPair::from_pem(&key).map_err(invalid)?;
+
+    let mut dtls_config = DtlsConfig {
+        certificates: vec![Certificate {
+            certificate,
+            private_key: CryptoPrivateKey::from_key_pair(&key).map_err(invalid)?,
+        }],
+        extended_master_secret: ExtendedMasterSecretType::Require,
+        ..Default::default()
+    };
+
+    if let Some(path) = &config.client_ca {
+        dtls_config.client_auth = ClientAuthType::RequireAndVerifyClientCert;
+        dtls_config.client_cas = read_roots(path)?;
+    }
+
+    // Only a handshake starts an association, like in the DTLS listener.
+    let mut listen_config = ListenConfig {
+        accept_filter: Some(Box::new(|packet: &[u8]| {
+            let handshake = packet.first() == Some(&(ContentType::Handshake as u8));
+            Box::pin(async move { handshake })
+        })),
+        ..Default::default()
+    };
+
+    let associations = listen_config
+        .listen(addr.to_owned())
+        .await
+        .map_err(io::Error::other)?;
+
+    Ok(DtlsSocket {
+        associations: Arc::new(associations),
+        config: dtls_config,
+    })
+}
+
+/// Accepts DTLS associations and passes every record on as a datagram.
+/// Every association keeps its own templates, just like TCP connections.
+pub async fn receive_dtls(
+    socket: DtlsSocket,
+    listener: Arc<str>,
+    config: watch::Receiver<Arc<Config>>,
+    sender: mpsc::Sender<Datagram>,
+    mut shutdown: watch::Receiver<bool>,
+    metrics: Metrics,
+) {
+    let dropped = metrics
+        .datagrams_dropped
+        .get_or_create(&vec![("listener".to_owned(), listener.to_string())])
+        .clone();
+
+    loop {
+        let accepted = select! {
+            accepted = socket.associations.accept() => accepted,
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        };
+
+        let (conn, exporter) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(target: "listener", "Cannot accept DTLS association on {listener}: {e}");
+                continue;
+            }
+        };
+
+        if !config.borrow().ipfix.is_allowed(&exporter.ip()) {
+            dropped.inc();
+            let _ = conn.close().await;
+            continue;
+        }
+
+        let ipfix = &config.borrow().ipfix;
+        let handshake_timeout = ipfix.handshake_timeout;
+        let read_timeout = ipfix.read_timeout;
+        let buffer_size = ipfix.buffer_size;
+
+        let dtls_config = socket.config.clone();
+        let listener = listener.clone();
+        let sender = sender.clone();
+        let shutdown = shutdown.clone();
+
+        spawn(async move {
+            let handshake = DTLSConn::new(conn.clone(), dtls_config, false, None);
+
+            let accepted = match timeout(handshake_timeout, handshake).await {
+                Ok(accepted) => accepted.map_err(io::Error::other),
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+            };
+
+            match accepted {
+                Ok(dtls_conn) => {
+                    read_records(
+                        Arc::new(dtls_conn),
+                        exporter,
+                        listener,
+                        buffer_size,
+                        read_timeout,
+                        sender,
+                        shutdown,
+                    )
+                    .await
+                }
+                Err(e) => {
+                    warn!(target: "listener", "DTLS handshake with {exporter} on {listener} failed: {e}");
+                    let _ = conn.close().await;
+                }
+            }
+        });
+    }
+
+    let _ = socket.associations.close().await;
+}
+
+async fn read_records(
+    conn: Arc<dyn Conn + Send + Sync>,
+    exporter: SocketAddr,
+    listener: Arc<str>,
+    buffer_size: usize,
+    read_timeout: Duration,
+    sender: mpsc::Sender<Datagram>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut buf = vec![0; buffer_size];
+
+    loop {
+        // Associations of exporters that went away are never closed, so
+        // they and their templates go once nothing comes for a while.
+        let received = select! {
+            received = timeout(read_timeout, conn.recv(&mut buf)) => match received {
+                Ok(received) => received.map_err(io::Error::other),
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "no message in time")),
+            },
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        };
+
+        let size = match received {
+            Ok(size) => size,
+            Err(e) => {
+                warn!(target: "listener", "Dropping DTLS association with {exporter} on {listener}: {e}");
+                break;
+            }
+        };
+
+        let datagram = Datagram {
+            listener: listener.clone(),
+            exporter,
+            format: Format::Netflow,
+            data: buf[..size].to_vec(),
+        };
+
+        if sender.send(datagram).await.is_err() {
+            break;
+        }
+    }
+
+    let _ = conn.close().await;
+
+    let _ = sender
+        .send(Datagram {
+            listener,
+            exporter,
+            format: Format::Netflow,
+            data: vec![],
+        })
+        .await;
+}
+
+fn read(path: Option<&Path>) -> io::Result<Vec<u8>> {
+    // Presence is checked when the configuration is loaded.
+    let path = path.ok_or_else(|| invalid("certificate and private key are required"))?;
+
+    fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+}
+
+fn read_certificates(path: Option<&Path>) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates =
+        rustls_pemfile::certs(&mut read(path)?.as_slice()).collect::<Result<Vec<_>, _>>()?;
+
+    if certificates.is_empty() {
+        return Err(invalid("no certificates found"));
+    }
+
+    Ok(certificates)
+}
+
+fn read_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    for certificate in read_certificates(Some(path))? {
+        roots.add(certificate).map_err(invalid)?;
+    }
+
+    Ok(roots)
+}
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
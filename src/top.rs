@@ -0,0 +1,271 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::TopConfig, metrics::AppState};
+
+/// Bytes are summed over this long, so a window costs a fixed number of
+/// buckets however many flows there are.
+const BUCKET: Duration = Duration::from_secs(10);
+
+/// Bytes of devices, servers and protocols over the last `window`, for
+/// the top talkers on `/api/top` without a trip to ClickHouse.
+#[derive(Clone, Default)]
+pub struct Top {
+    talkers: Arc<Mutex<Talkers>>,
+}
+
+#[derive(Default)]
+struct Talkers {
+    window: Duration,
+    limit: usize,
+    /// When counting started, the window isn't full of buckets before.
+    since: Option<Instant>,
+    buckets: VecDeque<Bucket>,
+}
+
+struct Bucket {
+    start: Instant,
+    devices: HashMap<String, Total>,
+    servers: HashMap<IpAddr, Total>,
+    protocols: HashMap<&'static str, Total>,
+}
+
+#[derive(Clone, Default)]
+struct Total {
+    /// Device name or server domain, whatever the first flow had.
+    name: String,
+    received: u64,
+    sent: u64,
+}
+
+impl Top {
+    /// Forgets everything once disabled.
+    pub fn configure(&self, config: &TopConfig) {
+        let mut talkers = self.talkers.lock().unwrap();
+
+        talkers.window = match config.enabled {
+            true => config.window,
+            false => Duration::ZERO,
+        };
+        talkers.limit = config.limit;
+
+        talkers.since = match config.enabled {
+            true => Some(talkers.since.unwrap_or_else(Instant::now)),
+            false => None,
+        };
+
+        let window = talkers.window;
+        talkers.expire(window);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add(
+        &self,
+        mac: &str,
+        device: &str,
+        server: IpAddr,
+        domain: &str,
+        protocol: &'static str,
+        bytes: u64,
+        is_download: bool,
+    ) {
+        let mut talkers = self.talkers.lock().unwrap();
+
+        if talkers.window.is_zero() {
+            return;
+        }
+
+        if talkers
+            .buckets
+            .back()
+            .is_none_or(|bucket| bucket.start.elapsed() >= BUCKET)
+        {
+            let window = talkers.window;
+            talkers.expire(window);
+
+            talkers.buckets.push_back(Bucket {
+                start: Instant::now(),
+                devices: HashMap::new(),
+                servers: HashMap::new(),
+                protocols: HashMap::new(),
+            });
+        }
+
+        let bucket = talkers.buckets.back_mut().unwrap();
+
+        count(
+            &mut bucket.devices,
+            mac.to_owned(),
+            device,
+            bytes,
+            is_download,
+        );
+        count(&mut bucket.servers, server, domain, bytes, is_download);
+        count(&mut bucket.protocols, protocol, "", bytes, is_download);
+    }
+
+    /// Devices or servers with the most bytes over the window, which is
+    /// at most `window` of the config, along with the window itself and
+    /// the part of it that was counted.
+    fn top(&self, by: By, window: Option<Duration>, limit: Option<usize>) -> Option<Listing> {
+        let talkers = self.talkers.lock().unwrap();
+
+        if talkers.window.is_zero() {
+            return None;
+        }
+
+        let window = window.unwrap_or(talkers.window).min(talkers.window);
+        let limit = limit.unwrap_or(talkers.limit);
+
+        // Right after the start, rates over all of the window would come
+        // out too low.
+        let counted = talkers
+            .since
+            .map_or(window, |since| since.elapsed().min(window));
+
+        let buckets = talkers
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.start.elapsed() < window);
+
+        let mut talkers = match by {
+            By::Mac => sum(buckets.map(|bucket| &bucket.devices)),
+            By::Server => sum(buckets.map(|bucket| &bucket.servers)),
+            By::Protocol => sum(buckets.map(|bucket| &bucket.protocols)),
+        };
+
+        talkers.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.key.cmp(&b.key)));
+        talkers.truncate(limit);
+
+        Some(Listing {
+            by,
+            window: humantime::format_duration(window).to_string(),
+            seconds: counted.as_secs().max(1),
+            talkers,
+        })
+    }
+}
+
+impl Talkers {
+    fn expire(&mut self, window: Duration) {
+        while self
+            .buckets
+            .front()
+            .is_some_and(|bucket| bucket.start.elapsed() >= window)
+        {
+            self.buckets.pop_front();
+        }
+    }
+}
+
+fn count<K: Eq + Hash>(
+    totals: &mut HashMap<K, Total>,
+    key: K,
+    name: &str,
+    bytes: u64,
+    is_download: bool,
+) {
+    let total = totals.entry(key).or_default();
+
+    if total.name.is_empty() {
+        name.clone_into(&mut total.name);
+    }
+
+    match is_download {
+        true => total.received += bytes,
+        false => total.sent += bytes,
+    }
+}
+
+fn sum<'a, K: Eq + Hash + ToString + 'a>(
+    buckets: impl Iterator<Item = &'a HashMap<K, Total>>,
+) -> Vec<Talker> {
+    let mut totals = HashMap::<&K, Total>::new();
+
+    for bucket in buckets {
+        for (key, total) in bucket {
+            let sum = totals.entry(key).or_default();
+
+            if sum.name.is_empty() {
+                sum.name.clone_from(&total.name);
+            }
+
+            sum.received += total.received;
+            sum.sent += total.sent;
+        }
+    }
+
+    totals
+        .into_iter()
+        .map(|(key, total)| Talker {
+            key: key.to_string(),
+            name: total.name,
+            bytes: total.received + total.sent,
+            received: total.received,
+            sent: total.sent,
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum By {
+    /// Devices by their MAC.
+    #[default]
+    Mac,
+    /// Remote addresses.
+    Server,
+    /// One of `tcp`, `udp`, `icmp` or `other`.
+    Protocol,
+}
+
+#[derive(Deserialize)]
+pub struct Params {
+    #[serde(default)]
+    by: By,
+    #[serde(default, with = "humantime_serde")]
+    window: Option<Duration>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Listing {
+    by: By,
+    window: String,
+    /// Seconds of the window that were counted, for rates.
+    seconds: u64,
+    talkers: Vec<Talker>,
+}
+
+#[derive(Serialize)]
+struct Talker {
+    /// MAC of the device, address of the server or the protocol.
+    key: String,
+    /// Name of the device or domain of the server, empty if unknown.
+    name: String,
+    bytes: u64,
+    received: u64,
+    sent: u64,
+}
+
+/// Serves the top devices or servers by bytes as JSON, like
+/// `/api/top?window=5m&by=mac&limit=10`.
+pub async fn handler(State(state): State<Arc<AppState>>, Query(params): Query<Params>) -> Response {
+    match state.top.top(params.by, params.window, params.limit) {
+        Some(listing) => Json(listing).into_response(),
+        None => (StatusCode::NOT_FOUND, "Top talkers are disabled\n").into_response(),
+    }
+}
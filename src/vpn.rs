@@ -0,0 +1,152 @@
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc, time::Duration};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::{
+    select,
+    sync::watch,
+    time::{sleep, timeout},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    config::Config,
+    prefixes::Prefixes,
+    sink::{http, Error},
+    threats,
+};
+
+/// Name of Tor relays in flows.
+pub const TOR: &str = "tor";
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Relays and lists that cannot be read are tried again sooner than `refresh`.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Addresses of Tor relays and VPN servers, with the name of the VPN.
+#[derive(Default)]
+pub struct Endpoints {
+    networks: Prefixes<Arc<str>>,
+}
+
+impl Endpoints {
+    fn new(fetched: &BTreeMap<String, Vec<IpNet>>) -> Self {
+        let mut networks = Prefixes::default();
+
+        for (name, endpoints) in fetched {
+            let name = Arc::<str>::from(name.as_str());
+
+            for network in endpoints {
+                networks.add(*network, name.clone());
+            }
+        }
+
+        Self { networks }
+    }
+
+    pub fn len(&self) -> usize {
+        self.networks.len()
+    }
+
+    /// Name of the VPN with a server at the address, if any.
+    pub fn vpn(&self, addr: IpAddr) -> Option<&str> {
+        self.networks.get(addr).map(|name| &**name)
+    }
+}
+
+/// Reads the Tor relays and VPN lists every `refresh`. Clients connect to
+/// relays rather than the exits that the Tor exit list has, so all running
+/// relays are taken. The last good copy is kept of what cannot be read.
+pub async fn poll(
+    mut config: watch::Receiver<Arc<Config>>,
+    endpoints: watch::Sender<Arc<Endpoints>>,
+) {
+    let mut fetched = BTreeMap::new();
+
+    loop {
+        let current = config.borrow().vpn.clone();
+
+        let mut interval = current.refresh;
+
+        fetched.retain(|name: &String, _| {
+            current.enabled
+                && match name.as_str() {
+                    TOR => !current.tor.is_empty(),
+                    name => current.lists.contains_key(name),
+                }
+        });
+
+        if current.enabled {
+            let tor = (!current.tor.is_empty()).then_some((TOR, &current.tor));
+            let lists = current
+                .lists
+                .iter()
+                .map(|(name, source)| (name.as_str(), source));
+
+            for (name, source) in tor.into_iter().chain(lists) {
+                let request = async {
+                    match name {
+                        TOR => relays(source).await,
+                        _ => threats::fetch(source).await,
+                    }
+                };
+
+                match timeout(FETCH_TIMEOUT, request).await {
+                    Ok(Ok(networks)) => {
+                        debug!(target: "vpn", "Read {} endpoints of {name} from {source}", networks.len());
+
+                        fetched.insert(name.to_owned(), networks);
+                    }
+                    Ok(Err(e)) => {
+                        warn!(target: "vpn", "Cannot read {name} from {source}: {e}");
+                        interval = interval.min(RETRY_INTERVAL);
+                    }
+                    Err(_) => {
+                        warn!(target: "vpn", "Timed out reading {name} from {source}");
+                        interval = interval.min(RETRY_INTERVAL);
+                    }
+                }
+            }
+        }
+
+        if !fetched.is_empty() || endpoints.borrow().len() > 0 {
+            endpoints.send_replace(Arc::new(Endpoints::new(&fetched)));
+        }
+
+        select! {
+            _ = sleep(interval) => {}
+            Ok(_) = config.wait_for(|config| config.vpn != current) => {}
+        }
+    }
+}
+
+/// Addresses of relays in a summary document of Onionoo, see
+/// https://metrics.torproject.org/onionoo.html.
+async fn relays(url: &str) -> Result<Vec<IpNet>, Error> {
+    let body = http::get(url, &[], None).await?;
+    let summary = serde_json::from_slice::<OnionooSummary>(&body)?;
+
+    let relays = summary
+        .relays
+        .iter()
+        .flat_map(|relay| &relay.a)
+        // IPv6 addresses are in brackets.
+        .filter_map(|addr| addr.trim_matches(['[', ']']).parse::<IpAddr>().ok())
+        .map(IpNet::from)
+        .collect();
+
+    Ok(relays)
+}
+
+#[derive(Deserialize)]
+struct OnionooSummary {
+    relays: Vec<OnionooRelay>,
+}
+
+#[derive(Deserialize)]
+struct OnionooRelay {
+    /// Addresses the relay listens on.
+    #[serde(default)]
+    a: Vec<String>,
+}
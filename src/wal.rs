@@ -0,0 +1,440 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    listener::{Datagram, Format},
+    segments::Segments,
+};
+
+/// Received datagrams, appended before they're parsed and removed once
+/// the sinks confirm that their records are stored. Whatever is left after
+/// a crash is replayed on the next start, so flows may be stored twice,
+/// but are not lost. Datagrams are kept in a directory of segments
+/// numbered in the order they were written.
+///
+/// NetFlow v9 and IPFIX data can't be parsed without its template, which
+/// may have come in a datagram that is gone already, so the last datagram
+/// with every template is kept aside and replayed first.
+pub struct Wal {
+    directory: PathBuf,
+    segments: Segments,
+    /// Leading segments whose records didn't make it to every sink,
+    /// kept until they're replayed on the next start.
+    retained: usize,
+    /// The last datagram with every template, encoded, in the order they
+    /// came, as later ones may redefine templates.
+    templates: HashMap<Template, (u64, Arc<[u8]>)>,
+    /// Datagrams with templates seen so far.
+    sequence: u64,
+    /// Whether the templates changed since they were saved.
+    changed: bool,
+}
+
+/// Template of an exporter: the listener, the exporter, the observation
+/// domain, the set the template came in and its id.
+type Template = (Arc<str>, SocketAddr, u32, u16, u16);
+
+/// Observation domain, set and id of a template within a datagram.
+type TemplateId = (u32, u16, u16);
+
+impl Wal {
+    /// Opens the log, returning the datagrams left over from previous runs,
+    /// the ones with templates first. Their segments go with the first
+    /// successful checkpoint.
+    pub fn open(directory: &Path, max_bytes: u64) -> io::Result<(Self, Vec<Datagram>)> {
+        let segments = Segments::open(directory, "wal", max_bytes)?;
+
+        let mut wal = Self {
+            directory: directory.to_owned(),
+            segments,
+            retained: 0,
+            templates: HashMap::new(),
+            sequence: 0,
+            changed: false,
+        };
+
+        let mut datagrams = match fs::read(wal.templates_path()) {
+            Ok(data) => wal.decode_all(&data, &wal.templates_path()),
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+
+        for datagram in &datagrams {
+            wal.remember(datagram);
+        }
+
+        wal.changed = false;
+
+        let templates = datagrams.len();
+
+        for number in wal.segments.numbers() {
+            let data = wal.segments.read(number)?;
+
+            for datagram in wal.decode_all(&data, &wal.segments.path(number)) {
+                wal.remember(&datagram);
+                datagrams.push(datagram);
+            }
+        }
+
+        if datagrams.len() > templates {
+            info!(
+                target: "wal",
+                "Replaying {} datagrams from {}",
+                datagrams.len() - templates,
+                directory.display()
+            );
+        }
+
+        Ok((wal, datagrams))
+    }
+
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.segments.set_max_bytes(max_bytes);
+    }
+
+    fn templates_path(&self) -> PathBuf {
+        self.directory.join("templates")
+    }
+
+    /// Appends a datagram to the last segment, starting a new one when it's full.
+    pub fn append(&mut self, datagram: &Datagram) -> io::Result<()> {
+        let data = encode(datagram);
+
+        self.remember(datagram);
+
+        let removed = self.segments.append(&data)?;
+
+        for path in &removed {
+            warn!(
+                target: "wal",
+                "Log is over {} bytes, dropped {}",
+                self.segments.max_bytes(),
+                path.display()
+            );
+        }
+
+        if !removed.is_empty() {
+            self.retained = self.retained.saturating_sub(removed.len());
+
+            // Templates of the dropped segments are only kept aside now.
+            self.save_templates()?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the datagrams appended so far once the sinks have stored
+    /// their records, or keeps them for the next start if they haven't.
+    /// Either way, the templates are kept aside first.
+    pub fn checkpoint(&mut self, stored: bool) -> io::Result<()> {
+        self.segments.close()?;
+        self.save_templates()?;
+
+        if !stored {
+            if self.retained < self.segments.len() {
+                warn!(
+                    target: "wal",
+                    "Keeping {} segments to replay on the next start",
+                    self.segments.len() - self.retained
+                );
+            }
+
+            self.retained = self.segments.len();
+
+            return Ok(());
+        }
+
+        for number in self.segments.numbers().split_off(self.retained) {
+            self.segments.remove(number)?;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the templates of the datagram, without its data so that it's
+    /// not stored again, or forgets the templates of a closed TCP connection.
+    fn remember(&mut self, datagram: &Datagram) {
+        if datagram.data.is_empty() {
+            let before = self.templates.len();
+
+            self.templates.retain(|(listener, exporter, ..), _| {
+                *listener != datagram.listener || *exporter != datagram.exporter
+            });
+
+            self.changed |= self.templates.len() != before;
+
+            return;
+        }
+
+        if matches!(datagram.format, Format::Sflow) {
+            return;
+        }
+
+        let Some((ids, templates)) = templates(&datagram.data) else {
+            return;
+        };
+
+        let data = Arc::<[u8]>::from(encode(&Datagram {
+            listener: datagram.listener.clone(),
+            exporter: datagram.exporter,
+            format: datagram.format,
+            data: templates,
+        }));
+
+        for (domain, set, id) in ids {
+            let template = (
+                datagram.listener.clone(),
+                datagram.exporter,
+                domain,
+                set,
+                id,
+            );
+
+            self.templates
+                .insert(template, (self.sequence, data.clone()));
+        }
+
+        self.sequence += 1;
+        self.changed = true;
+    }
+
+    /// Writes out the datagrams with templates, replacing the previous
+    /// ones only once they're on the disk.
+    fn save_templates(&mut self) -> io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        let mut templates = self.templates.values().collect::<Vec<_>>();
+
+        templates.sort_unstable_by_key(|(sequence, _)| *sequence);
+        templates.dedup_by_key(|(sequence, _)| *sequence);
+
+        let path = self.templates_path();
+        let mut file = File::create(path.with_extension("tmp"))?;
+
+        for (_, data) in templates {
+            file.write_all(data)?;
+        }
+
+        file.sync_data()?;
+
+        fs::rename(path.with_extension("tmp"), path)?;
+
+        self.changed = false;
+
+        Ok(())
+    }
+
+    fn decode_all(&self, data: &[u8], path: &Path) -> Vec<Datagram> {
+        let mut datagrams = vec![];
+        let mut rest = data;
+
+        while !rest.is_empty() {
+            // A datagram cut short by a crash is not worth the whole segment.
+            let Some((datagram, remaining)) = decode(rest) else {
+                warn!(
+                    target: "wal",
+                    "Skipping {} bytes at the end of {}",
+                    rest.len(),
+                    path.display()
+                );
+                break;
+            };
+
+            datagrams.push(datagram);
+            rest = remaining;
+        }
+
+        datagrams
+    }
+}
+
+/// Templates and options templates in a NetFlow v9 or IPFIX datagram,
+/// along with the datagram with only the sets of templates, if there are any.
+fn templates(data: &[u8]) -> Option<(Vec<TemplateId>, Vec<u8>)> {
+    let u16_at = |offset: usize| {
+        data.get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+
+    let u32_at = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    // Offsets of the first set and of the observation domain or source id.
+    let (version, header, domain) = match u16_at(0) {
+        Some(9) => (9, 20, u32_at(16)?),
+        Some(10) => (10, 16, u32_at(12)?),
+        _ => return None,
+    };
+
+    // End of the IPFIX field specifiers starting at the offset.
+    let fields = |mut offset: usize, count: u16| {
+        for _ in 0..count {
+            offset += match u16_at(offset)? & 0x8000 {
+                0 => 4,
+                _ => 8,
+            };
+        }
+
+        Some(offset)
+    };
+
+    let mut ids = vec![];
+    let mut sets = data.get(..header)?.to_vec();
+    let mut offset = header;
+
+    while let (Some(set), Some(length)) = (u16_at(offset), u16_at(offset + 2)) {
+        let length = length as usize;
+
+        if length < 4 {
+            break;
+        }
+
+        let end = (offset + length).min(data.len());
+        let mut record = offset + 4;
+
+        // Sets 0 and 1 of NetFlow v9 or 2 and 3 of IPFIX.
+        let is_template = match version {
+            9 => set <= 1,
+            _ => set == 2 || set == 3,
+        };
+
+        if is_template {
+            sets.extend_from_slice(&data[offset..end]);
+        }
+
+        // Anything shorter than a record at the end of a set is padding.
+        while is_template && record + 4 <= end {
+            let (Some(id), Some(count)) = (u16_at(record), u16_at(record + 2)) else {
+                break;
+            };
+
+            if id < 256 {
+                break;
+            }
+
+            ids.push((domain, set, id));
+
+            let next = match set {
+                0 => Some(record + 4 + count as usize * 4),
+                1 => {
+                    u16_at(record + 4).map(|options| record + 6 + count as usize + options as usize)
+                }
+                2 => fields(record + 4, count),
+                _ => fields(record + 6, count),
+            };
+
+            match next {
+                Some(next) if next > record => record = next,
+                _ => break,
+            }
+        }
+
+        offset += length;
+    }
+
+    if ids.is_empty() {
+        return None;
+    }
+
+    // The count of records of NetFlow v9 or the length of IPFIX.
+    let size = match version {
+        9 => ids.len(),
+        _ => sets.len(),
+    };
+
+    sets[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+
+    Some((ids, sets))
+}
+
+/// Datagrams are written with their length in front, followed by the
+/// format, the exporter address and port, the listener name and the data.
+fn encode(datagram: &Datagram) -> Vec<u8> {
+    let mut body = vec![match datagram.format {
+        Format::Netflow => 0,
+        Format::Sflow => 1,
+    }];
+
+    match datagram.exporter.ip() {
+        IpAddr::V4(ip) => {
+            body.push(4);
+            body.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            body.push(6);
+            body.extend(ip.octets());
+        }
+    }
+
+    body.extend(datagram.exporter.port().to_le_bytes());
+    body.extend((datagram.listener.len() as u16).to_le_bytes());
+    body.extend(datagram.listener.as_bytes());
+    body.extend(&datagram.data);
+
+    let mut data = (body.len() as u32).to_le_bytes().to_vec();
+    data.extend(body);
+
+    data
+}
+
+fn decode(data: &[u8]) -> Option<(Datagram, &[u8])> {
+    let (length, rest) = data.split_first_chunk::<4>()?;
+    let length = u32::from_le_bytes(*length) as usize;
+
+    if rest.len() < length {
+        return None;
+    }
+
+    let (body, rest) = rest.split_at(length);
+
+    let (&[format, family], body) = body.split_first_chunk::<2>()?;
+
+    let format = match format {
+        0 => Format::Netflow,
+        1 => Format::Sflow,
+        _ => return None,
+    };
+
+    let (ip, body) = match family {
+        4 => {
+            let (octets, body) = body.split_first_chunk::<4>()?;
+            (IpAddr::V4(Ipv4Addr::from(*octets)), body)
+        }
+        6 => {
+            let (octets, body) = body.split_first_chunk::<16>()?;
+            (IpAddr::V6(Ipv6Addr::from(*octets)), body)
+        }
+        _ => return None,
+    };
+
+    let (port, body) = body.split_first_chunk::<2>()?;
+    let (listener_length, body) = body.split_first_chunk::<2>()?;
+    let listener_length = u16::from_le_bytes(*listener_length) as usize;
+
+    if body.len() < listener_length {
+        return None;
+    }
+
+    let (listener, body) = body.split_at(listener_length);
+
+    let datagram = Datagram {
+        listener: Arc::from(std::str::from_utf8(listener).ok()?),
+        exporter: SocketAddr::new(ip, u16::from_le_bytes(*port)),
+        format,
+        data: body.to_vec(),
+    };
+
+    Some((datagram, rest))
+}